@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+
+use codecrafters_redis::common::unix_time_ms;
+use codecrafters_redis::parser::rdb::{RdbDecoder, RdbEntry, RdbError, RdbSummary, RdbValue};
+use codecrafters_redis::persistence::export::{command, entry_commands, entry_json, json_string};
+
+/// Checks RDB files and converts them to other formats, without a running server
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Validates the structure and checksum of the file
+    Check { file: PathBuf },
+    /// Prints the type, size and expiry of every key, then totals per type
+    Stats { file: PathBuf },
+    /// Converts the file to a JSON array with an object per key
+    Json { file: PathBuf },
+    /// Converts the file to the RESP commands recreating its dataset
+    Resp { file: PathBuf },
+}
+
+/// Number of elements of a value and the bytes they take, not counting any overhead
+fn value_size(value: &RdbValue) -> (usize, usize) {
+    match value {
+        RdbValue::String(value) => (1, value.len()),
+        RdbValue::List(elements) | RdbValue::Set(elements) => {
+            (elements.len(), elements.iter().map(Vec::len).sum())
+        }
+        RdbValue::SortedSet(members) => (
+            members.len(),
+            members.iter().map(|(member, _)| member.len() + 8).sum(),
+        ),
+        RdbValue::Hash(fields) => (
+            fields.len(),
+            fields
+                .iter()
+                .map(|(field, value)| field.len() + value.len())
+                .sum(),
+        ),
+        RdbValue::Stream(stream) => (
+            stream.entries.len(),
+            stream
+                .entries
+                .iter()
+                .flat_map(|entry| &entry.fields)
+                .map(|(field, value)| field.len() + value.len())
+                .sum::<usize>()
+                + stream.entries.len() * 16,
+        ),
+        RdbValue::Module { .. } => (0, 0),
+    }
+}
+
+impl Mode {
+    fn file(&self) -> &Path {
+        match self {
+            Mode::Check { file }
+            | Mode::Stats { file }
+            | Mode::Json { file }
+            | Mode::Resp { file } => file,
+        }
+    }
+}
+
+/// Decodes the file, handing every key to `on_entry`. Output errors stop the output
+/// but not the decoding, and are reported once it is done.
+fn decode_file(
+    reader: impl Read,
+    mut on_entry: impl FnMut(RdbEntry) -> io::Result<()>,
+) -> Result<RdbSummary, RdbError> {
+    let mut output_error = None;
+    let summary = RdbDecoder::new(reader).decode(|entry| {
+        if output_error.is_none() {
+            output_error = on_entry(entry).err();
+        }
+    })?;
+    match output_error {
+        Some(err) => Err(RdbError::Io(err)),
+        None => Ok(summary),
+    }
+}
+
+fn check(path: &Path, reader: impl Read) -> Result<(), RdbError> {
+    println!("[info] Checking RDB file {path:?}");
+    let now_ms = unix_time_ms();
+    let (mut keys, mut expires, mut already_expired) = (0, 0, 0);
+    let result = decode_file(reader, |entry| {
+        keys += 1;
+        if let Some(expire_at_ms) = entry.expire_at_ms {
+            expires += 1;
+            if expire_at_ms <= now_ms {
+                already_expired += 1;
+            }
+        }
+        Ok(())
+    });
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(err) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("[error] {err}");
+            println!("[info] {keys} keys read before the error");
+            return Err(err);
+        }
+    };
+
+    println!("[info] RDB version {}", summary.version);
+    for (key, value) in &summary.aux {
+        println!(
+            "[info] AUX FIELD {} = {}",
+            json_string(key),
+            json_string(value)
+        );
+    }
+    println!("[info] {} function libraries", summary.functions.len());
+    println!("[info] {keys} keys read");
+    println!("[info] {expires} expires");
+    println!("[info] {already_expired} already expired");
+    if summary.checksum == 0 {
+        println!("[info] Checksum disabled when the file was written");
+    } else {
+        println!("[info] Checksum OK ({:#018x})", summary.checksum);
+    }
+    println!("[info] RDB looks OK!");
+    Ok(())
+}
+
+#[derive(Default)]
+struct TypeTotals {
+    keys: usize,
+    elements: usize,
+    bytes: usize,
+    expires: usize,
+}
+
+fn stats(reader: impl Read, output: &mut impl Write) -> Result<(), RdbError> {
+    let mut totals = BTreeMap::<String, TypeTotals>::new();
+    writeln!(output, "db\ttype\telements\tbytes\texpire_at_ms\tkey")?;
+    decode_file(reader, |entry| {
+        let (elements, bytes) = value_size(&entry.value);
+        let type_name = entry.value.type_name();
+        let totals = totals.entry(type_name.to_string()).or_default();
+        totals.keys += 1;
+        totals.elements += elements;
+        totals.bytes += bytes;
+        totals.expires += entry.expire_at_ms.is_some() as usize;
+        let expiry = entry
+            .expire_at_ms
+            .map_or("-".to_string(), |ms| ms.to_string());
+        writeln!(
+            output,
+            "{}\t{type_name}\t{elements}\t{bytes}\t{expiry}\t{}",
+            entry.db,
+            json_string(&entry.key)
+        )
+    })?;
+
+    writeln!(output)?;
+    writeln!(output, "type\tkeys\telements\tbytes\texpires")?;
+    for (type_name, totals) in &totals {
+        writeln!(
+            output,
+            "{type_name}\t{}\t{}\t{}\t{}",
+            totals.keys, totals.elements, totals.bytes, totals.expires
+        )?;
+    }
+    Ok(())
+}
+
+fn json(reader: impl Read, output: &mut impl Write) -> Result<(), RdbError> {
+    let mut separator = "\n";
+    write!(output, "[")?;
+    decode_file(reader, |entry| {
+        write!(output, "{separator}{}", entry_json(&entry))?;
+        separator = ",\n";
+        Ok(())
+    })?;
+    writeln!(output, "\n]")?;
+    Ok(())
+}
+
+fn resp(reader: impl Read, output: &mut impl Write) -> Result<(), RdbError> {
+    let mut current_db = 0;
+    decode_file(reader, |entry| {
+        if entry.db != current_db {
+            current_db = entry.db;
+            output.write_all(&command(&[b"SELECT", current_db.to_string().as_bytes()]))?;
+        }
+        if let RdbValue::Module { name, .. } = &entry.value {
+            eprintln!(
+                "skipping key {} holding a value of module {name}",
+                json_string(&entry.key)
+            );
+        }
+        output.write_all(&entry_commands(&entry))
+    })?;
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let path = args.mode.file();
+    let reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(err) => {
+            eprintln!("error: failed to open {path:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut output = BufWriter::new(io::stdout().lock());
+    let result = match &args.mode {
+        Mode::Check { .. } => check(path, reader),
+        Mode::Stats { .. } => stats(reader, &mut output),
+        Mode::Json { .. } => json(reader, &mut output),
+        Mode::Resp { .. } => resp(reader, &mut output),
+    };
+    // whatever was converted before an error is still written out
+    let flushed = output.flush();
+    let result = result.and_then(|()| Ok(flushed?));
+    match (result, &args.mode) {
+        (Ok(()), _) => ExitCode::SUCCESS,
+        // already reported along with the rest of the check
+        (Err(_), Mode::Check { .. }) => ExitCode::FAILURE,
+        (Err(err), _) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
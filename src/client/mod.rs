@@ -1,6 +1,11 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
 use crate::{
     network::connection::Connection,
-    parser::resp::{parse_buffer, ParseError, ParseResult},
+    parser::resp::{parse_buffer, ParseError, ParseResult, Token},
 };
 
 pub struct Client {
@@ -29,6 +34,35 @@ impl Client {
         Self { conn }
     }
 
+    /// Connects to `host:port`, giving up on connecting or on any reply after `timeout`
+    pub fn connect(host: &str, port: u16, timeout: Duration) -> Result<Self, ClientError> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| ClientError::ConnectionError(format!("could not resolve {host}")))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self::new(Connection::new(stream)))
+    }
+
+    /// Sends a command made of `args` and waits for its reply
+    pub fn request(&mut self, args: &[&str]) -> Result<Vec<Token>, ClientError> {
+        let args = args.iter().map(|arg| arg.as_bytes()).collect::<Vec<_>>();
+        self.request_binary(&args)
+    }
+
+    /// Same as `request`, for commands with arguments which are not valid UTF-8
+    pub fn request_binary(&mut self, args: &[&[u8]]) -> Result<Vec<Token>, ClientError> {
+        let command = Token::Array(
+            args.iter()
+                .map(|arg| Token::BulkString(arg.to_vec()))
+                .collect(),
+        );
+        self.conn.write_message(&command.serialize())?;
+        Ok(self.get_next_message()?.tokens)
+    }
+
     pub fn get_connection(&mut self) -> &mut Connection {
         &mut self.conn
     }
@@ -0,0 +1,117 @@
+use std::{
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+};
+
+use crate::{
+    network::connection::Connection,
+    parser::resp::{parse_buffer, ParseError},
+    server::data::Server,
+};
+
+use super::message::{BusMessage, MessageKind};
+
+/// Learns what another node tells us and answers with our own view of the cluster, a
+/// vote being the answer to a request for one
+fn handle_message(server: &Arc<Server>, message: &BusMessage) -> BusMessage {
+    let repl_offset = server.live_data.lock().unwrap().replication_offset();
+    let cluster = server
+        .cluster
+        .as_ref()
+        .expect("the cluster bus only runs in cluster mode");
+
+    let (reply, new_master) = {
+        let mut cluster = cluster.lock().unwrap();
+        let new_master = cluster.process_message(message, message.kind == MessageKind::Meet);
+        let kind = match message.kind {
+            MessageKind::AuthRequest => {
+                if cluster.grant_vote(&message.header, server.metadata.cluster_node_timeout) {
+                    MessageKind::AuthAck
+                } else {
+                    MessageKind::AuthDenied
+                }
+            }
+            _ => MessageKind::Pong,
+        };
+        cluster.save_if_needed();
+        let reply = cluster.message(kind, repl_offset, Some(&message.header.id));
+        (reply, new_master)
+    };
+    if let Some((host, port)) = new_master {
+        server.become_replica(host, port);
+    }
+    reply
+}
+
+fn handle_read_loop(conn: &mut Connection, server: &Arc<Server>) -> io::Result<()> {
+    let mut stream: TcpStream = conn.stream.try_clone()?;
+    loop {
+        match parse_buffer(conn.get_buffer()) {
+            Ok(result) => {
+                let message = BusMessage::parse(&result.tokens).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid cluster bus message")
+                })?;
+                let reply = handle_message(server, &message);
+                stream.write_all(&reply.to_resp_token().serialize())?;
+                conn.consume(result.len);
+            }
+            Err(ParseError::Incomplete) => conn.read_message()?,
+            Err(ParseError::Invalid) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid message format",
+                ));
+            }
+        }
+    }
+}
+
+pub(super) fn handle_connection(conn: &mut Connection, server: Arc<Server>) {
+    match handle_read_loop(conn, &server) {
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => {
+            eprintln!(
+                "ERROR: failed to handle cluster bus link with error {:?}",
+                &err
+            );
+        }
+        _ => println!("INFO: cluster bus link closed"),
+    }
+}
+
+/// Starts accepting the links other nodes of the cluster open to our bus port
+pub fn start(server: &Arc<Server>, host: &str) -> io::Result<()> {
+    let bus_port = server
+        .cluster
+        .as_ref()
+        .expect("the cluster bus only runs in cluster mode")
+        .lock()
+        .unwrap()
+        .myself()
+        .bus_port;
+    let listener = TcpListener::bind((host, bus_port))?;
+    println!(
+        "INFO: started cluster bus listener on {:?}",
+        listener.local_addr()
+    );
+
+    let server = server.clone();
+    std::thread::spawn(move || {
+        for incoming_stream in listener.incoming() {
+            match incoming_stream {
+                Ok(stream) => {
+                    let mut conn = Connection::new(stream);
+                    let server = server.clone();
+                    std::thread::spawn(move || handle_connection(&mut conn, server));
+                }
+                Err(error) => {
+                    eprintln!(
+                        "ERROR: failed to accept a cluster bus link with error {:?}",
+                        &error
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
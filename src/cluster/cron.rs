@@ -0,0 +1,520 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{client::Client, common::random_hex_id, server::data::Server};
+
+use super::{
+    message::{BusMessage, MessageKind},
+    state::{ClusterState, NodeRole},
+};
+
+/// How often the cluster cron runs
+const CLUSTER_CRON_INTERVAL: Duration = Duration::from_millis(100);
+/// Every node is pinged this often, or twice per node timeout if that is shorter
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Upper bound for connecting to a node and for waiting on its reply
+const BUS_REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+/// Delay before a replica of a failing master asks for votes, which leaves the FAIL
+/// message time to reach every master
+const ELECTION_DELAY_MS: u64 = 500;
+/// Upper bound for the random delay added on top, which keeps replicas noticing the
+/// failure at the same time from splitting the vote
+const MAX_ELECTION_JITTER_MS: u64 = 500;
+/// Extra delay per replica of the same master which is further in the replication
+/// stream than us, giving the best placed replica the first go
+const ELECTION_RANK_DELAY_MS: u64 = 1000;
+
+/// Links to the bus of other nodes, re-established on the next message after any
+/// failure so that a late reply is never mistaken for the next one
+#[derive(Default)]
+struct BusLinks {
+    clients: HashMap<(String, u16), Client>,
+}
+
+impl BusLinks {
+    /// Sends a message to the bus listening on `host:bus_port` and waits for the reply
+    fn send(&mut self, host: &str, bus_port: u16, message: &BusMessage) -> Option<BusMessage> {
+        let key = (host.to_string(), bus_port);
+        let mut client = match self.clients.remove(&key) {
+            Some(client) => client,
+            None => Client::connect(host, bus_port, BUS_REQUEST_TIMEOUT).ok()?,
+        };
+        let args = message.to_args();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        let reply = BusMessage::parse(&client.request(&args).ok()?).ok()?;
+        self.clients.insert(key, client);
+        Some(reply)
+    }
+}
+
+/// A replica's attempt at taking over from its failing master
+struct FailoverAttempt {
+    /// When to ask the masters for their vote
+    start_time: Instant,
+    /// Epoch in which we asked for votes, once we did
+    epoch: Option<u64>,
+}
+
+fn cluster(server: &Server) -> &Mutex<ClusterState> {
+    server
+        .cluster
+        .as_ref()
+        .expect("the cluster cron only runs in cluster mode")
+}
+
+/// Sends a message of the given kind to a node and learns what its reply tells.
+/// `receiver` is the id of the node, unknown when meeting it.
+fn exchange(
+    server: &Arc<Server>,
+    links: &mut BusLinks,
+    kind: MessageKind,
+    receiver: Option<&str>,
+    (host, bus_port): (&str, u16),
+) -> Option<BusMessage> {
+    let repl_offset = server.live_data.lock().unwrap().replication_offset();
+    let accept_unknown = kind == MessageKind::Meet;
+    let message = cluster(server)
+        .lock()
+        .unwrap()
+        .message(kind, repl_offset, receiver);
+    let reply = links.send(host, bus_port, &message)?;
+
+    let new_master = {
+        let mut cluster = cluster(server).lock().unwrap();
+        let new_master = cluster.process_message(&reply, accept_unknown);
+        cluster.save_if_needed();
+        new_master
+    };
+    if let Some((host, port)) = new_master {
+        server.become_replica(host, port);
+    }
+    Some(reply)
+}
+
+/// Ids and bus addresses of every other node matching `filter`
+fn other_nodes(
+    server: &Server,
+    filter: impl Fn(&ClusterState, &str) -> bool,
+) -> Vec<(String, String, u16)> {
+    let cluster = cluster(server).lock().unwrap();
+    cluster
+        .nodes
+        .values()
+        .filter(|node| node.id != cluster.myself && filter(&cluster, &node.id))
+        .map(|node| (node.id.clone(), node.host.clone(), node.bus_port))
+        .collect()
+}
+
+fn broadcast(server: &Arc<Server>, links: &mut BusLinks, kind: MessageKind) {
+    for (id, host, bus_port) in other_nodes(server, |_, _| true) {
+        exchange(server, links, kind.clone(), Some(&id), (&host, bus_port));
+    }
+}
+
+fn meet_nodes(server: &Arc<Server>, links: &mut BusLinks) {
+    let pending_meets = cluster(server).lock().unwrap().take_pending_meets();
+    for (host, bus_port) in pending_meets {
+        let address = (host.as_str(), bus_port);
+        if exchange(server, links, MessageKind::Meet, None, address).is_none() {
+            eprintln!("ERROR: failed to meet the node with bus port {host}:{bus_port}");
+        }
+    }
+}
+
+fn ping_nodes(
+    server: &Arc<Server>,
+    links: &mut BusLinks,
+    last_ping: &mut HashMap<String, Instant>,
+) {
+    let interval = PING_INTERVAL.min(server.metadata.cluster_node_timeout / 2);
+    let nodes = other_nodes(server, |_, _| true);
+    last_ping.retain(|id, _| nodes.iter().any(|(node_id, _, _)| node_id == id));
+    for (id, host, bus_port) in nodes {
+        if last_ping
+            .get(&id)
+            .is_some_and(|time| time.elapsed() < interval)
+        {
+            continue;
+        }
+        last_ping.insert(id.clone(), Instant::now());
+        exchange(
+            server,
+            links,
+            MessageKind::Ping,
+            Some(&id),
+            (&host, bus_port),
+        );
+    }
+}
+
+/// Flags the nodes which stopped answering, and tells everyone about those a majority
+/// of masters agree are failing
+fn detect_failures(server: &Arc<Server>, links: &mut BusLinks) {
+    let failed = {
+        let mut cluster = cluster(server).lock().unwrap();
+        let failed = cluster.check_failures(server.metadata.cluster_node_timeout);
+        cluster.save_if_needed();
+        failed
+    };
+    for node_id in failed {
+        broadcast(server, links, MessageKind::Fail(node_id));
+    }
+}
+
+fn random_election_jitter() -> u64 {
+    let random = u64::from_str_radix(&random_hex_id(8), 16).unwrap_or(0);
+    random % MAX_ELECTION_JITTER_MS
+}
+
+/// Asks the masters serving slots for their vote in a new epoch, and takes over the
+/// slots of our master once a majority of them agree
+fn run_election(server: &Arc<Server>, links: &mut BusLinks) -> u64 {
+    let (epoch, votes_needed) = {
+        let mut cluster = cluster(server).lock().unwrap();
+        let epoch = cluster.start_election();
+        cluster.save_if_needed();
+        (epoch, cluster.votes_needed())
+    };
+    println!("INFO: starting a failover election for epoch {epoch}");
+
+    let voters = other_nodes(server, |cluster, id| {
+        cluster.nodes[id].is_master() && !cluster.slot_ranges(id).is_empty()
+    });
+    let mut votes = 0;
+    for (id, host, bus_port) in voters {
+        let reply = exchange(
+            server,
+            links,
+            MessageKind::AuthRequest,
+            Some(&id),
+            (&host, bus_port),
+        );
+        if reply.is_some_and(|reply| reply.kind == MessageKind::AuthAck) {
+            votes += 1;
+        }
+    }
+    if votes < votes_needed {
+        println!(
+            "INFO: failover election for epoch {epoch} got {votes} of the {votes_needed} \
+             votes needed"
+        );
+        return epoch;
+    }
+
+    server.become_master();
+    {
+        let mut cluster = cluster(server).lock().unwrap();
+        cluster.promote_myself(epoch);
+        cluster.save_if_needed();
+    }
+    // let everyone know about our claim straight away
+    broadcast(server, links, MessageKind::Pong);
+    epoch
+}
+
+/// Takes over from our master once it is agreed to be failing. The election is delayed
+/// by our rank among its replicas, and retried in a new epoch if it did not succeed.
+fn replica_failover(
+    server: &Arc<Server>,
+    links: &mut BusLinks,
+    attempt: &mut Option<FailoverAttempt>,
+) {
+    let repl_offset = server.live_data.lock().unwrap().replication_offset();
+    let rank = {
+        let cluster = cluster(server).lock().unwrap();
+        if cluster.failing_master().is_none() {
+            *attempt = None;
+            return;
+        }
+        cluster.failover_rank(repl_offset)
+    };
+
+    let node_timeout = server.metadata.cluster_node_timeout;
+    let retry_after = (node_timeout * 4).max(Duration::from_secs(4));
+    if attempt
+        .as_ref()
+        .is_some_and(|attempt| attempt.start_time.elapsed() > retry_after)
+    {
+        *attempt = None;
+    }
+    let attempt = attempt.get_or_insert_with(|| {
+        let delay =
+            ELECTION_DELAY_MS + random_election_jitter() + rank as u64 * ELECTION_RANK_DELAY_MS;
+        println!(
+            "INFO: start of election delayed for {delay} ms (rank #{rank}, offset {repl_offset})"
+        );
+        FailoverAttempt {
+            start_time: Instant::now() + Duration::from_millis(delay),
+            epoch: None,
+        }
+    });
+    if attempt.epoch.is_some() || Instant::now() < attempt.start_time {
+        return;
+    }
+    attempt.epoch = Some(run_election(server, links));
+}
+
+/// Picks up replication from the master recorded in the config, if we are a replica
+fn resume_replication(server: &Arc<Server>) {
+    let master = {
+        let cluster = cluster(server).lock().unwrap();
+        match &cluster.myself().role {
+            NodeRole::Replica(master_id) => cluster
+                .nodes
+                .get(master_id)
+                .map(|master| (master.host.clone(), master.port)),
+            NodeRole::Master => None,
+        }
+    };
+    if let Some((host, port)) = master {
+        server.become_replica(host, port);
+    }
+}
+
+/// Starts the thread which keeps in touch with the other nodes of the cluster
+pub fn start(server: Arc<Server>) {
+    std::thread::spawn(move || {
+        resume_replication(&server);
+
+        let mut links = BusLinks::default();
+        let mut last_ping = HashMap::new();
+        let mut failover_attempt = None;
+        loop {
+            let started = Instant::now();
+            meet_nodes(&server, &mut links);
+            ping_nodes(&server, &mut links, &mut last_ping);
+            detect_failures(&server, &mut links);
+            replica_failover(&server, &mut links, &mut failover_attempt);
+            std::thread::sleep(CLUSTER_CRON_INTERVAL.saturating_sub(started.elapsed()));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, TcpListener, TcpStream},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        cluster::{bus, message::NodeHealth, state::BUS_PORT_OFFSET},
+        network::connection::Connection,
+        persistence::tests::test_dir,
+        server::{
+            config::Config,
+            data::tests::replica_of_nowhere,
+            metadata::{MasterInfo, ReplicaInfo, ServerMetadata},
+        },
+    };
+
+    const NODE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// A node whose bus answers in the background until it is taken down
+    struct TestNode {
+        server: Arc<Server>,
+        links: BusLinks,
+        down: Arc<AtomicBool>,
+        streams: Arc<Mutex<Vec<TcpStream>>>,
+    }
+
+    impl TestNode {
+        fn start(name: &str, slots: &[i64], replica_info: ReplicaInfo) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let bus_port = listener.local_addr().unwrap().port();
+            let dir = test_dir(&format!("cluster-{name}"));
+
+            let mut state = ClusterState::new("127.0.0.1", 0, dir.join("nodes.conf"));
+            let myself = state.myself.clone();
+            let node = state.nodes.get_mut(&myself).unwrap();
+            node.port = bus_port - BUS_PORT_OFFSET;
+            node.bus_port = bus_port;
+            state.add_slots(slots).unwrap();
+
+            let timeout = NODE_TIMEOUT.as_millis().to_string();
+            let config = Config::parse_from(["redis", "--cluster-node-timeout", &timeout]);
+            let metadata = ServerMetadata::generate(&config);
+            let server = Arc::new(Server::new(metadata, replica_info, Some(state), None));
+
+            let down = Arc::new(AtomicBool::new(false));
+            let streams = Arc::new(Mutex::new(Vec::new()));
+            {
+                let (server, down, streams) = (server.clone(), down.clone(), streams.clone());
+                std::thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        if down.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        streams.lock().unwrap().push(stream.try_clone().unwrap());
+                        let server = server.clone();
+                        std::thread::spawn(move || {
+                            bus::handle_connection(&mut Connection::new(stream), server)
+                        });
+                    }
+                });
+            }
+            Self {
+                server,
+                links: BusLinks::default(),
+                down,
+                streams,
+            }
+        }
+
+        fn id(&self) -> String {
+            cluster(&self.server).lock().unwrap().myself.clone()
+        }
+
+        fn bus_address(&self) -> (String, u16) {
+            let cluster = cluster(&self.server).lock().unwrap();
+            ("127.0.0.1".to_string(), cluster.myself().bus_port)
+        }
+
+        /// Stops answering on the bus, dropping the links already open
+        fn take_down(&self) {
+            self.down.store(true, Ordering::SeqCst);
+            for stream in self.streams.lock().unwrap().drain(..) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        fn bring_up(&self) {
+            self.down.store(false, Ordering::SeqCst);
+        }
+
+        fn meet(&mut self, (host, bus_port): (String, u16)) {
+            cluster(&self.server).lock().unwrap().meet(host, bus_port);
+            meet_nodes(&self.server, &mut self.links);
+        }
+
+        /// Pings every other node, however recently it was pinged
+        fn ping(&mut self) {
+            ping_nodes(&self.server, &mut self.links, &mut HashMap::new());
+        }
+
+        fn health_of(&self, node: &TestNode) -> NodeHealth {
+            let id = node.id();
+            cluster(&self.server).lock().unwrap().nodes[&id].health
+        }
+    }
+
+    /// Masters serving slot 0, 1 and 2 which all met each other
+    fn start_masters(name: &str) -> Vec<TestNode> {
+        let mut nodes = (0..3)
+            .map(|slot| {
+                let info = ReplicaInfo::Master(MasterInfo::new());
+                TestNode::start(&format!("{name}-{slot}"), &[slot], info)
+            })
+            .collect::<Vec<_>>();
+        let addresses = nodes.iter().map(TestNode::bus_address).collect::<Vec<_>>();
+        nodes[0].meet(addresses[1].clone());
+        nodes[0].meet(addresses[2].clone());
+        nodes[1].meet(addresses[2].clone());
+        nodes
+    }
+
+    #[test]
+    fn test_unreachable_master_is_agreed_failing() {
+        let mut nodes = start_masters("fail");
+        for node in &mut nodes {
+            node.ping();
+        }
+        for node in &nodes {
+            let cluster = cluster(&node.server).lock().unwrap();
+            assert_eq!(cluster.nodes.len(), 3);
+            assert!((0..3).all(|slot| cluster.slot_owner(slot).is_some()));
+            assert!(cluster
+                .nodes
+                .values()
+                .all(|node| node.health == NodeHealth::Online));
+        }
+
+        let [a, b, c] = &mut nodes[..] else {
+            unreachable!()
+        };
+        c.take_down();
+        std::thread::sleep(NODE_TIMEOUT + Duration::from_millis(50));
+        a.ping();
+        b.ping();
+
+        // on its own a master only suspects the node
+        detect_failures(&a.server, &mut a.links);
+        assert_eq!(a.health_of(c), NodeHealth::PFail);
+        assert_eq!(a.health_of(b), NodeHealth::Online);
+        detect_failures(&b.server, &mut b.links);
+        assert_eq!(b.health_of(c), NodeHealth::PFail);
+
+        // gossip in either direction carries the suspicions, making a majority
+        b.ping();
+        detect_failures(&a.server, &mut a.links);
+        assert_eq!(a.health_of(c), NodeHealth::Fail);
+        // which is broadcast to the others
+        assert_eq!(b.health_of(c), NodeHealth::Fail);
+    }
+
+    #[test]
+    fn test_replica_takes_over_failing_master() {
+        let mut nodes = start_masters("takeover");
+        let mut replica = TestNode::start("takeover-replica", &[], replica_of_nowhere());
+        replica.meet(nodes[2].bus_address());
+        let (master_id, replica_id) = (nodes[2].id(), replica.id());
+        cluster(&replica.server)
+            .lock()
+            .unwrap()
+            .replicate(&master_id)
+            .unwrap();
+        for node in nodes.iter_mut().chain([&mut replica]) {
+            node.ping();
+        }
+        assert_eq!(
+            cluster(&nodes[0].server).lock().unwrap().nodes[&replica_id].role,
+            NodeRole::Replica(master_id.clone())
+        );
+
+        nodes[2].take_down();
+        std::thread::sleep(NODE_TIMEOUT + Duration::from_millis(50));
+        for _ in 0..2 {
+            for node in nodes[..2].iter_mut().chain([&mut replica]) {
+                node.ping();
+                detect_failures(&node.server, &mut node.links);
+            }
+        }
+        assert_eq!(replica.health_of(&nodes[2]), NodeHealth::Fail);
+
+        let mut attempt = None;
+        replica_failover(&replica.server, &mut replica.links, &mut attempt);
+        assert!(attempt
+            .as_ref()
+            .is_some_and(|attempt| attempt.epoch.is_none()));
+        attempt.as_mut().unwrap().start_time = Instant::now();
+        replica_failover(&replica.server, &mut replica.links, &mut attempt);
+        let epoch = attempt.unwrap().epoch.unwrap();
+
+        assert!(replica.server.is_master());
+        {
+            let cluster = cluster(&replica.server).lock().unwrap();
+            assert_eq!(cluster.myself().role, NodeRole::Master);
+            assert_eq!(cluster.myself().config_epoch, epoch);
+            assert_eq!(cluster.slot_ranges(&replica_id), vec![(2, 2)]);
+        }
+        // the masters learned about the claim from the broadcast that followed
+        for node in &nodes[..2] {
+            let cluster = cluster(&node.server).lock().unwrap();
+            assert_eq!(cluster.slot_owner(2).unwrap().id, replica_id);
+        }
+
+        // the old master coming back finds its slots taken and follows the new one
+        nodes[2].bring_up();
+        replica.ping();
+        assert!(!nodes[2].server.is_master());
+        let cluster = cluster(&nodes[2].server).lock().unwrap();
+        assert_eq!(cluster.myself().role, NodeRole::Replica(replica_id));
+        assert!(cluster.slot_ranges(&master_id).is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::parser::resp::{ParseError, Result, Token};
+
+use super::slot::CLUSTER_SLOTS;
+
+/// Health of a node as seen by the node describing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeHealth {
+    Online,
+    /// The node did not answer our pings within the node timeout
+    PFail,
+    /// Enough masters agree the node is unreachable
+    Fail,
+}
+
+impl NodeHealth {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeHealth::Online => "ok",
+            NodeHealth::PFail => "pfail",
+            NodeHealth::Fail => "fail",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "ok" => Ok(NodeHealth::Online),
+            "pfail" => Ok(NodeHealth::PFail),
+            "fail" => Ok(NodeHealth::Fail),
+            _ => Err(ParseError::Invalid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKind {
+    /// A ping from a node which does not know us yet, asking to join its cluster
+    Meet,
+    Ping,
+    /// Reply to a meet, a ping or a fail message
+    Pong,
+    /// Tells every node that the node with the given id is failing
+    Fail(String),
+    /// Asks the masters for their vote to take over from a failing master
+    AuthRequest,
+    AuthAck,
+    AuthDenied,
+}
+
+impl MessageKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Meet => "MEET",
+            MessageKind::Ping => "PING",
+            MessageKind::Pong => "PONG",
+            MessageKind::Fail(_) => "FAIL",
+            MessageKind::AuthRequest => "AUTH-REQUEST",
+            MessageKind::AuthAck => "AUTH-ACK",
+            MessageKind::AuthDenied => "AUTH-DENIED",
+        }
+    }
+}
+
+/// What the sender of a message says about itself. A replica advertises the slots and
+/// config epoch of its master.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeHeader {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub bus_port: u16,
+    /// Master of the sender, when it is a replica
+    pub master: Option<String>,
+    pub config_epoch: u64,
+    pub current_epoch: u64,
+    pub repl_offset: usize,
+    pub slots: Vec<(u16, u16)>,
+}
+
+/// What the sender of a message knows about another node
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipEntry {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub health: NodeHealth,
+}
+
+/// A message of the cluster bus, sent as a RESP array of strings:
+/// `<kind> [<failed id>] <header fields> [<gossip entry fields>]...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusMessage {
+    pub kind: MessageKind,
+    pub header: NodeHeader,
+    pub gossip: Vec<GossipEntry>,
+}
+
+const HEADER_FIELDS: usize = 9;
+const GOSSIP_FIELDS: usize = 5;
+
+fn format_slots(slots: &[(u16, u16)]) -> String {
+    if slots.is_empty() {
+        // empty strings would go out as null bulk strings
+        return "-".to_string();
+    }
+    slots
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_slots(slots: &str) -> Result<Vec<(u16, u16)>> {
+    if slots == "-" {
+        return Ok(Vec::new());
+    }
+    slots
+        .split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').ok_or(ParseError::Invalid)?;
+            let (start, end): (u16, u16) = (start.parse()?, end.parse()?);
+            if start > end || end >= CLUSTER_SLOTS {
+                return Err(ParseError::Invalid);
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+impl BusMessage {
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.kind.as_str().to_string()];
+        if let MessageKind::Fail(node_id) = &self.kind {
+            args.push(node_id.clone());
+        }
+        let header = &self.header;
+        args.extend([
+            header.id.clone(),
+            header.host.clone(),
+            header.port.to_string(),
+            header.bus_port.to_string(),
+            header.master.clone().unwrap_or_else(|| "-".to_string()),
+            header.config_epoch.to_string(),
+            header.current_epoch.to_string(),
+            header.repl_offset.to_string(),
+            format_slots(&header.slots),
+        ]);
+        for entry in &self.gossip {
+            args.extend([
+                entry.id.clone(),
+                entry.host.clone(),
+                entry.port.to_string(),
+                entry.bus_port.to_string(),
+                entry.health.as_str().to_string(),
+            ]);
+        }
+        args
+    }
+
+    pub fn to_resp_token(&self) -> Token {
+        Token::Array(
+            self.to_args()
+                .into_iter()
+                .map(|arg| Token::BulkString(arg.into_bytes()))
+                .collect(),
+        )
+    }
+
+    pub fn parse(tokens: &[Token]) -> Result<Self> {
+        let args = tokens
+            .iter()
+            .map(|token| Ok(std::str::from_utf8(token.get_bulk_string_data()?)?))
+            .collect::<Result<Vec<_>>>()?;
+        let Some((kind, args)) = args.split_first() else {
+            return Err(ParseError::Invalid);
+        };
+        let (kind, args) = match (*kind, args) {
+            ("MEET", args) => (MessageKind::Meet, args),
+            ("PING", args) => (MessageKind::Ping, args),
+            ("PONG", args) => (MessageKind::Pong, args),
+            ("FAIL", [node_id, args @ ..]) => (MessageKind::Fail(node_id.to_string()), args),
+            ("AUTH-REQUEST", args) => (MessageKind::AuthRequest, args),
+            ("AUTH-ACK", args) => (MessageKind::AuthAck, args),
+            ("AUTH-DENIED", args) => (MessageKind::AuthDenied, args),
+            _ => return Err(ParseError::Invalid),
+        };
+        if args.len() < HEADER_FIELDS || !(args.len() - HEADER_FIELDS).is_multiple_of(GOSSIP_FIELDS)
+        {
+            return Err(ParseError::Invalid);
+        }
+
+        let (header, gossip) = args.split_at(HEADER_FIELDS);
+        let header = NodeHeader {
+            id: header[0].to_string(),
+            host: header[1].to_string(),
+            port: header[2].parse()?,
+            bus_port: header[3].parse()?,
+            master: (header[4] != "-").then(|| header[4].to_string()),
+            config_epoch: header[5].parse()?,
+            current_epoch: header[6].parse()?,
+            repl_offset: header[7].parse()?,
+            slots: parse_slots(header[8])?,
+        };
+        let gossip = gossip
+            .chunks(GOSSIP_FIELDS)
+            .map(|entry| {
+                Ok(GossipEntry {
+                    id: entry[0].to_string(),
+                    host: entry[1].to_string(),
+                    port: entry[2].parse()?,
+                    bus_port: entry[3].parse()?,
+                    health: NodeHealth::parse(entry[4])?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            kind,
+            header,
+            gossip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::resp::parse_buffer;
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = BusMessage {
+            kind: MessageKind::Fail("c".repeat(40)),
+            header: NodeHeader {
+                id: "a".repeat(40),
+                host: "127.0.0.1".to_string(),
+                port: 7000,
+                bus_port: 17000,
+                master: None,
+                config_epoch: 2,
+                current_epoch: 5,
+                repl_offset: 1337,
+                slots: vec![(0, 5460), (16383, 16383)],
+            },
+            gossip: vec![GossipEntry {
+                id: "b".repeat(40),
+                host: "127.0.0.1".to_string(),
+                port: 7001,
+                bus_port: 17001,
+                health: NodeHealth::PFail,
+            }],
+        };
+        let serialized = message.to_resp_token().serialize();
+        let tokens = parse_buffer(&serialized).unwrap().tokens;
+        assert_eq!(BusMessage::parse(&tokens).unwrap(), message);
+
+        let mut replica = message.clone();
+        replica.kind = MessageKind::Ping;
+        replica.header.master = Some("b".repeat(40));
+        replica.header.slots = Vec::new();
+        replica.gossip = Vec::new();
+        let tokens = parse_buffer(&replica.to_resp_token().serialize())
+            .unwrap()
+            .tokens;
+        assert_eq!(BusMessage::parse(&tokens).unwrap(), replica);
+    }
+
+    #[test]
+    fn test_parse_invalid_message() {
+        let args = [
+            "PING",
+            "a",
+            "127.0.0.1",
+            "7000",
+            "17000",
+            "-",
+            "0",
+            "0",
+            "0",
+        ];
+        let tokens = args
+            .iter()
+            .map(|arg| Token::BulkString(arg.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert!(BusMessage::parse(&tokens).is_err());
+
+        let mut tokens = tokens;
+        tokens.push(Token::BulkString(b"0-16384".to_vec()));
+        assert!(BusMessage::parse(&tokens).is_err());
+        tokens.pop();
+        tokens.push(Token::BulkString(b"0-16383".to_vec()));
+        assert!(BusMessage::parse(&tokens).is_ok());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod bus;
+pub mod cron;
+pub mod message;
+pub mod slot;
+pub mod state;
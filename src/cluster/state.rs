@@ -0,0 +1,1120 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::{random_hex_id, CRLF},
+    parser::command::SetSlotAction,
+};
+
+use super::{
+    message::{BusMessage, GossipEntry, MessageKind, NodeHeader, NodeHealth},
+    slot::CLUSTER_SLOTS,
+};
+
+pub const NODE_ID_LEN: usize = 40;
+/// The cluster bus listens on the client port shifted by this much
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeRole {
+    Master,
+    /// Replica of the node with the given id
+    Replica(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterNode {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub role: NodeRole,
+    /// Epoch in which the node last claimed its slots
+    pub config_epoch: u64,
+    pub health: NodeHealth,
+    /// Replication offset the node last advertised
+    pub repl_offset: usize,
+    /// Last time the node answered one of our pings
+    pub pong_received: Option<Instant>,
+    /// When the node was flagged as failing, unknown for a flag restored from the config
+    pub fail_time: Option<Instant>,
+    /// Masters which reported the node as failing, and when they last did
+    pub fail_reports: HashMap<String, Instant>,
+    /// Last time we voted for a replica of this node to take over from it
+    pub voted_time: Option<Instant>,
+}
+
+impl ClusterNode {
+    pub fn new(id: String, host: String, port: u16) -> Self {
+        Self {
+            id,
+            host,
+            port,
+            bus_port: port + BUS_PORT_OFFSET,
+            role: NodeRole::Master,
+            config_epoch: 0,
+            health: NodeHealth::Online,
+            repl_offset: 0,
+            pong_received: None,
+            fail_time: None,
+            fail_reports: HashMap::new(),
+            voted_time: None,
+        }
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.role == NodeRole::Master
+    }
+}
+
+/// Layout of the cluster as seen by this node, persisted to the node config file
+#[derive(Debug)]
+pub struct ClusterState {
+    pub myself: String,
+    pub nodes: BTreeMap<String, ClusterNode>,
+    /// Id of the node serving each hash slot
+    slots: Vec<Option<String>>,
+    /// Slots we serve whose keys are being moved to the node with the given id
+    pub migrating: BTreeMap<u16, String>,
+    /// Slots whose keys are being moved to us from the node with the given id
+    pub importing: BTreeMap<u16, String>,
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    config_path: PathBuf,
+    /// Bus addresses of the nodes we were asked to meet
+    pending_meets: Vec<(String, u16)>,
+    /// Nodes we never got a pong from are given a node timeout from this moment
+    started: Instant,
+    /// Set whenever something persisted in the config changes
+    todo_save: bool,
+}
+
+fn invalid_config(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.into())
+}
+
+/// Parses `<ip>:<port>@<bus port>`, the bus port being optional
+fn parse_node_address(address: &str) -> Option<(String, u16, u16)> {
+    let (address, bus_port) = match address.split_once('@') {
+        Some((address, bus_port)) => (address, Some(bus_port.parse().ok()?)),
+        None => (address, None),
+    };
+    let (host, port) = address.rsplit_once(':')?;
+    let port: u16 = port.parse().ok()?;
+    Some((
+        host.to_string(),
+        port,
+        bus_port.unwrap_or(port + BUS_PORT_OFFSET),
+    ))
+}
+
+/// Parses `[<slot>->-<id>]` for a slot we migrate to the node with the given id, or
+/// `[<slot>-<-<id>]` for one we import from it
+fn parse_migration(field: &str) -> Option<(u16, bool, String)> {
+    let field = field.strip_prefix('[')?.strip_suffix(']')?;
+    let (slot, importing, node_id) = match field.split_once("->-") {
+        Some((slot, node_id)) => (slot, false, node_id),
+        None => {
+            let (slot, node_id) = field.split_once("-<-")?;
+            (slot, true, node_id)
+        }
+    };
+    let slot = slot.parse().ok()?;
+    (slot < CLUSTER_SLOTS).then(|| (slot, importing, node_id.to_string()))
+}
+
+/// Parses a slot or an inclusive `<start>-<end>` range of slots
+fn parse_slot_range(range: &str) -> Option<(u16, u16)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let slot = range.parse().ok()?;
+            (slot, slot)
+        }
+    };
+    (start <= end && end < CLUSTER_SLOTS).then_some((start, end))
+}
+
+impl ClusterState {
+    /// A cluster made of this node only, serving no slots yet
+    pub fn new(host: &str, port: u16, config_path: PathBuf) -> Self {
+        let myself = ClusterNode::new(random_hex_id(NODE_ID_LEN), host.to_string(), port);
+        Self {
+            myself: myself.id.clone(),
+            nodes: BTreeMap::from([(myself.id.clone(), myself)]),
+            slots: vec![None; CLUSTER_SLOTS as usize],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            config_path,
+            pending_meets: Vec::new(),
+            started: Instant::now(),
+            todo_save: false,
+        }
+    }
+
+    /// Restores the state saved in `config_path`, or starts a new cluster node and saves
+    /// it there if there is nothing to restore yet
+    pub fn load_or_create(host: &str, port: u16, config_path: PathBuf) -> io::Result<Self> {
+        if !config_path.exists() {
+            let state = Self::new(host, port, config_path);
+            state.save()?;
+            println!(
+                "INFO: no cluster config file found, starting as node {}",
+                state.myself
+            );
+            return Ok(state);
+        }
+
+        let content = fs::read_to_string(&config_path)?;
+        let mut state = Self::parse(&content, config_path)?;
+        // we may have been restarted with another address
+        let myself = state.myself_mut();
+        myself.host = host.to_string();
+        myself.port = port;
+        myself.bus_port = port + BUS_PORT_OFFSET;
+        println!(
+            "INFO: loaded cluster config, running as node {}",
+            state.myself
+        );
+        Ok(state)
+    }
+
+    fn parse(content: &str, config_path: PathBuf) -> io::Result<Self> {
+        let mut myself = None;
+        let mut nodes = BTreeMap::new();
+        let mut slots = vec![None; CLUSTER_SLOTS as usize];
+        let mut migrating = BTreeMap::new();
+        let mut importing = BTreeMap::new();
+        let mut current_epoch = 0;
+        let mut last_vote_epoch = 0;
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields[0] == "vars" {
+                for pair in fields[1..].chunks(2) {
+                    match pair {
+                        ["currentEpoch", epoch] => current_epoch = epoch.parse().unwrap_or(0),
+                        ["lastVoteEpoch", epoch] => last_vote_epoch = epoch.parse().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+
+            // <id> <address> <flags> <master> <ping-sent> <pong-recv> <config-epoch> <link-state> <slot>...
+            let [id, address, flags, master, _, _, config_epoch, _, slot_ranges @ ..] =
+                fields.as_slice()
+            else {
+                return Err(invalid_config(format!("malformed node line {line:?}")));
+            };
+            let (host, port, bus_port) = parse_node_address(address)
+                .ok_or_else(|| invalid_config(format!("malformed node address {address:?}")))?;
+            let flags = flags.split(',').collect::<Vec<_>>();
+            let role = if flags.contains(&"slave") && *master != "-" {
+                NodeRole::Replica(master.to_string())
+            } else {
+                NodeRole::Master
+            };
+            if flags.contains(&"myself") {
+                myself = Some(id.to_string());
+            }
+            for range in slot_ranges {
+                // slots in the middle of a migration are recorded in brackets
+                if range.starts_with('[') {
+                    let (slot, is_importing, node_id) = parse_migration(range)
+                        .ok_or_else(|| invalid_config(format!("malformed migration {range:?}")))?;
+                    match is_importing {
+                        true => importing.insert(slot, node_id),
+                        false => migrating.insert(slot, node_id),
+                    };
+                    continue;
+                }
+                let (start, end) = parse_slot_range(range)
+                    .ok_or_else(|| invalid_config(format!("malformed slot range {range:?}")))?;
+                for slot in start..=end {
+                    slots[slot as usize] = Some(id.to_string());
+                }
+            }
+            let mut node = ClusterNode::new(id.to_string(), host, port);
+            node.bus_port = bus_port;
+            node.role = role;
+            node.config_epoch = config_epoch.parse().unwrap_or(0);
+            if flags.contains(&"fail") {
+                node.health = NodeHealth::Fail;
+            } else if flags.contains(&"fail?") {
+                node.health = NodeHealth::PFail;
+            }
+            nodes.insert(id.to_string(), node);
+        }
+
+        let myself = myself.ok_or_else(|| invalid_config("no node is flagged as myself"))?;
+        Ok(Self {
+            myself,
+            nodes,
+            slots,
+            migrating,
+            importing,
+            current_epoch,
+            last_vote_epoch,
+            config_path,
+            pending_meets: Vec::new(),
+            started: Instant::now(),
+            todo_save: false,
+        })
+    }
+
+    /// Writes the config to a temporary file renamed into place, so that a crash never
+    /// leaves a truncated config behind
+    pub fn save(&self) -> io::Result<()> {
+        let mut content = self.nodes_description();
+        content.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            self.current_epoch, self.last_vote_epoch
+        ));
+
+        let dir = self.config_path.parent().unwrap_or(Path::new("."));
+        let temp_path = dir.join(format!("temp-{}.nodes.conf", std::process::id()));
+        let result = File::create(&temp_path).and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        });
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        fs::rename(&temp_path, &self.config_path)
+    }
+
+    /// Saves the config if anything in it changed since the last save
+    pub fn save_if_needed(&mut self) {
+        if !std::mem::take(&mut self.todo_save) {
+            return;
+        }
+        if let Err(err) = self.save() {
+            eprintln!("ERROR: failed to save cluster config with error {err:?}");
+        }
+    }
+
+    pub fn myself(&self) -> &ClusterNode {
+        &self.nodes[&self.myself]
+    }
+
+    fn myself_mut(&mut self) -> &mut ClusterNode {
+        self.nodes
+            .get_mut(&self.myself)
+            .expect("the cluster always knows about this node")
+    }
+
+    pub fn slot_owner(&self, slot: u16) -> Option<&ClusterNode> {
+        self.slots[slot as usize]
+            .as_ref()
+            .and_then(|id| self.nodes.get(id))
+    }
+
+    /// Inclusive ranges of the slots served by the node with the given id
+    pub fn slot_ranges(&self, node_id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..CLUSTER_SLOTS {
+            if self.slots[slot as usize].as_deref() != Some(node_id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// Assigns the given slots to this node, none of them if any is out of range or
+    /// already served
+    pub fn add_slots(&mut self, slots: &[i64]) -> Result<(), String> {
+        let mut seen = vec![false; CLUSTER_SLOTS as usize];
+        for &slot in slots {
+            if !(0..CLUSTER_SLOTS as i64).contains(&slot) {
+                return Err("ERR Invalid or out of range slot".to_string());
+            }
+            if self.slots[slot as usize].is_some() {
+                return Err(format!("ERR Slot {slot} is already busy"));
+            }
+            if std::mem::replace(&mut seen[slot as usize], true) {
+                return Err(format!("ERR Slot {slot} specified multiple times"));
+            }
+        }
+        for &slot in slots {
+            self.slots[slot as usize] = Some(self.myself.clone());
+        }
+        self.todo_save = true;
+        Ok(())
+    }
+
+    pub fn assigned_slots(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    /// Whether every slot is served by a node which is not failing, without which the
+    /// cluster is reported as failing
+    pub fn is_ok(&self) -> bool {
+        self.slots.iter().all(|owner| {
+            owner
+                .as_ref()
+                .and_then(|id| self.nodes.get(id))
+                .is_some_and(|node| node.health != NodeHealth::Fail)
+        })
+    }
+
+    /// Masters which serve at least one slot
+    pub fn size(&self) -> usize {
+        self.nodes
+            .values()
+            .filter(|node| node.role == NodeRole::Master)
+            .filter(|node| {
+                self.slots
+                    .iter()
+                    .any(|owner| owner.as_ref() == Some(&node.id))
+            })
+            .count()
+    }
+
+    /// Votes a replica needs to take over from its master: a majority of the masters
+    /// serving slots, the failing one included
+    pub fn votes_needed(&self) -> usize {
+        self.size() / 2 + 1
+    }
+
+    /// The master this node replicates from, if that master serves slots and has been
+    /// agreed to be failing
+    pub fn failing_master(&self) -> Option<&ClusterNode> {
+        let NodeRole::Replica(master_id) = &self.myself().role else {
+            return None;
+        };
+        let master = self.nodes.get(master_id)?;
+        (master.health == NodeHealth::Fail && !self.slot_ranges(master_id).is_empty())
+            .then_some(master)
+    }
+
+    /// How many replicas of our master are further in its replication stream than
+    /// `repl_offset`, the best placed replica being the first to ask for votes
+    pub fn failover_rank(&self, repl_offset: usize) -> usize {
+        let role = &self.myself().role;
+        self.nodes
+            .values()
+            .filter(|node| node.id != self.myself && &node.role == role)
+            .filter(|node| node.repl_offset > repl_offset)
+            .count()
+    }
+
+    /// Starts a new epoch in which this replica asks for votes
+    pub fn start_election(&mut self) -> u64 {
+        self.current_epoch += 1;
+        self.todo_save = true;
+        self.current_epoch
+    }
+
+    /// Takes over the slots of the master this node replicates from, claiming them in
+    /// `epoch` so that our claim wins over the one of the old master
+    pub fn promote_myself(&mut self, epoch: u64) {
+        let NodeRole::Replica(master_id) = self.myself().role.clone() else {
+            return;
+        };
+        for owner in self.slots.iter_mut() {
+            if owner.as_ref() == Some(&master_id) {
+                *owner = Some(self.myself.clone());
+            }
+        }
+        let myself = self.myself_mut();
+        myself.role = NodeRole::Master;
+        myself.config_epoch = epoch;
+        println!("INFO: failover won, taking over the slots of {master_id} in epoch {epoch}");
+        self.todo_save = true;
+    }
+
+    /// Makes this node a replica of the master with the given id. Returns the address of
+    /// that master to replicate from.
+    pub fn replicate(&mut self, node_id: &str) -> Result<(String, u16), String> {
+        if node_id == self.myself {
+            return Err("ERR Can't replicate myself".to_string());
+        }
+        let Some(master) = self.nodes.get(node_id) else {
+            return Err(format!("ERR Unknown node {node_id}"));
+        };
+        if !master.is_master() {
+            return Err("ERR I can only replicate a master, not a replica.".to_string());
+        }
+        if self.myself().is_master() && !self.slot_ranges(&self.myself).is_empty() {
+            return Err(
+                "ERR To set a master the node must be empty and without assigned slots."
+                    .to_string(),
+            );
+        }
+        let address = (master.host.clone(), master.port);
+        self.myself_mut().role = NodeRole::Replica(node_id.to_string());
+        self.todo_save = true;
+        Ok(address)
+    }
+
+    /// Remembers to introduce ourselves to the node listening on the given bus address
+    pub fn meet(&mut self, host: String, bus_port: u16) {
+        self.pending_meets.push((host, bus_port));
+    }
+
+    pub fn take_pending_meets(&mut self) -> Vec<(String, u16)> {
+        std::mem::take(&mut self.pending_meets)
+    }
+
+    /// Changes the migration state or the owner of a slot. Moving a slot we import to
+    /// ourselves claims it in a new epoch, so that the rest of the cluster picks up the
+    /// new owner without waiting for a vote.
+    pub fn set_slot(&mut self, slot: i64, action: &SetSlotAction) -> Result<(), String> {
+        if !(0..CLUSTER_SLOTS as i64).contains(&slot) {
+            return Err("ERR Invalid or out of range slot".to_string());
+        }
+        let slot = slot as u16;
+        let owned = self.slots[slot as usize].as_ref() == Some(&self.myself);
+        let known = |node_id: &str| -> Result<(), String> {
+            match self.nodes.contains_key(node_id) {
+                true => Ok(()),
+                false => Err(format!("ERR I don't know about node {node_id}")),
+            }
+        };
+        match action {
+            SetSlotAction::Migrating(node_id) => {
+                if !owned {
+                    return Err(format!("ERR I'm not the owner of hash slot {slot}"));
+                }
+                known(node_id)?;
+                self.migrating.insert(slot, node_id.clone());
+            }
+            SetSlotAction::Importing(node_id) => {
+                if owned {
+                    return Err(format!("ERR I'm already the owner of hash slot {slot}"));
+                }
+                known(node_id)?;
+                self.importing.insert(slot, node_id.clone());
+            }
+            SetSlotAction::Stable => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            SetSlotAction::Node(node_id) => {
+                known(node_id)?;
+                if !self.nodes[node_id].is_master() {
+                    return Err("ERR Target node is not a master".to_string());
+                }
+                if *node_id == self.myself {
+                    if self.importing.remove(&slot).is_some() {
+                        self.current_epoch += 1;
+                        let epoch = self.current_epoch;
+                        self.myself_mut().config_epoch = epoch;
+                        println!("INFO: imported slot {slot}, claiming it in epoch {epoch}");
+                    }
+                } else {
+                    self.migrating.remove(&slot);
+                }
+                self.slots[slot as usize] = Some(node_id.clone());
+            }
+        }
+        self.todo_save = true;
+        Ok(())
+    }
+
+    /// Header describing this node, sent with every bus message
+    pub fn header(&self, repl_offset: usize) -> NodeHeader {
+        let myself = self.myself();
+        let (master, slots_owner) = match &myself.role {
+            NodeRole::Master => (None, &myself.id),
+            NodeRole::Replica(master_id) => (Some(master_id.clone()), master_id),
+        };
+        let config_epoch = self
+            .nodes
+            .get(slots_owner)
+            .map_or(0, |node| node.config_epoch);
+        NodeHeader {
+            id: myself.id.clone(),
+            host: myself.host.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            master,
+            config_epoch,
+            current_epoch: self.current_epoch,
+            repl_offset,
+            slots: self.slot_ranges(slots_owner),
+        }
+    }
+
+    /// A message carrying our header along with gossip about every node but ourselves
+    /// and the receiver. Clusters are small enough for all of them to fit.
+    pub fn message(
+        &self,
+        kind: MessageKind,
+        repl_offset: usize,
+        receiver: Option<&str>,
+    ) -> BusMessage {
+        let gossip = self
+            .nodes
+            .values()
+            .filter(|node| node.id != self.myself && Some(node.id.as_str()) != receiver)
+            .map(|node| GossipEntry {
+                id: node.id.clone(),
+                host: node.host.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                health: node.health,
+            })
+            .collect();
+        BusMessage {
+            kind,
+            header: self.header(repl_offset),
+            gossip,
+        }
+    }
+
+    /// Learns what a message tells about its sender and the rest of the cluster. Senders
+    /// we do not know about are only accepted if `accept_unknown`, which is the case for
+    /// a meet and for the reply to one of ours.
+    /// Returns the address of the master this node has to replicate from now on, when
+    /// the sender took over the last slots of our master or of ourselves.
+    pub fn process_message(
+        &mut self,
+        message: &BusMessage,
+        accept_unknown: bool,
+    ) -> Option<(String, u16)> {
+        let header = &message.header;
+        if header.id == self.myself {
+            return None;
+        }
+        if header.current_epoch > self.current_epoch {
+            self.current_epoch = header.current_epoch;
+            self.todo_save = true;
+        }
+        if !self.nodes.contains_key(&header.id) {
+            if !accept_unknown {
+                return None;
+            }
+            println!(
+                "INFO: met node {} at {}:{}",
+                header.id, header.host, header.port
+            );
+            let node = ClusterNode::new(header.id.clone(), header.host.clone(), header.port);
+            self.add_node(node);
+        }
+
+        let node = self
+            .nodes
+            .get_mut(&header.id)
+            .expect("unknown senders were added above");
+        if (&node.host, node.port, node.bus_port) != (&header.host, header.port, header.bus_port) {
+            node.host = header.host.clone();
+            node.port = header.port;
+            node.bus_port = header.bus_port;
+            self.todo_save = true;
+        }
+        node.repl_offset = header.repl_offset;
+        let role = match &header.master {
+            Some(master_id) => NodeRole::Replica(master_id.clone()),
+            None => NodeRole::Master,
+        };
+        if node.role != role {
+            println!("INFO: node {} is now {role:?}", header.id);
+            node.role = role;
+            self.todo_save = true;
+        }
+        if node.is_master() && node.config_epoch != header.config_epoch {
+            node.config_epoch = header.config_epoch;
+            self.todo_save = true;
+        }
+        if message.kind == MessageKind::Pong {
+            node.pong_received = Some(Instant::now());
+            if node.health == NodeHealth::PFail {
+                node.health = NodeHealth::Online;
+            }
+        }
+
+        if let MessageKind::Fail(node_id) = &message.kind {
+            self.mark_failed(node_id);
+        }
+        for entry in &message.gossip {
+            self.process_gossip(&header.id, header.master.is_none(), entry);
+        }
+        if header.master.is_some() {
+            return None;
+        }
+        self.handle_config_epoch_collision(header);
+        self.update_slots(header)
+    }
+
+    fn add_node(&mut self, mut node: ClusterNode) {
+        // give the node a full node timeout to answer our first ping
+        node.pong_received = Some(Instant::now());
+        self.nodes.insert(node.id.clone(), node);
+        self.todo_save = true;
+    }
+
+    fn mark_failed(&mut self, node_id: &str) {
+        if node_id == self.myself {
+            return;
+        }
+        let Some(node) = self.nodes.get_mut(node_id) else {
+            return;
+        };
+        if node.health != NodeHealth::Fail {
+            println!("INFO: FAIL message received about {node_id}");
+            node.health = NodeHealth::Fail;
+            node.fail_time = Some(Instant::now());
+            self.todo_save = true;
+        }
+    }
+
+    /// Discovers the nodes the sender knows about, and records its opinion about their
+    /// health if it is a master
+    fn process_gossip(&mut self, sender: &str, sender_is_master: bool, entry: &GossipEntry) {
+        if entry.id == self.myself {
+            return;
+        }
+        match self.nodes.get_mut(&entry.id) {
+            Some(node) if sender_is_master => match entry.health {
+                NodeHealth::PFail | NodeHealth::Fail => {
+                    node.fail_reports.insert(sender.to_string(), Instant::now());
+                }
+                NodeHealth::Online => {
+                    node.fail_reports.remove(sender);
+                }
+            },
+            Some(_) => {}
+            // a failing node would only be found failing again
+            None if entry.health != NodeHealth::Fail => {
+                println!(
+                    "INFO: discovered node {} at {}:{} through {sender}",
+                    entry.id, entry.host, entry.port
+                );
+                let mut node = ClusterNode::new(entry.id.clone(), entry.host.clone(), entry.port);
+                node.bus_port = entry.bus_port;
+                self.add_node(node);
+            }
+            None => {}
+        }
+    }
+
+    /// Two masters sharing a config epoch could both win a claim on the same slot, the
+    /// one with the smaller id moves on to a new epoch
+    fn handle_config_epoch_collision(&mut self, header: &NodeHeader) {
+        let myself = self.myself();
+        if !myself.is_master()
+            || myself.config_epoch != header.config_epoch
+            || header.id.as_str() < self.myself.as_str()
+        {
+            return;
+        }
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        self.myself_mut().config_epoch = epoch;
+        self.todo_save = true;
+        println!(
+            "INFO: config epoch collision with node {}, moving to config epoch {epoch}",
+            header.id
+        );
+    }
+
+    /// Hands the slots a master claims over to it when its claim is newer than the one
+    /// of their current owner
+    fn update_slots(&mut self, header: &NodeHeader) -> Option<(String, u16)> {
+        let my_master = match &self.myself().role {
+            NodeRole::Master => self.myself.clone(),
+            NodeRole::Replica(master_id) => master_id.clone(),
+        };
+        let mut lost_slots = false;
+        let mut claimed = 0;
+        for &(start, end) in &header.slots {
+            for slot in start..=end {
+                let owner = self.slots[slot as usize].as_ref();
+                if owner == Some(&header.id) || self.importing.contains_key(&slot) {
+                    continue;
+                }
+                let owner_epoch = owner
+                    .and_then(|id| self.nodes.get(id))
+                    .map(|node| node.config_epoch);
+                if owner_epoch.is_some_and(|epoch| epoch >= header.config_epoch) {
+                    continue;
+                }
+                lost_slots |= owner == Some(&my_master);
+                self.slots[slot as usize] = Some(header.id.clone());
+                self.migrating.remove(&slot);
+                claimed += 1;
+            }
+        }
+        if claimed == 0 {
+            return None;
+        }
+        println!(
+            "INFO: node {} claimed {claimed} slots in config epoch {}",
+            header.id, header.config_epoch
+        );
+        self.todo_save = true;
+
+        if !lost_slots || !self.slot_ranges(&my_master).is_empty() {
+            return None;
+        }
+        println!(
+            "INFO: {} lost its last slot, replicating from {} from now on",
+            my_master, header.id
+        );
+        self.myself_mut().role = NodeRole::Replica(header.id.clone());
+        Some((header.host.clone(), header.port))
+    }
+
+    /// Flags the nodes which did not answer our pings within `node_timeout` as possibly
+    /// failing, and the ones a majority of masters agree about as failing. Nodes back
+    /// online lose their flags, except for a failing master whose slots nobody took over
+    /// yet which keeps them for a while so that its replicas get a chance to.
+    /// Returns the nodes which were just agreed to be failing.
+    pub fn check_failures(&mut self, node_timeout: Duration) -> Vec<String> {
+        let votes_needed = self.votes_needed();
+        let masters = self
+            .nodes
+            .values()
+            .filter(|node| node.is_master())
+            .map(|node| node.id.clone())
+            .collect::<Vec<_>>();
+        let myself_is_master = self.myself().is_master();
+        let serving = self
+            .nodes
+            .keys()
+            .filter(|id| !self.slot_ranges(id).is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut failed = Vec::new();
+        for node in self.nodes.values_mut() {
+            if node.id == self.myself {
+                continue;
+            }
+            node.fail_reports
+                .retain(|_, report_time| report_time.elapsed() <= node_timeout * 2);
+            let reachable = node.pong_received.unwrap_or(self.started).elapsed() <= node_timeout;
+            match node.health {
+                NodeHealth::Online if !reachable => {
+                    println!(
+                        "INFO: *** marking node {} as failing (quorum not reached)",
+                        node.id
+                    );
+                    node.health = NodeHealth::PFail;
+                }
+                NodeHealth::PFail if reachable => node.health = NodeHealth::Online,
+                NodeHealth::Fail
+                    if reachable
+                        && (!node.is_master()
+                            || !serving.contains(&node.id)
+                            || node
+                                .fail_time
+                                .is_none_or(|time| time.elapsed() > node_timeout * 2)) =>
+                {
+                    println!("INFO: clearing FAIL state for node {}", node.id);
+                    node.health = NodeHealth::Online;
+                    node.fail_time = None;
+                    self.todo_save = true;
+                }
+                _ => {}
+            }
+
+            if node.health != NodeHealth::PFail {
+                continue;
+            }
+            let reports = node
+                .fail_reports
+                .keys()
+                .filter(|reporter| masters.contains(reporter))
+                .count()
+                + myself_is_master as usize;
+            if reports >= votes_needed {
+                println!(
+                    "INFO: *** marking node {} as failing (quorum reached)",
+                    node.id
+                );
+                node.health = NodeHealth::Fail;
+                node.fail_time = Some(Instant::now());
+                self.todo_save = true;
+                failed.push(node.id.clone());
+            }
+        }
+        failed
+    }
+
+    /// Decides whether to vote for the replica sending `header` to take over from its
+    /// master. We vote once per epoch, only for a replica of a master we agree is failing,
+    /// and not twice for the same master within twice the node timeout.
+    pub fn grant_vote(&mut self, header: &NodeHeader, node_timeout: Duration) -> bool {
+        let myself = self.myself();
+        if !myself.is_master() || self.slot_ranges(&self.myself).is_empty() {
+            return false;
+        }
+        if header.current_epoch < self.current_epoch || self.last_vote_epoch == self.current_epoch {
+            return false;
+        }
+        let Some(master_id) = &header.master else {
+            return false;
+        };
+        let Some(master) = self.nodes.get_mut(master_id) else {
+            return false;
+        };
+        if master.health != NodeHealth::Fail
+            || master
+                .voted_time
+                .is_some_and(|time| time.elapsed() < node_timeout * 2)
+        {
+            return false;
+        }
+        master.voted_time = Some(Instant::now());
+        self.last_vote_epoch = self.current_epoch;
+        self.todo_save = true;
+        println!(
+            "INFO: voting for {} to take over from {master_id} in epoch {}",
+            header.id, self.current_epoch
+        );
+        true
+    }
+
+    /// One line per node in the format of `CLUSTER NODES` and of the config file
+    pub fn nodes_description(&self) -> String {
+        let mut description = String::new();
+        for node in self.nodes.values() {
+            let mut flags = Vec::new();
+            if node.id == self.myself {
+                flags.push("myself");
+            }
+            let master = match &node.role {
+                NodeRole::Master => {
+                    flags.push("master");
+                    "-"
+                }
+                NodeRole::Replica(master) => {
+                    flags.push("slave");
+                    master.as_str()
+                }
+            };
+            match node.health {
+                NodeHealth::Online => {}
+                NodeHealth::PFail => flags.push("fail?"),
+                NodeHealth::Fail => flags.push("fail"),
+            }
+            description.push_str(&format!(
+                "{} {}:{}@{} {} {} 0 0 {} connected",
+                node.id,
+                node.host,
+                node.port,
+                node.bus_port,
+                flags.join(","),
+                master,
+                node.config_epoch,
+            ));
+            for (start, end) in self.slot_ranges(&node.id) {
+                if start == end {
+                    description.push_str(&format!(" {start}"));
+                } else {
+                    description.push_str(&format!(" {start}-{end}"));
+                }
+            }
+            if node.id == self.myself {
+                for (slot, node_id) in &self.migrating {
+                    description.push_str(&format!(" [{slot}->-{node_id}]"));
+                }
+                for (slot, node_id) in &self.importing {
+                    description.push_str(&format!(" [{slot}-<-{node_id}]"));
+                }
+            }
+            description.push('\n');
+        }
+        description
+    }
+
+    /// Fields reported by `CLUSTER INFO`
+    pub fn info(&self) -> String {
+        format!(
+            "cluster_state:{}{CRLF}cluster_slots_assigned:{}{CRLF}cluster_known_nodes:{}{CRLF}\
+             cluster_size:{}{CRLF}cluster_current_epoch:{}{CRLF}cluster_my_epoch:{}{CRLF}",
+            if self.is_ok() { "ok" } else { "fail" },
+            self.assigned_slots(),
+            self.nodes.len(),
+            self.size(),
+            self.current_epoch,
+            self.myself().config_epoch,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes_config_round_trip() {
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        state.add_slots(&[0, 1, 2, 5]).unwrap();
+        state.current_epoch = 3;
+        let other = ClusterNode::new("b".repeat(NODE_ID_LEN), "127.0.0.1".to_string(), 7001);
+        state.nodes.insert(other.id.clone(), other.clone());
+        state.slots[16383] = Some(other.id.clone());
+
+        let content = format!(
+            "{}vars currentEpoch 3 lastVoteEpoch 0\n",
+            state.nodes_description()
+        );
+        let parsed = ClusterState::parse(&content, PathBuf::from("nodes.conf")).unwrap();
+        assert_eq!(parsed.myself, state.myself);
+        assert_eq!(parsed.nodes, state.nodes);
+        assert_eq!(parsed.slot_ranges(&state.myself), vec![(0, 2), (5, 5)]);
+        assert_eq!(parsed.slot_owner(16383), Some(&other));
+        assert_eq!(parsed.current_epoch, 3);
+    }
+
+    #[test]
+    fn test_add_slots_is_all_or_nothing() {
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        state.add_slots(&[10]).unwrap();
+        assert_eq!(
+            state.add_slots(&[11, 10]),
+            Err("ERR Slot 10 is already busy".to_string())
+        );
+        assert!(state.add_slots(&[16384]).is_err());
+        assert!(state.add_slots(&[12, 12]).is_err());
+        assert_eq!(state.assigned_slots(), 1);
+    }
+
+    fn pong_from(state: &ClusterState, node_id: &str, config_epoch: u64) -> BusMessage {
+        let node = &state.nodes[node_id];
+        BusMessage {
+            kind: MessageKind::Pong,
+            header: NodeHeader {
+                id: node.id.clone(),
+                host: node.host.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                master: None,
+                config_epoch,
+                current_epoch: config_epoch,
+                repl_offset: 0,
+                slots: state.slot_ranges(node_id),
+            },
+            gossip: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_migration_survives_config_round_trip() {
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        let other = ClusterNode::new("b".repeat(NODE_ID_LEN), "127.0.0.1".to_string(), 7001);
+        state.nodes.insert(other.id.clone(), other.clone());
+        state.add_slots(&[1]).unwrap();
+        state
+            .set_slot(1, &SetSlotAction::Migrating(other.id.clone()))
+            .unwrap();
+        state
+            .set_slot(2, &SetSlotAction::Importing(other.id.clone()))
+            .unwrap();
+        assert!(state
+            .set_slot(2, &SetSlotAction::Migrating(other.id.clone()))
+            .is_err());
+
+        let content = format!(
+            "{}vars currentEpoch 0 lastVoteEpoch 0\n",
+            state.nodes_description()
+        );
+        let parsed = ClusterState::parse(&content, PathBuf::from("nodes.conf")).unwrap();
+        assert_eq!(parsed.migrating, BTreeMap::from([(1, other.id.clone())]));
+        assert_eq!(parsed.importing, BTreeMap::from([(2, other.id.clone())]));
+
+        // taking over an imported slot claims it in a new epoch
+        state
+            .set_slot(2, &SetSlotAction::Node(state.myself.clone()))
+            .unwrap();
+        assert!(state.importing.is_empty());
+        assert_eq!(state.myself().config_epoch, 1);
+        assert_eq!(state.slot_ranges(&state.myself), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_newer_claim_takes_over_slots() {
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        let other = ClusterNode::new("b".repeat(NODE_ID_LEN), "127.0.0.1".to_string(), 7001);
+        state.nodes.insert(other.id.clone(), other.clone());
+        state.add_slots(&[0, 1]).unwrap();
+        state.myself_mut().config_epoch = 3;
+
+        let mut claim = pong_from(&state, &other.id, 2);
+        claim.header.slots = vec![(0, 1)];
+        assert_eq!(state.process_message(&claim, false), None);
+        assert_eq!(state.slot_ranges(&state.myself), vec![(0, 1)]);
+
+        claim.header.config_epoch = 4;
+        assert_eq!(
+            state.process_message(&claim, false),
+            Some(("127.0.0.1".to_string(), 7001))
+        );
+        assert_eq!(state.slot_owner(0), state.nodes.get(&other.id));
+        assert_eq!(state.myself().role, NodeRole::Replica(other.id.clone()));
+    }
+
+    #[test]
+    fn test_failure_needs_majority_of_masters() {
+        let node_timeout = Duration::from_millis(100);
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        state.add_slots(&[0]).unwrap();
+        for (id, port, slot) in [("b", 7001, 1), ("c", 7002, 2)] {
+            let node = ClusterNode::new(id.repeat(NODE_ID_LEN), "127.0.0.1".to_string(), port);
+            state.slots[slot] = Some(node.id.clone());
+            state.add_node(node);
+        }
+        let (b, c) = ("b".repeat(NODE_ID_LEN), "c".repeat(NODE_ID_LEN));
+        state.nodes.get_mut(&c).unwrap().pong_received =
+            Some(Instant::now() - Duration::from_secs(1));
+
+        assert!(state.check_failures(node_timeout).is_empty());
+        assert_eq!(state.nodes[&c].health, NodeHealth::PFail);
+
+        // b agrees, which together with us makes two masters out of three
+        let mut gossip = pong_from(&state, &b, 0);
+        gossip.gossip.push(GossipEntry {
+            id: c.clone(),
+            host: "127.0.0.1".to_string(),
+            port: 7002,
+            bus_port: 17002,
+            health: NodeHealth::PFail,
+        });
+        state.process_message(&gossip, false);
+        assert_eq!(state.check_failures(node_timeout), vec![c.clone()]);
+        assert_eq!(state.nodes[&c].health, NodeHealth::Fail);
+        assert!(!state.is_ok());
+    }
+
+    #[test]
+    fn test_vote_once_per_epoch() {
+        let node_timeout = Duration::from_secs(15);
+        let mut state = ClusterState::new("127.0.0.1", 7000, PathBuf::from("nodes.conf"));
+        state.add_slots(&[0]).unwrap();
+        let mut master = ClusterNode::new("b".repeat(NODE_ID_LEN), "127.0.0.1".to_string(), 7001);
+        master.health = NodeHealth::Fail;
+        state.nodes.insert(master.id.clone(), master.clone());
+        state.slots[1] = Some(master.id.clone());
+
+        let mut request = pong_from(&state, &master.id, 0).header;
+        request.id = "c".repeat(NODE_ID_LEN);
+        request.master = Some(master.id.clone());
+        request.current_epoch = 1;
+        state.current_epoch = 1;
+        assert!(state.grant_vote(&request, node_timeout));
+        assert_eq!(state.last_vote_epoch, 1);
+
+        request.id = "d".repeat(NODE_ID_LEN);
+        assert!(!state.grant_vote(&request, node_timeout));
+        // nor again for the same master in a later epoch, until twice the node timeout
+        state.current_epoch = 2;
+        request.current_epoch = 2;
+        assert!(!state.grant_vote(&request, node_timeout));
+    }
+}
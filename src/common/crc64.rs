@@ -0,0 +1,48 @@
+//! CRC-64/Jones as used by Redis for RDB checksums and DUMP payloads
+//! (reflected polynomial 0xad93d23594c935a9, zero init, no final xor).
+
+const POLY_REFLECTED: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY_REFLECTED
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE: [u64; 256] = build_table();
+
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn test_crc64_incremental() {
+        let crc = crc64(0, b"1234");
+        assert_eq!(crc64(crc, b"56789"), crc64(0, b"123456789"));
+    }
+}
@@ -0,0 +1,80 @@
+//! Decompression of LZF, the format Redis compresses long strings of RDB files with.
+//!
+//! The data is a sequence of chunks, each starting with a control byte: below 32 it is
+//! followed by that many plus one literal bytes, otherwise its top 3 bits (extended by
+//! the next byte when all set) give the length minus 2 of a back reference, and its
+//! low 5 bits with the next byte give the distance minus 1 to copy from.
+
+/// The most output a byte of input can produce: a back reference of 3 bytes copies
+/// at most 264 bytes
+const MAX_EXPANSION: usize = 88;
+
+/// Decompresses `data` into exactly `len` bytes, or `None` if it is not valid LZF
+/// data of that length
+pub fn lzf_decompress(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    // the length comes from untrusted input, refuse it before allocating for it
+    if len > data.len().saturating_mul(MAX_EXPANSION) {
+        return None;
+    }
+    let mut output = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < data.len() {
+        let control = data[pos] as usize;
+        pos += 1;
+        if control < 32 {
+            let literal = data.get(pos..pos + control + 1)?;
+            output.extend_from_slice(literal);
+            pos += control + 1;
+        } else {
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *data.get(pos)? as usize;
+                pos += 1;
+            }
+            let distance = ((control & 0x1f) << 8) + *data.get(pos)? as usize + 1;
+            pos += 1;
+            let start = output.len().checked_sub(distance)?;
+            // the reference may overlap what it produces, so it is copied byte by byte
+            for i in start..start + run + 2 {
+                output.push(output[i]);
+            }
+        }
+        if output.len() > len {
+            return None;
+        }
+    }
+    (output.len() == len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lzf_literals_and_back_references() {
+        // "abc", then 5 bytes from 3 back, then 11 bytes from 1 back
+        let data = [0x02, b'a', b'b', b'c', 0x60, 0x02, 0xE0, 0x02, 0x00];
+        assert_eq!(
+            lzf_decompress(&data, 19).unwrap(),
+            b"abcabcabbbbbbbbbbbb".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_lzf_invalid_data() {
+        // wrong length
+        assert_eq!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 4), None);
+        // truncated literal
+        assert_eq!(lzf_decompress(&[0x05, b'a'], 6), None);
+        // reference before the start of the output
+        assert_eq!(lzf_decompress(&[0x00, b'a', 0x20, 0x05], 4), None);
+        // more than the input could ever expand to
+        assert_eq!(lzf_decompress(&[0x00, b'a'], 1 << 62), None);
+    }
+
+    #[test]
+    fn test_lzf_longest_back_reference() {
+        let data = [0x00, b'a', 0xE0, 0xFF, 0x00];
+        assert_eq!(lzf_decompress(&data, 265).unwrap(), vec![b'a'; 265]);
+    }
+}
@@ -1 +1,56 @@
+pub mod crc16;
+pub mod crc64;
+pub mod lzf;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 pub const CRLF: &str = "\r\n";
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Generates a random identifier of `len` lowercase hex characters, such as a
+/// replication ID. Draws on the randomly seeded keys of the std hasher so no
+/// external source of randomness is needed.
+pub fn random_hex_id(len: usize) -> String {
+    let mut id = String::with_capacity(len + 16);
+    let mut counter = 0u64;
+    while id.len() < len {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(counter);
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos())
+                .unwrap_or(0),
+        );
+        id.push_str(&format!("{:016x}", hasher.finish()));
+        counter += 1;
+    }
+    id.truncate(len);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_hex_id() {
+        for len in [0, 7, 16, 40] {
+            let id = random_hex_id(len);
+            assert_eq!(id.len(), len);
+            assert!(id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')));
+        }
+        assert_ne!(random_hex_id(40), random_hex_id(40));
+    }
+}
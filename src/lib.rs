@@ -1,7 +1,10 @@
 pub mod client;
+pub mod cluster;
 pub mod common;
 pub mod network;
 pub mod parser;
+pub mod persistence;
 pub mod replication;
+pub mod sentinel;
 pub mod server;
 pub mod storage;
@@ -1,120 +1,68 @@
 use std::net::TcpListener;
-use std::str;
 use std::sync::Arc;
 
+use codecrafters_redis::cluster;
+use codecrafters_redis::cluster::state::ClusterState;
 use codecrafters_redis::network::connection::Connection;
-use codecrafters_redis::parser::command::parse_command;
-use codecrafters_redis::parser::resp::ParseError;
-use codecrafters_redis::replication::handshake::{self, Handshaker};
+use codecrafters_redis::persistence::aof::{self, AppendOnlyFile};
+use codecrafters_redis::persistence::prepare_shutdown;
+use codecrafters_redis::persistence::rdb::{self, load_rdb_file};
+use codecrafters_redis::replication;
+use codecrafters_redis::sentinel;
+use codecrafters_redis::sentinel::state::{Sentinel, SentinelSettings};
 use codecrafters_redis::server::config::Config;
-use codecrafters_redis::server::data::{LiveData, Server};
-use codecrafters_redis::server::handler::CommandHandler;
+use codecrafters_redis::server::data::Server;
 use codecrafters_redis::server::metadata::{ReplicaInfo, ServerMetadata};
+use codecrafters_redis::server::session::handle_connection;
 
-const HOST: &str = "127.0.0.1";
-
-fn handle_read_loop(conn: &mut Connection, server: Arc<Server>) -> std::io::Result<()> {
-    let mut handler = CommandHandler::new(conn.stream.try_clone()?, server.clone());
+fn serve_clients(server: Arc<Server>, host: &str) -> anyhow::Result<()> {
+    let listening_port = server.metadata.listening_port;
 
-    loop {
-        match parse_command(conn.get_buffer()) {
-            Ok(result) => {
-                let command = result.command;
+    let addr = (host, listening_port);
+    let listener = TcpListener::bind(addr)?;
 
-                handler.handle_command(&command)?;
+    println!("INFO: started listener on {:?}", listener.local_addr());
 
-                if let LiveData::Slave(data) = &mut *server.live_data.lock().unwrap() {
-                    data.offset += result.len;
-                }
-                conn.consume(result.len);
-            }
-            Err(ParseError::Incomplete) => conn.read_message()?, // Not enough data to parse the command
-            Err(ParseError::Invalid) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid message format",
-                ));
+    for incoming_stream in listener.incoming() {
+        match incoming_stream {
+            Ok(stream) => {
+                println!(
+                    "INFO: accepted incoming connection from {:?}",
+                    stream.peer_addr()
+                );
+                let mut conn = Connection::new(stream);
+                let server = server.clone();
+                std::thread::spawn(move || handle_connection(&mut conn, server, None));
             }
-        }
-    }
-}
-
-fn handle_connection(conn: &mut Connection, server: Arc<Server>) -> std::io::Result<()> {
-    match handle_read_loop(conn, server.clone()) {
-        Ok(_) => {
-            println!("INFO: client disconnected");
-        }
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                println!("INFO: client disconnected");
-            } else {
-                eprintln!("ERROR: failed to handle connection with error {:?}", &err);
+            Err(error) => {
+                eprintln!(
+                    "ERROR: failed to accept an incoming connection with error {:?}",
+                    &error
+                );
             }
         }
     }
-    // TOOD: Instead of calling remove_replica unconditionally, propagate replica status for the client here
-    // and only make remove_replica call if the client is actively replicating
-    //
-    // We cannot know whether there is atleast one replica listening for commands since this
-    // information is only known by replication sub-system, hence propagate all commands
-    if let ReplicaInfo::Master(_) = server.metadata.replica_info {
-        server.handle_disconnect(conn);
-    }
-    Ok(())
-}
-
-fn initiate_replication(
-    host: String,
-    port: u16,
-    host_port: u16,
-    server: Arc<Server>,
-) -> anyhow::Result<()> {
-    println!("INFO: connecting to master at {}:{}", &host, port);
-
-    let config = handshake::Config {
-        master_host: host.clone(),
-        master_port: port,
-        replica_port: host_port,
-    };
-    let handshaker = Handshaker::new(config);
-
-    let mut payload = match handshaker.perform_handshake() {
-        Ok(payload) => {
-            println!("INFO: successfully performed handshake with master");
-            payload
-        }
-        Err(err) => {
-            eprintln!("ERROR: failed to perform handshake with master: {:?}", &err);
-            return Err(anyhow::anyhow!(
-                "Failed to perform handshake with master: {:?}",
-                &err
-            ));
-        }
-    };
-
-    handle_connection(payload.client.get_connection(), server)?;
 
     Ok(())
 }
 
-fn serve_clients(server: Arc<Server>) -> anyhow::Result<()> {
-    let listening_port = server.metadata.listening_port;
-
-    let addr = (HOST, listening_port);
+fn serve_sentinel(sentinel: Arc<Sentinel>, host: &str) -> anyhow::Result<()> {
+    let addr = (host, sentinel.settings.listening_port);
     let listener = TcpListener::bind(addr)?;
 
-    println!("INFO: started listener on {:?}", listener.local_addr());
+    println!(
+        "INFO: started sentinel listener on {:?}",
+        listener.local_addr()
+    );
 
     for incoming_stream in listener.incoming() {
         match incoming_stream {
             Ok(stream) => {
-                println!(
-                    "INFO: accepted incoming connection from {:?}",
-                    stream.peer_addr()
-                );
                 let mut conn = Connection::new(stream);
-                let server = server.clone();
-                std::thread::spawn(move || handle_connection(&mut conn, server));
+                let sentinel = sentinel.clone();
+                std::thread::spawn(move || {
+                    sentinel::handler::handle_connection(&mut conn, sentinel)
+                });
             }
             Err(error) => {
                 eprintln!(
@@ -128,35 +76,170 @@ fn serve_clients(server: Arc<Server>) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn run_sentinel(config: &Config) -> anyhow::Result<()> {
+    let monitors = config.get_sentinel_monitors();
+    if monitors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Sentinel mode requires at least one --sentinel-monitor"
+        ));
+    }
+
+    let sentinel = Arc::new(Sentinel::new(SentinelSettings::generate(config), &monitors));
+    for monitor in monitors {
+        println!(
+            "INFO: +monitor master {} {} {} quorum {}",
+            monitor.name, monitor.host, monitor.port, monitor.quorum
+        );
+        sentinel::monitor::start(sentinel.clone(), monitor.name);
+    }
+
+    serve_sentinel(sentinel, config.get_bind())
+}
+
+/// Replays the append-only file, which takes precedence over the RDB file. When nothing
+/// was logged yet the RDB file is loaded instead and becomes the base of the log, so that
+/// enabling appendonly keeps the existing dataset.
+fn load_aof(server: &Arc<Server>) -> anyhow::Result<()> {
+    let Some(aof) = server.aof.as_ref() else {
+        return Ok(());
+    };
+    if aof.lock().unwrap().is_empty() {
+        load_dataset(server)?;
+        return aof::rewrite(server).map_err(|err| {
+            eprintln!("ERROR: failed to create the AOF base file: {err}");
+            anyhow::anyhow!("Failed to create the AOF base file: {}", err)
+        });
+    }
+
+    aof::load_aof_files(server).map_err(|err| {
+        eprintln!("ERROR: failed to load AOF: {err}");
+        anyhow::anyhow!("Failed to load AOF: {}", err)
+    })
+}
+
+fn load_dataset(server: &Server) -> anyhow::Result<()> {
+    let Some(rdb_config) = server.metadata.rdb_config.as_ref() else {
+        return Ok(());
+    };
+
+    let path = rdb_config.get_path();
+    if !path.exists() {
+        println!("INFO: no RDB file found at {path:?}, starting with an empty dataset");
+        return Ok(());
+    }
+
+    match load_rdb_file(server, &path) {
+        Ok(stats) => {
+            println!(
+                "INFO: loaded {} keys from {path:?} ({})",
+                stats.loaded,
+                stats.skipped()
+            );
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("ERROR: failed to load RDB file {path:?}: {err}");
+            Err(anyhow::anyhow!(
+                "Failed to load RDB file {:?}: {}",
+                &path,
+                err
+            ))
+        }
+    }
+}
+
+/// Saves what has to be on SIGTERM and SIGINT before exiting. The server keeps running
+/// if that fails, like it does for a failed SHUTDOWN.
+fn handle_signals(server: Arc<Server>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let (mut terminate, mut interrupt) = runtime.block_on(async {
+        anyhow::Ok((
+            signal(SignalKind::terminate())?,
+            signal(SignalKind::interrupt())?,
+        ))
+    })?;
+    std::thread::spawn(move || loop {
+        let name = runtime.block_on(async {
+            tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            }
+        });
+        println!("INFO: received {name}, scheduling shutdown");
+        match prepare_shutdown(&server, None) {
+            Ok(()) => {
+                println!("INFO: shutting down on {name}");
+                std::process::exit(0);
+            }
+            Err(err) => eprintln!("ERROR: failed to prepare the shutdown with error {err:?}"),
+        }
+    });
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     let config = Config::new();
     println!("DEBUG: parsed cli args: {:?}", &config);
 
+    if config.is_sentinel() {
+        return run_sentinel(&config);
+    }
+
     let metadata = ServerMetadata::generate(&config);
-    let server = Arc::new(Server::new(metadata));
+    let cluster = match &metadata.cluster_config_path {
+        Some(path) => {
+            let port = metadata.listening_port;
+            Some(
+                ClusterState::load_or_create(config.get_bind(), port, path.clone()).map_err(
+                    |err| {
+                        eprintln!("ERROR: failed to load cluster config {path:?}: {err}");
+                        anyhow::anyhow!("Failed to load cluster config {:?}: {}", path, err)
+                    },
+                )?,
+            )
+        }
+        None => None,
+    };
+    let aof = match &metadata.aof_config {
+        Some(aof_config) => Some(AppendOnlyFile::open(aof_config).map_err(|err| {
+            eprintln!("ERROR: failed to open AOF in {:?}: {err}", aof_config.dir);
+            anyhow::anyhow!("Failed to open AOF in {:?}: {}", aof_config.dir, err)
+        })?),
+        None => None,
+    };
+    let server = Arc::new(Server::new(
+        metadata,
+        ReplicaInfo::from_config(&config),
+        cluster,
+        aof,
+    ));
+
+    // restore the dataset before accepting any connections
+    if server.aof.is_some() {
+        load_aof(&server)?;
+        aof::start(server.clone());
+    } else {
+        load_dataset(&server)?;
+    }
+    rdb::start(server.clone());
+    handle_signals(server.clone())?;
 
     // start replication
-    if let ReplicaInfo::Slave(ref info) = server.metadata.replica_info {
-        println!("INFO: starting replication as slave");
-
-        let master_host = info.master_host.clone();
-        let master_port = info.master_port;
-        let server = server.clone();
-        std::thread::spawn(move || {
-            let result = initiate_replication(
-                master_host,
-                master_port,
-                server.metadata.listening_port,
-                server,
-            );
-            if let Err(err) = result {
-                eprintln!("ERROR: replication failed with error {:?}", &err);
-            }
-        });
+    replication::link::start(&server);
+    replication::cron::start(server.clone());
+
+    // get in touch with the rest of the cluster
+    if server.cluster.is_some() {
+        cluster::bus::start(&server, config.get_bind())?;
+        cluster::cron::start(server.clone());
     }
 
     // start server
-    serve_clients(server.clone())?;
+    serve_clients(server.clone(), config.get_bind())?;
 
     Ok(())
 }
@@ -1,7 +1,10 @@
 use crate::parser::resp::ParseError;
 use std::io;
 use std::io::Write;
-use std::{io::Read, net::TcpStream};
+use std::{
+    io::Read,
+    net::{SocketAddr, TcpStream},
+};
 
 pub type ConnectionResult<T> = std::result::Result<T, ConnectionError>;
 
@@ -22,16 +25,24 @@ impl From<std::io::Error> for ConnectionError {
 pub struct Connection {
     pub stream: TcpStream,
     buffer: Vec<u8>,
+    // captured up front since it is no longer available once the peer goes away
+    peer_addr: Option<SocketAddr>,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
         Self {
             stream,
             buffer: Vec::new(),
+            peer_addr,
         }
     }
 
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
     pub fn from_existing(stream: TcpStream, buffer: &[u8]) -> Self {
         let mut conn = Self::new(stream);
         conn.buffer[..buffer.len()].copy_from_slice(buffer);
@@ -67,6 +78,31 @@ impl Connection {
         Ok(())
     }
 
+    /// Reads bytes past any message framing, handing out what is already buffered first.
+    /// Meant for bulk payloads too large to go through `read_message`.
+    pub fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffer.is_empty() {
+            let n = buf.len().min(self.buffer.len());
+            buf[..n].copy_from_slice(&self.buffer[..n]);
+            self.consume(n);
+            return Ok(n);
+        }
+
+        loop {
+            match self.stream.read(buf) {
+                Ok(0) if !buf.is_empty() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed",
+                    ))
+                }
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub fn write_message(&mut self, message: &[u8]) -> io::Result<()> {
         self.stream.write_all(message)?;
         Ok(())
@@ -80,3 +116,36 @@ impl Connection {
         &self.buffer
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::replica_manager::tests::connected_pair;
+
+    #[test]
+    fn test_read_raw_hands_out_buffered_bytes_first() {
+        let (local, mut remote) = connected_pair();
+        let mut conn = Connection::new(local);
+        remote.write_all(b"$10\r\nhello").unwrap();
+        while conn.get_buffer().len() < 10 {
+            conn.read_message().unwrap();
+        }
+        conn.consume(5);
+
+        let mut buf = [0; 3];
+        assert_eq!(conn.read_raw(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        let mut buf = [0; 10];
+        assert_eq!(conn.read_raw(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert!(conn.get_buffer().is_empty());
+
+        remote.write_all(b"world").unwrap();
+        assert_eq!(conn.read_raw(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+
+        drop(remote);
+        let err = conn.read_raw(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,5 +1,7 @@
 use std::time::Duration;
 
+use crate::common::unix_time_ms;
+
 use super::resp::parse_buffer;
 use super::resp::ParseError;
 use super::resp::Result;
@@ -17,6 +19,79 @@ pub enum ReplConfCommand {
 #[derive(Debug, PartialEq)]
 pub enum ConfigCommand {
     Get(String),
+    Set { param: String, value: String },
+}
+
+/// Configuration a sentinel announces to its peers, the fields of a Redis hello message
+#[derive(Debug, PartialEq, Clone)]
+pub struct SentinelHello {
+    pub host: String,
+    pub port: u16,
+    pub runid: String,
+    pub current_epoch: u64,
+    pub master_name: String,
+    pub master_host: String,
+    pub master_port: u16,
+    pub master_config_epoch: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SentinelCommand {
+    Masters,
+    Master(String),
+    Replicas(String),
+    GetMasterAddrByName(String),
+    /// Asked by peer sentinels, `runid` is `*` unless the peer also wants our vote as leader
+    IsMasterDownByAddr {
+        host: String,
+        port: u16,
+        current_epoch: u64,
+        runid: String,
+    },
+    Hello(SentinelHello),
+}
+
+/// What `CLUSTER SETSLOT` does with a slot
+#[derive(Debug, PartialEq)]
+pub enum SetSlotAction {
+    /// Keys of the slot are being moved to the node with the given id
+    Migrating(String),
+    /// Keys of the slot are being moved here from the node with the given id
+    Importing(String),
+    /// Ends a migration in either direction
+    Stable,
+    /// Assigns the slot to the node with the given id
+    Node(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClusterCommand {
+    Info,
+    MyId,
+    Nodes,
+    Slots,
+    Shards,
+    KeySlot(Vec<u8>),
+    /// Slots are range checked when they are assigned
+    AddSlots(Vec<i64>),
+    /// Introduces the node listening on `host:port` to the cluster, its bus port being
+    /// derived from the client port unless given
+    Meet {
+        host: String,
+        port: u16,
+        bus_port: Option<u16>,
+    },
+    /// Makes this node a replica of the master with the given id
+    Replicate(String),
+    SetSlot {
+        slot: i64,
+        action: SetSlotAction,
+    },
+    CountKeysInSlot(i64),
+    GetKeysInSlot {
+        slot: i64,
+        count: usize,
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -31,17 +106,215 @@ pub enum Command {
     },
     Info(Vec<u8>),
     ReplConf(ReplConfCommand),
-    Psync,
+    Psync {
+        replication_id: String,
+        offset: i64,
+    },
     Wait {
         replica_count: usize,
         timeout: Duration,
     },
     Config(ConfigCommand),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// `save` is `None` when neither SAVE nor NOSAVE was given
+    Shutdown {
+        save: Option<bool>,
+    },
+    /// `None` stands for `REPLICAOF NO ONE`
+    ReplicaOf(Option<(String, u16)>),
+    Role,
+    Failover {
+        target: Option<(String, u16)>,
+        timeout: Option<Duration>,
+        force: bool,
+        abort: bool,
+    },
+    Sentinel(SentinelCommand),
+    Cluster(ClusterCommand),
+    Del(Vec<Vec<u8>>),
+    /// Lets the next command reach a slot this node is importing
+    Asking,
+    Dump(Vec<u8>),
+    Restore {
+        key: Vec<u8>,
+        payload: Vec<u8>,
+        expiry: Option<Duration>,
+        replace: bool,
+    },
+    /// Moves keys to another node, an empty key standing for the keys given with KEYS
+    Migrate {
+        host: String,
+        port: u16,
+        keys: Vec<Vec<u8>>,
+        db: u64,
+        timeout: Duration,
+        copy: bool,
+        replace: bool,
+    },
+    LPush {
+        key: Vec<u8>,
+        elements: Vec<Vec<u8>>,
+    },
+    RPush {
+        key: Vec<u8>,
+        elements: Vec<Vec<u8>>,
+    },
+    /// `count` is `None` when not given, in which case a single element is replied
+    /// instead of an array
+    LPop {
+        key: Vec<u8>,
+        count: Option<usize>,
+    },
+    RPop {
+        key: Vec<u8>,
+        count: Option<usize>,
+    },
+    /// Indexes may be negative to count from the tail, -1 being the last element
+    LRange {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    LLen(Vec<u8>),
+    LIndex {
+        key: Vec<u8>,
+        index: i64,
+    },
+    LSet {
+        key: Vec<u8>,
+        index: i64,
+        element: Vec<u8>,
+    },
+    /// Removes `count` occurrences from the head, or from the tail if negative, or
+    /// all of them if zero
+    LRem {
+        key: Vec<u8>,
+        count: i64,
+        element: Vec<u8>,
+    },
+    LTrim {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    LInsert {
+        key: Vec<u8>,
+        before: bool,
+        pivot: Vec<u8>,
+        element: Vec<u8>,
+    },
+    /// Pops an element from one end of `source` and pushes it at one end of
+    /// `destination`, the head being the left end
+    LMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from_left: bool,
+        to_left: bool,
+    },
+    /// A command with arguments Redis refuses, replied to with the error it holds
+    Invalid(String),
 }
 
 impl Command {
-    pub fn to_resp_token(&self) -> Token {
+    /// Whether the command modifies the dataset and therefore has to reach the replicas
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Del(_)
+                | Command::Restore { .. }
+                | Command::LPush { .. }
+                | Command::RPush { .. }
+                | Command::LPop { .. }
+                | Command::RPop { .. }
+                | Command::LSet { .. }
+                | Command::LRem { .. }
+                | Command::LTrim { .. }
+                | Command::LInsert { .. }
+                | Command::LMove { .. }
+        )
+    }
+
+    /// Keys the command operates on, which decide the node serving it in cluster mode
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Get(key)
+            | Command::Set { key, .. }
+            | Command::Dump(key)
+            | Command::Restore { key, .. }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::LPop { key, .. }
+            | Command::RPop { key, .. }
+            | Command::LRange { key, .. }
+            | Command::LLen(key)
+            | Command::LIndex { key, .. }
+            | Command::LSet { key, .. }
+            | Command::LRem { key, .. }
+            | Command::LTrim { key, .. }
+            | Command::LInsert { key, .. } => vec![key],
+            Command::LMove {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+            Command::Del(keys) | Command::Migrate { keys, .. } => {
+                keys.iter().map(Vec::as_slice).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns a deterministic form of a write command to propagate in place of the
+    /// bytes we received, or `None` if those can be replayed as they are. Relative
+    /// expiries become absolute so that replicas expire keys at the same moment.
+    pub fn to_propagated_token(&self, now_ms: u64) -> Option<Token> {
         match self {
+            Command::Set {
+                key,
+                value,
+                expiry: Some(expiry),
+            } => {
+                let expire_at_ms = expire_at_ms(now_ms, expiry);
+                Some(Token::Array(vec![
+                    Token::BulkString(b"SET".to_vec()),
+                    Token::BulkString(key.to_vec()),
+                    Token::BulkString(value.to_vec()),
+                    Token::BulkString(b"PXAT".to_vec()),
+                    Token::BulkString(expire_at_ms.to_string().into_bytes()),
+                ]))
+            }
+            Command::Restore {
+                key,
+                payload,
+                expiry: Some(expiry),
+                replace,
+            } => {
+                let expire_at_ms = expire_at_ms(now_ms, expiry);
+                let mut tokens = vec![
+                    Token::BulkString(b"RESTORE".to_vec()),
+                    Token::BulkString(key.to_vec()),
+                    Token::BulkString(expire_at_ms.to_string().into_bytes()),
+                    Token::BulkString(payload.to_vec()),
+                ];
+                if *replace {
+                    tokens.push(Token::BulkString(b"REPLACE".to_vec()));
+                }
+                tokens.push(Token::BulkString(b"ABSTTL".to_vec()));
+                Some(Token::Array(tokens))
+            }
+            _ => None,
+        }
+    }
+
+    /// The RESP form of the commands we send to other nodes ourselves, `None` for the
+    /// other ones
+    pub fn to_resp_token(&self) -> Option<Token> {
+        let token = match self {
+            Command::Ping => Token::Array(vec![Token::BulkString(b"PING".to_vec())]),
             Command::Set { key, value, expiry } => {
                 let mut tokens = vec![
                     Token::BulkString(b"set".to_vec()),
@@ -56,26 +329,42 @@ impl Command {
                 }
                 Token::Array(tokens)
             }
+            Command::Del(keys) => Token::Array(
+                std::iter::once(b"DEL".to_vec())
+                    .chain(keys.iter().cloned())
+                    .map(Token::BulkString)
+                    .collect(),
+            ),
             Command::ReplConf(replconf_cmd) => {
-                let mut tokens = vec![Token::BulkString(b"REPLCONF".to_vec())];
-                match replconf_cmd {
-                    ReplConfCommand::Ack(offset) => {
-                        tokens.push(Token::BulkString(b"ACK".to_vec()));
-                        tokens.push(Token::BulkString(offset.to_string().as_bytes().to_vec()));
+                let args = match replconf_cmd {
+                    ReplConfCommand::Ack(offset) => vec!["ACK".to_string(), offset.to_string()],
+                    ReplConfCommand::GetAck(offset) => vec!["GETACK".to_string(), offset.clone()],
+                    ReplConfCommand::ListeningPort(port) => {
+                        vec!["listening-port".to_string(), port.to_string()]
                     }
-                    ReplConfCommand::GetAck(offset) => {
-                        tokens.push(Token::BulkString(b"GETACK".to_vec()));
-                        tokens.push(Token::BulkString(offset.as_bytes().to_vec()));
+                    ReplConfCommand::Capa(capability) => {
+                        vec!["capa".to_string(), capability.clone()]
                     }
-                    _ => unimplemented!(),
-                }
-                Token::Array(tokens)
+                    ReplConfCommand::Other(_) => return None,
+                };
+                Token::Array(
+                    std::iter::once("REPLCONF".to_string())
+                        .chain(args)
+                        .map(|arg| Token::BulkString(arg.into_bytes()))
+                        .collect(),
+                )
             }
-            _ => unimplemented!(),
-        }
+            _ => return None,
+        };
+        Some(token)
     }
 }
 
+/// Deadline of an expiry in milliseconds since the epoch, the parser keeping it in range
+fn expire_at_ms(now_ms: u64, expiry: &Duration) -> u64 {
+    now_ms.saturating_add(u64::try_from(expiry.as_millis()).unwrap_or(u64::MAX))
+}
+
 pub struct CommandResult {
     pub command: Command,
     pub len: usize,
@@ -108,19 +397,16 @@ fn compile_set_command(tokens: &[Token]) -> Result<Command> {
                 match token {
                     Token::BulkString(arg) => {
                         let arg = std::str::from_utf8(arg)?.to_lowercase();
-                        match arg.as_str() {
-                            "px" => {
-                                if let Some(Token::BulkString(expiry_value)) = iter.next() {
-                                    let millis = std::str::from_utf8(expiry_value)?.parse()?;
-                                    expiry = Some(Duration::from_millis(millis));
-                                } else {
-                                    return Err(ParseError::Invalid)?;
-                                }
-                            }
-                            _ => {
-                                unimplemented!("Unsupported argument: {}", arg);
-                            }
-                        }
+                        let Some(Token::BulkString(expiry_value)) = iter.next() else {
+                            return Err(ParseError::Invalid)?;
+                        };
+                        let expiry_value: i64 = std::str::from_utf8(expiry_value)?.parse()?;
+                        let Some(time_left) = set_expiry(&arg, expiry_value)? else {
+                            return Ok(Command::Invalid(
+                                "ERR invalid expire time in 'set' command".to_string(),
+                            ));
+                        };
+                        expiry = Some(time_left);
                     }
                     _ => return Err(ParseError::Invalid)?,
                 }
@@ -135,6 +421,30 @@ fn compile_set_command(tokens: &[Token]) -> Result<Command> {
     }
 }
 
+/// Turns a SET expiry option into the time left from now, or `None` for the values
+/// Redis refuses: zero, negative, or a deadline past what milliseconds since the epoch
+/// hold, which replicas and the append-only file would get as PXAT. Absolute expiries
+/// are kept as the time left too, a deadline in the past expiring the key straight away.
+fn set_expiry(option: &str, value: i64) -> Result<Option<Duration>> {
+    let (unit_ms, absolute) = match option {
+        "ex" => (1000, false),
+        "px" => (1, false),
+        "exat" => (1000, true),
+        "pxat" => (1, true),
+        _ => return Err(ParseError::Invalid)?,
+    };
+    let now_ms = unix_time_ms();
+    let deadline_ms = Some(value)
+        .filter(|value| *value > 0)
+        .and_then(|value| value.checked_mul(unit_ms))
+        .and_then(|ms| match absolute {
+            true => Some(ms),
+            false => ms.checked_add(now_ms as i64),
+        });
+    Ok(deadline_ms
+        .map(|deadline_ms| Duration::from_millis((deadline_ms as u64).saturating_sub(now_ms))))
+}
+
 fn compile_info_command(tokens: &[Token]) -> Result<Command> {
     match tokens {
         [Token::BulkString(section)] => Ok(Command::Info(section.clone())),
@@ -171,12 +481,8 @@ fn compile_replconf_command(tokens: &[Token]) -> Result<Command> {
                     }
                     _ => Err(ParseError::Invalid)?,
                 },
-                _ => {
-                    assert!(rest.is_empty());
-                    // If there are no more tokens, we can safely ignore them
-                    // and return the command as Other
-                    ReplConfCommand::Other(replconf_type)
-                }
+                _ if rest.is_empty() => ReplConfCommand::Other(replconf_type),
+                _ => Err(ParseError::Invalid)?,
             };
             Ok(Command::ReplConf(command))
         }
@@ -184,8 +490,14 @@ fn compile_replconf_command(tokens: &[Token]) -> Result<Command> {
     }
 }
 
-fn compile_psync_command(_: &[Token]) -> Result<Command> {
-    Ok(Command::Psync)
+fn compile_psync_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(replication_id), Token::BulkString(offset)] => Ok(Command::Psync {
+            replication_id: std::str::from_utf8(replication_id)?.to_string(),
+            offset: std::str::from_utf8(offset)?.parse()?,
+        }),
+        _ => Err(ParseError::Invalid)?,
+    }
 }
 
 fn compile_wait_command(tokens: &[Token]) -> Result<Command> {
@@ -213,6 +525,13 @@ fn compile_config_command(tokens: &[Token]) -> Result<Command> {
                     }
                     _ => Err(ParseError::Invalid)?,
                 },
+                "set" => match rest {
+                    [Token::BulkString(param), Token::BulkString(value)] => ConfigCommand::Set {
+                        param: std::str::from_utf8(param)?.to_ascii_lowercase(),
+                        value: std::str::from_utf8(value)?.to_string(),
+                    },
+                    _ => Err(ParseError::Invalid)?,
+                },
                 _ => Err(ParseError::Invalid)?,
             };
             Ok(Command::Config(command))
@@ -221,6 +540,506 @@ fn compile_config_command(tokens: &[Token]) -> Result<Command> {
     }
 }
 
+fn compile_save_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::Save),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_bgsave_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::BgSave),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_bgrewriteaof_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::BgRewriteAof),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_shutdown_command(tokens: &[Token]) -> Result<Command> {
+    let mut save = None;
+    for token in tokens {
+        let arg = std::str::from_utf8(token.get_bulk_string_data()?)?.to_ascii_lowercase();
+        save = match arg.as_str() {
+            "save" if save.is_none() => Some(true),
+            "nosave" if save.is_none() => Some(false),
+            _ => Err(ParseError::Invalid)?,
+        };
+    }
+    Ok(Command::Shutdown { save })
+}
+
+fn compile_lastsave_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::LastSave),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_replicaof_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(host), Token::BulkString(port)] => {
+            let host = std::str::from_utf8(host)?;
+            let port = std::str::from_utf8(port)?;
+            if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                Ok(Command::ReplicaOf(None))
+            } else {
+                Ok(Command::ReplicaOf(Some((host.to_string(), port.parse()?))))
+            }
+        }
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_role_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::Role),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_failover_command(tokens: &[Token]) -> Result<Command> {
+    let mut target = None;
+    let mut timeout = None;
+    let mut force = false;
+    let mut abort = false;
+    let mut iter = tokens.iter();
+    while let Some(token) = iter.next() {
+        let Token::BulkString(arg) = token else {
+            return Err(ParseError::Invalid)?;
+        };
+        match std::str::from_utf8(arg)?.to_lowercase().as_str() {
+            "to" => match (iter.next(), iter.next()) {
+                (Some(Token::BulkString(host)), Some(Token::BulkString(port))) => {
+                    let host = std::str::from_utf8(host)?.to_string();
+                    target = Some((host, std::str::from_utf8(port)?.parse()?));
+                }
+                _ => return Err(ParseError::Invalid)?,
+            },
+            "timeout" => match iter.next() {
+                Some(Token::BulkString(millis)) => {
+                    let millis = std::str::from_utf8(millis)?.parse()?;
+                    if millis == 0 {
+                        Err(ParseError::Invalid)?;
+                    }
+                    timeout = Some(Duration::from_millis(millis));
+                }
+                _ => return Err(ParseError::Invalid)?,
+            },
+            "force" => force = true,
+            "abort" => abort = true,
+            _ => return Err(ParseError::Invalid)?,
+        }
+    }
+    // ABORT does not combine with any other option
+    if abort && (target.is_some() || timeout.is_some() || force) {
+        Err(ParseError::Invalid)?;
+    }
+    Ok(Command::Failover {
+        target,
+        timeout,
+        force,
+        abort,
+    })
+}
+
+fn compile_sentinel_command(tokens: &[Token]) -> Result<Command> {
+    let args = tokens
+        .iter()
+        .map(|token| Ok(std::str::from_utf8(token.get_bulk_string_data()?)?))
+        .collect::<Result<Vec<_>>>()?;
+    let Some((subcommand, args)) = args.split_first() else {
+        return Err(ParseError::Invalid)?;
+    };
+    let command = match (subcommand.to_lowercase().as_str(), args) {
+        ("masters", []) => SentinelCommand::Masters,
+        ("master", [name]) => SentinelCommand::Master(name.to_string()),
+        ("replicas" | "slaves", [name]) => SentinelCommand::Replicas(name.to_string()),
+        ("get-master-addr-by-name", [name]) => {
+            SentinelCommand::GetMasterAddrByName(name.to_string())
+        }
+        ("is-master-down-by-addr", [host, port, current_epoch, runid]) => {
+            SentinelCommand::IsMasterDownByAddr {
+                host: host.to_string(),
+                port: port.parse()?,
+                current_epoch: current_epoch.parse()?,
+                runid: runid.to_string(),
+            }
+        }
+        (
+            "hello",
+            [host, port, runid, current_epoch, master_name, master_host, master_port, master_config_epoch],
+        ) => SentinelCommand::Hello(SentinelHello {
+            host: host.to_string(),
+            port: port.parse()?,
+            runid: runid.to_string(),
+            current_epoch: current_epoch.parse()?,
+            master_name: master_name.to_string(),
+            master_host: master_host.to_string(),
+            master_port: master_port.parse()?,
+            master_config_epoch: master_config_epoch.parse()?,
+        }),
+        _ => Err(ParseError::Invalid)?,
+    };
+    Ok(Command::Sentinel(command))
+}
+
+fn compile_cluster_command(tokens: &[Token]) -> Result<Command> {
+    let Some((Token::BulkString(subcommand), args)) = tokens.split_first() else {
+        return Err(ParseError::Invalid)?;
+    };
+    let command = match (
+        std::str::from_utf8(subcommand)?.to_lowercase().as_str(),
+        args,
+    ) {
+        ("info", []) => ClusterCommand::Info,
+        ("myid", []) => ClusterCommand::MyId,
+        ("nodes", []) => ClusterCommand::Nodes,
+        ("slots", []) => ClusterCommand::Slots,
+        ("shards", []) => ClusterCommand::Shards,
+        ("keyslot", [Token::BulkString(key)]) => ClusterCommand::KeySlot(key.clone()),
+        ("addslots", slots) if !slots.is_empty() => ClusterCommand::AddSlots(
+            slots
+                .iter()
+                .map(|slot| Ok(std::str::from_utf8(slot.get_bulk_string_data()?)?.parse()?))
+                .collect::<Result<_>>()?,
+        ),
+        ("meet", [host, port, rest @ ..]) if rest.len() <= 1 => ClusterCommand::Meet {
+            host: std::str::from_utf8(host.get_bulk_string_data()?)?.to_string(),
+            port: std::str::from_utf8(port.get_bulk_string_data()?)?.parse()?,
+            bus_port: match rest {
+                [bus_port] => Some(std::str::from_utf8(bus_port.get_bulk_string_data()?)?.parse()?),
+                _ => None,
+            },
+        },
+        ("replicate", [Token::BulkString(node_id)]) => {
+            ClusterCommand::Replicate(std::str::from_utf8(node_id)?.to_string())
+        }
+        ("setslot", [Token::BulkString(slot), Token::BulkString(action), rest @ ..]) => {
+            let node_id = match rest {
+                [Token::BulkString(node_id)] => Some(std::str::from_utf8(node_id)?.to_string()),
+                [] => None,
+                _ => Err(ParseError::Invalid)?,
+            };
+            let action = match (
+                std::str::from_utf8(action)?.to_lowercase().as_str(),
+                node_id,
+            ) {
+                ("migrating", Some(node_id)) => SetSlotAction::Migrating(node_id),
+                ("importing", Some(node_id)) => SetSlotAction::Importing(node_id),
+                ("stable", None) => SetSlotAction::Stable,
+                ("node", Some(node_id)) => SetSlotAction::Node(node_id),
+                _ => Err(ParseError::Invalid)?,
+            };
+            ClusterCommand::SetSlot {
+                slot: std::str::from_utf8(slot)?.parse()?,
+                action,
+            }
+        }
+        ("countkeysinslot", [Token::BulkString(slot)]) => {
+            ClusterCommand::CountKeysInSlot(std::str::from_utf8(slot)?.parse()?)
+        }
+        ("getkeysinslot", [Token::BulkString(slot), Token::BulkString(count)]) => {
+            ClusterCommand::GetKeysInSlot {
+                slot: std::str::from_utf8(slot)?.parse()?,
+                count: std::str::from_utf8(count)?.parse()?,
+            }
+        }
+        _ => Err(ParseError::Invalid)?,
+    };
+    Ok(Command::Cluster(command))
+}
+
+fn compile_del_command(tokens: &[Token]) -> Result<Command> {
+    if tokens.is_empty() {
+        Err(ParseError::Invalid)?;
+    }
+    let keys = tokens
+        .iter()
+        .map(|token| Ok(token.get_bulk_string_data()?.to_vec()))
+        .collect::<Result<_>>()?;
+    Ok(Command::Del(keys))
+}
+
+fn compile_asking_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [] => Ok(Command::Asking),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_dump_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key)] => Ok(Command::Dump(key.clone())),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_restore_command(tokens: &[Token]) -> Result<Command> {
+    let [Token::BulkString(key), Token::BulkString(ttl), Token::BulkString(payload), rest @ ..] =
+        tokens
+    else {
+        return Err(ParseError::Invalid)?;
+    };
+    let ttl: i64 = std::str::from_utf8(ttl)?.parse()?;
+    let mut replace = false;
+    let mut absttl = false;
+    for token in rest {
+        match std::str::from_utf8(token.get_bulk_string_data()?)?
+            .to_lowercase()
+            .as_str()
+        {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            _ => return Err(ParseError::Invalid)?,
+        }
+    }
+    // the deadline is propagated as an absolute TTL, which has to hold it
+    let now_ms = unix_time_ms();
+    if ttl < 0 || (!absttl && ttl.checked_add(now_ms as i64).is_none()) {
+        return Ok(Command::Invalid(
+            "ERR Invalid TTL value, must be >= 0".to_string(),
+        ));
+    }
+    // a zero TTL means no expiry, and like for SET an absolute one is kept as the
+    // time left from now
+    let expiry = match (ttl as u64, absttl) {
+        (0, _) => None,
+        (ttl, false) => Some(Duration::from_millis(ttl)),
+        (ttl, true) => Some(Duration::from_millis(ttl.saturating_sub(now_ms))),
+    };
+    Ok(Command::Restore {
+        key: key.clone(),
+        payload: payload.clone(),
+        expiry,
+        replace,
+    })
+}
+
+fn compile_migrate_command(tokens: &[Token]) -> Result<Command> {
+    let [host, port, key, db, timeout, rest @ ..] = tokens else {
+        return Err(ParseError::Invalid)?;
+    };
+    let key = key.get_bulk_string_data()?;
+    let mut copy = false;
+    let mut replace = false;
+    let mut keys = Vec::new();
+    let mut iter = rest.iter();
+    while let Some(token) = iter.next() {
+        match std::str::from_utf8(token.get_bulk_string_data()?)?
+            .to_lowercase()
+            .as_str()
+        {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            // the keys come last, and only in place of the single key argument
+            "keys" if key.is_empty() => {
+                keys = iter
+                    .by_ref()
+                    .map(|token| Ok(token.get_bulk_string_data()?.to_vec()))
+                    .collect::<Result<_>>()?;
+            }
+            _ => return Err(ParseError::Invalid)?,
+        }
+    }
+    if !key.is_empty() {
+        keys.push(key.clone());
+    } else if keys.is_empty() {
+        Err(ParseError::Invalid)?;
+    }
+    let number = |token: &Token| -> Result<u64> {
+        Ok(std::str::from_utf8(token.get_bulk_string_data()?)?.parse()?)
+    };
+    Ok(Command::Migrate {
+        host: std::str::from_utf8(host.get_bulk_string_data()?)?.to_string(),
+        port: u16::try_from(number(port)?).map_err(|_| ParseError::Invalid)?,
+        keys,
+        db: number(db)?,
+        timeout: Duration::from_millis(number(timeout)?),
+        copy,
+        replace,
+    })
+}
+
+fn compile_push_command(tokens: &[Token], left: bool) -> Result<Command> {
+    let [Token::BulkString(key), elements @ ..] = tokens else {
+        return Err(ParseError::Invalid)?;
+    };
+    if elements.is_empty() {
+        Err(ParseError::Invalid)?;
+    }
+    let key = key.clone();
+    let elements = elements
+        .iter()
+        .map(|token| Ok(token.get_bulk_string_data()?.to_vec()))
+        .collect::<Result<_>>()?;
+    Ok(match left {
+        true => Command::LPush { key, elements },
+        false => Command::RPush { key, elements },
+    })
+}
+
+fn compile_pop_command(tokens: &[Token], left: bool) -> Result<Command> {
+    let (key, count) = match tokens {
+        [Token::BulkString(key)] => (key.clone(), None),
+        [Token::BulkString(key), Token::BulkString(count)] => match parse_index(count) {
+            Some(count) if count < 0 => {
+                return Ok(Command::Invalid(
+                    "ERR value is out of range, must be positive".to_string(),
+                ))
+            }
+            Some(count) => (key.clone(), Some(count as usize)),
+            None => return Ok(not_an_integer()),
+        },
+        _ => Err(ParseError::Invalid)?,
+    };
+    Ok(match left {
+        true => Command::LPop { key, count },
+        false => Command::RPop { key, count },
+    })
+}
+
+/// Parses a list index or count, `None` if it is not an integer which is an error for
+/// the client rather than a broken request
+fn parse_index(token: &[u8]) -> Option<i64> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+fn not_an_integer() -> Command {
+    Command::Invalid("ERR value is not an integer or out of range".to_string())
+}
+
+/// Parses the `LEFT` or `RIGHT` argument of LMOVE into whether it is the left end
+fn parse_list_end(token: &[u8]) -> Result<bool> {
+    match std::str::from_utf8(token)?.to_lowercase().as_str() {
+        "left" => Ok(true),
+        "right" => Ok(false),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_lrange_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key), Token::BulkString(start), Token::BulkString(stop)] => {
+            let (Some(start), Some(stop)) = (parse_index(start), parse_index(stop)) else {
+                return Ok(not_an_integer());
+            };
+            Ok(Command::LRange {
+                key: key.clone(),
+                start,
+                stop,
+            })
+        }
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_llen_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key)] => Ok(Command::LLen(key.clone())),
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_lindex_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key), Token::BulkString(index)] => match parse_index(index) {
+            Some(index) => Ok(Command::LIndex {
+                key: key.clone(),
+                index,
+            }),
+            None => Ok(not_an_integer()),
+        },
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_lset_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key), Token::BulkString(index), Token::BulkString(element)] => {
+            let Some(index) = parse_index(index) else {
+                return Ok(not_an_integer());
+            };
+            Ok(Command::LSet {
+                key: key.clone(),
+                index,
+                element: element.clone(),
+            })
+        }
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_lrem_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key), Token::BulkString(count), Token::BulkString(element)] => {
+            let Some(count) = parse_index(count) else {
+                return Ok(not_an_integer());
+            };
+            Ok(Command::LRem {
+                key: key.clone(),
+                count,
+                element: element.clone(),
+            })
+        }
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_ltrim_command(tokens: &[Token]) -> Result<Command> {
+    match tokens {
+        [Token::BulkString(key), Token::BulkString(start), Token::BulkString(stop)] => {
+            let (Some(start), Some(stop)) = (parse_index(start), parse_index(stop)) else {
+                return Ok(not_an_integer());
+            };
+            Ok(Command::LTrim {
+                key: key.clone(),
+                start,
+                stop,
+            })
+        }
+        _ => Err(ParseError::Invalid)?,
+    }
+}
+
+fn compile_linsert_command(tokens: &[Token]) -> Result<Command> {
+    let [Token::BulkString(key), Token::BulkString(position), Token::BulkString(pivot), Token::BulkString(element)] =
+        tokens
+    else {
+        return Err(ParseError::Invalid)?;
+    };
+    let before = match std::str::from_utf8(position)?.to_lowercase().as_str() {
+        "before" => true,
+        "after" => false,
+        _ => Err(ParseError::Invalid)?,
+    };
+    Ok(Command::LInsert {
+        key: key.clone(),
+        before,
+        pivot: pivot.clone(),
+        element: element.clone(),
+    })
+}
+
+fn compile_lmove_command(tokens: &[Token]) -> Result<Command> {
+    let [Token::BulkString(source), Token::BulkString(destination), Token::BulkString(from), Token::BulkString(to)] =
+        tokens
+    else {
+        return Err(ParseError::Invalid)?;
+    };
+    Ok(Command::LMove {
+        source: source.clone(),
+        destination: destination.clone(),
+        from_left: parse_list_end(from)?,
+        to_left: parse_list_end(to)?,
+    })
+}
+
 fn compile_and_get_command(tokens: &[Token]) -> Result<Command> {
     let mut tokens = tokens.iter();
     let command = match tokens.next() {
@@ -237,6 +1056,33 @@ fn compile_and_get_command(tokens: &[Token]) -> Result<Command> {
                 "psync" => compile_psync_command(rest)?,
                 "wait" => compile_wait_command(rest)?,
                 "config" => compile_config_command(rest)?,
+                "save" => compile_save_command(rest)?,
+                "bgsave" => compile_bgsave_command(rest)?,
+                "lastsave" => compile_lastsave_command(rest)?,
+                "bgrewriteaof" => compile_bgrewriteaof_command(rest)?,
+                "shutdown" => compile_shutdown_command(rest)?,
+                "replicaof" | "slaveof" => compile_replicaof_command(rest)?,
+                "role" => compile_role_command(rest)?,
+                "failover" => compile_failover_command(rest)?,
+                "sentinel" => compile_sentinel_command(rest)?,
+                "cluster" => compile_cluster_command(rest)?,
+                "del" => compile_del_command(rest)?,
+                "asking" => compile_asking_command(rest)?,
+                "dump" => compile_dump_command(rest)?,
+                "restore" => compile_restore_command(rest)?,
+                "migrate" => compile_migrate_command(rest)?,
+                "lpush" => compile_push_command(rest, true)?,
+                "rpush" => compile_push_command(rest, false)?,
+                "lpop" => compile_pop_command(rest, true)?,
+                "rpop" => compile_pop_command(rest, false)?,
+                "lrange" => compile_lrange_command(rest)?,
+                "llen" => compile_llen_command(rest)?,
+                "lindex" => compile_lindex_command(rest)?,
+                "lset" => compile_lset_command(rest)?,
+                "lrem" => compile_lrem_command(rest)?,
+                "ltrim" => compile_ltrim_command(rest)?,
+                "linsert" => compile_linsert_command(rest)?,
+                "lmove" => compile_lmove_command(rest)?,
                 _ => Err(ParseError::Invalid)?,
             }
         }
@@ -303,6 +1149,141 @@ mod tests {
         let message = b"*4\r\n$3\r\nset\r\n$5\r\nfruit\r\n$5\r\napple\r\n$2\r\npx\r\n";
         let result = parse_command(message);
         assert!(result.is_err());
+
+        let message = b"*5\r\n$3\r\nset\r\n$5\r\nfruit\r\n$5\r\napple\r\n$2\r\nxx\r\n$1\r\n1\r\n";
+        let result = parse_command(message);
+        assert!(matches!(result, Err(ParseError::Invalid)));
+    }
+
+    #[test]
+    fn test_parse_bad_list_arguments() {
+        let not_an_integer =
+            Command::Invalid("ERR value is not an integer or out of range".to_string());
+        for message in [
+            &b"*3\r\n$4\r\nlpop\r\n$1\r\nk\r\n$1\r\nx\r\n"[..],
+            b"*4\r\n$6\r\nlrange\r\n$1\r\nk\r\n$1\r\n0\r\n$3\r\n1.5\r\n",
+            b"*4\r\n$4\r\nlset\r\n$1\r\nk\r\n$1\r\nx\r\n$1\r\nv\r\n",
+            b"*4\r\n$5\r\nltrim\r\n$1\r\nk\r\n$1\r\nx\r\n$1\r\n1\r\n",
+        ] {
+            let result = parse_command(message).unwrap();
+            assert_eq!(result.command, not_an_integer);
+            assert_eq!(result.len, message.len());
+        }
+    }
+
+    #[test]
+    fn test_parse_out_of_range_expiry() {
+        let invalid = Command::Invalid("ERR invalid expire time in 'set' command".to_string());
+        for (option, value) in [
+            ("px", "0"),
+            ("ex", "-5"),
+            ("pxat", "0"),
+            ("px", "9223372036854775807"),
+            ("ex", "9223372036854775"),
+            ("exat", "9223372036854776"),
+        ] {
+            let message = format!(
+                "*5\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n${}\r\n{option}\r\n${}\r\n{value}\r\n",
+                option.len(),
+                value.len()
+            );
+            let result = parse_command(message.as_bytes()).unwrap();
+            assert_eq!(result.command, invalid, "{option} {value}");
+            assert_eq!(result.len, message.len());
+        }
+        // not even an i64
+        let message =
+            b"*5\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\npx\r\n$20\r\n18446744073709551615\r\n";
+        assert!(parse_command(message).is_err());
+
+        let message = b"*4\r\n$7\r\nrestore\r\n$1\r\nk\r\n$2\r\n-1\r\n$1\r\np\r\n";
+        assert_eq!(
+            parse_command(message).unwrap().command,
+            Command::Invalid("ERR Invalid TTL value, must be >= 0".to_string())
+        );
+
+        // a deadline past what fits is held at the largest one rather than wrapping
+        let command = Command::Set {
+            key: b"k".to_vec(),
+            value: b"v".to_vec(),
+            expiry: Some(Duration::MAX),
+        };
+        let token = command.to_propagated_token(1_000_000).unwrap();
+        assert_eq!(
+            token,
+            Token::Array(vec![
+                Token::BulkString(b"SET".to_vec()),
+                Token::BulkString(b"k".to_vec()),
+                Token::BulkString(b"v".to_vec()),
+                Token::BulkString(b"PXAT".to_vec()),
+                Token::BulkString(u64::MAX.to_string().into_bytes()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_resp_token() {
+        let command = Command::ReplConf(ReplConfCommand::GetAck("*".to_string()));
+        let token = command.to_resp_token().unwrap();
+        let result = parse_command(&token.serialize()).unwrap();
+        assert_eq!(result.command, command);
+
+        let command = Command::ReplConf(ReplConfCommand::ListeningPort(6380));
+        let token = command.to_resp_token().unwrap();
+        assert_eq!(parse_command(&token.serialize()).unwrap().command, command);
+
+        // commands we only ever receive have no RESP form to send
+        assert_eq!(Command::Role.to_resp_token(), None);
+        assert_eq!(Command::LLen(b"list".to_vec()).to_resp_token(), None);
+    }
+
+    #[test]
+    fn test_parse_set_absolute_expiry() {
+        let deadline = unix_time_ms() + 60_000;
+        let message = format!(
+            "*5\r\n$3\r\nSET\r\n$5\r\nfruit\r\n$5\r\napple\r\n$4\r\nPXAT\r\n${}\r\n{}\r\n",
+            deadline.to_string().len(),
+            deadline
+        );
+        let result = parse_command(message.as_bytes()).unwrap();
+        let Command::Set {
+            expiry: Some(expiry),
+            ..
+        } = result.command
+        else {
+            panic!("expected SET with an expiry, got {:?}", result.command);
+        };
+        assert!(expiry <= Duration::from_millis(60_000));
+        assert!(expiry > Duration::from_millis(50_000));
+
+        let message = b"*5\r\n$3\r\nset\r\n$5\r\nfruit\r\n$5\r\napple\r\n$4\r\nexat\r\n$1\r\n1\r\n";
+        let result = parse_command(message).unwrap();
+        assert!(matches!(
+            result.command,
+            Command::Set { expiry: Some(expiry), .. } if expiry.is_zero()
+        ));
+    }
+
+    #[test]
+    fn test_set_propagates_absolute_expiry() {
+        let command = Command::Set {
+            key: b"fruit".to_vec(),
+            value: b"apple".to_vec(),
+            expiry: Some(Duration::from_millis(1500)),
+        };
+        assert!(command.is_write());
+        assert_eq!(
+            command.to_propagated_token(1_000_000).unwrap().serialize(),
+            b"*5\r\n$3\r\nSET\r\n$5\r\nfruit\r\n$5\r\napple\r\n$4\r\nPXAT\r\n$7\r\n1001500\r\n"
+        );
+
+        let command = Command::Set {
+            key: b"fruit".to_vec(),
+            value: b"apple".to_vec(),
+            expiry: None,
+        };
+        assert_eq!(command.to_propagated_token(1_000_000), None);
+        assert!(!Command::Get(b"fruit".to_vec()).is_write());
     }
 
     #[test]
@@ -346,9 +1327,12 @@ mod tests {
     #[test]
     fn test_parse_replconf_getack() {
         let message = b"*3\r\n$8\r\nreplconf\r\n$6\r\ngetack\r\n$1\r\n*\r\n";
-        let result = parse_command(message);
-        assert!(result.is_err());
-        assert!(matches!(result.err().unwrap(), ParseError::Invalid));
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::ReplConf(ReplConfCommand::GetAck("*".to_string()))
+        );
+        assert_eq!(result.len, message.len());
     }
 
     #[test]
@@ -361,12 +1345,379 @@ mod tests {
 
     #[test]
     fn test_parse_psync() {
-        let message = b"*1\r\n$5\r\npsync\r\n";
+        let message = b"*3\r\n$5\r\npsync\r\n$1\r\n?\r\n$2\r\n-1\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Psync {
+                replication_id: "?".to_string(),
+                offset: -1
+            }
+        );
+        assert_eq!(result.len, message.len());
+    }
+
+    #[test]
+    fn test_parse_psync_with_offset() {
+        let message =
+            b"*3\r\n$5\r\npsync\r\n$40\r\n8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb\r\n$3\r\n101\r\n";
         let result = parse_command(message).unwrap();
-        assert_eq!(result.command, Command::Psync);
+        assert_eq!(
+            result.command,
+            Command::Psync {
+                replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+                offset: 101
+            }
+        );
+        assert_eq!(result.len, message.len());
+    }
+
+    #[test]
+    fn test_parse_config_set() {
+        let message = b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$4\r\nSAVE\r\n$8\r\n900 1 60\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Config(ConfigCommand::Set {
+                param: "save".to_string(),
+                value: "900 1 60".to_string(),
+            })
+        );
+
+        let message = b"*3\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$4\r\nsave\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_shutdown() {
+        let message = b"*1\r\n$8\r\nSHUTDOWN\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::Shutdown { save: None });
+
+        let message = b"*2\r\n$8\r\nshutdown\r\n$6\r\nNOSAVE\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::Shutdown { save: Some(false) });
+
+        let message = b"*3\r\n$8\r\nshutdown\r\n$6\r\nNOSAVE\r\n$4\r\nSAVE\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_save_commands() {
+        let message = b"*1\r\n$4\r\nSAVE\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::Save);
+
+        let message = b"*1\r\n$6\r\nbgsave\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::BgSave);
+
+        let message = b"*1\r\n$12\r\nBGREWRITEAOF\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::BgRewriteAof);
+
+        let message = b"*1\r\n$8\r\nlastsave\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::LastSave);
         assert_eq!(result.len, message.len());
     }
 
+    #[test]
+    fn test_parse_replicaof() {
+        let message = b"*3\r\n$9\r\nreplicaof\r\n$9\r\nlocalhost\r\n$4\r\n6379\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::ReplicaOf(Some(("localhost".to_string(), 6379)))
+        );
+        assert_eq!(result.len, message.len());
+
+        let message = b"*3\r\n$7\r\nSLAVEOF\r\n$2\r\nNO\r\n$3\r\nONE\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::ReplicaOf(None));
+
+        let message = b"*3\r\n$9\r\nreplicaof\r\n$9\r\nlocalhost\r\n$3\r\none\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_role() {
+        let message = b"*1\r\n$4\r\nROLE\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command, Command::Role);
+        assert_eq!(result.len, message.len());
+
+        let message = b"*2\r\n$4\r\nrole\r\n$6\r\nmaster\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_failover() {
+        let message = b"*1\r\n$8\r\nFAILOVER\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Failover {
+                target: None,
+                timeout: None,
+                force: false,
+                abort: false
+            }
+        );
+
+        let message = b"*7\r\n$8\r\nfailover\r\n$2\r\nTO\r\n$9\r\n127.0.0.1\r\n$4\r\n6380\r\n$7\r\nTIMEOUT\r\n$3\r\n500\r\n$5\r\nFORCE\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Failover {
+                target: Some(("127.0.0.1".to_string(), 6380)),
+                timeout: Some(Duration::from_millis(500)),
+                force: true,
+                abort: false
+            }
+        );
+        assert_eq!(result.len, message.len());
+
+        let message = b"*2\r\n$8\r\nfailover\r\n$5\r\nabort\r\n";
+        let result = parse_command(message).unwrap();
+        assert!(matches!(
+            result.command,
+            Command::Failover { abort: true, .. }
+        ));
+
+        let message = b"*3\r\n$8\r\nfailover\r\n$5\r\nabort\r\n$5\r\nforce\r\n";
+        assert!(parse_command(message).is_err());
+        let message = b"*3\r\n$8\r\nfailover\r\n$2\r\nto\r\n$9\r\n127.0.0.1\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_cluster() {
+        let message = b"*3\r\n$7\r\ncluster\r\n$7\r\nkeyslot\r\n$3\r\nfoo\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Cluster(ClusterCommand::KeySlot(b"foo".to_vec()))
+        );
+        assert_eq!(result.len, message.len());
+
+        let message = b"*4\r\n$7\r\nCLUSTER\r\n$8\r\nADDSLOTS\r\n$1\r\n0\r\n$5\r\n16383\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Cluster(ClusterCommand::AddSlots(vec![0, 16383]))
+        );
+
+        let message = b"*2\r\n$7\r\ncluster\r\n$8\r\naddslots\r\n";
+        assert!(parse_command(message).is_err());
+
+        let message = b"*5\r\n$7\r\ncluster\r\n$7\r\nsetslot\r\n$2\r\n42\r\n\
+                        $9\r\nMIGRATING\r\n$3\r\nabc\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Cluster(ClusterCommand::SetSlot {
+                slot: 42,
+                action: SetSlotAction::Migrating("abc".to_string())
+            })
+        );
+
+        let message = b"*4\r\n$7\r\ncluster\r\n$7\r\nsetslot\r\n$2\r\n42\r\n$4\r\nnode\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_migrate() {
+        let message = b"*6\r\n$7\r\nMIGRATE\r\n$9\r\n127.0.0.1\r\n$4\r\n7001\r\n\
+                        $3\r\nfoo\r\n$1\r\n0\r\n$4\r\n5000\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Migrate {
+                host: "127.0.0.1".to_string(),
+                port: 7001,
+                keys: vec![b"foo".to_vec()],
+                db: 0,
+                timeout: Duration::from_millis(5000),
+                copy: false,
+                replace: false
+            }
+        );
+        assert_eq!(result.len, message.len());
+
+        let message = b"*10\r\n$7\r\nmigrate\r\n$9\r\n127.0.0.1\r\n$4\r\n7001\r\n\
+                        $0\r\n\r\n$1\r\n0\r\n$4\r\n5000\r\n$4\r\nCOPY\r\n\
+                        $4\r\nKEYS\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let result = parse_command(message).unwrap();
+        assert!(matches!(
+            result.command,
+            Command::Migrate { keys, copy: true, .. } if keys == vec![b"a".to_vec(), b"b".to_vec()]
+        ));
+
+        let message = b"*8\r\n$7\r\nmigrate\r\n$9\r\n127.0.0.1\r\n$4\r\n7001\r\n\
+                        $3\r\nfoo\r\n$1\r\n0\r\n$4\r\n5000\r\n$4\r\nKEYS\r\n$1\r\na\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
+    #[test]
+    fn test_restore_propagates_absolute_ttl() {
+        let message = b"*5\r\n$7\r\nrestore\r\n$3\r\nfoo\r\n$4\r\n1500\r\n\
+                        $3\r\nbar\r\n$7\r\nREPLACE\r\n";
+        let result = parse_command(message).unwrap();
+        assert!(result.command.is_write());
+        assert_eq!(
+            result
+                .command
+                .to_propagated_token(1_000_000)
+                .unwrap()
+                .serialize(),
+            b"*6\r\n$7\r\nRESTORE\r\n$3\r\nfoo\r\n$7\r\n1001500\r\n$3\r\nbar\r\n\
+              $7\r\nREPLACE\r\n$6\r\nABSTTL\r\n"
+        );
+
+        let message = b"*4\r\n$7\r\nrestore\r\n$3\r\nfoo\r\n$1\r\n0\r\n$3\r\nbar\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(result.command.to_propagated_token(1_000_000), None);
+    }
+
+    #[test]
+    fn test_parse_list_push_and_pop() {
+        let message = b"*4\r\n$5\r\nLPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LPush {
+                key: b"list".to_vec(),
+                elements: vec![b"a".to_vec(), b"b".to_vec()]
+            }
+        );
+        assert!(result.command.is_write());
+        assert_eq!(result.len, message.len());
+
+        let message = b"*2\r\n$5\r\nrpush\r\n$4\r\nlist\r\n";
+        assert!(parse_command(message).is_err());
+
+        let message = b"*2\r\n$4\r\nlpop\r\n$4\r\nlist\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LPop {
+                key: b"list".to_vec(),
+                count: None
+            }
+        );
+
+        let message = b"*3\r\n$4\r\nrpop\r\n$4\r\nlist\r\n$1\r\n3\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::RPop {
+                key: b"list".to_vec(),
+                count: Some(3)
+            }
+        );
+
+        let message = b"*3\r\n$4\r\nrpop\r\n$4\r\nlist\r\n$2\r\n-1\r\n";
+        assert_eq!(
+            parse_command(message).unwrap().command,
+            Command::Invalid("ERR value is out of range, must be positive".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_list_indexes() {
+        let message = b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LRange {
+                key: b"list".to_vec(),
+                start: 0,
+                stop: -1
+            }
+        );
+        assert!(!result.command.is_write());
+
+        let message = b"*4\r\n$4\r\nlset\r\n$4\r\nlist\r\n$2\r\n-2\r\n$1\r\nx\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LSet {
+                key: b"list".to_vec(),
+                index: -2,
+                element: b"x".to_vec()
+            }
+        );
+
+        let message = b"*3\r\n$6\r\nlindex\r\n$4\r\nlist\r\n$3\r\none\r\n";
+        assert_eq!(
+            parse_command(message).unwrap().command,
+            Command::Invalid("ERR value is not an integer or out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_linsert_and_lmove() {
+        let message = b"*5\r\n$7\r\nLINSERT\r\n$4\r\nlist\r\n$6\r\nBEFORE\r\n\
+                        $1\r\nb\r\n$1\r\na\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LInsert {
+                key: b"list".to_vec(),
+                before: true,
+                pivot: b"b".to_vec(),
+                element: b"a".to_vec()
+            }
+        );
+
+        let message = b"*5\r\n$7\r\nlinsert\r\n$4\r\nlist\r\n$6\r\nbeside\r\n\
+                        $1\r\nb\r\n$1\r\na\r\n";
+        assert!(parse_command(message).is_err());
+
+        let message = b"*5\r\n$5\r\nlmove\r\n$3\r\nsrc\r\n$3\r\ndst\r\n\
+                        $5\r\nRIGHT\r\n$4\r\nleft\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::LMove {
+                source: b"src".to_vec(),
+                destination: b"dst".to_vec(),
+                from_left: false,
+                to_left: true
+            }
+        );
+        assert_eq!(result.command.keys(), vec![b"src".as_slice(), b"dst"]);
+    }
+
+    #[test]
+    fn test_parse_sentinel() {
+        let message =
+            b"*3\r\n$8\r\nSENTINEL\r\n$23\r\nget-master-addr-by-name\r\n$8\r\nmymaster\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Sentinel(SentinelCommand::GetMasterAddrByName("mymaster".to_string()))
+        );
+        assert_eq!(result.len, message.len());
+
+        let message = b"*6\r\n$8\r\nsentinel\r\n$22\r\nis-master-down-by-addr\r\n\
+                        $9\r\n127.0.0.1\r\n$4\r\n6379\r\n$1\r\n3\r\n$1\r\n*\r\n";
+        let result = parse_command(message).unwrap();
+        assert_eq!(
+            result.command,
+            Command::Sentinel(SentinelCommand::IsMasterDownByAddr {
+                host: "127.0.0.1".to_string(),
+                port: 6379,
+                current_epoch: 3,
+                runid: "*".to_string()
+            })
+        );
+
+        let message = b"*3\r\n$8\r\nsentinel\r\n$7\r\nmasters\r\n$5\r\nextra\r\n";
+        assert!(parse_command(message).is_err());
+    }
+
     #[test]
     fn test_parse_multiple_commands() {
         let message_part_one = b"*1\r\n$4\r\nping\r\n";
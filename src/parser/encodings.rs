@@ -0,0 +1,377 @@
+//! Compact encodings Redis stores small collections in, which RDB files keep as is
+//! inside a string: ziplists, listpacks, intsets and zipmaps. Integer entries are
+//! given back in their decimal form, like Redis shows them.
+
+use super::rdb::{FieldPairs, RdbError};
+
+const ZIPLIST_HEADER_SIZE: usize = 10;
+const ZIPLIST_END: u8 = 0xFF;
+const ZIPLIST_BIG_PREVLEN: u8 = 0xFE;
+
+const LISTPACK_HEADER_SIZE: usize = 6;
+const LISTPACK_END: u8 = 0xFF;
+
+const ZIPMAP_BIGLEN: u8 = 0xFE;
+const ZIPMAP_END: u8 = 0xFF;
+
+fn invalid(what: &str, reason: &str) -> RdbError {
+    RdbError::Invalid(format!("{what} is malformed: {reason}"))
+}
+
+/// Reads through the bytes of an encoded collection
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    what: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], what: &'static str) -> Self {
+        Self { data, pos: 0, what }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid(self.what, "unexpected end of data"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn take_u8(&mut self) -> Result<u8, RdbError> {
+        let [byte] = self.take_array()?;
+        Ok(byte)
+    }
+
+    fn peek(&self) -> Result<u8, RdbError> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| invalid(self.what, "missing end marker"))
+    }
+
+    fn finish(&self) -> Result<(), RdbError> {
+        if self.pos != self.data.len() {
+            return Err(invalid(self.what, "trailing bytes after the end marker"));
+        }
+        Ok(())
+    }
+}
+
+/// Sign-extends the low `bits` bits of `value`
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn integer(value: i64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+/// Entries of a ziplist: `<zlbytes><zltail><zllen>` then every entry as
+/// `<prevlen><encoding><data>`, closed by 0xFF
+pub fn parse_ziplist(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut cursor = Cursor::new(data, "ziplist");
+    let header = cursor.take(ZIPLIST_HEADER_SIZE)?;
+    let total = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err(invalid("ziplist", "size does not match its header"));
+    }
+
+    let mut entries = Vec::new();
+    while cursor.peek()? != ZIPLIST_END {
+        if cursor.take_u8()? == ZIPLIST_BIG_PREVLEN {
+            cursor.take(4)?;
+        }
+        let encoding = cursor.take_u8()?;
+        let entry = match encoding >> 6 {
+            0b00 => cursor.take((encoding & 0x3F) as usize)?.to_vec(),
+            0b01 => {
+                let len = (((encoding & 0x3F) as usize) << 8) | cursor.take_u8()? as usize;
+                cursor.take(len)?.to_vec()
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(cursor.take_array()?) as usize;
+                cursor.take(len)?.to_vec()
+            }
+            _ => match encoding {
+                0xC0 => integer(i16::from_le_bytes(cursor.take_array()?) as i64),
+                0xD0 => integer(i32::from_le_bytes(cursor.take_array()?) as i64),
+                0xE0 => integer(i64::from_le_bytes(cursor.take_array()?)),
+                0xF0 => {
+                    let [a, b, c] = cursor.take_array()?;
+                    integer(sign_extend(u32::from_le_bytes([a, b, c, 0]) as u64, 24))
+                }
+                0xFE => integer(cursor.take_u8()? as i8 as i64),
+                // immediate values 0 to 12 stored as 1 to 13 in the low bits
+                0xF1..=0xFD => integer((encoding & 0x0F) as i64 - 1),
+                _ => {
+                    return Err(invalid(
+                        "ziplist",
+                        &format!("unknown entry encoding {encoding:#x}"),
+                    ))
+                }
+            },
+        };
+        entries.push(entry);
+    }
+    cursor.take_u8()?;
+    cursor.finish()?;
+    Ok(entries)
+}
+
+/// Size of the back length closing a listpack entry of `len` bytes
+fn listpack_backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Entries of a listpack: `<total bytes><num elements>` then every entry as
+/// `<encoding><data><backlen>`, closed by 0xFF
+pub fn parse_listpack(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut cursor = Cursor::new(data, "listpack");
+    let header = cursor.take(LISTPACK_HEADER_SIZE)?;
+    let total = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err(invalid("listpack", "size does not match its header"));
+    }
+
+    let mut entries = Vec::new();
+    while cursor.peek()? != LISTPACK_END {
+        let start = cursor.pos;
+        let encoding = cursor.take_u8()?;
+        let entry = if encoding >> 7 == 0 {
+            integer(encoding as i64)
+        } else if encoding >> 6 == 0b10 {
+            cursor.take((encoding & 0x3F) as usize)?.to_vec()
+        } else if encoding >> 5 == 0b110 {
+            let value = (((encoding & 0x1F) as u64) << 8) | cursor.take_u8()? as u64;
+            integer(sign_extend(value, 13))
+        } else if encoding >> 4 == 0b1110 {
+            let len = (((encoding & 0x0F) as usize) << 8) | cursor.take_u8()? as usize;
+            cursor.take(len)?.to_vec()
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = u32::from_le_bytes(cursor.take_array()?) as usize;
+                    cursor.take(len)?.to_vec()
+                }
+                0xF1 => integer(i16::from_le_bytes(cursor.take_array()?) as i64),
+                0xF2 => {
+                    let [a, b, c] = cursor.take_array()?;
+                    integer(sign_extend(u32::from_le_bytes([a, b, c, 0]) as u64, 24))
+                }
+                0xF3 => integer(i32::from_le_bytes(cursor.take_array()?) as i64),
+                0xF4 => integer(i64::from_le_bytes(cursor.take_array()?)),
+                _ => {
+                    return Err(invalid(
+                        "listpack",
+                        &format!("unknown entry encoding {encoding:#x}"),
+                    ))
+                }
+            }
+        };
+        cursor.take(listpack_backlen_size(cursor.pos - start))?;
+        entries.push(entry);
+    }
+    cursor.take_u8()?;
+    cursor.finish()?;
+    Ok(entries)
+}
+
+/// Members of an intset: `<encoding><length>` then the sorted integers, each 2, 4 or
+/// 8 bytes long as given by the encoding
+pub fn parse_intset(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut cursor = Cursor::new(data, "intset");
+    let encoding = u32::from_le_bytes(cursor.take_array()?);
+    let len = u32::from_le_bytes(cursor.take_array()?);
+    let members = (0..len)
+        .map(|_| {
+            let value = match encoding {
+                2 => i16::from_le_bytes(cursor.take_array()?) as i64,
+                4 => i32::from_le_bytes(cursor.take_array()?) as i64,
+                8 => i64::from_le_bytes(cursor.take_array()?),
+                _ => return Err(invalid("intset", &format!("unknown encoding {encoding}"))),
+            };
+            Ok(integer(value))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    cursor.finish()?;
+    Ok(members)
+}
+
+fn zipmap_len(cursor: &mut Cursor) -> Result<usize, RdbError> {
+    match cursor.take_u8()? {
+        ZIPMAP_BIGLEN => Ok(u32::from_le_bytes(cursor.take_array()?) as usize),
+        ZIPMAP_END => Err(invalid("zipmap", "unexpected end marker")),
+        len => Ok(len as usize),
+    }
+}
+
+/// Fields of a zipmap, the hash encoding of RDB versions before 4: `<zmlen>` then
+/// `<len>field<len><free>value<free bytes>` for every field, closed by 0xFF
+pub fn parse_zipmap(data: &[u8]) -> Result<FieldPairs, RdbError> {
+    let mut cursor = Cursor::new(data, "zipmap");
+    cursor.take_u8()?;
+
+    let mut fields = Vec::new();
+    while cursor.peek()? != ZIPMAP_END {
+        let len = zipmap_len(&mut cursor)?;
+        let field = cursor.take(len)?.to_vec();
+        let len = zipmap_len(&mut cursor)?;
+        let free = cursor.take_u8()? as usize;
+        let value = cursor.take(len)?.to_vec();
+        cursor.take(free)?;
+        fields.push((field, value));
+    }
+    cursor.take_u8()?;
+    cursor.finish()?;
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ziplist(entries: &[&[u8]]) -> Vec<u8> {
+        let body = entries.concat();
+        let total = (ZIPLIST_HEADER_SIZE + body.len() + 1) as u32;
+        [
+            &total.to_le_bytes()[..],
+            &[0; 4],
+            &(entries.len() as u16).to_le_bytes(),
+            &body,
+            &[ZIPLIST_END],
+        ]
+        .concat()
+    }
+
+    fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+        let body = entries.concat();
+        let total = (LISTPACK_HEADER_SIZE + body.len() + 1) as u32;
+        [
+            &total.to_le_bytes()[..],
+            &(entries.len() as u16).to_le_bytes(),
+            &body,
+            &[LISTPACK_END],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_parse_ziplist() {
+        let data = ziplist(&[
+            b"\x00\x05hello",
+            b"\x07\xF1",
+            b"\x02\xFD",
+            b"\x02\xFE\x9C",
+            b"\x03\xC0\x39\x30",
+            b"\x04\xF0\xFF\xFF\xFF",
+            b"\x05\xD0\x60\x79\xFE\xFF",
+            b"\x07\xE0\x00\x00\x00\x00\x00\x00\x00\x80",
+        ]);
+        assert_eq!(
+            parse_ziplist(&data).unwrap(),
+            [
+                &b"hello"[..],
+                b"0",
+                b"12",
+                b"-100",
+                b"12345",
+                b"-1",
+                b"-100000",
+                b"-9223372036854775808"
+            ]
+        );
+
+        let long = vec![b'x'; 300];
+        let data = ziplist(&[
+            &[0x00, 0x41, 0x2C],
+            &long,
+            &[0xFE, 0x2F, 0x01, 0, 0, 0x01],
+            b"a",
+        ]);
+        assert_eq!(parse_ziplist(&data).unwrap(), [long, b"a".to_vec()]);
+
+        let mut data = ziplist(&[b"\x00\x05hello"]);
+        data.pop();
+        assert!(parse_ziplist(&data).is_err());
+    }
+
+    #[test]
+    fn test_parse_listpack() {
+        let long = vec![b'y'; 200];
+        let data = listpack(&[
+            b"\x85hello\x06",
+            b"\x7F\x01",
+            b"\xDF\xFF\x02",
+            b"\xF1\x39\x30\x03",
+            b"\xF2\x60\x79\xFE\x04",
+            b"\xF3\x00\x00\x00\x80\x05",
+            &[&[0xE0, 0xC8][..], &long, &[0x4A, 0x01]].concat(),
+        ]);
+        assert_eq!(
+            parse_listpack(&data).unwrap(),
+            [
+                b"hello".to_vec(),
+                b"127".to_vec(),
+                b"-1".to_vec(),
+                b"12345".to_vec(),
+                b"-100000".to_vec(),
+                b"-2147483648".to_vec(),
+                long,
+            ]
+        );
+
+        assert!(parse_listpack(&listpack(&[b"\x85hel"])).is_err());
+        assert!(parse_listpack(&listpack(&[b"\xF5\x00"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_intset() {
+        let data = [
+            &2u32.to_le_bytes()[..],
+            &2u32.to_le_bytes(),
+            b"\xFF\xFF\x05\x00",
+        ]
+        .concat();
+        assert_eq!(parse_intset(&data).unwrap(), [&b"-1"[..], b"5"]);
+
+        let data = [
+            &8u32.to_le_bytes()[..],
+            &1u32.to_le_bytes(),
+            &i64::MAX.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            parse_intset(&data).unwrap(),
+            [i64::MAX.to_string().into_bytes()]
+        );
+
+        let data = [&4u32.to_le_bytes()[..], &2u32.to_le_bytes(), &[0; 4]].concat();
+        assert!(parse_intset(&data).is_err());
+    }
+
+    #[test]
+    fn test_parse_zipmap() {
+        let data = b"\x02\x03foo\x03\x02bar\x00\x00\x05fruit\x05\x00apple\xFF";
+        assert_eq!(
+            parse_zipmap(data).unwrap(),
+            [
+                (b"foo".to_vec(), b"bar".to_vec()),
+                (b"fruit".to_vec(), b"apple".to_vec())
+            ]
+        );
+        assert!(parse_zipmap(b"\x01\x03foo\x03").is_err());
+    }
+}
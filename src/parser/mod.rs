@@ -1,3 +1,4 @@
 pub mod command;
+pub mod encodings;
 pub mod rdb;
 pub mod resp;
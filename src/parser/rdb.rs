@@ -1,5 +1,82 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::common::crc64::crc64;
+use crate::common::lzf::lzf_decompress;
+
+use super::encodings::{parse_intset, parse_listpack, parse_ziplist, parse_zipmap};
 use super::resp::{find_first_crlf, ParseError, Result};
 
+pub const RDB_MAGIC: &[u8] = b"REDIS";
+pub const RDB_VERSION: u32 = 11;
+const REDIS_VERSION: &str = "7.2.0";
+
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const RDB_6BITLEN: u8 = 0;
+const RDB_14BITLEN: u8 = 1;
+const RDB_32BITLEN: u8 = 0x80;
+const RDB_64BITLEN: u8 = 0x81;
+const RDB_ENCVAL: u8 = 3;
+
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+/// Scores of the first sorted set encoding which are not written out as numbers
+const RDB_SCORE_NAN: u8 = 253;
+const RDB_SCORE_POS_INF: u8 = 254;
+const RDB_SCORE_NEG_INF: u8 = 255;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Tags of the values a module serializes, which let them be skipped without the module
+const RDB_MODULE_OPCODE_EOF: u64 = 0;
+const RDB_MODULE_OPCODE_SINT: u64 = 1;
+const RDB_MODULE_OPCODE_UINT: u64 = 2;
+const RDB_MODULE_OPCODE_FLOAT: u64 = 3;
+const RDB_MODULE_OPCODE_DOUBLE: u64 = 4;
+const RDB_MODULE_OPCODE_STRING: u64 = 5;
+const MODULE_NAME_CHARSET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+/// Stored in place of the entries read by a consumer group when it is not known
+const STREAM_ENTRIES_READ_INVALID: u64 = u64::MAX;
+
 pub struct RdbParseResult {
     pub rdb: Vec<u8>,
     pub len: usize,
@@ -25,3 +102,1467 @@ pub fn parse_rdb_payload(message: &[u8]) -> Result<RdbParseResult> {
         None => Err(ParseError::Incomplete),
     }
 }
+
+#[derive(Debug)]
+pub enum RdbError {
+    Io(io::Error),
+    Invalid(String),
+    /// A value of a type we can decode but not write back
+    Unencodable(String),
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Io(err) => write!(f, "failed to read RDB data: {err}"),
+            RdbError::Invalid(reason) => write!(f, "RDB data is malformed: {reason}"),
+            RdbError::Unencodable(type_name) => {
+                write!(f, "{type_name} values cannot be encoded in RDB format")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
+
+impl From<io::Error> for RdbError {
+    fn from(value: io::Error) -> Self {
+        RdbError::Io(value)
+    }
+}
+
+/// Field and value pairs of a hash or a stream entry
+pub type FieldPairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// Reads the 16 bytes big endian form used for the keys of the stream listpacks
+    fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        let (ms, seq) = bytes.split_at_checked(8)?;
+        Some(StreamId {
+            ms: u64::from_be_bytes(ms.try_into().ok()?),
+            seq: u64::from_be_bytes(seq.try_into().ok()?),
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: FieldPairs,
+}
+
+/// An entry delivered to a consumer of a group and not acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub delivery_time_ms: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamConsumer {
+    pub name: Vec<u8>,
+    pub seen_time_ms: u64,
+    pub active_time_ms: u64,
+    /// Ids of the pending entries of the group delivered to this consumer
+    pub pending: Vec<StreamId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    pub last_id: StreamId,
+    /// `None` when the group lost track of it, or for files written before Redis 7
+    pub entries_read: Option<u64>,
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<StreamConsumer>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RdbStream {
+    pub entries: Vec<StreamEntry>,
+    pub length: u64,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_entry_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+/// A value in any of the types an RDB file can hold, whatever encoding it was stored in
+#[derive(Debug, Clone, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    Hash(FieldPairs),
+    Stream(RdbStream),
+    /// A value only the module which wrote it can make sense of, which is skipped
+    Module {
+        name: String,
+        encoding_version: u64,
+    },
+}
+
+impl RdbValue {
+    /// The name of the type as reported by TYPE
+    pub fn type_name(&self) -> &str {
+        match self {
+            RdbValue::String(_) => "string",
+            RdbValue::List(_) => "list",
+            RdbValue::Set(_) => "set",
+            RdbValue::SortedSet(_) => "zset",
+            RdbValue::Hash(_) => "hash",
+            RdbValue::Stream(_) => "stream",
+            RdbValue::Module { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RdbEntry {
+    pub db: usize,
+    pub key: Vec<u8>,
+    pub value: RdbValue,
+    /// Absolute expiry as milliseconds since the unix epoch
+    pub expire_at_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RdbSummary {
+    pub version: u32,
+    pub aux: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Code of the function libraries, which we keep but never run
+    pub functions: Vec<Vec<u8>>,
+    /// CRC64 trailer as stored in the file, zero when checksumming was disabled
+    pub checksum: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RdbFile {
+    pub summary: RdbSummary,
+    pub entries: Vec<RdbEntry>,
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u64),
+}
+
+/// Name and encoding version packed into the 64-bit id of a module type: 9 characters
+/// of 6 bits each, followed by a 10-bit version
+fn module_type_name(module_id: u64) -> (String, u64) {
+    let name = (0..9)
+        .map(|i| MODULE_NAME_CHARSET[((module_id >> (58 - 6 * i)) & 0x3F) as usize] as char)
+        .collect();
+    (name, module_id & 0x3FF)
+}
+
+/// Pairs up the flat field and value entries of a compact hash or sorted set
+fn into_pairs(entries: Vec<Vec<u8>>) -> std::result::Result<FieldPairs, RdbError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(RdbError::Invalid(
+            "odd number of entries in a hash or sorted set".to_string(),
+        ));
+    }
+    let mut entries = entries.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(first), Some(second)) = (entries.next(), entries.next()) {
+        pairs.push((first, second));
+    }
+    Ok(pairs)
+}
+
+fn parse_score(score: &[u8]) -> std::result::Result<f64, RdbError> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse().ok())
+        .ok_or_else(|| RdbError::Invalid(format!("invalid sorted set score {score:?}")))
+}
+
+fn into_scored(entries: Vec<Vec<u8>>) -> std::result::Result<Vec<(Vec<u8>, f64)>, RdbError> {
+    into_pairs(entries)?
+        .into_iter()
+        .map(|(member, score)| Ok((member, parse_score(&score)?)))
+        .collect()
+}
+
+fn next_stream_item<'a>(
+    items: &mut impl Iterator<Item = &'a Vec<u8>>,
+) -> std::result::Result<&'a Vec<u8>, RdbError> {
+    items.next().ok_or_else(|| {
+        RdbError::Invalid("stream listpack ends in the middle of an entry".to_string())
+    })
+}
+
+fn next_stream_integer<'a>(
+    items: &mut impl Iterator<Item = &'a Vec<u8>>,
+) -> std::result::Result<i64, RdbError> {
+    let item = next_stream_item(items)?;
+    std::str::from_utf8(item)
+        .ok()
+        .and_then(|item| item.parse().ok())
+        .ok_or_else(|| {
+            RdbError::Invalid(format!(
+                "expected an integer in stream listpack, found {item:?}"
+            ))
+        })
+}
+
+/// Decodes the entries of a stream listpack. It starts with a master entry holding
+/// the count of valid and deleted entries and the fields the entries share, then every
+/// entry has its flags, its id as a delta to `master_id`, its fields (or only their
+/// values when they are the master fields) and its number of items.
+fn parse_stream_listpack(
+    master_id: StreamId,
+    listpack: &[u8],
+    entries: &mut Vec<StreamEntry>,
+) -> std::result::Result<(), RdbError> {
+    let items = parse_listpack(listpack)?;
+    let mut items = items.iter().peekable();
+
+    let _count = next_stream_integer(&mut items)?;
+    let _deleted = next_stream_integer(&mut items)?;
+    let master_fields = (0..next_stream_integer(&mut items)?)
+        .map(|_| next_stream_item(&mut items))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    // closes the master entry
+    next_stream_item(&mut items)?;
+
+    while items.peek().is_some() {
+        let flags = next_stream_integer(&mut items)?;
+        let id = StreamId {
+            ms: master_id
+                .ms
+                .wrapping_add(next_stream_integer(&mut items)? as u64),
+            seq: master_id
+                .seq
+                .wrapping_add(next_stream_integer(&mut items)? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|&field| Ok((field.clone(), next_stream_item(&mut items)?.clone())))
+                .collect::<std::result::Result<Vec<_>, RdbError>>()?
+        } else {
+            (0..next_stream_integer(&mut items)?)
+                .map(|_| {
+                    let field = next_stream_item(&mut items)?.clone();
+                    Ok((field, next_stream_item(&mut items)?.clone()))
+                })
+                .collect::<std::result::Result<Vec<_>, RdbError>>()?
+        };
+        // the number of items of the entry, only there to walk the listpack backwards
+        next_stream_item(&mut items)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(StreamEntry { id, fields });
+        }
+    }
+    Ok(())
+}
+
+pub struct RdbDecoder<R: Read> {
+    reader: R,
+    checksum: u64,
+}
+
+impl<R: Read> RdbDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            checksum: 0,
+        }
+    }
+
+    /// Decodes the whole RDB stream, handing every key to `on_entry` as soon as it is read
+    pub fn decode<F>(mut self, mut on_entry: F) -> std::result::Result<RdbSummary, RdbError>
+    where
+        F: FnMut(RdbEntry),
+    {
+        let mut summary = RdbSummary {
+            version: self.read_header()?,
+            ..Default::default()
+        };
+
+        let mut db = 0;
+        let mut expire_at_ms = None;
+
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                RDB_OPCODE_AUX => {
+                    let key = self.read_string()?;
+                    let value = self.read_string()?;
+                    summary.aux.push((key, value));
+                }
+                RDB_OPCODE_MODULE_AUX => {
+                    let module_id = self.read_plain_length()?;
+                    let when_opcode = self.read_plain_length()?;
+                    if when_opcode != RDB_MODULE_OPCODE_UINT {
+                        return Err(RdbError::Invalid(format!(
+                            "bad when opcode {when_opcode} in the aux data of module {}",
+                            module_type_name(module_id).0
+                        )));
+                    }
+                    self.read_plain_length()?;
+                    self.skip_module_data()?;
+                }
+                RDB_OPCODE_FUNCTION2 => {
+                    summary.functions.push(self.read_string()?);
+                }
+                RDB_OPCODE_IDLE => {
+                    // Eviction hints for the next key, nothing to keep
+                    self.read_plain_length()?;
+                }
+                RDB_OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                RDB_OPCODE_SELECTDB => {
+                    db = self.read_plain_length()? as usize;
+                }
+                RDB_OPCODE_RESIZEDB => {
+                    // Only a sizing hint for the hash tables, nothing to keep
+                    self.read_plain_length()?;
+                    self.read_plain_length()?;
+                }
+                RDB_OPCODE_EXPIRETIME => {
+                    let seconds = u32::from_le_bytes(self.read_array()?);
+                    expire_at_ms = Some(seconds as u64 * 1000);
+                }
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    expire_at_ms = Some(u64::from_le_bytes(self.read_array()?));
+                }
+                RDB_OPCODE_EOF => {
+                    // Versions before 5 have no checksum trailer, and a stored checksum
+                    // of zero means the writer had checksumming disabled
+                    if summary.version >= 5 {
+                        let expected = self.checksum;
+                        let mut trailer = [0u8; 8];
+                        self.reader.read_exact(&mut trailer)?;
+                        summary.checksum = u64::from_le_bytes(trailer);
+                        if summary.checksum != 0 && summary.checksum != expected {
+                            return Err(RdbError::Invalid(format!(
+                                "checksum mismatch, expected {expected:#x} found {:#x}",
+                                summary.checksum
+                            )));
+                        }
+                    }
+                    return Ok(summary);
+                }
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = self.read_value(value_type)?;
+                    on_entry(RdbEntry {
+                        db,
+                        key,
+                        value,
+                        expire_at_ms: expire_at_ms.take(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn read_header(&mut self) -> std::result::Result<u32, RdbError> {
+        let header: [u8; 9] = self.read_array()?;
+        if &header[..RDB_MAGIC.len()] != RDB_MAGIC {
+            return Err(RdbError::Invalid("missing REDIS magic string".to_string()));
+        }
+        let version = std::str::from_utf8(&header[RDB_MAGIC.len()..])
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| RdbError::Invalid("invalid RDB version".to_string()))?;
+        if version > RDB_VERSION {
+            return Err(RdbError::Invalid(format!(
+                "can't handle RDB format version {version}"
+            )));
+        }
+        Ok(version)
+    }
+
+    fn read_value(&mut self, value_type: u8) -> std::result::Result<RdbValue, RdbError> {
+        let value = match value_type {
+            RDB_TYPE_STRING => RdbValue::String(self.read_string()?),
+            RDB_TYPE_LIST => RdbValue::List(self.read_strings()?),
+            RDB_TYPE_SET => RdbValue::Set(self.read_strings()?),
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_plain_length()?;
+                let members = (0..len)
+                    .map(|_| {
+                        let member = self.read_string()?;
+                        let score = if value_type == RDB_TYPE_ZSET_2 {
+                            f64::from_le_bytes(self.read_array()?)
+                        } else {
+                            self.read_string_score()?
+                        };
+                        Ok((member, score))
+                    })
+                    .collect::<std::result::Result<_, RdbError>>()?;
+                RdbValue::SortedSet(members)
+            }
+            RDB_TYPE_HASH => {
+                let len = self.read_plain_length()?;
+                let fields = (0..len)
+                    .map(|_| Ok((self.read_string()?, self.read_string()?)))
+                    .collect::<std::result::Result<_, RdbError>>()?;
+                RdbValue::Hash(fields)
+            }
+            RDB_TYPE_MODULE_2 => {
+                let (name, encoding_version) = module_type_name(self.read_plain_length()?);
+                self.skip_module_data()?;
+                RdbValue::Module {
+                    name,
+                    encoding_version,
+                }
+            }
+            RDB_TYPE_HASH_ZIPMAP => RdbValue::Hash(parse_zipmap(&self.read_string()?)?),
+            RDB_TYPE_LIST_ZIPLIST => RdbValue::List(parse_ziplist(&self.read_string()?)?),
+            RDB_TYPE_SET_INTSET => RdbValue::Set(parse_intset(&self.read_string()?)?),
+            RDB_TYPE_ZSET_ZIPLIST => {
+                RdbValue::SortedSet(into_scored(parse_ziplist(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_HASH_ZIPLIST => {
+                RdbValue::Hash(into_pairs(parse_ziplist(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_LIST_QUICKLIST => {
+                let nodes = self.read_plain_length()?;
+                let mut elements = Vec::new();
+                for _ in 0..nodes {
+                    elements.extend(parse_ziplist(&self.read_string()?)?);
+                }
+                RdbValue::List(elements)
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_plain_length()?;
+                let mut elements = Vec::new();
+                for _ in 0..nodes {
+                    match self.read_plain_length()? {
+                        QUICKLIST_NODE_PLAIN => elements.push(self.read_string()?),
+                        QUICKLIST_NODE_PACKED => {
+                            elements.extend(parse_listpack(&self.read_string()?)?)
+                        }
+                        container => {
+                            return Err(RdbError::Invalid(format!(
+                                "unknown quicklist node container {container}"
+                            )))
+                        }
+                    }
+                }
+                RdbValue::List(elements)
+            }
+            RDB_TYPE_HASH_LISTPACK => {
+                RdbValue::Hash(into_pairs(parse_listpack(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_ZSET_LISTPACK => {
+                RdbValue::SortedSet(into_scored(parse_listpack(&self.read_string()?)?)?)
+            }
+            RDB_TYPE_SET_LISTPACK => RdbValue::Set(parse_listpack(&self.read_string()?)?),
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => RdbValue::Stream(self.read_stream(value_type)?),
+            _ => {
+                return Err(RdbError::Invalid(format!(
+                    "unsupported value type {value_type}"
+                )))
+            }
+        };
+        Ok(value)
+    }
+
+    fn read_strings(&mut self) -> std::result::Result<Vec<Vec<u8>>, RdbError> {
+        let len = self.read_plain_length()?;
+        (0..len).map(|_| self.read_string()).collect()
+    }
+
+    /// Reads a score of the first sorted set encoding, written out in decimal
+    fn read_string_score(&mut self) -> std::result::Result<f64, RdbError> {
+        match self.read_u8()? {
+            RDB_SCORE_NAN => Ok(f64::NAN),
+            RDB_SCORE_POS_INF => Ok(f64::INFINITY),
+            RDB_SCORE_NEG_INF => Ok(f64::NEG_INFINITY),
+            len => parse_score(&self.read_bytes(len as usize)?),
+        }
+    }
+
+    fn read_stream_id(&mut self) -> std::result::Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: self.read_plain_length()?,
+            seq: self.read_plain_length()?,
+        })
+    }
+
+    fn read_raw_stream_id(&mut self) -> std::result::Result<StreamId, RdbError> {
+        let bytes: [u8; 16] = self.read_array()?;
+        Ok(StreamId::from_be_bytes(&bytes).expect("16 bytes hold a stream id"))
+    }
+
+    fn read_stream(&mut self, value_type: u8) -> std::result::Result<RdbStream, RdbError> {
+        let mut stream = RdbStream::default();
+        let listpacks = self.read_plain_length()?;
+        for _ in 0..listpacks {
+            let master_id = StreamId::from_be_bytes(&self.read_string()?)
+                .ok_or_else(|| RdbError::Invalid("bad stream listpack key".to_string()))?;
+            parse_stream_listpack(master_id, &self.read_string()?, &mut stream.entries)?;
+        }
+
+        stream.length = self.read_plain_length()?;
+        stream.last_id = self.read_stream_id()?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            stream.first_id = self.read_stream_id()?;
+            stream.max_deleted_entry_id = self.read_stream_id()?;
+            stream.entries_added = self.read_plain_length()?;
+        } else {
+            // the way Redis fills them in for older files
+            stream.first_id = stream
+                .entries
+                .first()
+                .map(|entry| entry.id)
+                .unwrap_or_default();
+            stream.entries_added = stream.length;
+        }
+
+        let groups = self.read_plain_length()?;
+        for _ in 0..groups {
+            let name = self.read_string()?;
+            let last_id = self.read_stream_id()?;
+            let entries_read = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                Some(self.read_plain_length()?).filter(|&read| read != STREAM_ENTRIES_READ_INVALID)
+            } else {
+                None
+            };
+
+            let pending = (0..self.read_plain_length()?)
+                .map(|_| {
+                    Ok(PendingEntry {
+                        id: self.read_raw_stream_id()?,
+                        delivery_time_ms: u64::from_le_bytes(self.read_array()?),
+                        delivery_count: self.read_plain_length()?,
+                    })
+                })
+                .collect::<std::result::Result<_, RdbError>>()?;
+
+            let consumers = (0..self.read_plain_length()?)
+                .map(|_| {
+                    let name = self.read_string()?;
+                    let seen_time_ms = u64::from_le_bytes(self.read_array()?);
+                    let active_time_ms = if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                        u64::from_le_bytes(self.read_array()?)
+                    } else {
+                        seen_time_ms
+                    };
+                    let pending = (0..self.read_plain_length()?)
+                        .map(|_| self.read_raw_stream_id())
+                        .collect::<std::result::Result<_, RdbError>>()?;
+                    Ok(StreamConsumer {
+                        name,
+                        seen_time_ms,
+                        active_time_ms,
+                        pending,
+                    })
+                })
+                .collect::<std::result::Result<_, RdbError>>()?;
+
+            stream.groups.push(ConsumerGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+        Ok(stream)
+    }
+
+    /// Skips the values serialized by a module, each of them tagged with its kind
+    fn skip_module_data(&mut self) -> std::result::Result<(), RdbError> {
+        loop {
+            match self.read_plain_length()? {
+                RDB_MODULE_OPCODE_EOF => return Ok(()),
+                RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => {
+                    self.read_plain_length()?;
+                }
+                RDB_MODULE_OPCODE_FLOAT => {
+                    self.read_array::<4>()?;
+                }
+                RDB_MODULE_OPCODE_DOUBLE => {
+                    self.read_array::<8>()?;
+                }
+                RDB_MODULE_OPCODE_STRING => {
+                    self.read_string()?;
+                }
+                opcode => {
+                    return Err(RdbError::Invalid(format!(
+                        "unknown module value opcode {opcode}"
+                    )))
+                }
+            }
+        }
+    }
+
+    fn read_length(&mut self) -> std::result::Result<Length, RdbError> {
+        let first = self.read_u8()?;
+        let length = match (first & 0xC0) >> 6 {
+            RDB_6BITLEN => Length::Len((first & 0x3F) as u64),
+            RDB_14BITLEN => {
+                let next = self.read_u8()?;
+                Length::Len((((first & 0x3F) as u64) << 8) | next as u64)
+            }
+            RDB_ENCVAL => Length::Encoded((first & 0x3F) as u64),
+            _ => match first {
+                RDB_32BITLEN => Length::Len(u32::from_be_bytes(self.read_array()?) as u64),
+                RDB_64BITLEN => Length::Len(u64::from_be_bytes(self.read_array()?)),
+                _ => {
+                    return Err(RdbError::Invalid(format!(
+                        "unknown length encoding {first:#x}"
+                    )))
+                }
+            },
+        };
+        Ok(length)
+    }
+
+    fn read_plain_length(&mut self) -> std::result::Result<u64, RdbError> {
+        match self.read_length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::Invalid(
+                "expected a length, found an encoded value".to_string(),
+            )),
+        }
+    }
+
+    fn read_string(&mut self) -> std::result::Result<Vec<u8>, RdbError> {
+        match self.read_length()? {
+            Length::Len(len) => self.read_bytes(len as usize),
+            Length::Encoded(RDB_ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT16) => Ok(i16::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(RDB_ENC_INT32) => Ok(i32::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_len = self.read_plain_length()? as usize;
+                let len = self.read_plain_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+                    .ok_or_else(|| RdbError::Invalid("invalid LZF compressed string".to_string()))
+            }
+            Length::Encoded(encoding) => Err(RdbError::Invalid(format!(
+                "unsupported string encoding {encoding}"
+            ))),
+        }
+    }
+
+    fn read_u8(&mut self) -> std::result::Result<u8, RdbError> {
+        let [byte] = self.read_array()?;
+        Ok(byte)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> std::result::Result<[u8; N], RdbError> {
+        let mut buffer = [0u8; N];
+        self.reader.read_exact(&mut buffer)?;
+        self.checksum = crc64(self.checksum, &buffer);
+        Ok(buffer)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> std::result::Result<Vec<u8>, RdbError> {
+        let mut buffer = Vec::new();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut buffer)?;
+        if buffer.len() != len {
+            return Err(RdbError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "RDB data ended in the middle of a string",
+            )));
+        }
+        self.checksum = crc64(self.checksum, &buffer);
+        Ok(buffer)
+    }
+}
+
+pub fn decode_rdb(data: &[u8]) -> std::result::Result<RdbFile, RdbError> {
+    let mut entries = Vec::new();
+    let summary = RdbDecoder::new(data).decode(|entry| entries.push(entry))?;
+    Ok(RdbFile { summary, entries })
+}
+
+pub struct RdbEncoder {
+    buffer: Vec<u8>,
+}
+
+impl Default for RdbEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RdbEncoder {
+    pub fn new() -> Self {
+        let mut encoder = Self { buffer: Vec::new() };
+        encoder.buffer.extend(RDB_MAGIC);
+        encoder
+            .buffer
+            .extend(format!("{RDB_VERSION:04}").as_bytes());
+        encoder
+    }
+
+    pub fn write_aux(&mut self, key: &[u8], value: &[u8]) {
+        self.buffer.push(RDB_OPCODE_AUX);
+        self.write_string(key);
+        self.write_string(value);
+    }
+
+    pub fn write_db_header(&mut self, db: usize, keys: usize, expires: usize) {
+        self.buffer.push(RDB_OPCODE_SELECTDB);
+        self.write_length(db as u64);
+        self.buffer.push(RDB_OPCODE_RESIZEDB);
+        self.write_length(keys as u64);
+        self.write_length(expires as u64);
+    }
+
+    /// Fails without writing anything for the values `value_type` refuses
+    pub fn write_entry(&mut self, entry: &RdbEntry) -> std::result::Result<(), RdbError> {
+        let value_type = Self::value_type(&entry.value)?;
+        if let Some(expire_at_ms) = entry.expire_at_ms {
+            self.buffer.push(RDB_OPCODE_EXPIRETIME_MS);
+            self.buffer.extend(expire_at_ms.to_le_bytes());
+        }
+        self.buffer.push(value_type);
+        self.write_string(&entry.key);
+        self.write_value(&entry.value);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buffer.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &self.buffer);
+        self.buffer.extend(checksum.to_le_bytes());
+        self.buffer
+    }
+
+    /// Type byte of the plain encoding we write values in, which every Redis version
+    /// can load. Streams and module values have no such encoding we could write, the
+    /// data of the latter not even being kept when decoding them.
+    fn value_type(value: &RdbValue) -> std::result::Result<u8, RdbError> {
+        match value {
+            RdbValue::String(_) => Ok(RDB_TYPE_STRING),
+            RdbValue::List(_) => Ok(RDB_TYPE_LIST),
+            RdbValue::Set(_) => Ok(RDB_TYPE_SET),
+            RdbValue::SortedSet(_) => Ok(RDB_TYPE_ZSET_2),
+            RdbValue::Hash(_) => Ok(RDB_TYPE_HASH),
+            RdbValue::Stream(_) | RdbValue::Module { .. } => {
+                Err(RdbError::Unencodable(value.type_name().to_string()))
+            }
+        }
+    }
+
+    fn write_value(&mut self, value: &RdbValue) {
+        match value {
+            RdbValue::String(data) => self.write_string(data),
+            RdbValue::List(elements) | RdbValue::Set(elements) => {
+                self.write_length(elements.len() as u64);
+                for element in elements {
+                    self.write_string(element);
+                }
+            }
+            RdbValue::SortedSet(members) => {
+                self.write_length(members.len() as u64);
+                for (member, score) in members {
+                    self.write_string(member);
+                    self.buffer.extend(score.to_le_bytes());
+                }
+            }
+            RdbValue::Hash(fields) => {
+                self.write_length(fields.len() as u64);
+                for (field, value) in fields {
+                    self.write_string(field);
+                    self.write_string(value);
+                }
+            }
+            RdbValue::Stream(_) | RdbValue::Module { .. } => {
+                unreachable!("refused by value_type")
+            }
+        }
+    }
+
+    fn write_length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.buffer.push((RDB_6BITLEN << 6) | len as u8);
+        } else if len < 1 << 14 {
+            self.buffer.push((RDB_14BITLEN << 6) | (len >> 8) as u8);
+            self.buffer.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buffer.push(RDB_32BITLEN);
+            self.buffer.extend((len as u32).to_be_bytes());
+        } else {
+            self.buffer.push(RDB_64BITLEN);
+            self.buffer.extend(len.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, data: &[u8]) {
+        if let Some(value) = Self::as_encodable_integer(data) {
+            let encval = RDB_ENCVAL << 6;
+            if let Ok(value) = i8::try_from(value) {
+                self.buffer.push(encval | RDB_ENC_INT8 as u8);
+                self.buffer.extend(value.to_le_bytes());
+            } else if let Ok(value) = i16::try_from(value) {
+                self.buffer.push(encval | RDB_ENC_INT16 as u8);
+                self.buffer.extend(value.to_le_bytes());
+            } else {
+                self.buffer.push(encval | RDB_ENC_INT32 as u8);
+                self.buffer.extend(value.to_le_bytes());
+            }
+            return;
+        }
+        self.write_length(data.len() as u64);
+        self.buffer.extend(data);
+    }
+
+    /// Strings are stored as integers only when decoding gives back the exact same bytes
+    fn as_encodable_integer(data: &[u8]) -> Option<i32> {
+        if data.is_empty() || data.len() > 11 {
+            return None;
+        }
+        let value: i32 = std::str::from_utf8(data).ok()?.parse().ok()?;
+        (value.to_string().as_bytes() == data).then_some(value)
+    }
+}
+
+/// Serializes a keyspace snapshot into a complete RDB file, CRC64 trailer included.
+/// Fails if any of the values cannot be encoded.
+pub fn encode_rdb(entries: &[RdbEntry], ctime_secs: u64) -> std::result::Result<Vec<u8>, RdbError> {
+    let mut encoder = RdbEncoder::new();
+    encoder.write_aux(b"redis-ver", REDIS_VERSION.as_bytes());
+    encoder.write_aux(b"redis-bits", b"64");
+    encoder.write_aux(b"ctime", ctime_secs.to_string().as_bytes());
+
+    for db_entries in entries.chunk_by(|a, b| a.db == b.db) {
+        let expires = db_entries
+            .iter()
+            .filter(|entry| entry.expire_at_ms.is_some())
+            .count();
+        encoder.write_db_header(db_entries[0].db, db_entries.len(), expires);
+        for entry in db_entries {
+            encoder.write_entry(entry)?;
+        }
+    }
+
+    Ok(encoder.finish())
+}
+
+/// Serializes a value the way DUMP does: its RDB encoding followed by the RDB version
+/// and a CRC64 of everything before it
+pub fn encode_dump_payload(value: &RdbValue) -> std::result::Result<Vec<u8>, RdbError> {
+    let mut encoder = RdbEncoder { buffer: Vec::new() };
+    encoder.buffer.push(RdbEncoder::value_type(value)?);
+    encoder.write_value(value);
+    encoder.buffer.extend((RDB_VERSION as u16).to_le_bytes());
+    let checksum = crc64(0, &encoder.buffer);
+    encoder.buffer.extend(checksum.to_le_bytes());
+    Ok(encoder.buffer)
+}
+
+/// Checks the trailer of a DUMP payload and decodes the value it holds. Payloads from
+/// a newer RDB version than ours are refused, as their encodings may be unknown to us.
+pub fn decode_dump_payload(payload: &[u8]) -> std::result::Result<RdbValue, RdbError> {
+    if payload.len() < 11 {
+        return Err(RdbError::Invalid("DUMP payload is too short".to_string()));
+    }
+    let (body, trailer) = payload.split_at(payload.len() - 8);
+    let checksum = u64::from_le_bytes(trailer.try_into().expect("trailer is 8 bytes long"));
+    if checksum != 0 && checksum != crc64(0, body) {
+        return Err(RdbError::Invalid(
+            "DUMP payload checksum mismatch".to_string(),
+        ));
+    }
+    let (data, version) = body.split_at(body.len() - 2);
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version as u32 > RDB_VERSION {
+        return Err(RdbError::Invalid(format!(
+            "unsupported DUMP payload version {version}"
+        )));
+    }
+
+    let mut decoder = RdbDecoder::new(data);
+    let value_type = decoder.read_u8()?;
+    let value = decoder.read_value(value_type)?;
+    if !decoder.reader.is_empty() {
+        return Err(RdbError::Invalid(
+            "trailing bytes after the DUMP payload value".to_string(),
+        ));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::rdb::get_empty_rdb;
+
+    const HEADER: &[u8] = b"REDIS0011";
+
+    fn build_rdb(body: &[u8]) -> Vec<u8> {
+        [HEADER, body, &[RDB_OPCODE_EOF], &[0u8; 8]].concat()
+    }
+
+    #[test]
+    fn test_decode_empty_rdb() {
+        let rdb = decode_rdb(&get_empty_rdb()).unwrap();
+        assert_eq!(rdb.summary.version, 11);
+        assert!(rdb.entries.is_empty());
+        assert!(rdb
+            .summary
+            .aux
+            .contains(&(b"redis-ver".to_vec(), b"7.2.0".to_vec())));
+    }
+
+    #[test]
+    fn test_decode_keys_with_expiry() {
+        let body = [
+            &[RDB_OPCODE_SELECTDB, 0x00, RDB_OPCODE_RESIZEDB, 0x03, 0x02][..],
+            &[RDB_TYPE_STRING, 0x03],
+            b"foo",
+            &[0x03],
+            b"bar",
+            &[RDB_OPCODE_EXPIRETIME_MS],
+            &1_713_824_559_637u64.to_le_bytes(),
+            &[RDB_TYPE_STRING, 0x05],
+            b"fruit",
+            &[0x05],
+            b"apple",
+            &[RDB_OPCODE_EXPIRETIME],
+            &1_714_089_298u32.to_le_bytes(),
+            &[RDB_TYPE_STRING, 0x03],
+            b"baz",
+            &[0x03],
+            b"qux",
+        ]
+        .concat();
+        let rdb = decode_rdb(&build_rdb(&body)).unwrap();
+        assert_eq!(
+            rdb.entries,
+            vec![
+                RdbEntry {
+                    db: 0,
+                    key: b"foo".to_vec(),
+                    value: RdbValue::String(b"bar".to_vec()),
+                    expire_at_ms: None,
+                },
+                RdbEntry {
+                    db: 0,
+                    key: b"fruit".to_vec(),
+                    value: RdbValue::String(b"apple".to_vec()),
+                    expire_at_ms: Some(1_713_824_559_637),
+                },
+                RdbEntry {
+                    db: 0,
+                    key: b"baz".to_vec(),
+                    value: RdbValue::String(b"qux".to_vec()),
+                    expire_at_ms: Some(1_714_089_298_000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_integer_encoded_strings() {
+        let body = [
+            &[RDB_TYPE_STRING, 0xC0, 0x7B, 0xC0, 0xF6][..],
+            &[RDB_TYPE_STRING, 0xC1],
+            &1234i16.to_le_bytes(),
+            &[0xC2],
+            &(-70000i32).to_le_bytes(),
+        ]
+        .concat();
+        let rdb = decode_rdb(&build_rdb(&body)).unwrap();
+        assert_eq!(rdb.entries[0].key, b"123".to_vec());
+        assert_eq!(rdb.entries[0].value, RdbValue::String(b"-10".to_vec()));
+        assert_eq!(rdb.entries[1].key, b"1234".to_vec());
+        assert_eq!(rdb.entries[1].value, RdbValue::String(b"-70000".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_long_lengths() {
+        let value = vec![b'x'; 20_000];
+        let body = [
+            &[RDB_TYPE_STRING, 0x41, 0x2C][..],
+            &vec![b'k'; 300],
+            &[RDB_32BITLEN],
+            &(value.len() as u32).to_be_bytes(),
+            &value,
+        ]
+        .concat();
+        let rdb = decode_rdb(&build_rdb(&body)).unwrap();
+        assert_eq!(rdb.entries[0].key.len(), 300);
+        assert_eq!(rdb.entries[0].value, RdbValue::String(value));
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: b"fruit".to_vec(),
+                value: RdbValue::String(b"apple".to_vec()),
+                expire_at_ms: Some(1_713_824_559_637),
+            },
+            RdbEntry {
+                db: 0,
+                key: b"counter".to_vec(),
+                value: RdbValue::String(b"-12345".to_vec()),
+                expire_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: b"padded".to_vec(),
+                value: RdbValue::String(b"007".to_vec()),
+                expire_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: b"large".to_vec(),
+                value: RdbValue::String(vec![b'z'; 70_000]),
+                expire_at_ms: None,
+            },
+        ];
+        let data = encode_rdb(&entries, 1_713_824_559).unwrap();
+        let rdb = decode_rdb(&data).unwrap();
+        assert_eq!(rdb.summary.version, RDB_VERSION);
+        assert_ne!(rdb.summary.checksum, 0);
+        assert_eq!(rdb.entries, entries);
+    }
+
+    #[test]
+    fn test_decode_checksum_mismatch() {
+        let entries = vec![RdbEntry {
+            db: 0,
+            key: b"foo".to_vec(),
+            value: RdbValue::String(b"bar".to_vec()),
+            expire_at_ms: None,
+        }];
+        let mut data = encode_rdb(&entries, 0).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        let result = decode_rdb(&data);
+        assert!(matches!(result, Err(RdbError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_decode_invalid_magic() {
+        let result = decode_rdb(b"RADIS0011\xFF");
+        assert!(matches!(result, Err(RdbError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let body = [&[RDB_TYPE_STRING, 0x03][..], b"foo", &[0x05], b"ba"].concat();
+        let data = [HEADER, body.as_slice()].concat();
+        let result = decode_rdb(&data);
+        assert!(matches!(result, Err(RdbError::Io(_))));
+    }
+
+    /// An encoder writing bare values, to build the bodies of test files
+    fn body_encoder() -> RdbEncoder {
+        RdbEncoder { buffer: Vec::new() }
+    }
+
+    /// A listpack holding every item as a short string, which gives back integers too
+    fn short_listpack(items: &[&[u8]]) -> Vec<u8> {
+        let body = items
+            .iter()
+            .flat_map(|item| {
+                [
+                    &[0x80 | item.len() as u8][..],
+                    item,
+                    &[item.len() as u8 + 1],
+                ]
+                .concat()
+            })
+            .collect::<Vec<_>>();
+        let total = (6 + body.len() + 1) as u32;
+        [
+            &total.to_le_bytes()[..],
+            &(items.len() as u16).to_le_bytes(),
+            &body,
+            &[0xFF],
+        ]
+        .concat()
+    }
+
+    fn short_ziplist(items: &[&[u8]]) -> Vec<u8> {
+        let body = items
+            .iter()
+            .flat_map(|item| [&[0x00, item.len() as u8][..], item].concat())
+            .collect::<Vec<_>>();
+        let total = (10 + body.len() + 1) as u32;
+        [
+            &total.to_le_bytes()[..],
+            &[0; 4],
+            &(items.len() as u16).to_le_bytes(),
+            &body,
+            &[0xFF],
+        ]
+        .concat()
+    }
+
+    fn decode_single_value(value_type: u8, write: impl FnOnce(&mut RdbEncoder)) -> RdbValue {
+        let mut encoder = body_encoder();
+        encoder.buffer.push(value_type);
+        encoder.write_string(b"key");
+        write(&mut encoder);
+        let mut rdb = decode_rdb(&build_rdb(&encoder.buffer)).unwrap();
+        assert_eq!(rdb.entries.len(), 1);
+        rdb.entries.remove(0).value
+    }
+
+    #[test]
+    fn test_decode_lzf_string() {
+        let body = [
+            &[RDB_TYPE_STRING, 0x01, b'k', 0xC3, 0x09, 0x13][..],
+            &[0x02, b'a', b'b', b'c', 0x60, 0x02, 0xE0, 0x02, 0x00],
+        ]
+        .concat();
+        let rdb = decode_rdb(&build_rdb(&body)).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::String(b"abcabcabbbbbbbbbbbb".to_vec())
+        );
+
+        let body = [
+            &[RDB_TYPE_STRING, 0x01, b'k', 0xC3, 0x02, 0x05][..],
+            &[0x00, b'a'],
+        ]
+        .concat();
+        assert!(matches!(
+            decode_rdb(&build_rdb(&body)),
+            Err(RdbError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_oversized_lzf_string() {
+        // a DUMP payload claiming to decompress into 2^62 bytes, with no checksum
+        let mut payload = vec![RDB_TYPE_STRING, 0xC3, 0x02, RDB_64BITLEN];
+        payload.extend((1u64 << 62).to_be_bytes());
+        payload.extend([0x00, b'a']);
+        payload.extend((RDB_VERSION as u16).to_le_bytes());
+        payload.extend([0; 8]);
+        assert!(matches!(
+            decode_dump_payload(&payload),
+            Err(RdbError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_unsupported_version() {
+        let rdb = [b"REDIS0012", &[RDB_OPCODE_EOF][..], &[0u8; 8]].concat();
+        assert!(matches!(decode_rdb(&rdb), Err(RdbError::Invalid(_))));
+        let rdb = [b"REDIS9999", &[RDB_OPCODE_EOF][..], &[0u8; 8]].concat();
+        assert!(matches!(decode_rdb(&rdb), Err(RdbError::Invalid(_))));
+        let rdb = [b"REDIS0009", &[RDB_OPCODE_EOF][..], &[0u8; 8]].concat();
+        assert_eq!(decode_rdb(&rdb).unwrap().summary.version, 9);
+    }
+
+    #[test]
+    fn test_decode_plain_collections() {
+        let value = decode_single_value(RDB_TYPE_ZSET, |encoder| {
+            encoder.write_length(3);
+            encoder.write_string(b"a");
+            encoder.buffer.extend(b"\x031.5");
+            encoder.write_string(b"b");
+            encoder.buffer.push(RDB_SCORE_POS_INF);
+            encoder.write_string(b"c");
+            encoder.buffer.push(RDB_SCORE_NEG_INF);
+        });
+        assert_eq!(
+            value,
+            RdbValue::SortedSet(vec![
+                (b"a".to_vec(), 1.5),
+                (b"b".to_vec(), f64::INFINITY),
+                (b"c".to_vec(), f64::NEG_INFINITY),
+            ])
+        );
+
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: b"list".to_vec(),
+                value: RdbValue::List(vec![b"a".to_vec(), b"12".to_vec(), b"a".to_vec()]),
+                expire_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: b"set".to_vec(),
+                value: RdbValue::Set(vec![b"x".to_vec(), b"y".to_vec()]),
+                expire_at_ms: Some(1_713_824_559_637),
+            },
+            RdbEntry {
+                db: 0,
+                key: b"hash".to_vec(),
+                value: RdbValue::Hash(vec![(b"field".to_vec(), b"value".to_vec())]),
+                expire_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: b"zset".to_vec(),
+                value: RdbValue::SortedSet(vec![(b"m".to_vec(), -0.25)]),
+                expire_at_ms: None,
+            },
+        ];
+        let rdb = decode_rdb(&encode_rdb(&entries, 0).unwrap()).unwrap();
+        assert_eq!(rdb.entries, entries);
+    }
+
+    #[test]
+    fn test_decode_compact_collections() {
+        let value = decode_single_value(RDB_TYPE_LIST_ZIPLIST, |encoder| {
+            encoder.write_string(&short_ziplist(&[b"a", b"b"]))
+        });
+        assert_eq!(value, RdbValue::List(vec![b"a".to_vec(), b"b".to_vec()]));
+
+        let value = decode_single_value(RDB_TYPE_SET_INTSET, |encoder| {
+            encoder
+                .write_string(&[&2u32.to_le_bytes()[..], &1u32.to_le_bytes(), &[0x07, 0]].concat())
+        });
+        assert_eq!(value, RdbValue::Set(vec![b"7".to_vec()]));
+
+        let value = decode_single_value(RDB_TYPE_HASH_ZIPLIST, |encoder| {
+            encoder.write_string(&short_ziplist(&[b"f", b"v"]))
+        });
+        assert_eq!(value, RdbValue::Hash(vec![(b"f".to_vec(), b"v".to_vec())]));
+
+        let value = decode_single_value(RDB_TYPE_ZSET_LISTPACK, |encoder| {
+            encoder.write_string(&short_listpack(&[b"m", b"2.5", b"n", b"3"]))
+        });
+        assert_eq!(
+            value,
+            RdbValue::SortedSet(vec![(b"m".to_vec(), 2.5), (b"n".to_vec(), 3.0)])
+        );
+
+        let value = decode_single_value(RDB_TYPE_SET_LISTPACK, |encoder| {
+            encoder.write_string(&short_listpack(&[b"s"]))
+        });
+        assert_eq!(value, RdbValue::Set(vec![b"s".to_vec()]));
+
+        let value = decode_single_value(RDB_TYPE_LIST_QUICKLIST_2, |encoder| {
+            encoder.write_length(2);
+            encoder.write_length(QUICKLIST_NODE_PACKED);
+            encoder.write_string(&short_listpack(&[b"a", b"b"]));
+            encoder.write_length(QUICKLIST_NODE_PLAIN);
+            encoder.write_string(b"large");
+        });
+        assert_eq!(
+            value,
+            RdbValue::List(vec![b"a".to_vec(), b"b".to_vec(), b"large".to_vec()])
+        );
+
+        let mut encoder = body_encoder();
+        encoder.buffer.push(RDB_TYPE_HASH_LISTPACK);
+        encoder.write_string(b"key");
+        encoder.write_string(&short_listpack(&[b"odd"]));
+        assert!(decode_rdb(&build_rdb(&encoder.buffer)).is_err());
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let master_id = StreamId {
+            ms: 1_700_000_000_000,
+            seq: 0,
+        };
+        let master_key = [master_id.ms.to_be_bytes(), master_id.seq.to_be_bytes()].concat();
+        let raw_id = [1_700_000_000_010u64.to_be_bytes(), 1u64.to_be_bytes()].concat();
+        let listpack = short_listpack(&[
+            // master entry: 2 valid and 1 deleted entries sharing the field "temp"
+            b"2", b"1", b"1", b"temp", b"0", // same fields as the master entry
+            b"2", b"0", b"0", b"20", b"4", // deleted
+            b"1", b"5", b"0", b"1", b"temp", b"25", b"6", b"0", b"10", b"1", b"2", b"temp", b"30",
+            b"unit", b"c", b"8",
+        ]);
+        let value = decode_single_value(RDB_TYPE_STREAM_LISTPACKS_3, |encoder| {
+            encoder.write_length(1);
+            encoder.write_string(&master_key);
+            encoder.write_string(&listpack);
+            // length, last id, first id, max deleted id and entries added
+            for len in [
+                2,
+                1_700_000_000_010,
+                1,
+                1_700_000_000_000,
+                0,
+                1_700_000_000_005,
+                0,
+                3,
+            ] {
+                encoder.write_length(len);
+            }
+            // one group with one entry pending for its only consumer
+            encoder.write_length(1);
+            encoder.write_string(b"group");
+            for len in [1_700_000_000_010, 1, 2, 1] {
+                encoder.write_length(len);
+            }
+            encoder.buffer.extend(&raw_id);
+            encoder.buffer.extend(1_700_000_001_000u64.to_le_bytes());
+            encoder.write_length(3);
+            encoder.write_length(1);
+            encoder.write_string(b"alice");
+            encoder.buffer.extend(1_700_000_002_000u64.to_le_bytes());
+            encoder.buffer.extend(1_700_000_001_000u64.to_le_bytes());
+            encoder.write_length(1);
+            encoder.buffer.extend(&raw_id);
+        });
+
+        let RdbValue::Stream(stream) = value else {
+            panic!("expected a stream, got {value:?}");
+        };
+        let last_id = StreamId {
+            ms: 1_700_000_000_010,
+            seq: 1,
+        };
+        assert_eq!(
+            stream.entries,
+            vec![
+                StreamEntry {
+                    id: master_id,
+                    fields: vec![(b"temp".to_vec(), b"20".to_vec())],
+                },
+                StreamEntry {
+                    id: last_id,
+                    fields: vec![
+                        (b"temp".to_vec(), b"30".to_vec()),
+                        (b"unit".to_vec(), b"c".to_vec())
+                    ],
+                },
+            ]
+        );
+        assert_eq!(stream.length, 2);
+        assert_eq!(stream.last_id, last_id);
+        assert_eq!(stream.first_id, master_id);
+        assert_eq!(stream.max_deleted_entry_id.to_string(), "1700000000005-0");
+        assert_eq!(stream.entries_added, 3);
+        assert_eq!(
+            stream.groups,
+            vec![ConsumerGroup {
+                name: b"group".to_vec(),
+                last_id,
+                entries_read: Some(2),
+                pending: vec![PendingEntry {
+                    id: last_id,
+                    delivery_time_ms: 1_700_000_001_000,
+                    delivery_count: 3,
+                }],
+                consumers: vec![StreamConsumer {
+                    name: b"alice".to_vec(),
+                    seen_time_ms: 1_700_000_002_000,
+                    active_time_ms: 1_700_000_001_000,
+                    pending: vec![last_id],
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_skip_module_data() {
+        // "mymodule1" with encoding version 3
+        let module_id = b"mymodule1".iter().fold(0u64, |id, &c| {
+            let index = MODULE_NAME_CHARSET.iter().position(|&x| x == c).unwrap();
+            (id << 6) | index as u64
+        }) << 10
+            | 3;
+        let mut encoder = body_encoder();
+        encoder.buffer.push(RDB_OPCODE_MODULE_AUX);
+        encoder.write_length(module_id);
+        encoder.write_length(RDB_MODULE_OPCODE_UINT);
+        encoder.write_length(2);
+        encoder.write_length(RDB_MODULE_OPCODE_STRING);
+        encoder.write_string(b"aux data");
+        encoder.write_length(RDB_MODULE_OPCODE_EOF);
+        encoder
+            .buffer
+            .extend([RDB_OPCODE_IDLE, 0x05, RDB_OPCODE_FREQ, 0x10]);
+        encoder.buffer.push(RDB_TYPE_MODULE_2);
+        encoder.write_string(b"key");
+        encoder.write_length(module_id);
+        encoder.write_length(RDB_MODULE_OPCODE_SINT);
+        encoder.write_length(42);
+        encoder.write_length(RDB_MODULE_OPCODE_DOUBLE);
+        encoder.buffer.extend(1.5f64.to_le_bytes());
+        encoder.write_length(RDB_MODULE_OPCODE_FLOAT);
+        encoder.buffer.extend(1.5f32.to_le_bytes());
+        encoder.write_length(RDB_MODULE_OPCODE_EOF);
+        encoder.buffer.push(RDB_TYPE_STRING);
+        encoder.write_string(b"after");
+        encoder.write_string(b"module");
+
+        let rdb = decode_rdb(&build_rdb(&encoder.buffer)).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::Module {
+                name: "mymodule1".to_string(),
+                encoding_version: 3
+            }
+        );
+        assert_eq!(rdb.entries[0].value.type_name(), "mymodule1");
+        assert_eq!(rdb.entries[1].value, RdbValue::String(b"module".to_vec()));
+    }
+
+    #[test]
+    fn test_dump_payload_roundtrip() {
+        for value in [&b"apple"[..], b"-12345", &[b'z'; 20_000]] {
+            let value = RdbValue::String(value.to_vec());
+            let payload = encode_dump_payload(&value).unwrap();
+            assert_eq!(decode_dump_payload(&payload).unwrap(), value);
+        }
+        let value = RdbValue::SortedSet(vec![(b"a".to_vec(), 1.5), (b"b".to_vec(), -2.0)]);
+        assert_eq!(
+            decode_dump_payload(&encode_dump_payload(&value).unwrap()).unwrap(),
+            value
+        );
+
+        let mut payload = encode_dump_payload(&RdbValue::String(b"apple".to_vec())).unwrap();
+        payload[2] ^= 0xFF;
+        assert!(matches!(
+            decode_dump_payload(&payload),
+            Err(RdbError::Invalid(_))
+        ));
+        assert!(decode_dump_payload(b"\x00\x03foo").is_err());
+    }
+
+    #[test]
+    fn test_encode_unencodable_value() {
+        let value = RdbValue::Module {
+            name: "mymodule1".to_string(),
+            encoding_version: 3,
+        };
+        assert!(matches!(
+            encode_dump_payload(&value),
+            Err(RdbError::Unencodable(name)) if name == "mymodule1"
+        ));
+
+        let entries = [RdbEntry {
+            db: 0,
+            key: b"key".to_vec(),
+            value,
+            expire_at_ms: None,
+        }];
+        assert!(matches!(
+            encode_rdb(&entries, 0),
+            Err(RdbError::Unencodable(_))
+        ));
+    }
+}
@@ -10,8 +10,13 @@ pub type Result<T> = std::result::Result<T, ParseError>;
 pub enum Token {
     Array(Vec<Token>),
     SimpleString(String),
+    Error(String),
     BulkString(Vec<u8>),
     Integer(i64),
+    /// The null reply of commands answering with an array
+    NullArray,
+    /// A bulk string of length zero, `BulkString` holding nothing being the null reply
+    EmptyBulkString,
 }
 
 impl Token {
@@ -51,6 +56,13 @@ impl Token {
                 result.extend(CRLF);
                 result
             }
+            Token::Error(message) => {
+                let mut result = Vec::new();
+                result.push(b'-');
+                result.extend(message.as_bytes());
+                result.extend(CRLF);
+                result
+            }
             Token::BulkString(data) => {
                 let mut result = Vec::new();
                 result.push(b'$');
@@ -77,6 +89,8 @@ impl Token {
                 result.extend(CRLF);
                 result
             }
+            Token::NullArray => b"*-1\r\n".to_vec(),
+            Token::EmptyBulkString => b"$0\r\n\r\n".to_vec(),
         }
     }
 }
@@ -160,6 +174,30 @@ fn parse_simple_string(message: &[u8]) -> Result<ParseResult> {
     })
 }
 
+fn parse_error(message: &[u8]) -> Result<ParseResult> {
+    assert_eq!(message.first(), Some(&b'-'));
+
+    let str_size = find_first_crlf(message).ok_or(ParseError::Incomplete)?;
+    let data = std::str::from_utf8(&message[1..str_size])?;
+
+    Ok(ParseResult {
+        tokens: vec![Token::Error(data.to_owned())],
+        len: str_size + 2,
+    })
+}
+
+fn parse_integer(message: &[u8]) -> Result<ParseResult> {
+    assert_eq!(message.first(), Some(&b':'));
+
+    let str_size = find_first_crlf(message).ok_or(ParseError::Incomplete)?;
+    let value = std::str::from_utf8(&message[1..str_size])?.parse::<i64>()?;
+
+    Ok(ParseResult {
+        tokens: vec![Token::Integer(value)],
+        len: str_size + 2,
+    })
+}
+
 fn parse_array(message: &[u8]) -> Result<ParseResult> {
     assert_eq!(message.first(), Some(&b'*'));
 
@@ -186,7 +224,9 @@ pub fn parse_buffer(buffer: &[u8]) -> Result<ParseResult> {
         [first_byte, ..] => match first_byte {
             b'*' => parse_array(buffer),
             b'+' => parse_simple_string(buffer),
+            b'-' => parse_error(buffer),
             b'$' => parse_bulk_string(buffer),
+            b':' => parse_integer(buffer),
             byte => unimplemented!(
                 "parser does not support parsing messages starting with {:?}",
                 byte
@@ -211,6 +251,27 @@ mod tests {
         )
     }
 
+    #[test]
+    fn error_parsing_works() {
+        let message = b"-ERR unknown command\r\n";
+        let result = parse_buffer(message).unwrap();
+        assert_eq!(result.len, message.len());
+        assert_eq!(
+            result.tokens.first(),
+            Some(&Token::Error("ERR unknown command".to_owned()))
+        );
+        assert_eq!(result.tokens[0].serialize(), message.to_vec());
+    }
+
+    #[test]
+    fn integer_parsing_works() {
+        let message = b":-42\r\n";
+        let result = parse_buffer(message).unwrap();
+        assert_eq!(result.len, message.len());
+        assert_eq!(result.tokens.first(), Some(&Token::Integer(-42)));
+        assert_eq!(result.tokens[0].serialize(), message.to_vec());
+    }
+
     #[test]
     fn bulk_string_parsing_works() {
         let message = b"$5\r\nhello\r\n";
@@ -0,0 +1,901 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    common::unix_time_ms,
+    parser::{
+        command::parse_command,
+        rdb::{encode_rdb, RdbDecoder, RdbEntry, RDB_MAGIC},
+        resp::ParseError,
+    },
+    server::{config::AppendFsync, data::Server, handler::CommandHandler, metadata::AofConfig},
+};
+
+use super::{
+    export::entry_commands,
+    manifest::{base_file, AofFileInfo, AofFileType, AofManifest},
+    rdb::{restore_entry, snapshot_entries, LoadStats},
+};
+
+/// How often pending writes are retried, automatic rewrites are considered and, with
+/// `everysec`, the file is synced
+const AOF_CRON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Log of every write applied to the dataset, in the RESP form it was propagated in.
+/// It is made of a base file holding a snapshot of the dataset and of incremental files
+/// holding the writes made since, listed in a manifest. Writes go to the last
+/// incremental file.
+pub struct AppendOnlyFile {
+    config: AofConfig,
+    manifest: AofManifest,
+    file: File,
+    /// Writes which could not be written to the file yet, retried by the cron
+    buffer: Vec<u8>,
+    /// Size of the incremental file up to the end of the last complete write
+    size: u64,
+    /// Size of all the files in the manifest
+    current_size: u64,
+    /// `current_size` right after the last rewrite or load, which the growth triggering
+    /// an automatic rewrite is measured against
+    base_size: u64,
+    /// Whether anything was written since the file was last synced
+    fsync_pending: bool,
+    /// Set while writing to the file fails, client writes are refused meanwhile
+    pub write_error: Option<String>,
+    /// Sequence of the next base file, every rewrite writing its own
+    next_base_seq: u64,
+    /// Bumped by every rewrite, a rewrite finishing after a newer one started is dropped
+    rewrite_generation: u64,
+    pub rewrite_in_progress: bool,
+    pub last_rewrite_ok: bool,
+}
+
+/// What a rewrite produces, the base file replacing every file of the manifest which
+/// precedes the incremental file `incr_seq`
+struct RewriteTarget {
+    base: AofFileInfo,
+    path: PathBuf,
+    incr_seq: u64,
+    generation: u64,
+}
+
+fn open_for_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes a file next to its final location and renames it into place, so readers
+/// never observe a partially written one
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!("temp-{file_name}"));
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(err) = result.and_then(|()| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+impl AppendOnlyFile {
+    /// Opens the files listed in the manifest, creating the directory and the first
+    /// incremental file if needed. The single file of older versions is adopted as the
+    /// base.
+    pub fn open(config: &AofConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut manifest = match fs::read_to_string(Self::manifest_path(config)) {
+            Ok(contents) => AofManifest::parse(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => AofManifest::default(),
+            Err(err) => return Err(err),
+        };
+        let mut manifest_changed = false;
+        if manifest.files().next().is_none() && config.legacy_path.exists() {
+            let path = config.dir.join(&config.filename);
+            fs::rename(&config.legacy_path, &path)?;
+            println!(
+                "INFO: moved the AOF file {:?} to {path:?} as the base of a multi-part AOF",
+                config.legacy_path
+            );
+            manifest.base = Some(AofFileInfo {
+                name: config.filename.clone(),
+                seq: 1,
+                file_type: AofFileType::Base,
+            });
+            manifest_changed = true;
+        }
+        let incr = match manifest.incrs.last() {
+            Some(incr) => incr.clone(),
+            None => {
+                let incr = manifest.next_incr(&config.filename);
+                manifest.incrs.push(incr.clone());
+                manifest_changed = true;
+                incr
+            }
+        };
+        let file = open_for_append(&config.dir.join(&incr.name))?;
+        if manifest_changed {
+            Self::write_manifest(config, &manifest)?;
+        }
+
+        let size = file.metadata()?.len();
+        let next_base_seq = manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+        let mut aof = Self {
+            config: config.clone(),
+            manifest,
+            file,
+            buffer: Vec::new(),
+            size,
+            current_size: 0,
+            base_size: 0,
+            fsync_pending: false,
+            write_error: None,
+            next_base_seq,
+            rewrite_generation: 0,
+            rewrite_in_progress: false,
+            last_rewrite_ok: true,
+        };
+        aof.current_size = aof.files_size();
+        aof.base_size = aof.current_size;
+        Ok(aof)
+    }
+
+    fn manifest_path(config: &AofConfig) -> PathBuf {
+        config.dir.join(format!("{}.manifest", config.filename))
+    }
+
+    fn write_manifest(config: &AofConfig, manifest: &AofManifest) -> io::Result<()> {
+        write_file(
+            &Self::manifest_path(config),
+            manifest.serialize().as_bytes(),
+        )
+    }
+
+    fn path_of(&self, file: &AofFileInfo) -> PathBuf {
+        self.config.dir.join(&file.name)
+    }
+
+    fn files_size(&self) -> u64 {
+        self.manifest
+            .files()
+            .filter_map(|file| fs::metadata(self.path_of(file)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Whether nothing was ever logged, in which case there is no dataset to load
+    pub fn is_empty(&self) -> bool {
+        self.manifest.base.is_none() && self.current_size == 0
+    }
+
+    pub fn current_size(&self) -> u64 {
+        self.current_size
+    }
+
+    pub fn base_size(&self) -> u64 {
+        self.base_size
+    }
+
+    pub fn append(&mut self, message: &[u8]) {
+        self.buffer.extend_from_slice(message);
+        self.flush();
+    }
+
+    /// Writes out the buffered writes. A partial write is cut off the file again so that
+    /// a retry does not leave half a command behind.
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if let Err(err) = self.file.write_all(&self.buffer) {
+            if self.write_error.is_none() {
+                eprintln!("ERROR: failed to write to the AOF file: {err}");
+            }
+            if let Err(err) = self.file.set_len(self.size) {
+                eprintln!("ERROR: failed to truncate the AOF file: {err}");
+            }
+            self.write_error = Some(err.to_string());
+            return;
+        }
+        if self.write_error.take().is_some() {
+            println!("INFO: AOF write error looks solved, the server can write again");
+        }
+        self.size += self.buffer.len() as u64;
+        self.current_size += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.fsync_pending = true;
+        if self.config.fsync == AppendFsync::Always {
+            self.sync();
+        }
+    }
+
+    fn sync(&mut self) {
+        match self.file.sync_data() {
+            Ok(()) => self.fsync_pending = false,
+            Err(err) => eprintln!("ERROR: failed to fsync the AOF file: {err}"),
+        }
+    }
+
+    /// Writes out and syncs everything logged so far, for a shutdown
+    pub fn flush_and_sync(&mut self) -> io::Result<()> {
+        self.flush();
+        if let Some(err) = &self.write_error {
+            return Err(io::Error::other(format!("AOF writes are failing: {err}")));
+        }
+        self.file.sync_data()?;
+        self.fsync_pending = false;
+        Ok(())
+    }
+
+    /// Drops everything past the first `len` bytes of the incremental file
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.current_size -= self.size - len;
+        self.size = len;
+        Ok(())
+    }
+
+    /// Moves the writes to a new incremental file, which is all that will be left of
+    /// the current files once the rewrite installs its base
+    fn begin_rewrite(&mut self) -> io::Result<RewriteTarget> {
+        self.flush();
+        if let Some(err) = &self.write_error {
+            return Err(io::Error::other(format!("AOF writes are failing: {err}")));
+        }
+        if self.fsync_pending {
+            self.sync();
+        }
+
+        let incr = self.manifest.next_incr(&self.config.filename);
+        let file = open_for_append(&self.path_of(&incr))?;
+        let mut manifest = self.manifest.clone();
+        manifest.incrs.push(incr.clone());
+        Self::write_manifest(&self.config, &manifest)?;
+        self.manifest = manifest;
+        self.file = file;
+        self.size = 0;
+
+        let base = base_file(
+            &self.config.filename,
+            self.next_base_seq,
+            self.config.use_rdb_preamble,
+        );
+        self.next_base_seq += 1;
+        self.rewrite_generation += 1;
+        Ok(RewriteTarget {
+            path: self.path_of(&base),
+            base,
+            incr_seq: incr.seq,
+            generation: self.rewrite_generation,
+        })
+    }
+
+    /// Installs the base written by a rewrite and removes the files it replaces
+    fn end_rewrite(&mut self, target: &RewriteTarget) -> io::Result<()> {
+        if target.generation != self.rewrite_generation {
+            println!("INFO: dropping AOF rewrite superseded by a newer one");
+            return fs::remove_file(&target.path);
+        }
+        let manifest = AofManifest {
+            base: Some(target.base.clone()),
+            incrs: self
+                .manifest
+                .incrs
+                .iter()
+                .filter(|incr| incr.seq >= target.incr_seq)
+                .cloned()
+                .collect(),
+        };
+        Self::write_manifest(&self.config, &manifest)?;
+        let previous = std::mem::replace(&mut self.manifest, manifest);
+        for file in previous.files() {
+            if self
+                .manifest
+                .files()
+                .all(|current| current.name != file.name)
+            {
+                if let Err(err) = fs::remove_file(self.path_of(file)) {
+                    eprintln!("ERROR: failed to remove AOF file {:?}: {err}", file.name);
+                }
+            }
+        }
+        self.current_size = self.files_size();
+        self.base_size = self.current_size;
+        Ok(())
+    }
+
+    /// Whether the log grew enough since the last rewrite to be rewritten again
+    fn needs_auto_rewrite(&self) -> bool {
+        let percentage = self.config.auto_rewrite_percentage;
+        if percentage == 0
+            || self.rewrite_in_progress
+            || self.current_size < self.config.auto_rewrite_min_size
+        {
+            return false;
+        }
+        let base_size = self.base_size.max(1);
+        self.current_size.saturating_sub(base_size) * 100 / base_size >= percentage
+    }
+
+    /// Retries the writes which failed and syncs the file when the policy asks for it
+    fn cron(&mut self) {
+        self.flush();
+        if self.config.fsync == AppendFsync::Everysec && self.fsync_pending {
+            self.sync();
+        }
+    }
+}
+
+fn aof_of(server: &Server) -> io::Result<&Mutex<AppendOnlyFile>> {
+    server
+        .aof
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "appendonly is not enabled"))
+}
+
+/// The commands recreating the given entries, expiries being kept absolute
+fn dataset_commands(entries: &[RdbEntry]) -> Vec<u8> {
+    entries.iter().flat_map(entry_commands).collect()
+}
+
+/// Snapshots the dataset and switches to a new incremental file at the same point, which
+/// writes are held back for
+fn begin_rewrite(server: &Server) -> io::Result<(RewriteTarget, Vec<RdbEntry>)> {
+    let aof = aof_of(server)?;
+    let _live_data = server.live_data.lock().unwrap();
+    let entries = snapshot_entries(server);
+    let target = aof.lock().unwrap().begin_rewrite()?;
+    Ok((target, entries))
+}
+
+fn finish_rewrite(server: &Server, target: &RewriteTarget, entries: &[RdbEntry]) -> io::Result<()> {
+    let contents = if target.base.name.ends_with(".rdb") {
+        encode_rdb(entries, unix_time_ms() / 1000).map_err(io::Error::other)?
+    } else {
+        dataset_commands(entries)
+    };
+    write_file(&target.path, &contents)?;
+    aof_of(server)?.lock().unwrap().end_rewrite(target)?;
+    println!(
+        "INFO: AOF rewritten to {:?} with {} keys",
+        target.base.name,
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Rewrites the append-only file into the commands recreating the current dataset. Also
+/// needed whenever the dataset changes by other means than writes, such as loading a
+/// snapshot.
+pub fn rewrite(server: &Server) -> io::Result<()> {
+    if server.aof.is_none() {
+        return Ok(());
+    }
+    let (target, entries) = begin_rewrite(server)?;
+    finish_rewrite(server, &target, &entries)
+}
+
+/// Marks a rewrite as running and writes the new base on a separate thread. Returns
+/// false if another rewrite is already in progress.
+pub fn start_background_rewrite(server: Arc<Server>) -> io::Result<bool> {
+    {
+        let mut aof = aof_of(&server)?.lock().unwrap();
+        if aof.rewrite_in_progress {
+            return Ok(false);
+        }
+        aof.rewrite_in_progress = true;
+    }
+
+    let (target, entries) = match begin_rewrite(&server) {
+        Ok(started) => started,
+        Err(err) => {
+            let mut aof = aof_of(&server)?.lock().unwrap();
+            aof.rewrite_in_progress = false;
+            aof.last_rewrite_ok = false;
+            return Err(err);
+        }
+    };
+    println!("INFO: Background append only file rewriting started");
+
+    std::thread::spawn(move || {
+        let result = finish_rewrite(&server, &target, &entries);
+        if let Err(err) = &result {
+            eprintln!("ERROR: background AOF rewrite failed with error {err:?}");
+            let _ = fs::remove_file(&target.path);
+        }
+        if let Ok(aof) = aof_of(&server) {
+            let mut aof = aof.lock().unwrap();
+            aof.rewrite_in_progress = false;
+            aof.last_rewrite_ok = result.is_ok();
+        }
+    });
+
+    Ok(true)
+}
+
+/// Replays the commands of one file, returning how many there were and, if the last
+/// one is cut short, the length of the complete ones
+fn replay(handler: &mut CommandHandler, contents: &[u8]) -> io::Result<(usize, Option<usize>)> {
+    let mut position = 0;
+    let mut commands = 0;
+    while position < contents.len() {
+        match parse_command(&contents[position..]) {
+            Ok(result) => {
+                let raw = &contents[position..position + result.len];
+                handler.handle_command(&result.command, raw)?;
+                position += result.len;
+                commands += 1;
+            }
+            Err(ParseError::Incomplete) => return Ok((commands, Some(position))),
+            Err(ParseError::Invalid) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad command at offset {position}"),
+                ));
+            }
+        }
+    }
+    Ok((commands, None))
+}
+
+/// Loads one file of the manifest, which may start with an RDB preamble. Only the last
+/// incremental file may end with a command cut short by a crash, which is dropped along
+/// with its bytes so that new writes do not follow it.
+fn load_file(
+    server: &Arc<Server>,
+    handler: &mut CommandHandler,
+    path: &Path,
+    is_last: bool,
+) -> io::Result<()> {
+    let contents = fs::read(path)?;
+    let mut remaining = contents.as_slice();
+    if remaining.starts_with(RDB_MAGIC) {
+        let mut stats = LoadStats::default();
+        RdbDecoder::new(&mut remaining)
+            .decode(|entry| restore_entry(server, entry, &mut stats))
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad RDB preamble: {err}"),
+                )
+            })?;
+        println!(
+            "INFO: loaded {} keys from the RDB preamble of {path:?} ({})",
+            stats.loaded,
+            stats.skipped()
+        );
+    }
+    let preamble_len = contents.len() - remaining.len();
+
+    let (commands, truncated_at) = replay(handler, remaining)
+        .map_err(|err| io::Error::new(err.kind(), format!("{path:?}: {err}")))?;
+    println!("INFO: replayed {commands} commands from AOF file {path:?}");
+    if let Some(position) = truncated_at {
+        let len = preamble_len + position;
+        if !is_last {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path:?} is truncated at offset {len}"),
+            ));
+        }
+        eprintln!(
+            "ERROR: AOF file {path:?} ends with a truncated command, dropping its last {} bytes",
+            contents.len() - len
+        );
+        aof_of(server)?.lock().unwrap().truncate(len as u64)?;
+    }
+    Ok(())
+}
+
+/// Replays the base file and then every incremental file of the append-only file
+pub fn load_aof_files(server: &Arc<Server>) -> io::Result<()> {
+    let paths = {
+        let aof = aof_of(server)?.lock().unwrap();
+        aof.manifest
+            .files()
+            .map(|file| aof.path_of(file))
+            .collect::<Vec<_>>()
+    };
+    let mut handler = CommandHandler::for_replay(server.clone());
+    for (index, path) in paths.iter().enumerate() {
+        load_file(server, &mut handler, path, index == paths.len() - 1)?;
+    }
+
+    let mut aof = aof_of(server)?.lock().unwrap();
+    aof.base_size = aof.current_size;
+    Ok(())
+}
+
+/// Starts the thread syncing the append-only file, retrying failed writes and rewriting
+/// the file once it grew too much
+pub fn start(server: Arc<Server>) {
+    if server.aof.is_none() {
+        return;
+    }
+    std::thread::spawn(move || loop {
+        std::thread::sleep(AOF_CRON_INTERVAL);
+        let Ok(aof) = aof_of(&server) else {
+            return;
+        };
+        let needs_rewrite = {
+            let mut aof = aof.lock().unwrap();
+            aof.cron();
+            aof.needs_auto_rewrite()
+        };
+        if needs_rewrite {
+            println!("INFO: starting automatic rewriting of AOF");
+            if let Err(err) = start_background_rewrite(server.clone()) {
+                eprintln!("ERROR: failed to start automatic AOF rewrite: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::command::Command;
+    use crate::parser::rdb::RdbValue;
+    use crate::parser::resp::Token;
+    use crate::persistence::tests::{open_server, test_dir};
+    use crate::replication::replica_manager::tests::connected_pair;
+    use crate::storage::value::Value;
+
+    const APPENDONLY: &[&str] = &["--appendonly", "yes"];
+    const WITHOUT_PREAMBLE: &[&str] = &["--appendonly", "yes", "--aof-use-rdb-preamble", "no"];
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        Token::Array(
+            args.iter()
+                .map(|arg| Token::BulkString(arg.as_bytes().to_vec()))
+                .collect(),
+        )
+        .serialize()
+    }
+
+    /// Runs writes the way a client's connection would
+    fn client_writes(server: &Arc<Server>, commands: &[&[&str]]) {
+        let (local, _remote) = connected_pair();
+        let mut handler = CommandHandler::new(local, server.clone(), None);
+        for args in commands {
+            let raw = command(args);
+            let result = parse_command(&raw).unwrap();
+            handler.handle_command(&result.command, &raw).unwrap();
+        }
+    }
+
+    fn keys(server: &Server) -> Vec<Vec<u8>> {
+        let mut keys = snapshot_entries(server)
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    fn incr_path(dir: &Path, seq: u64) -> PathBuf {
+        dir.join("appendonlydir")
+            .join(format!("appendonly.aof.{seq}.incr.aof"))
+    }
+
+    fn read_manifest(dir: &Path) -> AofManifest {
+        let path = dir.join("appendonlydir").join("appendonly.aof.manifest");
+        AofManifest::parse(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// Names of the files in the directory of the append-only file
+    fn aof_files(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir.join("appendonlydir"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn names(manifest: &AofManifest) -> Vec<&str> {
+        manifest.files().map(|file| file.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_dataset_commands_replay() {
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: b"foo".to_vec(),
+                value: RdbValue::String(b"bar".to_vec()),
+                expire_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: b"baz".to_vec(),
+                value: RdbValue::String(b"qux".to_vec()),
+                expire_at_ms: Some(u64::MAX / 2),
+            },
+        ];
+        let commands = dataset_commands(&entries);
+
+        let first = parse_command(&commands).unwrap();
+        assert_eq!(
+            first.command,
+            Command::Set {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+                expiry: None,
+            }
+        );
+        let second = parse_command(&commands[first.len..]).unwrap();
+        assert!(matches!(
+            second.command,
+            Command::Set { key, expiry: Some(_), .. } if key == b"baz"
+        ));
+        assert_eq!(first.len + second.len, commands.len());
+
+        // a truncated last command is told apart from a broken one
+        assert_eq!(
+            parse_command(&commands[..commands.len() - 1]).unwrap().len,
+            first.len
+        );
+        assert!(matches!(
+            parse_command(&commands[first.len..commands.len() - 1]),
+            Err(ParseError::Incomplete)
+        ));
+    }
+
+    #[test]
+    fn test_load_drops_truncated_last_command() {
+        let dir = test_dir("aof-truncated");
+        let server = open_server(&dir, APPENDONLY);
+        client_writes(&server, &[&["SET", "a", "1"], &["RPUSH", "list", "x", "y"]]);
+        drop(server);
+        let path = incr_path(&dir, 1);
+        let complete_len = fs::metadata(&path).unwrap().len();
+        let cut_short = command(&["SET", "b", "2"]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&cut_short[..cut_short.len() - 3])
+            .unwrap();
+
+        let server = open_server(&dir, APPENDONLY);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"list".to_vec()]);
+        assert_eq!(
+            server.get(b"list"),
+            Some(Value::List(vec![b"x".to_vec(), b"y".to_vec()].into()))
+        );
+        // the prefix is kept as is, replaying it logs nothing again
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+        assert_eq!(
+            server.aof.as_ref().unwrap().lock().unwrap().current_size(),
+            complete_len
+        );
+
+        // new writes follow the last complete command
+        client_writes(&server, &[&["SET", "c", "3"]]);
+        drop(server);
+        let server = open_server(&dir, APPENDONLY);
+        load_aof_files(&server).unwrap();
+        assert_eq!(
+            keys(&server),
+            [b"a".to_vec(), b"c".to_vec(), b"list".to_vec()]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_fails_on_corrupt_command() {
+        let dir = test_dir("aof-corrupt");
+        let server = open_server(&dir, APPENDONLY);
+        client_writes(&server, &[&["SET", "a", "1"]]);
+        drop(server);
+        let path = incr_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"*2\r\n:not a bulk string\r\n").unwrap();
+        file.write_all(&command(&["SET", "b", "2"])).unwrap();
+        drop(file);
+
+        let server = open_server(&dir, APPENDONLY);
+        let err = load_aof_files(&server).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_switches_to_new_base() {
+        let dir = test_dir("aof-rewrite");
+        let server = open_server(&dir, WITHOUT_PREAMBLE);
+        client_writes(&server, &[&["SET", "a", "1"], &["SET", "a", "2"]]);
+        rewrite(&server).unwrap();
+
+        let manifest = read_manifest(&dir);
+        assert_eq!(
+            names(&manifest),
+            ["appendonly.aof.1.base.aof", "appendonly.aof.2.incr.aof"]
+        );
+        // the replaced incremental file is gone, the base holds the dataset only
+        assert_eq!(aof_files(&dir), {
+            let mut files = names(&manifest);
+            files.push("appendonly.aof.manifest");
+            files
+        });
+        let base = fs::read(dir.join("appendonlydir/appendonly.aof.1.base.aof")).unwrap();
+        assert_eq!(base, command(&["SET", "a", "2"]));
+
+        client_writes(&server, &[&["SET", "b", "3"]]);
+        rewrite(&server).unwrap();
+        assert_eq!(
+            names(&read_manifest(&dir)),
+            ["appendonly.aof.2.base.aof", "appendonly.aof.3.incr.aof"]
+        );
+        client_writes(&server, &[&["SET", "c", "4"]]);
+        drop(server);
+
+        let server = open_server(&dir, WITHOUT_PREAMBLE);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(server.get(b"a"), Some(Value::String(b"2".to_vec())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_base_with_rdb_preamble() {
+        let dir = test_dir("aof-preamble");
+        let server = open_server(&dir, APPENDONLY);
+        client_writes(
+            &server,
+            &[&["SET", "a", "1", "PX", "100000"], &["RPUSH", "list", "x"]],
+        );
+        rewrite(&server).unwrap();
+        client_writes(&server, &[&["RPUSH", "list", "y"]]);
+        drop(server);
+
+        let manifest = read_manifest(&dir);
+        let base = manifest.base.as_ref().unwrap();
+        assert_eq!(base.name, "appendonly.aof.1.base.rdb");
+        let contents = fs::read(dir.join("appendonlydir").join(&base.name)).unwrap();
+        assert!(contents.starts_with(RDB_MAGIC));
+
+        let server = open_server(&dir, APPENDONLY);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"list".to_vec()]);
+        assert_eq!(
+            server.get(b"list"),
+            Some(Value::List(vec![b"x".to_vec(), b"y".to_vec()].into()))
+        );
+        let entries = snapshot_entries(&server);
+        let a = entries.iter().find(|entry| entry.key == b"a").unwrap();
+        assert!(a.expire_at_ms.is_some_and(|at| at > unix_time_ms()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_superseded_rewrite_is_dropped() {
+        let dir = test_dir("aof-superseded");
+        let server = open_server(&dir, WITHOUT_PREAMBLE);
+        client_writes(&server, &[&["SET", "a", "1"]]);
+        let (first, first_entries) = begin_rewrite(&server).unwrap();
+        client_writes(&server, &[&["SET", "b", "2"]]);
+        let (second, second_entries) = begin_rewrite(&server).unwrap();
+
+        finish_rewrite(&server, &first, &first_entries).unwrap();
+        assert!(!first.path.exists());
+        assert_eq!(
+            names(&read_manifest(&dir)),
+            [
+                "appendonly.aof.1.incr.aof",
+                "appendonly.aof.2.incr.aof",
+                "appendonly.aof.3.incr.aof"
+            ]
+        );
+
+        finish_rewrite(&server, &second, &second_entries).unwrap();
+        assert_eq!(
+            names(&read_manifest(&dir)),
+            ["appendonly.aof.2.base.aof", "appendonly.aof.3.incr.aof"]
+        );
+        assert!(!incr_path(&dir, 1).exists() && !incr_path(&dir, 2).exists());
+        drop(server);
+
+        let server = open_server(&dir, WITHOUT_PREAMBLE);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"b".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_adopts_legacy_aof() {
+        let dir = test_dir("aof-legacy");
+        let legacy = [command(&["SET", "a", "1"]), command(&["SET", "b", "2"])].concat();
+        fs::write(dir.join("appendonly.aof"), &legacy).unwrap();
+
+        let server = open_server(&dir, APPENDONLY);
+        assert!(!dir.join("appendonly.aof").exists());
+        let manifest = read_manifest(&dir);
+        assert_eq!(
+            manifest.base,
+            Some(AofFileInfo {
+                name: "appendonly.aof".to_string(),
+                seq: 1,
+                file_type: AofFileType::Base,
+            })
+        );
+        assert_eq!(
+            names(&manifest),
+            ["appendonly.aof", "appendonly.aof.1.incr.aof"]
+        );
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"b".to_vec()]);
+
+        // the next rewrite does not reuse the sequence of the adopted base
+        client_writes(&server, &[&["SET", "c", "3"]]);
+        rewrite(&server).unwrap();
+        assert_eq!(
+            names(&read_manifest(&dir)),
+            ["appendonly.aof.2.base.rdb", "appendonly.aof.2.incr.aof"]
+        );
+        assert!(!dir.join("appendonlydir/appendonly.aof").exists());
+        drop(server);
+
+        let server = open_server(&dir, APPENDONLY);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_writes_during_background_rewrite() {
+        let dir = test_dir("aof-background");
+        let server = open_server(&dir, APPENDONLY);
+        client_writes(&server, &[&["SET", "before", "1"]]);
+
+        // writes between the snapshot and the new base go to the new incremental file
+        let (target, entries) = begin_rewrite(&server).unwrap();
+        client_writes(&server, &[&["SET", "during", "1"], &["DEL", "before"]]);
+        finish_rewrite(&server, &target, &entries).unwrap();
+
+        let writer = {
+            let server = server.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let key = format!("key{i}");
+                    client_writes(&server, &[&["SET", &key, "1"]]);
+                }
+            })
+        };
+        assert!(start_background_rewrite(server.clone()).unwrap());
+        writer.join().unwrap();
+        while server
+            .aof
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .rewrite_in_progress
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(server.aof.as_ref().unwrap().lock().unwrap().last_rewrite_ok);
+        let expected = keys(&server);
+        assert_eq!(expected.len(), 201);
+        assert_eq!(
+            names(&read_manifest(&dir)),
+            ["appendonly.aof.2.base.rdb", "appendonly.aof.3.incr.aof"]
+        );
+        drop(server);
+
+        let server = open_server(&dir, APPENDONLY);
+        load_aof_files(&server).unwrap();
+        assert_eq!(keys(&server), expected);
+        assert!(server.get(b"before").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
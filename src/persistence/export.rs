@@ -0,0 +1,509 @@
+use std::fmt::Write;
+
+use crate::parser::rdb::{RdbEntry, RdbStream, RdbValue, StreamId};
+
+/// Elements per command when recreating a collection, like the AOF rewrite of Redis
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// Serializes a command as a RESP array of bulk strings. Empty arguments are written
+/// as empty strings rather than the null bulk string `Token` uses for them.
+pub fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut result = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        result.extend(format!("${}\r\n", arg.len()).as_bytes());
+        result.extend(*arg);
+        result.extend(b"\r\n");
+    }
+    result
+}
+
+/// A command adding `items` to `key`, split into several when there are many of them
+fn batched_commands(name: &[u8], key: &[u8], items: &[Vec<u8>], per_item: usize) -> Vec<u8> {
+    items
+        .chunks(ITEMS_PER_COMMAND * per_item)
+        .flat_map(|chunk| {
+            let args = [name, key]
+                .into_iter()
+                .chain(chunk.iter().map(Vec::as_slice))
+                .collect::<Vec<_>>();
+            command(&args)
+        })
+        .collect()
+}
+
+fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        if score > 0.0 { "+inf" } else { "-inf" }.to_string()
+    } else {
+        score.to_string()
+    }
+}
+
+/// Recreates a stream along with its consumer groups the way the AOF rewrite of Redis
+/// does: XADD for every entry, XSETID for its metadata, then the groups with their
+/// consumers and the entries pending for them
+fn stream_commands(key: &[u8], stream: &RdbStream) -> Vec<u8> {
+    let mut commands = Vec::new();
+    for entry in &stream.entries {
+        let id = entry.id.to_string();
+        let mut args = vec![&b"XADD"[..], key, id.as_bytes()];
+        for (field, value) in &entry.fields {
+            args.push(field);
+            args.push(value);
+        }
+        commands.extend(command(&args));
+    }
+    if stream.entries.is_empty() {
+        // an empty stream still exists, which takes adding an entry and trimming it
+        let id = stream.last_id.to_string();
+        commands.extend(command(&[
+            b"XADD",
+            key,
+            b"MAXLEN",
+            b"0",
+            id.as_bytes(),
+            b"x",
+            b"y",
+        ]));
+    }
+    let (last_id, entries_added, max_deleted) = (
+        stream.last_id.to_string(),
+        stream.entries_added.to_string(),
+        stream.max_deleted_entry_id.to_string(),
+    );
+    commands.extend(command(&[
+        b"XSETID",
+        key,
+        last_id.as_bytes(),
+        b"ENTRIESADDED",
+        entries_added.as_bytes(),
+        b"MAXDELETEDID",
+        max_deleted.as_bytes(),
+    ]));
+
+    for group in &stream.groups {
+        let last_id = group.last_id.to_string();
+        let mut args = vec![
+            &b"XGROUP"[..],
+            b"CREATE",
+            key,
+            &group.name,
+            last_id.as_bytes(),
+        ];
+        let entries_read = group.entries_read.map(|read| read.to_string());
+        if let Some(entries_read) = &entries_read {
+            args.extend([&b"ENTRIESREAD"[..], entries_read.as_bytes()]);
+        }
+        commands.extend(command(&args));
+
+        for consumer in &group.consumers {
+            if consumer.pending.is_empty() {
+                commands.extend(command(&[
+                    b"XGROUP",
+                    b"CREATECONSUMER",
+                    key,
+                    &group.name,
+                    &consumer.name,
+                ]));
+            }
+            for id in &consumer.pending {
+                let Some(pending) = group.pending.iter().find(|pending| pending.id == *id) else {
+                    continue;
+                };
+                let (id, time, count) = (
+                    id.to_string(),
+                    pending.delivery_time_ms.to_string(),
+                    pending.delivery_count.to_string(),
+                );
+                commands.extend(command(&[
+                    b"XCLAIM",
+                    key,
+                    &group.name,
+                    &consumer.name,
+                    b"0",
+                    id.as_bytes(),
+                    b"TIME",
+                    time.as_bytes(),
+                    b"RETRYCOUNT",
+                    count.as_bytes(),
+                    b"JUSTID",
+                    b"FORCE",
+                ]));
+            }
+        }
+    }
+    commands
+}
+
+/// The commands recreating an entry, as RESP. Strings carry their expiry with PXAT as
+/// the AOF does, other types get a PEXPIREAT. Module values cannot be recreated
+/// without the module and give no commands.
+pub fn entry_commands(entry: &RdbEntry) -> Vec<u8> {
+    let key = entry.key.as_slice();
+    let expire_at_ms = entry.expire_at_ms.map(|ms| ms.to_string());
+    let mut commands = match &entry.value {
+        RdbValue::String(value) => {
+            let mut args = vec![&b"SET"[..], key, value];
+            if let Some(expire_at_ms) = &expire_at_ms {
+                args.extend([&b"PXAT"[..], expire_at_ms.as_bytes()]);
+            }
+            return command(&args);
+        }
+        RdbValue::List(elements) => batched_commands(b"RPUSH", key, elements, 1),
+        RdbValue::Set(members) => batched_commands(b"SADD", key, members, 1),
+        RdbValue::SortedSet(members) => {
+            let items = members
+                .iter()
+                .flat_map(|(member, score)| [format_score(*score).into_bytes(), member.clone()])
+                .collect::<Vec<_>>();
+            batched_commands(b"ZADD", key, &items, 2)
+        }
+        RdbValue::Hash(fields) => {
+            let items = fields
+                .iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()])
+                .collect::<Vec<_>>();
+            batched_commands(b"HSET", key, &items, 2)
+        }
+        RdbValue::Stream(stream) => stream_commands(key, stream),
+        RdbValue::Module { .. } => return Vec::new(),
+    };
+    if let Some(expire_at_ms) = &expire_at_ms {
+        commands.extend(command(&[b"PEXPIREAT", key, expire_at_ms.as_bytes()]));
+    }
+    commands
+}
+
+/// Writes bytes as a JSON string. Bytes which are not valid UTF-8 are written as the
+/// escape of the code point of the same value, `\u0080` to `\u00ff`.
+pub fn json_string(bytes: &[u8]) -> String {
+    let mut json = String::from("\"");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => json.push_str("\\\""),
+                '\\' => json.push_str("\\\\"),
+                '\n' => json.push_str("\\n"),
+                '\r' => json.push_str("\\r"),
+                '\t' => json.push_str("\\t"),
+                c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+                c => json.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(json, "\\u{byte:04x}").unwrap();
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// JSON has no infinity, so infinite scores are written as strings
+fn json_score(score: f64) -> String {
+    if score.is_finite() {
+        score.to_string()
+    } else {
+        json_string(format_score(score).as_bytes())
+    }
+}
+
+fn json_array<T>(items: &[T], item_json: impl Fn(&T) -> String) -> String {
+    let items = items.iter().map(item_json).collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+fn json_object<'a>(fields: impl Iterator<Item = (&'a [u8], String)>) -> String {
+    let fields = fields
+        .map(|(name, value)| format!("{}:{value}", json_string(name)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_id(id: &StreamId) -> String {
+    json_string(id.to_string().as_bytes())
+}
+
+fn json_optional(value: Option<u64>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+fn stream_json(stream: &RdbStream) -> String {
+    let entries = json_array(&stream.entries, |entry| {
+        let fields = entry
+            .fields
+            .iter()
+            .map(|(field, value)| (field.as_slice(), json_string(value)));
+        format!(
+            "{{\"id\":{},\"fields\":{}}}",
+            json_id(&entry.id),
+            json_object(fields)
+        )
+    });
+    let groups = json_array(&stream.groups, |group| {
+        let pending = json_array(&group.pending, |pending| {
+            format!(
+                "{{\"id\":{},\"delivery_time_ms\":{},\"delivery_count\":{}}}",
+                json_id(&pending.id),
+                pending.delivery_time_ms,
+                pending.delivery_count
+            )
+        });
+        let consumers = json_array(&group.consumers, |consumer| {
+            format!(
+                "{{\"name\":{},\"seen_time_ms\":{},\"active_time_ms\":{},\"pending\":{}}}",
+                json_string(&consumer.name),
+                consumer.seen_time_ms,
+                consumer.active_time_ms,
+                json_array(&consumer.pending, json_id)
+            )
+        });
+        format!(
+            "{{\"name\":{},\"last_id\":{},\"entries_read\":{},\"pending\":{pending},\
+             \"consumers\":{consumers}}}",
+            json_string(&group.name),
+            json_id(&group.last_id),
+            json_optional(group.entries_read)
+        )
+    });
+    format!(
+        "{{\"entries\":{entries},\"length\":{},\"last_id\":{},\"first_id\":{},\
+         \"max_deleted_entry_id\":{},\"entries_added\":{},\"groups\":{groups}}}",
+        stream.length,
+        json_id(&stream.last_id),
+        json_id(&stream.first_id),
+        json_id(&stream.max_deleted_entry_id),
+        stream.entries_added
+    )
+}
+
+/// A JSON object describing an entry: its database, key, type, expiry and value.
+/// Hashes and sorted sets are objects, lists and sets arrays, module values null.
+pub fn entry_json(entry: &RdbEntry) -> String {
+    let value = match &entry.value {
+        RdbValue::String(value) => json_string(value),
+        RdbValue::List(elements) | RdbValue::Set(elements) => {
+            json_array(elements, |element| json_string(element))
+        }
+        RdbValue::SortedSet(members) => json_object(
+            members
+                .iter()
+                .map(|(member, score)| (member.as_slice(), json_score(*score))),
+        ),
+        RdbValue::Hash(fields) => json_object(
+            fields
+                .iter()
+                .map(|(field, value)| (field.as_slice(), json_string(value))),
+        ),
+        RdbValue::Stream(stream) => stream_json(stream),
+        RdbValue::Module { .. } => "null".to_string(),
+    };
+    format!(
+        "{{\"db\":{},\"key\":{},\"type\":{},\"expire_at_ms\":{},\"value\":{value}}}",
+        entry.db,
+        json_string(&entry.key),
+        json_string(entry.value.type_name().as_bytes()),
+        json_optional(entry.expire_at_ms)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::command::{parse_command, Command};
+    use crate::parser::rdb::{ConsumerGroup, PendingEntry, StreamConsumer, StreamEntry};
+    use crate::parser::resp::parse_buffer;
+
+    fn entry(key: &[u8], value: RdbValue, expire_at_ms: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            db: 0,
+            key: key.to_vec(),
+            value,
+            expire_at_ms,
+        }
+    }
+
+    /// Splits RESP commands back into their arguments
+    fn parse_commands(mut data: &[u8]) -> Vec<Vec<Vec<u8>>> {
+        let mut commands = Vec::new();
+        while !data.is_empty() {
+            let result = parse_buffer(data).unwrap();
+            commands.push(
+                result
+                    .tokens
+                    .iter()
+                    .map(|arg| arg.get_bulk_string_data().unwrap().clone())
+                    .collect(),
+            );
+            data = &data[result.len..];
+        }
+        commands
+    }
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_string_commands() {
+        let commands = entry_commands(&entry(b"foo", RdbValue::String(Vec::new()), Some(42)));
+        let parsed = parse_command(&commands).unwrap();
+        assert_eq!(parsed.len, commands.len());
+        assert!(matches!(
+            parsed.command,
+            Command::Set { key, value, expiry: Some(_) } if key == b"foo" && value.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_collection_commands() {
+        let elements = (0..100).map(|i| i.to_string().into_bytes()).collect();
+        let commands = parse_commands(&entry_commands(&entry(
+            b"list",
+            RdbValue::List(elements),
+            Some(1_700_000_000_000),
+        )));
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0].len(), 2 + 64);
+        assert_eq!(commands[1][..3], args(&["RPUSH", "list", "64"]));
+        assert_eq!(commands[2], args(&["PEXPIREAT", "list", "1700000000000"]));
+
+        let commands = parse_commands(&entry_commands(&entry(
+            b"zset",
+            RdbValue::SortedSet(vec![
+                (b"a".to_vec(), 1.5),
+                (b"b".to_vec(), f64::NEG_INFINITY),
+            ]),
+            None,
+        )));
+        assert_eq!(commands, [args(&["ZADD", "zset", "1.5", "a", "-inf", "b"])]);
+
+        let commands = parse_commands(&entry_commands(&entry(
+            b"hash",
+            RdbValue::Hash(vec![(b"f".to_vec(), b"v".to_vec())]),
+            None,
+        )));
+        assert_eq!(commands, [args(&["HSET", "hash", "f", "v"])]);
+
+        let module = RdbValue::Module {
+            name: "mymodule1".to_string(),
+            encoding_version: 1,
+        };
+        assert!(entry_commands(&entry(b"module", module, None)).is_empty());
+    }
+
+    #[test]
+    fn test_stream_commands() {
+        let id = StreamId { ms: 5, seq: 1 };
+        let stream = RdbStream {
+            entries: vec![StreamEntry {
+                id,
+                fields: vec![(b"f".to_vec(), b"v".to_vec())],
+            }],
+            length: 1,
+            last_id: id,
+            first_id: id,
+            max_deleted_entry_id: StreamId { ms: 3, seq: 0 },
+            entries_added: 2,
+            groups: vec![ConsumerGroup {
+                name: b"g".to_vec(),
+                last_id: id,
+                entries_read: Some(1),
+                pending: vec![PendingEntry {
+                    id,
+                    delivery_time_ms: 7,
+                    delivery_count: 2,
+                }],
+                consumers: vec![
+                    StreamConsumer {
+                        name: b"alice".to_vec(),
+                        seen_time_ms: 7,
+                        active_time_ms: 7,
+                        pending: vec![id],
+                    },
+                    StreamConsumer {
+                        name: b"bob".to_vec(),
+                        seen_time_ms: 8,
+                        active_time_ms: 8,
+                        pending: Vec::new(),
+                    },
+                ],
+            }],
+        };
+        let commands = parse_commands(&entry_commands(&entry(
+            b"s",
+            RdbValue::Stream(stream),
+            None,
+        )));
+        assert_eq!(
+            commands,
+            [
+                args(&["XADD", "s", "5-1", "f", "v"]),
+                args(&[
+                    "XSETID",
+                    "s",
+                    "5-1",
+                    "ENTRIESADDED",
+                    "2",
+                    "MAXDELETEDID",
+                    "3-0"
+                ]),
+                args(&["XGROUP", "CREATE", "s", "g", "5-1", "ENTRIESREAD", "1"]),
+                args(&[
+                    "XCLAIM",
+                    "s",
+                    "g",
+                    "alice",
+                    "0",
+                    "5-1",
+                    "TIME",
+                    "7",
+                    "RETRYCOUNT",
+                    "2",
+                    "JUSTID",
+                    "FORCE"
+                ]),
+                args(&["XGROUP", "CREATECONSUMER", "s", "g", "bob"]),
+            ]
+        );
+
+        let commands = parse_commands(&entry_commands(&entry(
+            b"s",
+            RdbValue::Stream(RdbStream::default()),
+            None,
+        )));
+        assert_eq!(
+            commands[0],
+            args(&["XADD", "s", "MAXLEN", "0", "0-0", "x", "y"])
+        );
+    }
+
+    #[test]
+    fn test_entry_json() {
+        let json = entry_json(&entry(
+            b"k\"\n",
+            RdbValue::String(b"\xffa\x01".to_vec()),
+            Some(9),
+        ));
+        assert_eq!(
+            json,
+            r#"{"db":0,"key":"k\"\n","type":"string","expire_at_ms":9,"value":"\u00ffa\u0001"}"#
+        );
+
+        let json = entry_json(&entry(
+            b"z",
+            RdbValue::SortedSet(vec![(b"a".to_vec(), 2.0), (b"b".to_vec(), f64::INFINITY)]),
+            None,
+        ));
+        assert_eq!(
+            json,
+            r#"{"db":0,"key":"z","type":"zset","expire_at_ms":null,"value":{"a":2,"b":"+inf"}}"#
+        );
+
+        let json = entry_json(&entry(
+            b"l",
+            RdbValue::List(vec![b"x".to_vec(), b"y".to_vec()]),
+            None,
+        ));
+        assert!(json.ends_with(r#""type":"list","expire_at_ms":null,"value":["x","y"]}"#));
+    }
+}
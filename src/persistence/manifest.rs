@@ -0,0 +1,181 @@
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AofFileType {
+    /// Snapshot of the dataset the incremental files apply on top of
+    Base,
+    /// Writes made since the base was taken
+    Incr,
+}
+
+impl AofFileType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AofFileType::Base => "b",
+            AofFileType::Incr => "i",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AofFileInfo {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+/// Lists the files making up a multi-part append-only file, one per line:
+/// `file <name> seq <seq> type <b|i>`. The dataset is the base followed by the
+/// incremental files in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AofManifest {
+    pub base: Option<AofFileInfo>,
+    pub incrs: Vec<AofFileInfo>,
+}
+
+fn invalid_manifest(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid AOF manifest: {reason}"),
+    )
+}
+
+impl AofManifest {
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut manifest = AofManifest::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+            for pair in fields.chunks(2) {
+                match pair {
+                    ["file", value] => name = Some(value.to_string()),
+                    ["seq", value] => {
+                        seq = Some(value.parse().map_err(|_| {
+                            invalid_manifest(format!("bad sequence in line {line:?}"))
+                        })?)
+                    }
+                    ["type", "b"] => file_type = Some(AofFileType::Base),
+                    ["type", "i"] => file_type = Some(AofFileType::Incr),
+                    // files which are no longer part of the dataset
+                    ["type", "h"] => file_type = None,
+                    // fields of newer versions are skipped
+                    [_, _] => {}
+                    _ => return Err(invalid_manifest(format!("odd line {line:?}"))),
+                }
+            }
+            let (Some(name), Some(seq)) = (name, seq) else {
+                return Err(invalid_manifest(format!("incomplete line {line:?}")));
+            };
+            match file_type {
+                Some(AofFileType::Base) if manifest.base.is_some() => {
+                    return Err(invalid_manifest("more than one base file".to_string()));
+                }
+                Some(AofFileType::Base) => {
+                    manifest.base = Some(AofFileInfo {
+                        name,
+                        seq,
+                        file_type: AofFileType::Base,
+                    })
+                }
+                Some(AofFileType::Incr) => {
+                    if manifest.incrs.last().is_some_and(|last| last.seq >= seq) {
+                        return Err(invalid_manifest(format!("out of order line {line:?}")));
+                    }
+                    manifest.incrs.push(AofFileInfo {
+                        name,
+                        seq,
+                        file_type: AofFileType::Incr,
+                    })
+                }
+                None => {}
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn serialize(&self) -> String {
+        self.base
+            .iter()
+            .chain(&self.incrs)
+            .map(|file| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    file.name,
+                    file.seq,
+                    file.file_type.as_str()
+                )
+            })
+            .collect()
+    }
+
+    /// Name of every file in the manifest, base first
+    pub fn files(&self) -> impl Iterator<Item = &AofFileInfo> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    /// The next incremental file, `<prefix>.<seq>.incr.aof`
+    pub fn next_incr(&self, prefix: &str) -> AofFileInfo {
+        let seq = self.incrs.last().map_or(1, |last| last.seq + 1);
+        AofFileInfo {
+            name: format!("{prefix}.{seq}.incr.aof"),
+            seq,
+            file_type: AofFileType::Incr,
+        }
+    }
+}
+
+/// A base file named `<prefix>.<seq>.base.<rdb|aof>`
+pub fn base_file(prefix: &str, seq: u64, rdb_preamble: bool) -> AofFileInfo {
+    let extension = if rdb_preamble { "rdb" } else { "aof" };
+    AofFileInfo {
+        name: format!("{prefix}.{seq}.base.{extension}"),
+        seq,
+        file_type: AofFileType::Base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let mut manifest = AofManifest {
+            base: Some(base_file("appendonly.aof", 2, true)),
+            incrs: Vec::new(),
+        };
+        manifest.incrs.push(manifest.next_incr("appendonly.aof"));
+        manifest.incrs.push(manifest.next_incr("appendonly.aof"));
+
+        let serialized = manifest.serialize();
+        assert_eq!(
+            serialized,
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(AofManifest::parse(&serialized).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = AofManifest::parse(
+            "# written by an older version\n\
+             file appendonly.aof seq 1 type b\n\
+             file appendonly.aof.1.base.rdb seq 1 type h\n\
+             file appendonly.aof.3.incr.aof seq 3 type i startoffset 0\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.base.unwrap().name, "appendonly.aof");
+        assert_eq!(manifest.incrs.len(), 1);
+        assert_eq!(manifest.incrs[0].seq, 3);
+
+        assert!(AofManifest::parse("file a seq 1 type b\nfile b seq 2 type b\n").is_err());
+        assert!(AofManifest::parse("file a seq 2 type i\nfile b seq 1 type i\n").is_err());
+        assert!(AofManifest::parse("file a seq x type i\n").is_err());
+        assert!(AofManifest::parse("file a type i\n").is_err());
+    }
+}
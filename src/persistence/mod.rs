@@ -0,0 +1,280 @@
+pub mod aof;
+pub mod export;
+pub mod manifest;
+pub mod rdb;
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::{unix_time_ms, CRLF},
+    server::data::Server,
+};
+
+/// How long a failed background save keeps the save points from triggering another one
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Default of Redis: after an hour if anything changed, after 5 minutes if at least 100
+/// keys changed and after a minute if at least 10000 keys changed
+pub const DEFAULT_SAVE_POINTS: &str = "3600 1 300 100 60 10000";
+
+/// A `save <seconds> <changes>` rule: the dataset is saved once at least `changes` writes
+/// were made and `seconds` passed since the last save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses `<seconds> <changes>` pairs, an empty string standing for no save points
+pub fn parse_save_points(value: &str) -> Result<Vec<SavePoint>, String> {
+    let fields = value.split_whitespace().collect::<Vec<_>>();
+    if !fields.len().is_multiple_of(2) {
+        return Err(format!("invalid save points {value:?}"));
+    }
+    fields
+        .chunks(2)
+        .map(|pair| match (pair[0].parse(), pair[1].parse()) {
+            (Ok(seconds), Ok(changes)) => Ok(SavePoint { seconds, changes }),
+            _ => Err(format!("invalid save point {:?}", pair.join(" "))),
+        })
+        .collect()
+}
+
+pub fn format_save_points(save_points: &[SavePoint]) -> String {
+    save_points
+        .iter()
+        .map(|point| format!("{} {}", point.seconds, point.changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub struct SaveState {
+    /// Unix time in seconds of the last successful save, or of startup
+    pub last_save_time: u64,
+    pub bgsave_in_progress: bool,
+    pub last_bgsave_ok: bool,
+    /// When the last background save started
+    pub last_bgsave_try: Option<Instant>,
+    pub save_points: Vec<SavePoint>,
+    /// Writes made since the last successful save
+    pub dirty: u64,
+    /// Set once the final save before exiting is under way, no other save may start
+    pub shutting_down: bool,
+}
+
+impl Default for SaveState {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl SaveState {
+    pub fn new(save_points: Vec<SavePoint>) -> Self {
+        Self {
+            last_save_time: unix_time_ms() / 1000,
+            bgsave_in_progress: false,
+            last_bgsave_ok: true,
+            last_bgsave_try: None,
+            save_points,
+            dirty: 0,
+            shutting_down: false,
+        }
+    }
+
+    /// The save point calling for a background save at `now_secs`, if any. A failed save
+    /// is only retried after a delay, not to keep failing in a loop.
+    pub fn due_save_point(&self, now_secs: u64) -> Option<SavePoint> {
+        if self.bgsave_in_progress || self.shutting_down {
+            return None;
+        }
+        if !self.last_bgsave_ok
+            && self
+                .last_bgsave_try
+                .is_some_and(|time| time.elapsed() < BGSAVE_RETRY_DELAY)
+        {
+            return None;
+        }
+        let elapsed = now_secs.saturating_sub(self.last_save_time);
+        self.save_points
+            .iter()
+            .find(|point| self.dirty >= point.changes && elapsed >= point.seconds)
+            .copied()
+    }
+}
+
+/// The persistence section of INFO
+pub fn info(server: &Server) -> Vec<u8> {
+    let mut info = {
+        let state = server.save_state.lock().unwrap();
+        format!(
+            "loading:0{CRLF}rdb_changes_since_last_save:{}{CRLF}rdb_bgsave_in_progress:{}{CRLF}\
+             rdb_last_save_time:{}{CRLF}rdb_last_bgsave_status:{}{CRLF}aof_enabled:{}",
+            state.dirty,
+            state.bgsave_in_progress as u8,
+            state.last_save_time,
+            if state.last_bgsave_ok { "ok" } else { "err" },
+            server.aof.is_some() as u8,
+        )
+    };
+    if let Some(aof) = &server.aof {
+        let aof = aof.lock().unwrap();
+        info.push_str(&format!(
+            "{CRLF}aof_rewrite_in_progress:{}{CRLF}aof_last_bgrewrite_status:{}{CRLF}\
+             aof_last_write_status:{}{CRLF}aof_current_size:{}{CRLF}aof_base_size:{}",
+            aof.rewrite_in_progress as u8,
+            if aof.last_rewrite_ok { "ok" } else { "err" },
+            if aof.write_error.is_none() {
+                "ok"
+            } else {
+                "err"
+            },
+            aof.current_size(),
+            aof.base_size(),
+        ));
+    }
+    info.into_bytes()
+}
+
+/// Persists what has to be before the process exits: whatever the append-only file still
+/// buffers, and a final snapshot if save points are configured or `save` asks for one
+pub fn prepare_shutdown(server: &Server, save: Option<bool>) -> io::Result<()> {
+    println!("INFO: preparing to shut down");
+    if let Some(aof) = &server.aof {
+        aof.lock().unwrap().flush_and_sync()?;
+    }
+
+    let save_points_set = {
+        let mut state = server.save_state.lock().unwrap();
+        state.shutting_down = true;
+        !state.save_points.is_empty()
+    };
+    if server.metadata.rdb_config.is_none() || !save.unwrap_or(save_points_set) {
+        return Ok(());
+    }
+    // no background save starts from now on, one already running completes before ours
+    // so that the final snapshot is the last one written
+    while server.save_state.lock().unwrap().bgsave_in_progress {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    println!("INFO: saving the final RDB snapshot before exiting");
+    let result = rdb::save(server);
+    if result.is_err() {
+        // the server keeps running, background saves included
+        server.save_state.lock().unwrap().shutting_down = false;
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::persistence::aof::AppendOnlyFile;
+    use crate::server::{
+        config::Config,
+        metadata::{MasterInfo, ReplicaInfo, ServerMetadata},
+    };
+    use crate::storage::value::Value;
+
+    /// A fresh directory named after the test
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redis-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A master keeping its files in `dir`, saving to `dump.rdb` and started with the
+    /// given options on top. The append-only file they may enable is opened, not loaded.
+    pub(crate) fn open_server(dir: &Path, args: &[&str]) -> Arc<Server> {
+        let dir = dir.to_str().unwrap();
+        let config = Config::parse_from(
+            ["redis", "--dir", dir, "--dbfilename", "dump.rdb"]
+                .iter()
+                .chain(args),
+        );
+        let metadata = ServerMetadata::generate(&config);
+        let aof = metadata
+            .aof_config
+            .as_ref()
+            .map(|config| AppendOnlyFile::open(config).unwrap());
+        let replica_info = ReplicaInfo::Master(MasterInfo::new());
+        Arc::new(Server::new(metadata, replica_info, None, aof))
+    }
+
+    /// A master started with the given options in a fresh directory named after the test
+    pub(crate) fn test_server(name: &str, args: &[&str]) -> (Arc<Server>, PathBuf) {
+        let dir = test_dir(name);
+        (open_server(&dir, args), dir)
+    }
+
+    #[test]
+    fn test_parse_save_points() {
+        let save_points = parse_save_points(DEFAULT_SAVE_POINTS).unwrap();
+        assert_eq!(save_points.len(), 3);
+        assert_eq!(
+            save_points[1],
+            SavePoint {
+                seconds: 300,
+                changes: 100
+            }
+        );
+        assert_eq!(format_save_points(&save_points), DEFAULT_SAVE_POINTS);
+
+        assert_eq!(parse_save_points("").unwrap(), Vec::new());
+        assert!(parse_save_points("900").is_err());
+        assert!(parse_save_points("900 -1").is_err());
+    }
+
+    #[test]
+    fn test_due_save_point() {
+        let mut state = SaveState::new(parse_save_points("900 1 300 10").unwrap());
+        let now = state.last_save_time;
+        assert_eq!(state.due_save_point(now + 1000), None);
+
+        state.dirty = 5;
+        assert_eq!(state.due_save_point(now + 400), None);
+        assert_eq!(state.due_save_point(now + 900).unwrap().seconds, 900);
+        state.dirty = 10;
+        assert_eq!(state.due_save_point(now + 400).unwrap().seconds, 300);
+
+        state.bgsave_in_progress = true;
+        assert_eq!(state.due_save_point(now + 400), None);
+
+        // a failed save is not retried straight away
+        state.bgsave_in_progress = false;
+        state.last_bgsave_ok = false;
+        state.last_bgsave_try = Some(Instant::now());
+        assert_eq!(state.due_save_point(now + 400), None);
+    }
+
+    #[test]
+    fn test_no_background_save_during_shutdown() {
+        let (server, dir) = test_server("shutdown", &["--save", "1 1"]);
+        server.set(b"foo", Value::String(b"bar".to_vec()), None);
+        server.save_state.lock().unwrap().dirty = 1;
+
+        // a background save already running completes before the final one
+        assert!(rdb::start_background_save(server.clone()));
+        prepare_shutdown(&server, None).unwrap();
+        {
+            let state = server.save_state.lock().unwrap();
+            assert!(!state.bgsave_in_progress);
+            assert_eq!(state.due_save_point(u64::MAX), None);
+        }
+        assert!(!rdb::start_background_save(server.clone()));
+
+        let stats = rdb::load_rdb_file(&server, &dir.join("dump.rdb")).unwrap();
+        assert_eq!(stats.loaded, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::unix_time_ms,
+    parser::rdb::{encode_rdb, RdbDecoder, RdbEntry, RdbError},
+    server::data::Server,
+    storage::value::Value,
+};
+
+/// How often the save points are checked
+const SAVE_CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Numbers the temporary files of the saves, so that no two of them share one
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+pub struct LoadStats {
+    pub loaded: usize,
+    pub expired: usize,
+    /// Keys holding a type other than strings and lists, which the store cannot keep yet
+    pub unsupported: usize,
+    /// Keys of databases other than 0, the only one the store has
+    pub other_db: usize,
+}
+
+impl LoadStats {
+    /// Accounts for the keys which were not loaded, for the logs
+    pub fn skipped(&self) -> String {
+        format!(
+            "{} expired keys, {} keys of unsupported types and {} keys of other databases \
+             skipped",
+            self.expired, self.unsupported, self.other_db
+        )
+    }
+}
+
+/// Converts an absolute RDB expiry into the relative TTL used by the store.
+/// Returns `None` for keys which have already expired.
+fn remaining_ttl(expire_at_ms: Option<u64>, now_ms: u64) -> Option<Option<Duration>> {
+    match expire_at_ms {
+        None => Some(None),
+        Some(expire_at_ms) if expire_at_ms > now_ms => {
+            Some(Some(Duration::from_millis(expire_at_ms - now_ms)))
+        }
+        Some(_) => None,
+    }
+}
+
+pub fn restore_entry(server: &Server, entry: RdbEntry, stats: &mut LoadStats) {
+    if entry.db != 0 {
+        println!(
+            "DEBUG: skipping key {:?} of database {}",
+            entry.key, entry.db
+        );
+        stats.other_db += 1;
+        return;
+    }
+    let value = match Value::try_from(entry.value) {
+        Ok(value) => value,
+        Err(value) => {
+            println!(
+                "DEBUG: skipping key {:?} of unsupported type {}",
+                entry.key,
+                value.type_name()
+            );
+            stats.unsupported += 1;
+            return;
+        }
+    };
+    match remaining_ttl(entry.expire_at_ms, unix_time_ms()) {
+        Some(expiry) => {
+            server.set(&entry.key, value, expiry);
+            stats.loaded += 1;
+        }
+        None => stats.expired += 1,
+    }
+}
+
+pub fn load_rdb_file(server: &Server, path: &Path) -> Result<LoadStats, RdbError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut stats = LoadStats::default();
+    RdbDecoder::new(reader).decode(|entry| restore_entry(server, entry, &mut stats))?;
+    Ok(stats)
+}
+
+/// Replaces the whole dataset with the contents of an RDB stream. The stream is decoded
+/// in full before the current keys are dropped, so a transfer failing halfway through
+/// leaves the dataset untouched.
+pub fn replace_dataset<R: Read>(server: &Server, reader: R) -> Result<LoadStats, RdbError> {
+    let mut entries = Vec::new();
+    RdbDecoder::new(reader).decode(|entry| entries.push(entry))?;
+
+    let mut stats = LoadStats::default();
+    server.store.lock().unwrap().clear();
+    for entry in entries {
+        restore_entry(server, entry, &mut stats);
+    }
+    Ok(stats)
+}
+
+/// Takes a point-in-time copy of the keyspace with expiries as unix milliseconds
+pub fn snapshot_entries(server: &Server) -> Vec<RdbEntry> {
+    let snapshot = server.store.lock().unwrap().snapshot();
+    let now = Instant::now();
+    let now_ms = unix_time_ms();
+
+    snapshot
+        .into_iter()
+        .map(|(key, value, expiry)| RdbEntry {
+            db: 0,
+            key,
+            value: value.into(),
+            expire_at_ms: expiry
+                .map(|ttl| now_ms + ttl.saturating_duration_since(now).as_millis() as u64),
+        })
+        .collect()
+}
+
+pub fn snapshot_rdb(server: &Server) -> Result<Vec<u8>, RdbError> {
+    encode_rdb(&snapshot_entries(server), unix_time_ms() / 1000)
+}
+
+/// Writes the RDB to a temporary file next to `path` and renames it into place,
+/// so readers never observe a partially written snapshot
+pub fn write_rdb_file(rdb: &[u8], path: &Path) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!(
+        "temp-{}-{}.rdb",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(rdb)?;
+        file.sync_all()
+    });
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+
+    fs::rename(&temp_path, path)
+}
+
+fn configured_path(server: &Server) -> io::Result<PathBuf> {
+    server
+        .metadata
+        .rdb_config
+        .as_ref()
+        .map(|config| config.get_path())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no RDB location configured"))
+}
+
+/// Saves the dataset to the configured RDB file, waiting for any save already running
+/// to complete first
+pub fn save(server: &Server) -> io::Result<()> {
+    let path = configured_path(server)?;
+    let _guard = server.save_lock.lock().unwrap();
+    // writes made while the snapshot is taken may or may not be in it, they are counted
+    // as not saved
+    let dirty = server.save_state.lock().unwrap().dirty;
+    let rdb = snapshot_rdb(server).map_err(io::Error::other)?;
+    write_rdb_file(&rdb, &path)?;
+    let mut state = server.save_state.lock().unwrap();
+    state.last_save_time = unix_time_ms() / 1000;
+    state.dirty = state.dirty.saturating_sub(dirty);
+    println!("INFO: DB saved on disk at {path:?}");
+    Ok(())
+}
+
+/// Marks a background save as running and performs it on a separate thread.
+/// Returns false if another background save is already in progress, or if the server
+/// is shutting down.
+pub fn start_background_save(server: Arc<Server>) -> bool {
+    {
+        let mut state = server.save_state.lock().unwrap();
+        if state.bgsave_in_progress || state.shutting_down {
+            return false;
+        }
+        state.bgsave_in_progress = true;
+        state.last_bgsave_try = Some(Instant::now());
+    }
+
+    std::thread::spawn(move || {
+        let result = save(&server);
+        if let Err(err) = &result {
+            eprintln!("ERROR: background save failed with error {err:?}");
+        }
+        let mut state = server.save_state.lock().unwrap();
+        state.bgsave_in_progress = false;
+        state.last_bgsave_ok = result.is_ok();
+    });
+
+    true
+}
+
+/// Starts the thread triggering a background save whenever a save point is reached
+pub fn start(server: Arc<Server>) {
+    if server.metadata.rdb_config.is_none() {
+        return;
+    }
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SAVE_CRON_INTERVAL);
+        let due = server
+            .save_state
+            .lock()
+            .unwrap()
+            .due_save_point(unix_time_ms() / 1000);
+        if let Some(point) = due {
+            println!(
+                "INFO: {} changes in {} seconds. Saving...",
+                point.changes, point.seconds
+            );
+            start_background_save(server.clone());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::rdb::RdbValue;
+    use crate::persistence::tests::test_server;
+
+    #[test]
+    fn test_load_skips_other_databases() {
+        let (server, dir) = test_server("other-databases", &["--save", ""]);
+        let entry = |db, key: &[u8]| RdbEntry {
+            db,
+            key: key.to_vec(),
+            value: RdbValue::String(b"value".to_vec()),
+            expire_at_ms: None,
+        };
+        let rdb = encode_rdb(
+            &[entry(0, b"kept"), entry(1, b"other"), entry(1, b"another")],
+            0,
+        )
+        .unwrap();
+        let path = dir.join("dump.rdb");
+        write_rdb_file(&rdb, &path).unwrap();
+
+        let stats = load_rdb_file(&server, &path).unwrap();
+        assert_eq!((stats.loaded, stats.other_db), (1, 2));
+        assert!(server.get(b"kept").is_some());
+        assert!(server.get(b"other").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_saves_write_whole_files() {
+        let (server, dir) = test_server("concurrent-saves", &["--save", ""]);
+        for i in 0..1000 {
+            let key = format!("key:{i}");
+            server.set(key.as_bytes(), Value::String(vec![b'x'; 100]), None);
+        }
+
+        // SAVE and BGSAVE at the same time, every one of them leaving a valid file
+        assert!(start_background_save(server.clone()));
+        let savers = (0..4)
+            .map(|_| {
+                let server = server.clone();
+                std::thread::spawn(move || save(&server))
+            })
+            .collect::<Vec<_>>();
+        for saver in savers {
+            saver.join().unwrap().unwrap();
+        }
+        while server.save_state.lock().unwrap().bgsave_in_progress {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(server.save_state.lock().unwrap().last_bgsave_ok);
+
+        let stats = load_rdb_file(&server, &dir.join("dump.rdb")).unwrap();
+        assert_eq!(stats.loaded, 1000);
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 1, "temporary files left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+/// Bounded window over the most recent bytes of the replication stream, used to
+/// serve partial resynchronization requests.
+pub struct ReplicationBacklog {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    /// Replication offset just past the last byte in the buffer
+    end_offset: usize,
+}
+
+impl ReplicationBacklog {
+    pub fn new(capacity: usize, end_offset: usize) -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            end_offset,
+        }
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        self.end_offset += data.len();
+
+        let data = if data.len() > self.capacity {
+            &data[data.len() - self.capacity..]
+        } else {
+            data
+        };
+        let overflow = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(data);
+    }
+
+    /// Replication offset of the first byte still held in the backlog
+    pub fn start_offset(&self) -> usize {
+        self.end_offset - self.buffer.len()
+    }
+
+    pub fn end_offset(&self) -> usize {
+        self.end_offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns every byte from `offset` up to the end of the stream, or `None`
+    /// if that part of the stream is no longer (or not yet) in the backlog
+    pub fn read_from(&self, offset: usize) -> Option<Vec<u8>> {
+        if offset < self.start_offset() || offset > self.end_offset {
+            return None;
+        }
+        let skip = offset - self.start_offset();
+        Some(self.buffer.iter().skip(skip).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_from_within_backlog() {
+        let mut backlog = ReplicationBacklog::new(16, 100);
+        backlog.append(b"hello");
+        backlog.append(b"world");
+        assert_eq!(backlog.start_offset(), 100);
+        assert_eq!(backlog.end_offset(), 110);
+        assert_eq!(backlog.read_from(100), Some(b"helloworld".to_vec()));
+        assert_eq!(backlog.read_from(105), Some(b"world".to_vec()));
+        assert_eq!(backlog.read_from(110), Some(Vec::new()));
+        assert_eq!(backlog.read_from(111), None);
+        assert_eq!(backlog.read_from(99), None);
+    }
+
+    #[test]
+    fn test_backlog_wraps_around() {
+        let mut backlog = ReplicationBacklog::new(8, 0);
+        backlog.append(b"abcdef");
+        backlog.append(b"ghij");
+        assert_eq!(backlog.len(), 8);
+        assert_eq!(backlog.start_offset(), 2);
+        assert_eq!(backlog.read_from(2), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.read_from(1), None);
+
+        backlog.append(b"0123456789");
+        assert_eq!(backlog.start_offset(), 12);
+        assert_eq!(backlog.end_offset(), 20);
+        assert_eq!(backlog.read_from(12), Some(b"23456789".to_vec()));
+    }
+}
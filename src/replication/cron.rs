@@ -0,0 +1,122 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    parser::command::{Command, ReplConfCommand},
+    replication::replica_manager::ReplicaManager,
+    server::{
+        data::{LiveData, MasterLinkState, MasterLiveData, Server},
+        metadata::ServerMetadata,
+    },
+};
+
+/// How often the replication cron runs, which is also how often a replica acknowledges
+/// its offset to the master
+const REPLICATION_CRON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drops the replicas which stopped acknowledging
+fn evict_timed_out_replicas(replica_manager: &mut ReplicaManager, metadata: &ServerMetadata) {
+    for addr in replica_manager.evict_timed_out_replicas(metadata.repl_timeout) {
+        println!(
+            "INFO: disconnecting replica {addr}, no ACK received for {:?}",
+            metadata.repl_timeout
+        );
+    }
+}
+
+/// Pings the replicas and drops the ones which stopped acknowledging
+fn master_cron(data: &mut MasterLiveData, metadata: &ServerMetadata, ping_due: bool) {
+    evict_timed_out_replicas(&mut data.replica_manager, metadata);
+
+    // the PING keeps the replicas' heartbeat going while no writes are happening
+    // and stays out of the way of a failover waiting for its target to reach our offset
+    if ping_due && data.failover.is_none() && data.replica_manager.get_connected_replica_count() > 0
+    {
+        if let Some(ping) = Command::Ping.to_resp_token() {
+            data.feed_replication_stream(&ping.serialize());
+        }
+    }
+}
+
+/// Reports our offset to the master, letting it track the lag and keep the link alive.
+/// Our own replicas are kept alive by the master's PINGs we forward to them.
+fn replica_cron(server: &Server) {
+    let (link, offset) = match &mut *server.live_data.lock().unwrap() {
+        LiveData::Slave(data) => {
+            evict_timed_out_replicas(&mut data.replica_manager, &server.metadata);
+            if data.link_state != MasterLinkState::Connected {
+                return;
+            }
+            (data.link.clone(), data.offset)
+        }
+        LiveData::Master(_) => return,
+    };
+    let Some(ack) = Command::ReplConf(ReplConfCommand::Ack(offset)).to_resp_token() else {
+        return;
+    };
+    if let Err(err) = link.send(&ack.serialize()) {
+        eprintln!("ERROR: failed to send ACK to master with error {:?}", &err);
+    }
+}
+
+fn run(server: Arc<Server>) {
+    let mut last_ping = Instant::now();
+
+    loop {
+        std::thread::sleep(REPLICATION_CRON_INTERVAL);
+
+        let ping_due = last_ping.elapsed() >= server.metadata.repl_ping_replica_period;
+        if ping_due {
+            last_ping = Instant::now();
+        }
+
+        let is_master = match &mut *server.live_data.lock().unwrap() {
+            LiveData::Master(data) => {
+                master_cron(data, &server.metadata, ping_due);
+                true
+            }
+            LiveData::Slave(_) => false,
+        };
+        if !is_master {
+            replica_cron(&server);
+        }
+    }
+}
+
+/// Starts the thread driving the periodic replication work for either role
+pub fn start(server: Arc<Server>) {
+    std::thread::spawn(move || run(server));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        replication::replica_manager::{tests::connected_pair, Replica},
+        server::{
+            data::tests::test_server,
+            metadata::{MasterInfo, ReplicaInfo},
+        },
+    };
+
+    #[test]
+    fn test_master_cron_pings_replicas() {
+        let server = test_server(&[], ReplicaInfo::Master(MasterInfo::new()));
+        let mut live_data = server.live_data.lock().unwrap();
+        let LiveData::Master(data) = &mut *live_data else {
+            panic!("expected a master");
+        };
+        // nobody to keep alive
+        master_cron(data, &server.metadata, true);
+        assert_eq!(data.replication_offset, 0);
+
+        let (local, _remote) = connected_pair();
+        data.replica_manager.add_replica(Replica::new(local));
+        master_cron(data, &server.metadata, false);
+        assert_eq!(data.replication_offset, 0);
+        master_cron(data, &server.metadata, true);
+        assert_eq!(data.replication_offset, b"*1\r\n$4\r\nPING\r\n".len());
+    }
+}
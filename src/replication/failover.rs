@@ -0,0 +1,439 @@
+use std::{
+    io::Write,
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::Client,
+    network::connection::Connection,
+    parser::{
+        command::{Command, ReplConfCommand},
+        resp::Token,
+    },
+    server::data::{LiveData, Server},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverPhase {
+    /// Client writes are paused until the target replica catches up with us
+    WaitingForSync,
+    /// The target is being promoted, after which we become its replica
+    InProgress,
+}
+
+impl FailoverPhase {
+    /// Name of the phase as reported by `INFO replication`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailoverPhase::WaitingForSync => "waiting-for-sync",
+            FailoverPhase::InProgress => "failover-in-progress",
+        }
+    }
+}
+
+pub struct FailoverState {
+    pub phase: FailoverPhase,
+    /// Address the target replica accepts clients on
+    pub target: (String, u16),
+    /// Replication offset the target has to acknowledge before it can take over
+    pub target_offset: usize,
+}
+
+/// Starts handing the master role over to one of our replicas, the most up to date one
+/// unless `target` names it. Returns the error to reply with if the failover cannot start.
+pub fn start(
+    server: &Arc<Server>,
+    target: Option<(String, u16)>,
+    timeout: Option<Duration>,
+    force: bool,
+) -> Result<(), String> {
+    if force && (target.is_none() || timeout.is_none()) {
+        return Err(
+            "ERR FAILOVER with force option requires both a timeout and target HOST and IP."
+                .to_string(),
+        );
+    }
+
+    // replicas are known by the address they connected from, which the target host
+    // has to resolve to
+    let target = match target {
+        Some((host, port)) => {
+            let addrs = (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            Some((addrs, port))
+        }
+        None => None,
+    };
+
+    let mut live_data = server.live_data.lock().unwrap();
+    let LiveData::Master(data) = &mut *live_data else {
+        return Err("ERR FAILOVER is not valid when server is a replica.".to_string());
+    };
+    if data.failover.is_some() {
+        return Err("ERR FAILOVER already in progress.".to_string());
+    }
+    let replicas = data.replica_manager.get_replicas_position();
+    if replicas.is_empty() {
+        return Err("ERR FAILOVER requires connected replicas.".to_string());
+    }
+    let target = match target {
+        Some((addrs, target_port)) => replicas
+            .iter()
+            .find(|(ip, port, _)| {
+                *port == target_port && ip.parse::<IpAddr>().is_ok_and(|ip| addrs.contains(&ip))
+            })
+            .map(|(ip, port, _)| (ip.clone(), *port))
+            .ok_or_else(|| "ERR FAILOVER target HOST and PORT is not a replica.".to_string())?,
+        None => replicas
+            .iter()
+            .max_by_key(|(_, _, offset)| *offset)
+            .map(|(ip, port, _)| (ip.clone(), *port))
+            .unwrap(),
+    };
+
+    println!(
+        "INFO: starting failover to {}:{} at offset {}",
+        target.0, target.1, data.replication_offset
+    );
+    data.failover = Some(FailoverState {
+        phase: FailoverPhase::WaitingForSync,
+        target,
+        target_offset: data.replication_offset,
+    });
+    // ask for fresh acknowledgements rather than waiting for the periodic ones
+    let getack = Command::ReplConf(ReplConfCommand::GetAck("*".to_string())).to_resp_token();
+    if let Some(getack) = getack {
+        data.feed_replication_stream(&getack.serialize());
+    }
+    drop(live_data);
+
+    let server = server.clone();
+    std::thread::spawn(move || run(server, timeout, force));
+    Ok(())
+}
+
+/// Cancels a failover which is still waiting for its target to catch up
+pub fn abort(server: &Server) -> Result<(), String> {
+    let mut live_data = server.live_data.lock().unwrap();
+    let LiveData::Master(data) = &mut *live_data else {
+        return Err("ERR No failover in progress.".to_string());
+    };
+    match &data.failover {
+        Some(failover) if failover.phase == FailoverPhase::WaitingForSync => {
+            println!("INFO: failover aborted");
+            data.failover = None;
+            server.replication_changed.notify_all();
+            Ok(())
+        }
+        Some(_) => Err("ERR FAILOVER is already promoting its target.".to_string()),
+        None => Err("ERR No failover in progress.".to_string()),
+    }
+}
+
+/// Ends a failover without handing the role over, resuming client writes
+fn cancel(server: &Server, reason: &str) {
+    eprintln!("ERROR: failover cancelled: {reason}");
+    if let LiveData::Master(data) = &mut *server.live_data.lock().unwrap() {
+        data.failover = None;
+    }
+    server.replication_changed.notify_all();
+}
+
+/// Waits until the target has acknowledged every write, or until the timeout elapses.
+/// Returns the target to promote, or `None` if the failover ended some other way.
+fn wait_for_target(
+    server: &Server,
+    deadline: Option<Instant>,
+    force: bool,
+) -> Result<Option<(String, u16)>, String> {
+    let mut live_data = server.live_data.lock().unwrap();
+    loop {
+        let LiveData::Master(data) = &mut *live_data else {
+            return Ok(None);
+        };
+        let Some(failover) = &mut data.failover else {
+            return Ok(None);
+        };
+        let Some(acked_offset) = data
+            .replica_manager
+            .get_replicas_position()
+            .into_iter()
+            .find(|(ip, port, _)| (ip, port) == (&failover.target.0, &failover.target.1))
+            .map(|(_, _, offset)| offset)
+        else {
+            return Err("target replica disconnected".to_string());
+        };
+
+        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if acked_offset >= failover.target_offset || (timed_out && force) {
+            failover.phase = FailoverPhase::InProgress;
+            return Ok(Some(failover.target.clone()));
+        }
+        if timed_out {
+            return Err("target replica did not catch up in time".to_string());
+        }
+
+        live_data = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                server
+                    .replication_changed
+                    .wait_timeout(live_data, remaining)
+                    .unwrap()
+                    .0
+            }
+            None => server.replication_changed.wait(live_data).unwrap(),
+        };
+    }
+}
+
+/// Tells the target to stop replicating from us and take over as master
+fn promote_target(host: &str, port: u16, timeout: Duration) -> Result<(), String> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or_else(|| format!("could not resolve {host}:{port}"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(|err| err.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|err| err.to_string())?;
+
+    let request = Token::Array(vec![
+        Token::BulkString(b"REPLICAOF".to_vec()),
+        Token::BulkString(b"NO".to_vec()),
+        Token::BulkString(b"ONE".to_vec()),
+    ]);
+    stream
+        .write_all(&request.serialize())
+        .map_err(|err| err.to_string())?;
+
+    let mut client = Client::new(Connection::new(stream));
+    let response = client
+        .get_next_message()
+        .map_err(|_| "no valid reply to REPLICAOF NO ONE".to_string())?;
+    match response.tokens.as_slice() {
+        [Token::SimpleString(reply)] if reply.starts_with("OK") => Ok(()),
+        tokens => Err(format!("unexpected reply to REPLICAOF NO ONE: {tokens:?}")),
+    }
+}
+
+fn run(server: Arc<Server>, timeout: Option<Duration>, force: bool) {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let (host, port) = match wait_for_target(&server, deadline, force) {
+        Ok(Some(target)) => target,
+        Ok(None) => return,
+        Err(reason) => return cancel(&server, &reason),
+    };
+
+    println!("INFO: promoting {host}:{port} to master");
+    if let Err(reason) = promote_target(&host, port, server.metadata.repl_timeout) {
+        return cancel(&server, &reason);
+    }
+
+    // becoming a replica ends the failover and lets the paused writes fail as read-only
+    server.become_replica(host, port);
+    println!("INFO: failover completed");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        thread::JoinHandle,
+    };
+
+    use super::*;
+    use crate::{
+        replication::replica_manager::{tests::connected_pair, Replica},
+        server::{
+            data::{tests::test_server, WriteOrigin},
+            metadata::{MasterInfo, ReplicaInfo},
+        },
+    };
+
+    /// A master with a replica connected from localhost which accepts clients on the
+    /// returned listener, along with both ends of the replica's connection
+    fn master_with_replica() -> (Arc<Server>, (TcpStream, TcpStream), TcpListener) {
+        let server = Arc::new(test_server(&[], ReplicaInfo::Master(MasterInfo::new())));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (local, remote) = connected_pair();
+        let port = listener.local_addr().unwrap().port();
+        let replica = Replica::new(local.try_clone().unwrap()).with_listening_port(Some(port));
+        server
+            .live_data
+            .lock()
+            .unwrap()
+            .replica_manager()
+            .add_replica(replica);
+        (server, (local, remote), listener)
+    }
+
+    /// Answers the REPLICAOF NO ONE the replica is promoted with
+    fn accept_promotion(listener: TcpListener) -> JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 64];
+            let len = stream.read(&mut request).unwrap();
+            request.truncate(len);
+            stream.write_all(b"+OK\r\n").unwrap();
+            request
+        })
+    }
+
+    fn write(server: &Server) -> Token {
+        let set = |_: &_| (Token::SimpleString("OK".to_string()), 1);
+        server.apply_write(set, b"write", WriteOrigin::Client)
+    }
+
+    fn failover_phase(server: &Server) -> Option<FailoverPhase> {
+        match &*server.live_data.lock().unwrap() {
+            LiveData::Master(data) => data.failover.as_ref().map(|failover| failover.phase),
+            LiveData::Slave(_) => None,
+        }
+    }
+
+    /// Waits for the failover thread to hand the role over, or to give up
+    fn wait_until(server: &Server, done: impl Fn(&LiveData) -> bool) {
+        let started = Instant::now();
+        while !done(&server.live_data.lock().unwrap()) {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn is_replica(live_data: &LiveData) -> bool {
+        matches!(live_data, LiveData::Slave(_))
+    }
+
+    #[test]
+    fn test_failover_once_target_catches_up() {
+        let (server, (replica, _remote), listener) = master_with_replica();
+        let port = listener.local_addr().unwrap().port();
+        write(&server);
+        let promotion = accept_promotion(listener);
+
+        start(&server, Some(("localhost".to_string(), port)), None, false).unwrap();
+        assert_eq!(failover_phase(&server), Some(FailoverPhase::WaitingForSync));
+        assert_eq!(
+            start(&server, None, None, false),
+            Err("ERR FAILOVER already in progress.".to_string())
+        );
+
+        // writes wait for the failover, which waits for the target's acknowledgement
+        std::thread::scope(|scope| {
+            let paused = scope.spawn(|| write(&server));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!paused.is_finished());
+            let offset = server.live_data.lock().unwrap().replication_offset();
+            server.update_replica_offset(&replica, offset);
+            wait_until(&server, is_replica);
+            // the paused write is refused once we are a replica
+            assert!(matches!(paused.join().unwrap(), Token::Error(_)));
+        });
+        assert_eq!(
+            promotion.join().unwrap(),
+            b"*3\r\n$9\r\nREPLICAOF\r\n$2\r\nNO\r\n$3\r\nONE\r\n"
+        );
+        server.become_master();
+    }
+
+    #[test]
+    fn test_failover_target_must_be_a_replica() {
+        let (server, _replica, listener) = master_with_replica();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(
+            start(
+                &server,
+                Some(("localhost".to_string(), port + 1)),
+                None,
+                false
+            ),
+            Err("ERR FAILOVER target HOST and PORT is not a replica.".to_string())
+        );
+        assert_eq!(
+            start(&server, Some(("127.0.0.1".to_string(), port)), None, true),
+            Err(
+                "ERR FAILOVER with force option requires both a timeout and target HOST and IP."
+                    .to_string()
+            )
+        );
+        assert_eq!(failover_phase(&server), None);
+    }
+
+    #[test]
+    fn test_failover_timeout_without_force() {
+        let (server, _replica, listener) = master_with_replica();
+        let port = listener.local_addr().unwrap().port();
+        write(&server);
+
+        let timeout = Some(Duration::from_millis(50));
+        start(
+            &server,
+            Some(("127.0.0.1".to_string(), port)),
+            timeout,
+            false,
+        )
+        .unwrap();
+        wait_until(
+            &server,
+            |live_data| matches!(live_data, LiveData::Master(data) if data.failover.is_none()),
+        );
+        assert_eq!(failover_phase(&server), None);
+        assert!(!is_replica(&server.live_data.lock().unwrap()));
+        assert_eq!(write(&server), Token::SimpleString("OK".to_string()));
+    }
+
+    #[test]
+    fn test_failover_force_promotes_after_timeout() {
+        let (server, _replica, listener) = master_with_replica();
+        let port = listener.local_addr().unwrap().port();
+        write(&server);
+        let promotion = accept_promotion(listener);
+
+        let timeout = Some(Duration::from_millis(50));
+        start(
+            &server,
+            Some(("127.0.0.1".to_string(), port)),
+            timeout,
+            true,
+        )
+        .unwrap();
+        wait_until(&server, is_replica);
+        promotion.join().unwrap();
+        server.become_master();
+    }
+
+    #[test]
+    fn test_failover_abort_lifts_pause() {
+        let (server, _replica, listener) = master_with_replica();
+        let port = listener.local_addr().unwrap().port();
+        write(&server);
+
+        start(&server, Some(("127.0.0.1".to_string(), port)), None, false).unwrap();
+        std::thread::scope(|scope| {
+            let paused = scope.spawn(|| write(&server));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!paused.is_finished());
+            abort(&server).unwrap();
+            assert_eq!(
+                paused.join().unwrap(),
+                Token::SimpleString("OK".to_string())
+            );
+        });
+        assert_eq!(failover_phase(&server), None);
+        assert_eq!(
+            abort(&server),
+            Err("ERR No failover in progress.".to_string())
+        );
+        // the failover thread notices and leaves us be
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!is_replica(&server.live_data.lock().unwrap()));
+    }
+}
@@ -1,18 +1,14 @@
-use std::net::TcpStream;
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
 
 use crate::{
     client::{Client, ClientError},
     network::connection::Connection,
-    parser::{
-        rdb::parse_rdb_payload,
-        resp::{ParseError, Token},
-    },
+    parser::resp::Token,
 };
 
-pub struct RDBPayload {
-    pub rdb: Vec<u8>,
-}
-
 #[derive(Debug)]
 pub enum HandshakeError {
     ConnectionError(Option<String>),
@@ -31,9 +27,19 @@ impl From<ClientError> for HandshakeError {
     }
 }
 
+/// Position in a master's replication stream, as remembered by a replica
+#[derive(Debug, Clone)]
+pub struct SyncPosition {
+    pub replication_id: String,
+    pub offset: usize,
+}
+
 pub struct HandshakePayload {
     pub client: Client,
-    pub rdb: RDBPayload,
+    /// Whether the master is about to send a snapshot, false when it accepted a partial
+    /// resynchronization. The snapshot is left unread on the client's connection.
+    pub full_resync: bool,
+    pub position: SyncPosition,
 }
 
 pub type HandshakeResult = Result<HandshakePayload, HandshakeError>;
@@ -42,6 +48,8 @@ pub struct Config {
     pub master_host: String,
     pub master_port: u16,
     pub replica_port: u16,
+    /// Upper bound for connecting and for waiting on any single reply from the master
+    pub timeout: Duration,
 }
 
 pub struct Handshaker {
@@ -53,7 +61,9 @@ impl Handshaker {
         Self { config }
     }
 
-    pub fn perform_handshake(&self) -> HandshakeResult {
+    /// Connects to the master and synchronizes with it, resuming from `resume_from`
+    /// through a partial resynchronization when the master still has that history
+    pub fn perform_handshake(&self, resume_from: Option<&SyncPosition>) -> HandshakeResult {
         let mut client = self.create_client()?;
 
         // Step 1: Send PING
@@ -63,11 +73,13 @@ impl Handshaker {
         self.send_replconf_information(&mut client)?;
 
         // Step 3: Send PSYNC message
-        self.perform_sync(&mut client)?;
-
-        let rdb = self.receive_rdb_payload(&mut client)?;
+        let (position, full_resync) = self.perform_sync(&mut client, resume_from)?;
 
-        Ok(HandshakePayload { client, rdb })
+        Ok(HandshakePayload {
+            client,
+            full_resync,
+            position,
+        })
     }
 
     fn create_client(&self) -> Result<Client, HandshakeError> {
@@ -77,10 +89,23 @@ impl Handshaker {
     }
 
     fn create_connection(&self) -> Result<Connection, HandshakeError> {
-        match TcpStream::connect((self.config.master_host.clone(), self.config.master_port)) {
-            Ok(stream) => Ok(Connection::new(stream)),
-            Err(e) => Err(HandshakeError::ConnectionError(Some(e.to_string()))),
-        }
+        let addr = (self.config.master_host.as_str(), self.config.master_port)
+            .to_socket_addrs()
+            .map_err(|e| HandshakeError::ConnectionError(Some(e.to_string())))?
+            .next()
+            .ok_or_else(|| {
+                HandshakeError::ConnectionError(Some(format!(
+                    "Could not resolve master address {}",
+                    &self.config.master_host
+                )))
+            })?;
+        let stream = TcpStream::connect_timeout(&addr, self.config.timeout)
+            .and_then(|stream| {
+                stream.set_read_timeout(Some(self.config.timeout))?;
+                Ok(stream)
+            })
+            .map_err(|e| HandshakeError::ConnectionError(Some(e.to_string())))?;
+        Ok(Connection::new(stream))
     }
 
     fn perform_ping(&self, client: &mut Client) -> Result<(), HandshakeError> {
@@ -159,11 +184,23 @@ impl Handshaker {
         Ok(())
     }
 
-    fn perform_sync(&self, client: &mut Client) -> Result<(), HandshakeError> {
+    fn perform_sync(
+        &self,
+        client: &mut Client,
+        resume_from: Option<&SyncPosition>,
+    ) -> Result<(SyncPosition, bool), HandshakeError> {
+        // The PSYNC offset names the first byte we are missing, counted from one
+        let (replication_id, offset) = match resume_from {
+            Some(position) => (
+                position.replication_id.clone(),
+                (position.offset + 1).to_string(),
+            ),
+            None => ("?".to_string(), "-1".to_string()),
+        };
         let psync = Token::Array(vec![
             Token::BulkString(b"PSYNC".to_vec()),
-            Token::BulkString(b"?".to_vec()),
-            Token::BulkString(b"-1".to_vec()),
+            Token::BulkString(replication_id.into_bytes()),
+            Token::BulkString(offset.into_bytes()),
         ]);
         let psync = psync.serialize();
         self.send_message(&psync, client.get_connection())?;
@@ -176,28 +213,48 @@ impl Handshaker {
             )));
         }
 
-        Ok(())
+        self.validate_psync(&response[0], resume_from)
     }
 
-    fn receive_rdb_payload(&self, client: &mut Client) -> Result<RDBPayload, HandshakeError> {
-        let conn = client.get_connection();
-        loop {
-            match parse_rdb_payload(conn.get_buffer()) {
-                Ok(rdb_payload) => {
-                    conn.consume(rdb_payload.len);
-                    return Ok(RDBPayload {
-                        rdb: rdb_payload.rdb,
-                    });
-                }
-                Err(err) => match err {
-                    ParseError::Incomplete => conn
-                        .read_message()
-                        .map_err(|err| HandshakeError::IoError(Some(err.to_string())))?,
-                    _ => {
-                        return Err(HandshakeError::ParseError(Some(err.to_string())));
-                    }
-                },
+    fn validate_psync(
+        &self,
+        response: &Token,
+        resume_from: Option<&SyncPosition>,
+    ) -> Result<(SyncPosition, bool), HandshakeError> {
+        let Token::SimpleString(data) = response else {
+            return Err(HandshakeError::ParseError(Some(
+                "Expected SimpleString in PSYNC response".to_string(),
+            )));
+        };
+        match (
+            data.split_whitespace().collect::<Vec<_>>().as_slice(),
+            resume_from,
+        ) {
+            (["FULLRESYNC", replication_id, offset], _) => {
+                let offset = offset.parse().map_err(|_| {
+                    HandshakeError::ParseError(Some(format!("Invalid FULLRESYNC offset: {offset}")))
+                })?;
+                let position = SyncPosition {
+                    replication_id: replication_id.to_string(),
+                    offset,
+                };
+                Ok((position, true))
+            }
+            (["CONTINUE", rest @ ..], Some(resume_from)) => {
+                // The master may announce a new replication ID if it was promoted since
+                let replication_id = rest
+                    .first()
+                    .map_or(resume_from.replication_id.clone(), |id| id.to_string());
+                let position = SyncPosition {
+                    replication_id,
+                    offset: resume_from.offset,
+                };
+                Ok((position, false))
             }
+            _ => Err(HandshakeError::ParseError(Some(format!(
+                "Unexpected PSYNC response: {}",
+                &data
+            )))),
         }
     }
 
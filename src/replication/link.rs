@@ -0,0 +1,325 @@
+use std::{
+    io::{self, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    persistence::aof,
+    server::{
+        data::{LiveData, MasterLinkState, Server, SlaveLiveData},
+        session::handle_connection,
+    },
+};
+
+use super::{
+    handshake::{self, HandshakePayload, Handshaker, SyncPosition},
+    snapshot::receive_snapshot,
+};
+
+/// How often a master link wakes up from a blocking read to check for heartbeat silence
+const MASTER_LINK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Handle on the thread which keeps a replica attached to its master. Stopping it
+/// closes the current connection and keeps the thread from touching the server again.
+pub struct MasterLink {
+    started: AtomicBool,
+    stopped: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl MasterLink {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            started: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            stream: Mutex::new(None),
+        })
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Writes to the master over the current connection, if there is one
+    pub fn send(&self, message: &[u8]) -> io::Result<()> {
+        match &mut *self.stream.lock().unwrap() {
+            Some(stream) => stream.write_all(message),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "not connected to master",
+            )),
+        }
+    }
+
+    fn attach_stream(&self, stream: &TcpStream) {
+        let mut current = self.stream.lock().unwrap();
+        *current = stream.try_clone().ok();
+        // the link may have been stopped while we were connecting
+        if self.is_stopped() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Runs `f` on the replica state if `link` is still the server's active master link
+pub fn with_link_data<F, T>(server: &Server, link: &Arc<MasterLink>, f: F) -> Option<T>
+where
+    F: FnOnce(&mut SlaveLiveData) -> T,
+{
+    match &mut *server.live_data.lock().unwrap() {
+        LiveData::Slave(data) if Arc::ptr_eq(&data.link, link) && !link.is_stopped() => {
+            Some(f(data))
+        }
+        _ => None,
+    }
+}
+
+pub fn check_master_heartbeat(server: &Server, link: &Arc<MasterLink>) -> io::Result<()> {
+    let last_heard = with_link_data(server, link, |data| data.heartbeat_recv_time).flatten();
+    match last_heard {
+        Some(time) if time.elapsed() > server.metadata.repl_timeout => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no data from master for {:?}", time.elapsed()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn set_master_link_state(server: &Server, link: &Arc<MasterLink>, state: MasterLinkState) {
+    with_link_data(server, link, |data| data.set_link_state(state));
+}
+
+fn link_stopped_error() -> anyhow::Error {
+    anyhow::anyhow!("Replication link was stopped")
+}
+
+/// Performs the handshake and applies the snapshot, leaving the connection ready to
+/// stream commands from the master
+fn sync_with_master(
+    host: &str,
+    port: u16,
+    server: &Server,
+    link: &Arc<MasterLink>,
+) -> anyhow::Result<HandshakePayload> {
+    println!("INFO: connecting to master at {}:{}", host, port);
+    set_master_link_state(server, link, MasterLinkState::Connecting);
+
+    let config = handshake::Config {
+        master_host: host.to_string(),
+        master_port: port,
+        replica_port: server.metadata.listening_port,
+        timeout: server.metadata.repl_timeout,
+    };
+    let handshaker = Handshaker::new(config);
+
+    // resume from where we left off if we have already been synchronized before
+    let resume_from = with_link_data(server, link, |data| {
+        data.master_replid.as_ref().map(|replid| SyncPosition {
+            replication_id: replid.clone(),
+            offset: data.offset,
+        })
+    })
+    .ok_or_else(link_stopped_error)?;
+
+    let mut payload = match handshaker.perform_handshake(resume_from.as_ref()) {
+        Ok(payload) => {
+            println!("INFO: successfully performed handshake with master");
+            payload
+        }
+        Err(err) => {
+            eprintln!("ERROR: failed to perform handshake with master: {:?}", &err);
+            return Err(anyhow::anyhow!(
+                "Failed to perform handshake with master: {:?}",
+                &err
+            ));
+        }
+    };
+    link.attach_stream(&payload.client.get_connection().stream);
+
+    // apply the snapshot before the command stream that follows it
+    if payload.full_resync {
+        set_master_link_state(server, link, MasterLinkState::Sync);
+        if link.is_stopped() {
+            return Err(link_stopped_error());
+        }
+        match receive_snapshot(server, link, payload.client.get_connection()) {
+            Ok(stats) => {
+                println!(
+                    "INFO: loaded {} keys from master snapshot ({})",
+                    stats.loaded,
+                    stats.skipped()
+                );
+                // the log of our previous dataset no longer leads to the new one
+                if let Err(err) = aof::rewrite(server) {
+                    eprintln!("ERROR: failed to rewrite AOF after sync: {err}");
+                }
+            }
+            Err(err) => {
+                eprintln!("ERROR: failed to load snapshot from master: {err}");
+                return Err(anyhow::anyhow!(
+                    "Failed to load snapshot from master: {}",
+                    err
+                ));
+            }
+        }
+    } else {
+        println!(
+            "INFO: partial resynchronization from offset {} accepted by master",
+            payload.position.offset
+        );
+    }
+    with_link_data(server, link, |data| {
+        let position = &payload.position;
+        // our replicas can keep streaming from us only if our history simply continues
+        if payload.full_resync || data.master_replid.as_ref() != Some(&position.replication_id) {
+            data.reset_replication_stream(position.replication_id.clone(), position.offset);
+        }
+        data.set_link_state(MasterLinkState::Connected);
+    })
+    .ok_or_else(link_stopped_error)?;
+
+    Ok(payload)
+}
+
+/// Keeps the replica attached to its master, reconnecting with exponential backoff
+/// whenever the link fails or goes silent for longer than the replication timeout
+fn run_replication(host: String, port: u16, server: Arc<Server>, link: Arc<MasterLink>) {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+
+    while !link.is_stopped() {
+        match sync_with_master(&host, port, &server, &link) {
+            Ok(mut payload) => {
+                backoff = MIN_RECONNECT_BACKOFF;
+
+                let conn = payload.client.get_connection();
+                if let Err(err) = conn
+                    .stream
+                    .set_read_timeout(Some(MASTER_LINK_POLL_INTERVAL))
+                {
+                    eprintln!("ERROR: failed to configure master link: {:?}", &err);
+                }
+                let _ = handle_connection(conn, server.clone(), Some(&link));
+                println!("INFO: lost connection to master at {}:{}", &host, port);
+            }
+            Err(err) => {
+                eprintln!("ERROR: replication failed with error {:?}", &err);
+            }
+        }
+
+        if link.is_stopped() {
+            break;
+        }
+        set_master_link_state(&server, &link, MasterLinkState::Connect);
+
+        println!("INFO: reconnecting to master in {backoff:?}");
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+
+    println!(
+        "INFO: stopped replicating from master at {}:{}",
+        &host, port
+    );
+}
+
+/// Starts the replication thread if the server is a replica whose link is not running yet
+pub fn start(server: &Arc<Server>) {
+    let (host, port, link) = match &*server.live_data.lock().unwrap() {
+        LiveData::Slave(data) => (
+            data.info.master_host.clone(),
+            data.info.master_port,
+            data.link.clone(),
+        ),
+        LiveData::Master(_) => return,
+    };
+    if link.started.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    println!("INFO: starting replication as slave");
+    let server = server.clone();
+    std::thread::spawn(move || run_replication(host, port, server, link));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::server::data::tests::{replica_of_nowhere, test_server};
+
+    fn link_of(server: &Server) -> Arc<MasterLink> {
+        match &*server.live_data.lock().unwrap() {
+            LiveData::Slave(data) => data.link.clone(),
+            LiveData::Master(_) => panic!("expected a replica"),
+        }
+    }
+
+    fn link_times(server: &Server, link: &Arc<MasterLink>) -> (bool, bool) {
+        with_link_data(server, link, |data| {
+            (
+                data.link_down_since.is_some(),
+                data.heartbeat_recv_time.is_some(),
+            )
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_master_link_state_transitions() {
+        let server = test_server(&[], replica_of_nowhere());
+        let link = link_of(&server);
+        assert_eq!(link_times(&server, &link), (true, false));
+
+        set_master_link_state(&server, &link, MasterLinkState::Connecting);
+        set_master_link_state(&server, &link, MasterLinkState::Sync);
+        assert_eq!(link_times(&server, &link), (true, false));
+
+        set_master_link_state(&server, &link, MasterLinkState::Connected);
+        assert_eq!(link_times(&server, &link), (false, true));
+
+        set_master_link_state(&server, &link, MasterLinkState::Connect);
+        assert_eq!(link_times(&server, &link), (true, true));
+        let state = with_link_data(&server, &link, |data| data.link_state);
+        assert_eq!(state, Some(MasterLinkState::Connect));
+
+        // a stopped link no longer owns the replica state
+        link.stop();
+        set_master_link_state(&server, &link, MasterLinkState::Connected);
+        let LiveData::Slave(data) = &*server.live_data.lock().unwrap() else {
+            panic!("expected a replica");
+        };
+        assert_eq!(data.link_state, MasterLinkState::Connect);
+    }
+
+    #[test]
+    fn test_check_master_heartbeat() {
+        let server = test_server(&["--repl-timeout", "1"], replica_of_nowhere());
+        let link = link_of(&server);
+        // nothing to time out before the link first comes up
+        assert!(check_master_heartbeat(&server, &link).is_ok());
+
+        set_master_link_state(&server, &link, MasterLinkState::Connected);
+        assert!(check_master_heartbeat(&server, &link).is_ok());
+
+        with_link_data(&server, &link, |data| {
+            data.heartbeat_recv_time = Instant::now().checked_sub(Duration::from_secs(2));
+        });
+        let err = check_master_heartbeat(&server, &link).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod backlog;
+pub mod cron;
+pub mod failover;
 pub mod handshake;
+pub mod link;
 pub mod rdb;
 pub mod replica_manager;
+pub mod snapshot;
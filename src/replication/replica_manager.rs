@@ -1,36 +1,174 @@
 use std::{
     collections::HashMap,
     io::Write,
-    net::{SocketAddr, TcpStream},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
 };
 
 use crate::network::connection::Connection;
 
 pub type ReplicaId = usize;
 
+/// Most output a replica may have waiting, while it receives its snapshot or because it
+/// reads slower than we write, before it is disconnected. The hard limit Redis puts on
+/// the output buffers of replicas.
+const REPLICA_OUTPUT_BUFFER_LIMIT: usize = 256 * 1024 * 1024;
+
+pub enum ReplicaState {
+    /// The snapshot is still being transferred, writes are held back until it completes
+    WaitingForSync { pending: Vec<u8> },
+    /// Writes are queued for the replica's writer thread
+    Online,
+}
+
+#[derive(Default)]
+struct OutputBuffer {
+    data: Vec<u8>,
+    /// Set once the replica is dropped or writing to it failed, ending its writer
+    closed: bool,
+}
+
+/// Output of an online replica, sent by a thread of its own so that a replica which
+/// stops reading never blocks the writes of our clients
+#[derive(Default)]
+struct ReplicaOutput {
+    buffer: Mutex<OutputBuffer>,
+    ready: Condvar,
+}
+
+impl ReplicaOutput {
+    /// Queues a message, returning how much output is waiting or `None` once closed
+    fn push(&self, message: &[u8]) -> Option<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.closed {
+            return None;
+        }
+        buffer.data.extend_from_slice(message);
+        self.ready.notify_one();
+        Some(buffer.data.len())
+    }
+
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Sends the queued output as it comes until closed, closing it when a write fails
+    fn run_writer(&self, mut stream: TcpStream) {
+        loop {
+            let data = {
+                let mut buffer = self.buffer.lock().unwrap();
+                while buffer.data.is_empty() && !buffer.closed {
+                    buffer = self.ready.wait(buffer).unwrap();
+                }
+                if buffer.closed {
+                    return;
+                }
+                std::mem::take(&mut buffer.data)
+            };
+            if let Err(err) = stream.write_all(&data) {
+                println!("INFO: failed to write to replica with error {err:?}");
+                self.close();
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+    }
+}
+
 pub struct Replica {
     pub stream: TcpStream,
     replica_offset: usize,
+    state: ReplicaState,
+    output: Arc<ReplicaOutput>,
+    /// Port the replica accepts clients on, as announced with REPLCONF listening-port
+    listening_port: Option<u16>,
+    /// When the replica last acknowledged its offset, or when it was registered
+    last_ack_time: Instant,
 }
 
 impl Replica {
     pub fn new(stream: TcpStream) -> Self {
+        let mut replica = Self::new_syncing(stream);
+        replica.go_online();
+        replica
+    }
+
+    pub fn new_syncing(stream: TcpStream) -> Self {
         Self {
             stream,
             replica_offset: 0,
+            state: ReplicaState::WaitingForSync {
+                pending: Vec::new(),
+            },
+            output: Arc::default(),
+            listening_port: None,
+            last_ack_time: Instant::now(),
+        }
+    }
+
+    pub fn with_listening_port(mut self, listening_port: Option<u16>) -> Self {
+        self.listening_port = listening_port;
+        self
+    }
+
+    /// Seconds since the replica last acknowledged its offset
+    pub fn lag(&self) -> u64 {
+        self.last_ack_time.elapsed().as_secs()
+    }
+
+    /// Starts the writer thread, the replica being dropped at the next write if it
+    /// cannot be
+    fn go_online(&mut self) {
+        self.state = ReplicaState::Online;
+        match self.stream.try_clone() {
+            Ok(stream) => {
+                let output = self.output.clone();
+                std::thread::spawn(move || output.run_writer(stream));
+            }
+            Err(err) => {
+                println!("INFO: failed to start writing to replica with error {err:?}");
+                self.output.close();
+            }
+        }
+    }
+
+    /// Queues a message for the replica, returning how much output it has waiting or
+    /// `None` once writing to it failed
+    fn send(&mut self, message: &[u8]) -> Option<usize> {
+        match &mut self.state {
+            ReplicaState::WaitingForSync { pending } => {
+                pending.extend_from_slice(message);
+                Some(pending.len())
+            }
+            ReplicaState::Online => self.output.push(message),
         }
     }
 }
 
-#[derive(Default)] // clippy
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.output.close();
+    }
+}
+
 pub struct ReplicaManager {
     replicas: HashMap<SocketAddr, Replica>,
+    output_buffer_limit: usize,
+}
+
+impl Default for ReplicaManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ReplicaManager {
     pub fn new() -> Self {
         Self {
             replicas: HashMap::new(),
+            output_buffer_limit: REPLICA_OUTPUT_BUFFER_LIMIT,
         }
     }
 
@@ -39,35 +177,326 @@ impl ReplicaManager {
     }
 
     pub fn add_replica(&mut self, replica: Replica) -> Option<Replica> {
-        self.replicas
-            .insert(replica.stream.peer_addr().unwrap(), replica)
+        let addr = replica.stream.peer_addr().ok()?;
+        self.replicas.insert(addr, replica)
     }
 
     pub fn remove_replica(&mut self, conn: &Connection) -> Option<Replica> {
-        self.replicas.remove(&conn.stream.peer_addr().unwrap())
+        self.replicas.remove(&conn.peer_addr()?)
+    }
+
+    /// Drops every replica, closing the connections so their sessions end as well
+    pub fn disconnect_all(&mut self) {
+        for (_, replica) in self.replicas.drain() {
+            let _ = replica.stream.shutdown(Shutdown::Both);
+        }
     }
 
     pub fn get_connected_replica_count(&self) -> usize {
         self.replicas.len()
     }
 
+    /// Queues a message for every replica, dropping the ones which have more output
+    /// waiting than the output buffer limit, or which could not be written to
     pub fn propagate_message_to_replicas(&mut self, message: &[u8]) {
-        for (_, replica) in self.replicas.iter_mut() {
-            let _ = replica.stream.write_all(message);
+        let limit = self.output_buffer_limit;
+        self.replicas.retain(|addr, replica| {
+            match replica.send(message) {
+                Some(len) if len <= limit => return true,
+                Some(_) => println!(
+                    "INFO: disconnecting replica {addr}, output buffer limit of {limit} bytes exceeded"
+                ),
+                None => println!("INFO: disconnecting replica {addr}, writing to it failed"),
+            }
+            let _ = replica.stream.shutdown(Shutdown::Both);
+            false
+        });
+    }
+
+    /// Takes the writes buffered for a replica during its snapshot transfer, for the
+    /// caller to send without holding up everyone else. The replica keeps buffering
+    /// until nothing is left, at which point it is marked online and `None` is returned.
+    pub fn take_pending_output(&mut self, stream: &TcpStream) -> Option<Vec<u8>> {
+        let replica = self.replicas.get_mut(&stream.peer_addr().ok()?)?;
+        match &mut replica.state {
+            ReplicaState::WaitingForSync { pending } if !pending.is_empty() => {
+                Some(std::mem::take(pending))
+            }
+            ReplicaState::WaitingForSync { .. } => {
+                replica.go_online();
+                None
+            }
+            ReplicaState::Online => None,
         }
     }
 
     pub fn update_replica_offset(&mut self, stream: &TcpStream, offset: usize) {
-        let replica = self.replicas.get_mut(&stream.peer_addr().unwrap());
-        if let Some(replica) = replica {
+        let Ok(addr) = stream.peer_addr() else {
+            return;
+        };
+        if let Some(replica) = self.replicas.get_mut(&addr) {
             replica.replica_offset = offset;
+            replica.last_ack_time = Instant::now();
         }
     }
 
+    /// Counts the online replicas which acknowledged their offset within `max_lag` seconds
+    pub fn get_good_replicas_count(&self, max_lag: u64) -> usize {
+        self.replicas
+            .values()
+            .filter(|replica| {
+                matches!(replica.state, ReplicaState::Online) && replica.lag() <= max_lag
+            })
+            .count()
+    }
+
+    /// Drops the online replicas which have not acknowledged anything within `timeout`,
+    /// closing their connections, and returns their addresses
+    pub fn evict_timed_out_replicas(&mut self, timeout: Duration) -> Vec<SocketAddr> {
+        let timed_out = self
+            .replicas
+            .iter()
+            .filter(|(_, replica)| {
+                matches!(replica.state, ReplicaState::Online)
+                    && replica.last_ack_time.elapsed() > timeout
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in timed_out.iter() {
+            if let Some(replica) = self.replicas.remove(addr) {
+                let _ = replica.stream.shutdown(Shutdown::Both);
+            }
+        }
+        timed_out
+    }
+
+    fn sorted_replicas(&self) -> Vec<(&SocketAddr, &Replica)> {
+        let mut replicas = self.replicas.iter().collect::<Vec<_>>();
+        replicas.sort_by_key(|(addr, _)| **addr);
+        replicas
+    }
+
+    /// Address, listening port and acknowledged offset of each replica, ordered by address
+    pub fn get_replicas_position(&self) -> Vec<(String, u16, usize)> {
+        self.sorted_replicas()
+            .into_iter()
+            .map(|(addr, replica)| {
+                (
+                    addr.ip().to_string(),
+                    replica.listening_port.unwrap_or(addr.port()),
+                    replica.replica_offset,
+                )
+            })
+            .collect()
+    }
+
+    /// Describes each replica the way `INFO replication` lists them, ordered by address
+    pub fn get_replicas_info(&self) -> Vec<String> {
+        self.sorted_replicas()
+            .into_iter()
+            .map(|(addr, replica)| {
+                let state = match replica.state {
+                    ReplicaState::WaitingForSync { .. } => "wait_bgsave",
+                    ReplicaState::Online => "online",
+                };
+                format!(
+                    "ip={},port={},state={},offset={},lag={}",
+                    addr.ip(),
+                    replica.listening_port.unwrap_or(addr.port()),
+                    state,
+                    replica.replica_offset,
+                    replica.lag(),
+                )
+            })
+            .collect()
+    }
+
+    /// Online replicas which acknowledged `offset`, those still receiving their snapshot
+    /// not holding any of our data yet whatever their offset
     pub fn get_up_to_date_replicas_count(&self, offset: usize) -> usize {
         self.replicas
             .values()
-            .filter(|replica| replica.replica_offset >= offset)
+            .filter(|replica| {
+                matches!(replica.state, ReplicaState::Online) && replica.replica_offset >= offset
+            })
             .count()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::Read,
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    /// Both ends of a loopback connection, ours first and the replica's second
+    pub(crate) fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (local, _) = listener.accept().unwrap();
+        (local, remote)
+    }
+
+    /// Whether anything arrived on `stream` within a short while
+    fn received_anything(stream: &mut TcpStream) -> bool {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut buf = [0; 64];
+        stream.read(&mut buf).is_ok()
+    }
+
+    #[test]
+    fn test_syncing_replica_buffers_writes() {
+        let (local, mut remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.add_replica(Replica::new_syncing(local.try_clone().unwrap()));
+
+        replica_manager.propagate_message_to_replicas(b"first");
+        replica_manager.propagate_message_to_replicas(b"second");
+        assert!(!received_anything(&mut remote));
+
+        let pending = replica_manager.take_pending_output(&local).unwrap();
+        assert_eq!(pending, b"firstsecond");
+        // still buffering until the caller is done sending
+        replica_manager.propagate_message_to_replicas(b"third");
+        assert!(!received_anything(&mut remote));
+        assert_eq!(
+            replica_manager.take_pending_output(&local).unwrap(),
+            b"third"
+        );
+        assert_eq!(replica_manager.take_pending_output(&local), None);
+
+        replica_manager.propagate_message_to_replicas(b"fourth");
+        let mut buf = [0; 6];
+        remote.set_read_timeout(None).unwrap();
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"fourth");
+    }
+
+    #[test]
+    fn test_syncing_replica_output_buffer_limit() {
+        let (syncing, mut syncing_remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.output_buffer_limit = 8;
+        replica_manager.add_replica(Replica::new_syncing(syncing.try_clone().unwrap()));
+
+        replica_manager.propagate_message_to_replicas(b"1234");
+        replica_manager.propagate_message_to_replicas(b"5678");
+        assert_eq!(replica_manager.get_connected_replica_count(), 1);
+        replica_manager.propagate_message_to_replicas(b"9");
+        assert_eq!(replica_manager.get_connected_replica_count(), 0);
+        assert_eq!(replica_manager.take_pending_output(&syncing), None);
+        let mut buf = [0; 1];
+        assert_eq!(syncing_remote.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_replica_which_never_reads_is_dropped() {
+        let (hung, mut hung_remote) = connected_pair();
+        let (reading, mut reading_remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.output_buffer_limit = 1024 * 1024;
+        replica_manager.add_replica(Replica::new(hung));
+        let reading_port = reading.peer_addr().unwrap().port();
+        replica_manager.add_replica(Replica::new(reading));
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 64 * 1024];
+            while let Ok(len @ 1..) = reading_remote.read(&mut buf) {
+                counter.fetch_add(len, Ordering::SeqCst);
+            }
+        });
+
+        // never blocks, however much the hung replica leaves unread, while the other one
+        // is given the time to keep up
+        let message = vec![b'x'; 64 * 1024];
+        let started = Instant::now();
+        let mut sent = 0;
+        while replica_manager.get_connected_replica_count() == 2 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            replica_manager.propagate_message_to_replicas(&message);
+            sent += message.len();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let positions = replica_manager.get_replicas_position();
+        assert_eq!(positions, [("127.0.0.1".to_string(), reading_port, 0)]);
+        let mut buf = [0; 64 * 1024];
+        hung_remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        while hung_remote.read(&mut buf).is_ok_and(|len| len > 0) {}
+
+        // the replica which kept up got everything
+        while received.load(Ordering::SeqCst) < sent {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received.load(Ordering::SeqCst), sent);
+    }
+
+    #[test]
+    fn test_replica_dropped_once_writing_fails() {
+        let (local, remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.add_replica(Replica::new(local));
+        drop(remote);
+
+        let started = Instant::now();
+        while replica_manager.get_connected_replica_count() == 1 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            replica_manager.propagate_message_to_replicas(b"message");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_replica_lag_and_eviction() {
+        let (fresh, _fresh_remote) = connected_pair();
+        let (stale, mut stale_remote) = connected_pair();
+        let (syncing, _syncing_remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.add_replica(Replica::new(fresh.try_clone().unwrap()));
+        let mut replica = Replica::new(stale.try_clone().unwrap());
+        replica.last_ack_time = Instant::now() - Duration::from_secs(5);
+        replica_manager.add_replica(replica);
+        let mut replica = Replica::new_syncing(syncing);
+        replica.last_ack_time = Instant::now() - Duration::from_secs(5);
+        replica_manager.add_replica(replica);
+
+        // replicas still receiving their snapshot count neither as good nor as timed out
+        assert_eq!(replica_manager.get_good_replicas_count(1), 1);
+        assert_eq!(replica_manager.get_good_replicas_count(10), 2);
+
+        let evicted = replica_manager.evict_timed_out_replicas(Duration::from_secs(3));
+        assert_eq!(evicted, [stale.peer_addr().unwrap()]);
+        assert_eq!(replica_manager.get_connected_replica_count(), 2);
+        let mut buf = [0; 1];
+        assert_eq!(stale_remote.read(&mut buf).unwrap(), 0);
+
+        replica_manager.update_replica_offset(&fresh, 42);
+        assert_eq!(replica_manager.get_up_to_date_replicas_count(42), 1);
+        assert!(replica_manager
+            .evict_timed_out_replicas(Duration::from_secs(3))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_syncing_replica_is_not_up_to_date() {
+        let (online, _online_remote) = connected_pair();
+        let (syncing, _syncing_remote) = connected_pair();
+        let mut replica_manager = ReplicaManager::new();
+        replica_manager.add_replica(Replica::new(online));
+        replica_manager.add_replica(Replica::new_syncing(syncing.try_clone().unwrap()));
+
+        // even before any write, a replica still loading the snapshot is not counted
+        assert_eq!(replica_manager.get_up_to_date_replicas_count(0), 1);
+        replica_manager.update_replica_offset(&syncing, 42);
+        assert_eq!(replica_manager.get_up_to_date_replicas_count(42), 0);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    common::unix_time_ms,
+    network::connection::Connection,
+    parser::{rdb::RdbError, resp::find_first_crlf},
+    persistence::rdb::{replace_dataset, LoadStats},
+    server::{config::ReplDisklessLoad, data::Server},
+};
+
+use super::link::{with_link_data, MasterLink};
+
+fn link_stopped_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "replication link was stopped",
+    )
+}
+
+/// Reads the bulk payload of a snapshot off the master connection, stopping at its end
+/// and publishing progress for INFO as it goes
+struct SnapshotReader<'a> {
+    conn: &'a mut Connection,
+    server: &'a Server,
+    link: &'a Arc<MasterLink>,
+    remaining: usize,
+}
+
+impl Read for SnapshotReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let limit = buf.len().min(self.remaining);
+        let n = self.conn.read_raw(&mut buf[..limit])?;
+        self.remaining -= n;
+        with_link_data(self.server, self.link, |data| data.sync_read_bytes += n)
+            .ok_or_else(link_stopped_error)?;
+        Ok(n)
+    }
+}
+
+/// Reads the `$<len>\r\n` header announcing the size of the snapshot
+fn read_payload_length(conn: &mut Connection) -> io::Result<usize> {
+    loop {
+        // a master may send bare newlines to keep the link alive while it prepares the snapshot
+        let keepalives = conn
+            .get_buffer()
+            .iter()
+            .take_while(|&&b| b == b'\n')
+            .count();
+        conn.consume(keepalives);
+
+        if let Some(end) = find_first_crlf(conn.get_buffer()) {
+            let header = &conn.get_buffer()[..end];
+            let len = header
+                .strip_prefix(b"$")
+                .and_then(|len| std::str::from_utf8(len).ok())
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "unexpected snapshot header {:?}",
+                            String::from_utf8_lossy(header)
+                        ),
+                    )
+                })?;
+            conn.consume(end + 2);
+            return Ok(len);
+        }
+        conn.read_message()?;
+    }
+}
+
+fn receive_to_file(reader: &mut SnapshotReader, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    io::copy(reader, &mut file)?;
+    file.sync_all()
+}
+
+/// Stores the whole snapshot in a temporary file before loading it, keeping the dataset
+/// as it was if the file does not decode. On success the file becomes our own RDB file
+/// when one is configured, as a save would write the same data.
+fn load_through_disk(server: &Server, reader: &mut SnapshotReader) -> Result<LoadStats, RdbError> {
+    let rdb_path = server
+        .metadata
+        .rdb_config
+        .as_ref()
+        .map(|config| config.get_path());
+    let dir = rdb_path
+        .as_deref()
+        .and_then(Path::parent)
+        .unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!(
+        "temp-{}.{}.rdb",
+        unix_time_ms() / 1000,
+        std::process::id()
+    ));
+
+    let result = receive_to_file(reader, &temp_path)
+        .map_err(RdbError::from)
+        .and_then(|()| replace_dataset(server, BufReader::new(File::open(&temp_path)?)));
+
+    match (&result, rdb_path) {
+        (Ok(_), Some(rdb_path)) => {
+            if let Err(err) = fs::rename(&temp_path, &rdb_path) {
+                eprintln!("ERROR: failed to keep master snapshot at {rdb_path:?}: {err}");
+                let _ = fs::remove_file(&temp_path);
+            }
+        }
+        _ => {
+            let _ = fs::remove_file(&temp_path);
+        }
+    }
+    result
+}
+
+/// Receives the snapshot following a FULLRESYNC and replaces the dataset with it, leaving
+/// the connection positioned at the start of the command stream
+pub fn receive_snapshot(
+    server: &Server,
+    link: &Arc<MasterLink>,
+    conn: &mut Connection,
+) -> Result<LoadStats, RdbError> {
+    let total = read_payload_length(conn)?;
+    with_link_data(server, link, |data| {
+        data.sync_total_bytes = total;
+        data.sync_read_bytes = 0;
+    })
+    .ok_or_else(link_stopped_error)?;
+
+    let mut reader = SnapshotReader {
+        conn,
+        server,
+        link,
+        remaining: total,
+    };
+    match server.metadata.repl_diskless_load {
+        ReplDisklessLoad::Disabled => load_through_disk(server, &mut reader),
+        ReplDisklessLoad::Swapdb => {
+            let stats = replace_dataset(server, BufReader::new(&mut reader))?;
+            // the decoder stops at the checksum, make sure nothing of the payload is left over
+            io::copy(&mut reader, &mut io::sink())?;
+            Ok(stats)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{
+        parser::rdb::{encode_rdb, RdbEntry, RdbValue},
+        persistence::tests::test_dir,
+        replication::replica_manager::tests::connected_pair,
+        server::data::{
+            tests::{replica_of_nowhere, test_server},
+            LiveData,
+        },
+        storage::value::Value,
+    };
+
+    /// Sends a snapshot holding a single key, followed by the start of the command stream.
+    /// A corrupt snapshot has a checksum which does not match.
+    fn receive_test_snapshot(
+        server: &Server,
+        corrupt: bool,
+    ) -> (Result<LoadStats, RdbError>, Connection) {
+        let link = match &*server.live_data.lock().unwrap() {
+            LiveData::Slave(data) => data.link.clone(),
+            LiveData::Master(_) => panic!("expected a replica"),
+        };
+        let mut rdb = encode_rdb(
+            &[RdbEntry {
+                db: 0,
+                key: b"key".to_vec(),
+                value: RdbValue::String(b"value".to_vec()),
+                expire_at_ms: None,
+            }],
+            0,
+        )
+        .unwrap();
+        if corrupt {
+            *rdb.last_mut().unwrap() ^= 0xff;
+        }
+        let (local, mut remote) = connected_pair();
+        remote
+            .write_all(format!("\n\n${}\r\n", rdb.len()).as_bytes())
+            .unwrap();
+        remote.write_all(&rdb).unwrap();
+        remote.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+
+        let mut conn = Connection::new(local);
+        let result = receive_snapshot(server, &link, &mut conn);
+        (result, conn)
+    }
+
+    /// Whatever follows the snapshot, waiting for the whole PING to arrive
+    fn rest_of_stream(conn: &mut Connection) -> Vec<u8> {
+        while conn.get_buffer().len() < 14 {
+            conn.read_message().unwrap();
+        }
+        conn.get_buffer().to_vec()
+    }
+
+    #[test]
+    fn test_receive_snapshot_through_disk() {
+        let dir = test_dir("receive-snapshot");
+        let server = test_server(
+            &["--dir", dir.to_str().unwrap(), "--dbfilename", "dump.rdb"],
+            replica_of_nowhere(),
+        );
+        server.set(b"stale", Value::String(b"value".to_vec()), None);
+
+        let (result, mut conn) = receive_test_snapshot(&server, false);
+        assert_eq!(result.unwrap().loaded, 1);
+        assert!(server.get(b"key").is_some());
+        assert!(server.get(b"stale").is_none());
+        assert_eq!(rest_of_stream(&mut conn), b"*1\r\n$4\r\nPING\r\n");
+        // the snapshot is kept as our own RDB file and nothing else is left behind
+        let files = fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert!(dir.join("dump.rdb").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_receive_snapshot_diskless() {
+        let server = test_server(&["--repl-diskless-load", "swapdb"], replica_of_nowhere());
+        server.set(b"stale", Value::String(b"value".to_vec()), None);
+
+        let (result, mut conn) = receive_test_snapshot(&server, false);
+        assert_eq!(result.unwrap().loaded, 1);
+        assert!(server.get(b"key").is_some());
+        assert!(server.get(b"stale").is_none());
+        assert_eq!(rest_of_stream(&mut conn), b"*1\r\n$4\r\nPING\r\n");
+        let LiveData::Slave(data) = &*server.live_data.lock().unwrap() else {
+            panic!("expected a replica");
+        };
+        assert_eq!(data.sync_read_bytes, data.sync_total_bytes);
+    }
+
+    #[test]
+    fn test_corrupt_snapshot_keeps_dataset() {
+        let dir = test_dir("corrupt-snapshot");
+        let through_disk = ["--dir", dir.to_str().unwrap(), "--dbfilename", "dump.rdb"];
+        for args in [&through_disk[..], &["--repl-diskless-load", "swapdb"]] {
+            let server = test_server(args, replica_of_nowhere());
+            server.set(b"stale", Value::String(b"value".to_vec()), None);
+
+            let (result, _conn) = receive_test_snapshot(&server, true);
+            assert!(result.is_err(), "{args:?}");
+            assert!(server.get(b"stale").is_some(), "{args:?}");
+            assert!(server.get(b"key").is_none(), "{args:?}");
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use std::{io, io::Write, net::TcpStream, sync::Arc};
+
+use crate::{
+    network::connection::Connection,
+    parser::{
+        command::{parse_command, Command, SentinelCommand},
+        resp::{ParseError, Token},
+    },
+};
+
+use super::state::{Instance, MonitoredMaster, Sentinel};
+
+fn bulk(value: impl ToString) -> Token {
+    Token::BulkString(value.to_string().into_bytes())
+}
+
+fn fields(fields: Vec<(&str, String)>) -> Token {
+    Token::Array(
+        fields
+            .into_iter()
+            .flat_map(|(field, value)| [bulk(field), bulk(value)])
+            .collect(),
+    )
+}
+
+fn master_fields(master: &MonitoredMaster, sentinel: &Sentinel) -> Token {
+    let mut flags = vec!["master"];
+    if master.master.is_sdown(sentinel.settings.down_after) {
+        flags.push("s_down");
+    }
+    if master.odown {
+        flags.push("o_down");
+    }
+    if master.failover_in_progress {
+        flags.push("failover_in_progress");
+    }
+    fields(vec![
+        ("name", master.name.clone()),
+        ("ip", master.master.host.clone()),
+        ("port", master.master.port.to_string()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            master.master.last_ok_ping.elapsed().as_millis().to_string(),
+        ),
+        ("num-slaves", master.replicas.len().to_string()),
+        ("quorum", master.quorum.to_string()),
+        ("config-epoch", master.config_epoch.to_string()),
+    ])
+}
+
+fn replica_fields(replica: &Instance, sentinel: &Sentinel) -> Token {
+    let mut flags = vec!["slave"];
+    if replica.is_sdown(sentinel.settings.down_after) {
+        flags.push("s_down");
+    }
+    let mut replica_fields = vec![
+        ("name", format!("{}:{}", replica.host, replica.port)),
+        ("ip", replica.host.clone()),
+        ("port", replica.port.to_string()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            replica.last_ok_ping.elapsed().as_millis().to_string(),
+        ),
+    ];
+    if let Some(info) = &replica.info {
+        let link_status = if info.master_link_up { "ok" } else { "err" };
+        replica_fields.push(("master-link-status", link_status.to_string()));
+        if let Some((host, port)) = &info.master {
+            replica_fields.push(("master-host", host.clone()));
+            replica_fields.push(("master-port", port.to_string()));
+        }
+        replica_fields.push(("slave-repl-offset", info.repl_offset.to_string()));
+    }
+    fields(replica_fields)
+}
+
+fn no_such_master() -> Token {
+    Token::Error("ERR No such master with that name".to_string())
+}
+
+fn handle_sentinel_command(sentinel: &Sentinel, command: &SentinelCommand) -> Token {
+    let mut state = sentinel.state.lock().unwrap();
+    match command {
+        SentinelCommand::Masters => Token::Array(
+            state
+                .masters
+                .iter()
+                .map(|master| master_fields(master, sentinel))
+                .collect(),
+        ),
+        SentinelCommand::Master(name) => state
+            .master(name)
+            .map_or_else(no_such_master, |master| master_fields(master, sentinel)),
+        SentinelCommand::Replicas(name) => match state.master(name) {
+            Some(master) => Token::Array(
+                master
+                    .replicas
+                    .iter()
+                    .map(|replica| replica_fields(replica, sentinel))
+                    .collect(),
+            ),
+            None => no_such_master(),
+        },
+        // unknown masters get a null reply
+        SentinelCommand::GetMasterAddrByName(name) => match state.master(name) {
+            Some(master) => Token::Array(vec![bulk(&master.master.host), bulk(master.master.port)]),
+            None => Token::BulkString(Vec::new()),
+        },
+        SentinelCommand::IsMasterDownByAddr {
+            host,
+            port,
+            current_epoch,
+            runid,
+        } => {
+            let (down, leader, leader_epoch) = state.is_master_down_by_addr(
+                host,
+                *port,
+                *current_epoch,
+                runid,
+                sentinel.settings.down_after,
+            );
+            Token::Array(vec![
+                Token::Integer(down as i64),
+                bulk(leader),
+                Token::Integer(leader_epoch as i64),
+            ])
+        }
+        SentinelCommand::Hello(hello) => {
+            state.apply_hello(hello);
+            Token::SimpleString("OK".to_string())
+        }
+    }
+}
+
+fn handle_command(sentinel: &Sentinel, command: &Command) -> Token {
+    match command {
+        Command::Ping => Token::SimpleString("PONG".to_string()),
+        Command::Role => {
+            let names = sentinel
+                .state
+                .lock()
+                .unwrap()
+                .masters
+                .iter()
+                .map(|master| bulk(&master.name))
+                .collect();
+            Token::Array(vec![bulk("sentinel"), Token::Array(names)])
+        }
+        Command::Sentinel(command) => {
+            println!("DEBUG: received SENTINEL command {command:?}");
+            handle_sentinel_command(sentinel, command)
+        }
+        _ => Token::Error("ERR command not supported in sentinel mode".to_string()),
+    }
+}
+
+fn handle_read_loop(conn: &mut Connection, sentinel: &Sentinel) -> io::Result<()> {
+    let mut stream: TcpStream = conn.stream.try_clone()?;
+    loop {
+        match parse_command(conn.get_buffer()) {
+            Ok(result) => {
+                let response = handle_command(sentinel, &result.command);
+                stream.write_all(&response.serialize())?;
+                conn.consume(result.len);
+            }
+            Err(ParseError::Incomplete) => conn.read_message()?,
+            Err(ParseError::Invalid) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid message format",
+                ));
+            }
+        }
+    }
+}
+
+/// Serves a client of the sentinel, answering its queries about the monitored masters
+pub fn handle_connection(conn: &mut Connection, sentinel: Arc<Sentinel>) {
+    match handle_read_loop(conn, &sentinel) {
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => {
+            eprintln!("ERROR: failed to handle connection with error {:?}", &err);
+        }
+        _ => println!("INFO: client disconnected"),
+    }
+}
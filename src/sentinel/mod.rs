@@ -0,0 +1,3 @@
+pub mod handler;
+pub mod monitor;
+pub mod state;
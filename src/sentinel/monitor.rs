@@ -0,0 +1,739 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::{Client, ClientError},
+    common::random_hex_id,
+    parser::resp::Token,
+};
+
+use super::state::{InstanceInfo, Sentinel};
+
+/// Upper bound for connecting to an instance or a peer and for waiting on its replies
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a replica may follow the wrong master before we repoint it. Leaves time
+/// for the hello of a peer which just failed over to reach us.
+const RECONFIGURE_GRACE: Duration = Duration::from_secs(4);
+/// Upper bound for the random delay before asking for votes, which keeps sentinels
+/// noticing a failure at the same time from splitting the vote
+const MAX_ELECTION_DELAY_MS: u64 = 500;
+
+/// Connection to an instance or a peer sentinel, re-established on the next request
+/// after any failure so that a late reply is never mistaken for the next one
+#[derive(Default)]
+struct Links {
+    clients: HashMap<(String, u16), Client>,
+}
+
+impl Links {
+    fn request(&mut self, host: &str, port: u16, args: &[&str]) -> Result<Vec<Token>, ClientError> {
+        let key = (host.to_string(), port);
+        let mut client = match self.clients.remove(&key) {
+            Some(client) => client,
+            None => Client::connect(host, port, REQUEST_TIMEOUT)?,
+        };
+        let response = client.request(args)?;
+        self.clients.insert(key, client);
+        Ok(response)
+    }
+
+    /// Address our connection to `host:port` comes from, which is how it can reach us
+    fn local_ip(&mut self, host: &str, port: u16) -> Option<String> {
+        let key = (host.to_string(), port);
+        let client = match self.clients.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Client::connect(host, port, REQUEST_TIMEOUT).ok()?)
+            }
+        };
+        let local_addr = client.get_connection().stream.local_addr().ok()?;
+        Some(local_addr.ip().to_string())
+    }
+
+    fn ping(&mut self, host: &str, port: u16) -> bool {
+        match self.request(host, port, &["PING"]).as_deref() {
+            Ok([Token::SimpleString(reply)]) => reply.eq_ignore_ascii_case("pong"),
+            // a busy or still loading instance is alive nonetheless
+            Ok([Token::Error(reply)]) => {
+                reply.starts_with("LOADING") || reply.starts_with("MASTERDOWN")
+            }
+            _ => false,
+        }
+    }
+
+    fn info(&mut self, host: &str, port: u16) -> Option<InstanceInfo> {
+        match self
+            .request(host, port, &["INFO", "replication"])
+            .as_deref()
+        {
+            Ok([Token::BulkString(info)]) => {
+                Some(InstanceInfo::parse(&String::from_utf8_lossy(info)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends a command whose reply is expected to be `+OK`
+    fn command(&mut self, host: &str, port: u16, args: &[&str]) -> Result<(), String> {
+        match self.request(host, port, args).as_deref() {
+            Ok([Token::SimpleString(reply)]) if reply.starts_with("OK") => Ok(()),
+            Ok(tokens) => Err(format!("unexpected reply {tokens:?}")),
+            Err(_) => Err(format!("no reply from {host}:{port}")),
+        }
+    }
+}
+
+/// Pings every known instance of the master and refreshes what we know about them
+fn refresh_instances(sentinel: &Sentinel, name: &str, links: &mut Links) {
+    let addresses = {
+        let state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master(name) else {
+            return;
+        };
+        std::iter::once(&master.master)
+            .chain(master.replicas.iter())
+            .map(|instance| instance.address())
+            .collect::<Vec<_>>()
+    };
+
+    for (host, port) in addresses {
+        let alive = links.ping(&host, port);
+        let info = if alive { links.info(&host, port) } else { None };
+
+        let mut state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master_mut(name) else {
+            return;
+        };
+        let is_master = master.master.is_at(&host, port);
+        let Some(instance) = master.instance_mut(&host, port) else {
+            // the master was switched while we were talking to the instance
+            continue;
+        };
+        if alive {
+            instance.last_ok_ping = Instant::now();
+        }
+        if let Some(info) = info {
+            let replicas = info.replicas.clone();
+            instance.info = Some(info);
+            if is_master {
+                master.add_replicas(&replicas);
+            }
+        }
+    }
+}
+
+/// Points replicas which report another master than ours back at it, once they have
+/// been doing so for long enough to rule out a failover we have not heard of yet
+fn reconfigure_replicas(sentinel: &Sentinel, name: &str, links: &mut Links) {
+    let down_after = sentinel.settings.down_after;
+    let (master_address, misconfigured) = {
+        let mut state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master_mut(name) else {
+            return;
+        };
+        // there is nothing to point replicas at while the master is unreachable
+        if master.failover_in_progress || master.master.is_sdown(down_after) {
+            return;
+        }
+        let master_address = master.master.address();
+
+        let mut misconfigured = Vec::new();
+        for replica in master.replicas.iter_mut() {
+            let follows_master = match &replica.info {
+                Some(info) if !replica.is_sdown(down_after) => {
+                    !info.is_master && info.master.as_ref() == Some(&master_address)
+                }
+                _ => true,
+            };
+            if follows_master {
+                replica.misconfigured_since = None;
+                continue;
+            }
+            let since = *replica.misconfigured_since.get_or_insert_with(Instant::now);
+            if since.elapsed() > RECONFIGURE_GRACE {
+                replica.misconfigured_since = None;
+                misconfigured.push(replica.address());
+            }
+        }
+        (master_address, misconfigured)
+    };
+
+    let (master_host, master_port) = master_address;
+    let master_port = master_port.to_string();
+    for (host, port) in misconfigured {
+        println!("INFO: +fix-slave-config {name} {host}:{port} @ {master_host} {master_port}");
+        let request = ["REPLICAOF", master_host.as_str(), master_port.as_str()];
+        if let Err(reason) = links.command(&host, port, &request) {
+            eprintln!("ERROR: failed to reconfigure {host}:{port}: {reason}");
+        }
+    }
+}
+
+/// Tells every peer which master we consider current for `name`
+fn send_hello(sentinel: &Sentinel, name: &str, links: &mut Links) {
+    let hello = {
+        let state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master(name) else {
+            return;
+        };
+        [
+            sentinel.settings.listening_port.to_string(),
+            state.runid.clone(),
+            state.current_epoch.to_string(),
+            master.name.clone(),
+            master.master.host.clone(),
+            master.master.port.to_string(),
+            master.config_epoch.to_string(),
+        ]
+    };
+
+    for (host, port) in &sentinel.settings.peers {
+        let announce_ip = match &sentinel.settings.announce_ip {
+            Some(announce_ip) => announce_ip.clone(),
+            None => match links.local_ip(host, *port) {
+                Some(local_ip) => local_ip,
+                None => continue,
+            },
+        };
+        let mut request = vec!["SENTINEL", "HELLO", announce_ip.as_str()];
+        request.extend(hello.iter().map(String::as_str));
+        let _ = links.command(host, *port, &request);
+    }
+}
+
+/// Asks every peer whether it sees the master at `host:port` down, also asking for its
+/// vote when `runid` is not `*`. Returns the replies which came back.
+fn ask_peers(
+    sentinel: &Sentinel,
+    links: &mut Links,
+    host: &str,
+    port: u16,
+    epoch: u64,
+    runid: &str,
+) -> Vec<(bool, String, u64)> {
+    let port = port.to_string();
+    let epoch = epoch.to_string();
+    let request = [
+        "SENTINEL",
+        "is-master-down-by-addr",
+        host,
+        &port,
+        &epoch,
+        runid,
+    ];
+    sentinel
+        .settings
+        .peers
+        .iter()
+        .filter_map(|(peer_host, peer_port)| {
+            // the reply is an array, which our parser flattens
+            match links.request(peer_host, *peer_port, &request).as_deref() {
+                Ok([Token::Integer(down), Token::BulkString(leader), Token::Integer(epoch)]) => {
+                    Some((
+                        *down == 1,
+                        String::from_utf8_lossy(leader).to_string(),
+                        *epoch as u64,
+                    ))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Updates the subjective and objective down states of the master.
+/// Returns whether it is objectively down.
+fn check_master_down(sentinel: &Sentinel, name: &str, links: &mut Links) -> bool {
+    let down_after = sentinel.settings.down_after;
+    let (host, port, quorum, was_odown) = {
+        let mut state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master_mut(name) else {
+            return false;
+        };
+        if !master.master.is_sdown(down_after) {
+            if master.odown {
+                println!("INFO: -odown master {name}");
+                master.odown = false;
+            }
+            return false;
+        }
+        let (host, port) = master.master.address();
+        (host, port, master.quorum, master.odown)
+    };
+
+    let replies = ask_peers(sentinel, links, &host, port, 0, "*");
+    let agreeing = 1 + replies.iter().filter(|(down, _, _)| *down).count();
+    let odown = agreeing >= quorum;
+
+    let mut state = sentinel.state.lock().unwrap();
+    let Some(master) = state.master_mut(name) else {
+        return false;
+    };
+    if !master.master.is_at(&host, port) {
+        return false;
+    }
+    if odown != was_odown {
+        let sign = if odown { "+" } else { "-" };
+        println!("INFO: {sign}odown master {name} {host} {port} #quorum {agreeing}/{quorum}");
+    }
+    master.odown = odown;
+    odown
+}
+
+fn random_election_delay() -> Duration {
+    let random = u64::from_str_radix(&random_hex_id(8), 16).unwrap_or(0);
+    Duration::from_millis(random % MAX_ELECTION_DELAY_MS)
+}
+
+/// Tries to get elected by the peers to lead a failover in a new epoch.
+/// Returns that epoch if we won.
+fn run_election(sentinel: &Sentinel, name: &str, links: &mut Links) -> Option<u64> {
+    std::thread::sleep(random_election_delay());
+
+    let (host, port, runid, epoch, needed) = {
+        let mut state = sentinel.state.lock().unwrap();
+        state.current_epoch += 1;
+        let epoch = state.current_epoch;
+        let runid = state.runid.clone();
+        let failover_timeout = sentinel.settings.failover_timeout;
+        let master = state.master_mut(name)?;
+        // a peer may have asked for our vote while we were waiting
+        if !master.odown || !master.is_failover_allowed(failover_timeout) {
+            return None;
+        }
+        master.leader = Some((runid.clone(), epoch));
+        master.failover_start_time = Some(Instant::now());
+        let needed = sentinel.settings.votes_needed(master.quorum);
+        println!("INFO: +try-failover master {name} for epoch {epoch}");
+        let (host, port) = master.master.address();
+        (host, port, runid, epoch, needed)
+    };
+
+    let replies = ask_peers(sentinel, links, &host, port, epoch, &runid);
+    let votes = 1 + replies
+        .iter()
+        .filter(|(_, leader, leader_epoch)| *leader == runid && *leader_epoch == epoch)
+        .count();
+    if votes < needed {
+        println!("INFO: -failover-abort-not-elected master {name} ({votes}/{needed} votes)");
+        return None;
+    }
+    println!("INFO: +elected-leader master {name} for epoch {epoch} ({votes}/{needed} votes)");
+    Some(epoch)
+}
+
+/// Picks the reachable replica which has received the most of the master's stream
+fn select_replica(sentinel: &Sentinel, name: &str) -> Option<(String, u16)> {
+    let state = sentinel.state.lock().unwrap();
+    let master = state.master(name)?;
+    master
+        .replicas
+        .iter()
+        .filter(|replica| !replica.is_sdown(sentinel.settings.down_after))
+        .filter_map(|replica| match &replica.info {
+            Some(info) if !info.is_master => Some((info.repl_offset, replica)),
+            _ => None,
+        })
+        .max_by(|(a_offset, a), (b_offset, b)| {
+            a_offset
+                .cmp(b_offset)
+                .then_with(|| (&b.host, b.port).cmp(&(&a.host, a.port)))
+        })
+        .map(|(_, replica)| replica.address())
+}
+
+/// Waits for the promoted replica to report itself as a master
+fn wait_for_promotion(sentinel: &Sentinel, links: &mut Links, host: &str, port: u16) -> bool {
+    let deadline = Instant::now() + sentinel.settings.failover_timeout;
+    while Instant::now() < deadline {
+        if links.info(host, port).is_some_and(|info| info.is_master) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+fn set_failover_in_progress(sentinel: &Sentinel, name: &str, in_progress: bool) {
+    if let Some(master) = sentinel.state.lock().unwrap().master_mut(name) {
+        master.failover_in_progress = in_progress;
+    }
+}
+
+/// Promotes the best replica, makes it the monitored master and repoints the others
+fn failover(sentinel: &Sentinel, name: &str, epoch: u64, links: &mut Links) {
+    let Some((host, port)) = select_replica(sentinel, name) else {
+        println!("INFO: -failover-abort-no-good-slave master {name}");
+        return;
+    };
+    set_failover_in_progress(sentinel, name, true);
+
+    println!("INFO: +promoted-slave {name} {host}:{port}");
+    let promoted = links
+        .command(&host, port, &["REPLICAOF", "NO", "ONE"])
+        .and_then(|()| {
+            if wait_for_promotion(sentinel, links, &host, port) {
+                Ok(())
+            } else {
+                Err("timed out waiting for the promotion".to_string())
+            }
+        });
+    if let Err(reason) = promoted {
+        eprintln!("ERROR: failed to promote {host}:{port}: {reason}");
+        set_failover_in_progress(sentinel, name, false);
+        return;
+    }
+
+    let replicas = {
+        let mut state = sentinel.state.lock().unwrap();
+        let Some(master) = state.master_mut(name) else {
+            return;
+        };
+        let previous = master.master.address();
+        master.switch_master(&host, port, epoch);
+        master
+            .replicas
+            .iter()
+            .map(|replica| replica.address())
+            .filter(|address| *address != previous)
+            .collect::<Vec<_>>()
+    };
+    send_hello(sentinel, name, links);
+
+    let port = port.to_string();
+    for (replica_host, replica_port) in replicas {
+        println!("INFO: +slave-reconf-sent {name} {replica_host}:{replica_port}");
+        let request = ["REPLICAOF", host.as_str(), port.as_str()];
+        if let Err(reason) = links.command(&replica_host, replica_port, &request) {
+            eprintln!("ERROR: failed to reconfigure {replica_host}:{replica_port}: {reason}");
+        }
+    }
+    println!("INFO: +failover-end master {name}");
+}
+
+fn tick(sentinel: &Sentinel, name: &str, links: &mut Links) {
+    refresh_instances(sentinel, name, links);
+    send_hello(sentinel, name, links);
+    reconfigure_replicas(sentinel, name, links);
+
+    if !check_master_down(sentinel, name, links) {
+        return;
+    }
+    let failover_allowed = sentinel
+        .state
+        .lock()
+        .unwrap()
+        .master(name)
+        .is_some_and(|master| master.is_failover_allowed(sentinel.settings.failover_timeout));
+    if !failover_allowed {
+        return;
+    }
+    if let Some(epoch) = run_election(sentinel, name, links) {
+        failover(sentinel, name, epoch, links);
+    }
+}
+
+/// Starts the thread watching over the master monitored as `name`
+pub fn start(sentinel: Arc<Sentinel>, name: String) {
+    // check several times per down-after period, to notice failures in time
+    let period = (sentinel.settings.down_after / 4)
+        .clamp(Duration::from_millis(100), Duration::from_secs(1));
+
+    std::thread::spawn(move || {
+        let mut links = Links::default();
+        loop {
+            let started = Instant::now();
+            tick(&sentinel, &name, &mut links);
+            std::thread::sleep(period.saturating_sub(started.elapsed()));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        network::connection::Connection,
+        sentinel::{handler, state::SentinelSettings},
+        server::{
+            config::MonitorConfig,
+            data::{tests::test_server, LiveData, Server},
+            metadata::{MasterInfo, ReplicaInfo, SlaveInfo},
+            session,
+        },
+    };
+
+    const NAME: &str = "mymaster";
+    /// Long enough for nothing to go down on its own while a test runs
+    const DOWN_AFTER: Duration = Duration::from_secs(10);
+
+    /// A port nothing listens on
+    fn dead_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// A server accepting clients in the background, a replica of the master at
+    /// `master_port` unless `None`
+    fn start_server(master_port: Option<u16>) -> (Arc<Server>, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let replica_info = match master_port {
+            Some(master_port) => ReplicaInfo::Slave(SlaveInfo {
+                master_host: "127.0.0.1".to_string(),
+                master_port,
+            }),
+            None => ReplicaInfo::Master(MasterInfo::new()),
+        };
+        let server = Arc::new(test_server(&["--port", &port.to_string()], replica_info));
+        let clients = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = clients.clone();
+                std::thread::spawn(move || {
+                    session::handle_connection(&mut Connection::new(stream), server, None)
+                });
+            }
+        });
+        (server, port)
+    }
+
+    /// A sentinel monitoring the master at `master_port` with the given quorum
+    fn new_sentinel(master_port: u16, quorum: usize, peers: &[u16]) -> Arc<Sentinel> {
+        let settings = SentinelSettings {
+            listening_port: dead_port(),
+            announce_ip: None,
+            peers: peers
+                .iter()
+                .map(|port| ("127.0.0.1".to_string(), *port))
+                .collect(),
+            down_after: DOWN_AFTER,
+            failover_timeout: Duration::from_secs(5),
+        };
+        let monitor = MonitorConfig {
+            name: NAME.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: master_port,
+            quorum,
+        };
+        Arc::new(Sentinel::new(settings, &[monitor]))
+    }
+
+    /// Peer sentinels answering in the background, returned with their ports
+    fn start_peers(master_port: u16, count: usize) -> Vec<(Arc<Sentinel>, u16)> {
+        (0..count)
+            .map(|_| {
+                let sentinel = new_sentinel(master_port, 2, &[]);
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = listener.local_addr().unwrap().port();
+                let peer = sentinel.clone();
+                std::thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        let peer = peer.clone();
+                        std::thread::spawn(move || {
+                            handler::handle_connection(&mut Connection::new(stream), peer)
+                        });
+                    }
+                });
+                (sentinel, port)
+            })
+            .collect()
+    }
+
+    fn set_master_sdown(sentinel: &Sentinel, sdown: bool) {
+        let mut state = sentinel.state.lock().unwrap();
+        let master = state.master_mut(NAME).unwrap();
+        master.master.last_ok_ping = match sdown {
+            true => Instant::now() - DOWN_AFTER * 2,
+            false => Instant::now(),
+        };
+    }
+
+    /// Master our in-process server replicates from, `None` if it is a master
+    fn master_port_of(server: &Server) -> Option<u16> {
+        match &*server.live_data.lock().unwrap() {
+            LiveData::Slave(data) => Some(data.info.master_port),
+            LiveData::Master(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_odown_needs_quorum() {
+        let master_port = dead_port();
+        let peers = start_peers(master_port, 2);
+        let peer_ports = peers.iter().map(|(_, port)| *port).collect::<Vec<_>>();
+        let sentinel = new_sentinel(master_port, 2, &peer_ports);
+        let mut links = Links::default();
+
+        assert!(!check_master_down(&sentinel, NAME, &mut links));
+        // down for us alone is not enough
+        set_master_sdown(&sentinel, true);
+        assert!(!check_master_down(&sentinel, NAME, &mut links));
+        assert!(!sentinel.state.lock().unwrap().master(NAME).unwrap().odown);
+
+        set_master_sdown(&peers[0].0, true);
+        assert!(check_master_down(&sentinel, NAME, &mut links));
+        assert!(sentinel.state.lock().unwrap().master(NAME).unwrap().odown);
+
+        // back up as far as we can tell
+        set_master_sdown(&sentinel, false);
+        assert!(!check_master_down(&sentinel, NAME, &mut links));
+        assert!(!sentinel.state.lock().unwrap().master(NAME).unwrap().odown);
+    }
+
+    #[test]
+    fn test_election_needs_majority() {
+        let master_port = dead_port();
+        let peers = start_peers(master_port, 2);
+        let peer_ports = peers.iter().map(|(_, port)| *port).collect::<Vec<_>>();
+        let sentinel = new_sentinel(master_port, 2, &peer_ports);
+        let mut links = Links::default();
+        let runid = sentinel.state.lock().unwrap().runid.clone();
+
+        // not objectively down, nothing to lead
+        assert_eq!(run_election(&sentinel, NAME, &mut links), None);
+
+        sentinel
+            .state
+            .lock()
+            .unwrap()
+            .master_mut(NAME)
+            .unwrap()
+            .odown = true;
+        let epoch = run_election(&sentinel, NAME, &mut links).unwrap();
+        for (peer, _) in &peers {
+            let state = peer.state.lock().unwrap();
+            let leader = state.master(NAME).unwrap().leader.clone();
+            assert_eq!(leader, Some((runid.clone(), epoch)));
+        }
+
+        // the peers already voted for someone else in the next epoch
+        for (peer, _) in &peers {
+            peer.state.lock().unwrap().is_master_down_by_addr(
+                "127.0.0.1",
+                master_port,
+                epoch + 1,
+                "other",
+                Duration::from_secs(30),
+            );
+        }
+        sentinel
+            .state
+            .lock()
+            .unwrap()
+            .master_mut(NAME)
+            .unwrap()
+            .failover_start_time = None;
+        assert_eq!(run_election(&sentinel, NAME, &mut links), None);
+    }
+
+    #[test]
+    fn test_failover_promotes_replica_and_repoints_others() {
+        let master_port = dead_port();
+        let (first, first_port) = start_server(Some(master_port));
+        let (second, second_port) = start_server(Some(master_port));
+        let peers = start_peers(master_port, 1);
+        let sentinel = new_sentinel(master_port, 1, &[peers[0].1]);
+        let mut links = Links::default();
+        let replicas = [
+            ("127.0.0.1".to_string(), first_port),
+            ("127.0.0.1".to_string(), second_port),
+        ];
+        sentinel
+            .state
+            .lock()
+            .unwrap()
+            .master_mut(NAME)
+            .unwrap()
+            .add_replicas(&replicas);
+        refresh_instances(&sentinel, NAME, &mut links);
+
+        failover(&sentinel, NAME, 1, &mut links);
+
+        // both are at offset 0, the lowest address wins
+        let (promoted, promoted_port, other) = match first_port < second_port {
+            true => (&first, first_port, &second),
+            false => (&second, second_port, &first),
+        };
+        assert_eq!(master_port_of(promoted), None);
+        assert_eq!(master_port_of(other), Some(promoted_port));
+        {
+            let state = sentinel.state.lock().unwrap();
+            let master = state.master(NAME).unwrap();
+            assert!(master.master.is_at("127.0.0.1", promoted_port));
+            assert_eq!(master.config_epoch, 1);
+            assert!(!master.failover_in_progress);
+            assert!(master
+                .replicas
+                .iter()
+                .any(|replica| replica.is_at("127.0.0.1", master_port)));
+        }
+        // the peer adopted the new master from our hello
+        let state = peers[0].0.state.lock().unwrap();
+        let master = state.master(NAME).unwrap();
+        assert!(master.master.is_at("127.0.0.1", promoted_port));
+        assert_eq!(master.config_epoch, 1);
+    }
+
+    #[test]
+    fn test_misconfigured_replica_is_repointed() {
+        let (_master, master_port) = start_server(None);
+        let (replica, replica_port) = start_server(Some(dead_port()));
+        let sentinel = new_sentinel(master_port, 1, &[]);
+        let mut links = Links::default();
+        sentinel
+            .state
+            .lock()
+            .unwrap()
+            .master_mut(NAME)
+            .unwrap()
+            .add_replicas(&[("127.0.0.1".to_string(), replica_port)]);
+        refresh_instances(&sentinel, NAME, &mut links);
+
+        // left alone for the grace period
+        reconfigure_replicas(&sentinel, NAME, &mut links);
+        assert_ne!(master_port_of(&replica), Some(master_port));
+
+        {
+            let mut state = sentinel.state.lock().unwrap();
+            let master = state.master_mut(NAME).unwrap();
+            let instance = master.instance_mut("127.0.0.1", replica_port).unwrap();
+            instance.misconfigured_since = Some(Instant::now() - RECONFIGURE_GRACE * 2);
+        }
+        reconfigure_replicas(&sentinel, NAME, &mut links);
+        assert_eq!(master_port_of(&replica), Some(master_port));
+    }
+
+    #[test]
+    fn test_hello_announces_address_of_link() {
+        let master_port = dead_port();
+        let peers = start_peers(master_port, 1);
+        let sentinel = new_sentinel(master_port, 1, &[peers[0].1]);
+        let mut links = Links::default();
+        assert_eq!(
+            links.local_ip("127.0.0.1", peers[0].1),
+            Some("127.0.0.1".to_string())
+        );
+
+        sentinel
+            .state
+            .lock()
+            .unwrap()
+            .master_mut(NAME)
+            .unwrap()
+            .switch_master("127.0.0.1", 7000, 3);
+        send_hello(&sentinel, NAME, &mut links);
+        let state = peers[0].0.state.lock().unwrap();
+        let master = state.master(NAME).unwrap();
+        assert!(master.master.is_at("127.0.0.1", 7000));
+        assert_eq!(master.config_epoch, 3);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::random_hex_id,
+    parser::command::SentinelHello,
+    server::config::{Config, MonitorConfig},
+};
+
+const RUNID_LEN: usize = 40;
+
+#[derive(Debug)]
+pub struct SentinelSettings {
+    pub listening_port: u16,
+    /// Address announced to peers in hello messages, `None` to announce the address of
+    /// our connection to each of them
+    pub announce_ip: Option<String>,
+    /// Other sentinels monitoring the same masters
+    pub peers: Vec<(String, u16)>,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+}
+
+impl SentinelSettings {
+    pub fn generate(config: &Config) -> Self {
+        Self {
+            listening_port: config.get_listening_port(),
+            announce_ip: config.get_sentinel_announce_ip(),
+            peers: config.get_sentinel_peers(),
+            down_after: config.get_sentinel_down_after(),
+            failover_timeout: config.get_sentinel_failover_timeout(),
+        }
+    }
+
+    /// Votes a sentinel needs to lead a failover: a majority of all sentinels, and at
+    /// least the quorum
+    pub fn votes_needed(&self, quorum: usize) -> usize {
+        let sentinels = self.peers.len() + 1;
+        quorum.max(sentinels / 2 + 1)
+    }
+}
+
+/// Replication state of an instance, as reported by its `INFO replication`
+#[derive(Debug, Default, PartialEq)]
+pub struct InstanceInfo {
+    pub is_master: bool,
+    /// Master the instance replicates from, when it is a replica
+    pub master: Option<(String, u16)>,
+    pub master_link_up: bool,
+    pub repl_offset: usize,
+    /// Replicas attached to the instance, when it is a master
+    pub replicas: Vec<(String, u16)>,
+}
+
+impl InstanceInfo {
+    pub fn parse(info: &str) -> Self {
+        let mut parsed = InstanceInfo::default();
+        let mut master_host = None;
+        let mut master_port = None;
+        for line in info.lines() {
+            let Some((field, value)) = line.trim().split_once(':') else {
+                continue;
+            };
+            match field {
+                "role" => parsed.is_master = value == "master",
+                "master_host" => master_host = Some(value.to_string()),
+                "master_port" => master_port = value.parse().ok(),
+                "master_link_status" => parsed.master_link_up = value == "up",
+                "slave_repl_offset" | "master_repl_offset" => {
+                    parsed.repl_offset = value.parse().unwrap_or(0)
+                }
+                _ if field.starts_with("slave") && field[5..].parse::<usize>().is_ok() => {
+                    let mut ip = None;
+                    let mut port = None;
+                    for property in value.split(',') {
+                        match property.split_once('=') {
+                            Some(("ip", value)) => ip = Some(value.to_string()),
+                            Some(("port", value)) => port = value.parse().ok(),
+                            _ => {}
+                        }
+                    }
+                    if let (Some(ip), Some(port)) = (ip, port) {
+                        parsed.replicas.push((ip, port));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let (Some(host), Some(port)) = (master_host, master_port) {
+            parsed.master = Some((host, port));
+        }
+        parsed
+    }
+}
+
+/// What a sentinel knows about one monitored instance, a master or one of its replicas
+pub struct Instance {
+    pub host: String,
+    pub port: u16,
+    /// Last time the instance gave a valid reply to PING, starting out as the time we
+    /// learnt about it so that it gets a full down-after period to answer
+    pub last_ok_ping: Instant,
+    pub info: Option<InstanceInfo>,
+    /// Since when a replica has been following another master than the one we monitor
+    pub misconfigured_since: Option<Instant>,
+}
+
+impl Instance {
+    pub fn new(host: String, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            last_ok_ping: Instant::now(),
+            info: None,
+            misconfigured_since: None,
+        }
+    }
+
+    pub fn is_at(&self, host: &str, port: u16) -> bool {
+        self.host == host && self.port == port
+    }
+
+    pub fn address(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+
+    /// Whether the instance is subjectively down, as far as this sentinel can tell
+    pub fn is_sdown(&self, down_after: Duration) -> bool {
+        self.last_ok_ping.elapsed() > down_after
+    }
+}
+
+pub struct MonitoredMaster {
+    pub name: String,
+    pub master: Instance,
+    pub quorum: usize,
+    pub replicas: Vec<Instance>,
+    /// Epoch of the failover which produced the current master, 0 for the configured one
+    pub config_epoch: u64,
+    /// Sentinel we voted for to lead a failover of this master, and the epoch of the vote
+    pub leader: Option<(String, u64)>,
+    /// Whether enough sentinels agree the master is down
+    pub odown: bool,
+    pub failover_in_progress: bool,
+    /// Last time we started or voted for a failover, a new one waits twice the failover timeout
+    pub failover_start_time: Option<Instant>,
+}
+
+impl MonitoredMaster {
+    pub fn new(config: &MonitorConfig) -> Self {
+        Self {
+            name: config.name.clone(),
+            master: Instance::new(config.host.clone(), config.port),
+            quorum: config.quorum,
+            replicas: Vec::new(),
+            config_epoch: 0,
+            leader: None,
+            odown: false,
+            failover_in_progress: false,
+            failover_start_time: None,
+        }
+    }
+
+    pub fn instance_mut(&mut self, host: &str, port: u16) -> Option<&mut Instance> {
+        if self.master.is_at(host, port) {
+            return Some(&mut self.master);
+        }
+        self.replicas
+            .iter_mut()
+            .find(|replica| replica.is_at(host, port))
+    }
+
+    /// Starts tracking replicas the master reports which we do not know about yet
+    pub fn add_replicas(&mut self, replicas: &[(String, u16)]) {
+        for (host, port) in replicas {
+            if self.instance_mut(host, *port).is_none() {
+                println!("INFO: +slave {} {host}:{port}", self.name);
+                self.replicas.push(Instance::new(host.clone(), *port));
+            }
+        }
+    }
+
+    /// Makes `host:port` the master as of `config_epoch`. The previous master stays
+    /// known as a replica, to be reconfigured whenever it comes back.
+    pub fn switch_master(&mut self, host: &str, port: u16, config_epoch: u64) {
+        println!(
+            "INFO: +switch-master {} {} {} {host} {port}",
+            self.name, self.master.host, self.master.port
+        );
+        let master = match self.replicas.iter().position(|r| r.is_at(host, port)) {
+            Some(index) => self.replicas.remove(index),
+            None => Instance::new(host.to_string(), port),
+        };
+        let mut previous = std::mem::replace(&mut self.master, master);
+        previous.misconfigured_since = None;
+        self.replicas.push(previous);
+
+        self.master.last_ok_ping = Instant::now();
+        self.master.misconfigured_since = None;
+        self.config_epoch = config_epoch;
+        self.odown = false;
+        self.failover_in_progress = false;
+    }
+
+    pub fn is_failover_allowed(&self, failover_timeout: Duration) -> bool {
+        !self.failover_in_progress
+            && self
+                .failover_start_time
+                .is_none_or(|time| time.elapsed() > failover_timeout * 2)
+    }
+}
+
+pub struct SentinelState {
+    pub runid: String,
+    /// Highest epoch seen from any sentinel, each failover attempt starts a new one
+    pub current_epoch: u64,
+    pub masters: Vec<MonitoredMaster>,
+}
+
+impl SentinelState {
+    pub fn master(&self, name: &str) -> Option<&MonitoredMaster> {
+        self.masters.iter().find(|master| master.name == name)
+    }
+
+    pub fn master_mut(&mut self, name: &str) -> Option<&mut MonitoredMaster> {
+        self.masters.iter_mut().find(|master| master.name == name)
+    }
+
+    /// Answers a peer asking whether the master at `host:port` is down. A peer passing
+    /// its `runid` also asks for our vote to lead the failover for `req_epoch`, which
+    /// we give to the first one asking in each epoch.
+    /// Returns whether we see the master down, and the leader we voted for with its epoch.
+    pub fn is_master_down_by_addr(
+        &mut self,
+        host: &str,
+        port: u16,
+        req_epoch: u64,
+        runid: &str,
+        down_after: Duration,
+    ) -> (bool, String, u64) {
+        if req_epoch > self.current_epoch {
+            self.current_epoch = req_epoch;
+        }
+        let current_epoch = self.current_epoch;
+        let my_runid = self.runid.clone();
+
+        let Some(master) = self
+            .masters
+            .iter_mut()
+            .find(|master| master.master.is_at(host, port))
+        else {
+            return (false, "*".to_string(), 0);
+        };
+        let down = master.master.is_sdown(down_after);
+        if runid == "*" {
+            return (down, "*".to_string(), 0);
+        }
+
+        let voted_epoch = master.leader.as_ref().map_or(0, |(_, epoch)| *epoch);
+        if voted_epoch < req_epoch && current_epoch <= req_epoch {
+            println!(
+                "INFO: +vote-for-leader {runid} {req_epoch} for master {}",
+                master.name
+            );
+            master.leader = Some((runid.to_string(), req_epoch));
+            if runid != my_runid {
+                // leave the voted sentinel time to carry out its failover
+                master.failover_start_time = Some(Instant::now());
+            }
+        }
+        let (leader, epoch) = master.leader.clone().unwrap_or(("*".to_string(), 0));
+        (down, leader, epoch)
+    }
+
+    /// Applies the configuration a peer announced, adopting its view of the master if it
+    /// comes from a newer failover than ours
+    pub fn apply_hello(&mut self, hello: &SentinelHello) {
+        if hello.current_epoch > self.current_epoch {
+            self.current_epoch = hello.current_epoch;
+        }
+        let Some(master) = self.master_mut(&hello.master_name) else {
+            return;
+        };
+        if hello.master_config_epoch > master.config_epoch {
+            master.switch_master(
+                &hello.master_host,
+                hello.master_port,
+                hello.master_config_epoch,
+            );
+        }
+    }
+}
+
+pub struct Sentinel {
+    pub settings: SentinelSettings,
+    pub state: Mutex<SentinelState>,
+}
+
+impl Sentinel {
+    pub fn new(settings: SentinelSettings, monitors: &[MonitorConfig]) -> Self {
+        let state = SentinelState {
+            runid: random_hex_id(RUNID_LEN),
+            current_epoch: 0,
+            masters: monitors.iter().map(MonitoredMaster::new).collect(),
+        };
+        Self {
+            settings,
+            state: Mutex::new(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_master_info() {
+        let info = "role:master\r\nconnected_slaves:2\r\n\
+                    slave0:ip=127.0.0.1,port=6380,state=online,offset=42,lag=0\r\n\
+                    slave1:ip=127.0.0.1,port=6381,state=wait_bgsave,offset=0,lag=1\r\n\
+                    master_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb\r\n\
+                    master_repl_offset:42";
+        let info = InstanceInfo::parse(info);
+        assert!(info.is_master);
+        assert_eq!(info.repl_offset, 42);
+        assert_eq!(
+            info.replicas,
+            vec![
+                ("127.0.0.1".to_string(), 6380),
+                ("127.0.0.1".to_string(), 6381)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_replica_info() {
+        let info = "role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\n\
+                    master_link_status:up\r\nslave_repl_offset:1337\r\nconnected_slaves:0";
+        let info = InstanceInfo::parse(info);
+        assert_eq!(
+            info,
+            InstanceInfo {
+                is_master: false,
+                master: Some(("127.0.0.1".to_string(), 6379)),
+                master_link_up: true,
+                repl_offset: 1337,
+                replicas: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_vote_for_first_leader_in_epoch() {
+        let mut state = SentinelState {
+            runid: "me".to_string(),
+            current_epoch: 0,
+            masters: vec![MonitoredMaster::new(&MonitorConfig {
+                name: "mymaster".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 6379,
+                quorum: 2,
+            })],
+        };
+        let down_after = Duration::from_secs(30);
+
+        let vote = state.is_master_down_by_addr("127.0.0.1", 6379, 1, "a", down_after);
+        assert_eq!(vote, (false, "a".to_string(), 1));
+        let vote = state.is_master_down_by_addr("127.0.0.1", 6379, 1, "b", down_after);
+        assert_eq!(vote, (false, "a".to_string(), 1));
+        let vote = state.is_master_down_by_addr("127.0.0.1", 6379, 2, "b", down_after);
+        assert_eq!(vote, (false, "b".to_string(), 2));
+        assert_eq!(state.current_epoch, 2);
+    }
+}
@@ -1,16 +1,172 @@
-use clap::Parser;
+use std::{net::IpAddr, time::Duration};
+
+use clap::{Parser, ValueEnum};
+
+use crate::persistence::{parse_save_points, SavePoint, DEFAULT_SAVE_POINTS};
+
+/// How a replica loads the snapshot it receives from its master during a full resync
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReplDisklessLoad {
+    /// Write the snapshot to a temporary file first, then load it from disk
+    Disabled,
+    /// Decode the snapshot straight off the socket and swap it in once complete
+    Swapdb,
+}
+
+/// When the append-only file is flushed to disk
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    /// After every write, which loses nothing on a crash but is slow
+    Always,
+    /// Once per second from a background thread, losing at most a second of writes
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Config {
     #[arg(short, long, default_value_t = 6379)]
     port: u16,
+    /// Address the listeners bind to
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
     #[arg(short, long)]
     replicaof: Option<String>,
     #[arg(long)]
     dir: Option<String>,
     #[arg(long)]
     dbfilename: Option<String>,
+    /// `<seconds> <changes>` pairs, the dataset is saved once `changes` writes were made
+    /// and `seconds` passed since the last save. May be repeated, `""` disables saving.
+    #[arg(long)]
+    save: Vec<String>,
+    #[arg(long, default_value_t = 1024 * 1024)]
+    repl_backlog_size: usize,
+    /// Seconds without any traffic before a replication link is considered dead
+    #[arg(long, default_value_t = 60)]
+    repl_timeout: u64,
+    /// Seconds between the PINGs a master sends down the replication stream
+    #[arg(long, default_value_t = 10)]
+    repl_ping_replica_period: u64,
+    /// Writes are refused unless at least this many replicas are connected with an
+    /// acceptable lag, 0 disables the check
+    #[arg(long, default_value_t = 0)]
+    min_replicas_to_write: usize,
+    /// Seconds since its last ACK for a replica to still count towards min-replicas-to-write
+    #[arg(long, default_value_t = 10)]
+    min_replicas_max_lag: u64,
+    /// Whether a replica refuses writes from its own clients
+    #[arg(
+        long,
+        default_value = "yes",
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    replica_read_only: bool,
+    #[arg(long, value_enum, default_value_t = ReplDisklessLoad::Disabled)]
+    repl_diskless_load: ReplDisklessLoad,
+    /// Whether every write is logged to an append-only file, replayed on startup
+    #[arg(
+        long,
+        default_value = "no",
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    appendonly: bool,
+    /// Prefix of the files making up the append-only file
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+    /// Directory holding the append-only file, relative to --dir
+    #[arg(long, default_value = "appendonlydir")]
+    appenddirname: String,
+    #[arg(long, value_enum, default_value_t = AppendFsync::Everysec)]
+    appendfsync: AppendFsync,
+    /// Whether a rewrite stores the dataset as an RDB snapshot rather than as commands
+    #[arg(
+        long,
+        default_value = "yes",
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    aof_use_rdb_preamble: bool,
+    /// Growth of the append-only file since the last rewrite, in percent of its size back
+    /// then, which triggers a new rewrite. 0 disables automatic rewrites.
+    #[arg(long, default_value_t = 100)]
+    auto_aof_rewrite_percentage: u64,
+    /// Size below which the append-only file is never rewritten automatically
+    #[arg(long, default_value = "64mb", value_parser = parse_memory_size)]
+    auto_aof_rewrite_min_size: u64,
+    /// Whether keys are spread over the nodes of a cluster by hash slot
+    #[arg(
+        long,
+        default_value = "no",
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    cluster_enabled: bool,
+    /// File where a cluster node keeps its view of the cluster, relative to --dir
+    #[arg(long, default_value = "nodes.conf")]
+    cluster_config_file: String,
+    /// Milliseconds a cluster node may go without answering pings before it is
+    /// suspected to be failing
+    #[arg(long, default_value_t = 15000)]
+    cluster_node_timeout: u64,
+    /// Run as a sentinel watching over the masters given with --sentinel-monitor
+    #[arg(long)]
+    sentinel: bool,
+    /// `<name> <host> <port> <quorum>` of a master to monitor, may be repeated
+    #[arg(long)]
+    sentinel_monitor: Vec<String>,
+    /// `<host> <port>` of another sentinel monitoring the same masters, may be repeated
+    #[arg(long)]
+    sentinel_peer: Vec<String>,
+    /// Milliseconds without a valid reply to PING before an instance is considered down
+    #[arg(long, default_value_t = 30000)]
+    sentinel_down_after_ms: u64,
+    /// Address a sentinel announces to its peers, the bind address unless it is a
+    /// wildcard one
+    #[arg(long)]
+    sentinel_announce_ip: Option<String>,
+    /// Milliseconds a failover may take, failed failovers are retried after twice as long
+    #[arg(long, default_value_t = 180000)]
+    sentinel_failover_timeout_ms: u64,
+}
+
+/// Parses a size like `64mb`, where `k`, `m` and `g` are powers of 1000 and `kb`, `mb`
+/// and `gb` powers of 1024
+pub fn parse_memory_size(value: &str) -> Result<u64, String> {
+    let value = value.to_ascii_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory unit {unit:?}")),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory size {value:?}"))
+}
+
+/// A master a sentinel is told to monitor
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    /// Number of sentinels which have to agree the master is down before failing over
+    pub quorum: usize,
 }
 
 impl Default for Config {
@@ -28,6 +184,10 @@ impl Config {
         self.port
     }
 
+    pub fn get_bind(&self) -> &str {
+        &self.bind
+    }
+
     pub fn master_address(&self) -> Option<(String, u16)> {
         if let Some(ref address) = self.replicaof {
             let parts = address.split_whitespace().collect::<Vec<_>>();
@@ -56,4 +216,155 @@ impl Config {
     pub fn get_dbfilename(&self) -> Option<&str> {
         self.dbfilename.as_deref()
     }
+
+    pub fn get_save_points(&self) -> Vec<SavePoint> {
+        if self.save.is_empty() {
+            return parse_save_points(DEFAULT_SAVE_POINTS).unwrap_or_default();
+        }
+        self.save
+            .iter()
+            .flat_map(|save| {
+                parse_save_points(save).unwrap_or_else(|err| {
+                    eprintln!("Invalid save points {save:?}: {err}. Expected <seconds> <changes>");
+                    Vec::new()
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_repl_backlog_size(&self) -> usize {
+        self.repl_backlog_size
+    }
+
+    pub fn get_repl_timeout(&self) -> Duration {
+        Duration::from_secs(self.repl_timeout)
+    }
+
+    pub fn get_repl_ping_replica_period(&self) -> Duration {
+        Duration::from_secs(self.repl_ping_replica_period)
+    }
+
+    pub fn get_min_replicas_to_write(&self) -> usize {
+        self.min_replicas_to_write
+    }
+
+    pub fn get_min_replicas_max_lag(&self) -> u64 {
+        self.min_replicas_max_lag
+    }
+
+    pub fn is_replica_read_only(&self) -> bool {
+        self.replica_read_only
+    }
+
+    pub fn get_repl_diskless_load(&self) -> ReplDisklessLoad {
+        self.repl_diskless_load
+    }
+
+    pub fn is_appendonly(&self) -> bool {
+        self.appendonly
+    }
+
+    pub fn get_appendfilename(&self) -> &str {
+        &self.appendfilename
+    }
+
+    pub fn get_appenddirname(&self) -> &str {
+        &self.appenddirname
+    }
+
+    pub fn get_appendfsync(&self) -> AppendFsync {
+        self.appendfsync
+    }
+
+    pub fn is_aof_use_rdb_preamble(&self) -> bool {
+        self.aof_use_rdb_preamble
+    }
+
+    pub fn get_auto_aof_rewrite_percentage(&self) -> u64 {
+        self.auto_aof_rewrite_percentage
+    }
+
+    pub fn get_auto_aof_rewrite_min_size(&self) -> u64 {
+        self.auto_aof_rewrite_min_size
+    }
+
+    pub fn is_cluster_enabled(&self) -> bool {
+        self.cluster_enabled
+    }
+
+    pub fn get_cluster_config_file(&self) -> &str {
+        &self.cluster_config_file
+    }
+
+    pub fn get_cluster_node_timeout(&self) -> Duration {
+        Duration::from_millis(self.cluster_node_timeout)
+    }
+
+    pub fn is_sentinel(&self) -> bool {
+        self.sentinel
+    }
+
+    pub fn get_sentinel_monitors(&self) -> Vec<MonitorConfig> {
+        self.sentinel_monitor
+            .iter()
+            .filter_map(|monitor| {
+                let parts = monitor.split_whitespace().collect::<Vec<_>>();
+                match parts.as_slice() {
+                    [name, host, port, quorum] => match (port.parse(), quorum.parse()) {
+                        (Ok(port), Ok(quorum)) if quorum > 0 => Some(MonitorConfig {
+                            name: name.to_string(),
+                            host: host.to_string(),
+                            port,
+                            quorum,
+                        }),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+                .or_else(|| {
+                    eprintln!(
+                        "Invalid sentinel monitor {monitor:?}. Expected <name> <host> <port> <quorum>"
+                    );
+                    None
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_sentinel_peers(&self) -> Vec<(String, u16)> {
+        self.sentinel_peer
+            .iter()
+            .filter_map(|peer| {
+                let parts = peer.split_whitespace().collect::<Vec<_>>();
+                match parts.as_slice() {
+                    [host, port] => port.parse().ok().map(|port| (host.to_string(), port)),
+                    _ => None,
+                }
+                .or_else(|| {
+                    eprintln!("Invalid sentinel peer {peer:?}. Expected <host> <port>");
+                    None
+                })
+            })
+            .collect()
+    }
+
+    /// Address to announce to peer sentinels, `None` when bound to a wildcard address,
+    /// in which case the address of our connection to each peer is announced
+    pub fn get_sentinel_announce_ip(&self) -> Option<String> {
+        let bind_is_wildcard = self
+            .bind
+            .parse::<IpAddr>()
+            .is_ok_and(|addr| addr.is_unspecified());
+        self.sentinel_announce_ip
+            .clone()
+            .or_else(|| (!bind_is_wildcard).then(|| self.bind.clone()))
+    }
+
+    pub fn get_sentinel_down_after(&self) -> Duration {
+        Duration::from_millis(self.sentinel_down_after_ms)
+    }
+
+    pub fn get_sentinel_failover_timeout(&self) -> Duration {
+        Duration::from_millis(self.sentinel_failover_timeout_ms)
+    }
 }
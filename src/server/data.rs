@@ -1,24 +1,158 @@
 use std::{
+    io::Write,
     net::TcpStream,
-    sync::Mutex,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
     time::{Duration, Instant},
 };
 
 use crate::{
-    network::connection::Connection, replication::replica_manager::ReplicaManager,
-    storage::expiring_map::ExpiringHashMap,
+    cluster::state::ClusterState,
+    common::CRLF,
+    network::connection::Connection,
+    parser::{
+        command::{Command, ReplConfCommand},
+        rdb::RdbEntry,
+        resp::Token,
+    },
+    persistence::{self, aof::AppendOnlyFile, SaveState},
+    replication::{
+        self,
+        backlog::ReplicationBacklog,
+        failover::FailoverState,
+        link::MasterLink,
+        replica_manager::{Replica, ReplicaManager},
+    },
+    storage::{expiring_map::ExpiringHashMap, value::Value},
 };
 
-use super::metadata::{ReplicaInfo, ServerMetadata};
+use super::metadata::{MasterInfo, ReplicaInfo, ServerMetadata, SlaveInfo, REPLICATION_ID_LEN};
 
 pub struct MasterLiveData {
+    pub info: MasterInfo,
     pub replication_offset: usize,
+    /// Replication offset just past the last write, which is what WAIT waits for
+    pub last_write_offset: usize,
     pub replica_manager: ReplicaManager,
+    pub backlog: ReplicationBacklog,
+    /// Set while a FAILOVER is handing the role over, client writes are paused meanwhile
+    pub failover: Option<FailoverState>,
+}
+
+impl MasterLiveData {
+    fn new(info: MasterInfo, replication_offset: usize, backlog: ReplicationBacklog) -> Self {
+        Self {
+            info,
+            replication_offset,
+            last_write_offset: replication_offset,
+            replica_manager: ReplicaManager::new(),
+            backlog,
+            failover: None,
+        }
+    }
+
+    /// Sends bytes down the replication stream, keeping the offset and backlog in step
+    pub fn feed_replication_stream(&mut self, message: &[u8]) {
+        self.replica_manager.propagate_message_to_replicas(message);
+        self.backlog.append(message);
+        self.replication_offset += message.len();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MasterLinkState {
+    /// Not connected, waiting to (re)connect
+    Connect,
+    /// Connected and performing the handshake
+    Connecting,
+    /// Receiving the snapshot from the master
+    Sync,
+    /// Streaming commands from the master
+    Connected,
+}
+
+impl MasterLinkState {
+    /// Name of the state as reported by ROLE
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MasterLinkState::Connect => "connect",
+            MasterLinkState::Connecting => "connecting",
+            MasterLinkState::Sync => "sync",
+            MasterLinkState::Connected => "connected",
+        }
+    }
 }
 
 pub struct SlaveLiveData {
+    pub info: SlaveInfo,
+    /// Handle on the thread replicating from `info`'s master
+    pub link: Arc<MasterLink>,
     pub offset: usize,
+    /// Last time anything was received from the master
     pub heartbeat_recv_time: Option<Instant>,
+    /// Replication ID of the master whose stream `offset` refers to, once synchronized
+    pub master_replid: Option<String>,
+    pub link_state: MasterLinkState,
+    pub link_down_since: Option<Instant>,
+    /// Size of the snapshot being received during a full resync, and how much of it has arrived
+    pub sync_total_bytes: usize,
+    pub sync_read_bytes: usize,
+    /// Our own replicas, which receive the master's stream exactly as we do
+    pub replica_manager: ReplicaManager,
+    pub backlog: ReplicationBacklog,
+}
+
+impl SlaveLiveData {
+    fn new(
+        info: SlaveInfo,
+        master_replid: Option<String>,
+        offset: usize,
+        backlog: ReplicationBacklog,
+    ) -> Self {
+        Self {
+            info,
+            link: MasterLink::new(),
+            offset,
+            heartbeat_recv_time: None,
+            master_replid,
+            link_state: MasterLinkState::Connect,
+            link_down_since: Some(Instant::now()),
+            sync_total_bytes: 0,
+            sync_read_bytes: 0,
+            replica_manager: ReplicaManager::new(),
+            backlog,
+        }
+    }
+
+    /// Forwards bytes of the master's stream to our replicas, keeping the offset and
+    /// backlog in step
+    pub fn feed_replication_stream(&mut self, message: &[u8]) {
+        self.replica_manager.propagate_message_to_replicas(message);
+        self.backlog.append(message);
+        self.offset += message.len();
+    }
+
+    /// Moves to a new position in the master's history. Our replicas cannot follow
+    /// the jump, so they are disconnected and will resynchronize with us.
+    pub fn reset_replication_stream(&mut self, master_replid: String, offset: usize) {
+        self.replica_manager.disconnect_all();
+        self.backlog = ReplicationBacklog::new(self.backlog.capacity(), offset);
+        self.master_replid = Some(master_replid);
+        self.offset = offset;
+    }
+
+    pub fn set_link_state(&mut self, state: MasterLinkState) {
+        match state {
+            MasterLinkState::Connected => {
+                self.link_down_since = None;
+                self.heartbeat_recv_time = Some(Instant::now());
+            }
+            _ if self.link_state == MasterLinkState::Connected => {
+                self.link_down_since = Some(Instant::now());
+            }
+            _ => {}
+        }
+        self.link_state = state;
+    }
 }
 
 pub enum LiveData {
@@ -26,17 +160,129 @@ pub enum LiveData {
     Slave(SlaveLiveData),
 }
 
+/// Where a write comes from, which decides whether it continues down the replication stream
+pub enum WriteOrigin<'a> {
+    Client,
+    /// Replicated to us over the given link
+    Master(&'a Arc<MasterLink>),
+    /// Replayed from the append-only file while loading, which must not log it again
+    Aof,
+}
+
 impl LiveData {
-    fn new(info: &ReplicaInfo) -> LiveData {
+    fn new(info: ReplicaInfo, metadata: &ServerMetadata) -> LiveData {
+        let backlog = ReplicationBacklog::new(metadata.repl_backlog_size, 0);
         match info {
-            ReplicaInfo::Master(..) => LiveData::Master(MasterLiveData {
-                replication_offset: 0,
-                replica_manager: ReplicaManager::new(),
-            }),
-            ReplicaInfo::Slave(..) => LiveData::Slave(SlaveLiveData {
-                offset: 0,
-                heartbeat_recv_time: None,
-            }),
+            ReplicaInfo::Master(info) => LiveData::Master(MasterLiveData::new(info, 0, backlog)),
+            ReplicaInfo::Slave(info) => LiveData::Slave(SlaveLiveData::new(info, None, 0, backlog)),
+        }
+    }
+
+    /// Whether client writes have to wait for a failover to finish
+    fn is_write_paused(&self) -> bool {
+        matches!(self, LiveData::Master(data) if data.failover.is_some())
+    }
+
+    pub fn replica_manager(&mut self) -> &mut ReplicaManager {
+        match self {
+            LiveData::Master(data) => &mut data.replica_manager,
+            LiveData::Slave(data) => &mut data.replica_manager,
+        }
+    }
+
+    /// How far we are in the replication stream, whether we produce it or receive it
+    pub fn replication_offset(&self) -> usize {
+        match self {
+            LiveData::Master(data) => data.replication_offset,
+            LiveData::Slave(data) => data.offset,
+        }
+    }
+
+    /// Replication ID and offset of the stream we serve to our replicas, `None` for a
+    /// replica which is not in sync with its master and has nothing to serve
+    fn replication_position(&self) -> Option<(String, usize)> {
+        match self {
+            LiveData::Master(data) => {
+                Some((data.info.replication_id.clone(), data.replication_offset))
+            }
+            LiveData::Slave(data) if data.link_state == MasterLinkState::Connected => data
+                .master_replid
+                .clone()
+                .map(|replid| (replid, data.offset)),
+            LiveData::Slave(_) => None,
+        }
+    }
+
+    pub fn get_replica_info(&self) -> Vec<u8> {
+        match self {
+            LiveData::Master(data) => {
+                let backlog = &data.backlog;
+                let replicas = data.replica_manager.get_replicas_info();
+                let mut info = format!("role:master{CRLF}connected_slaves:{}", replicas.len());
+                for (index, replica) in replicas.iter().enumerate() {
+                    info.push_str(&format!("{CRLF}slave{index}:{replica}"));
+                }
+                let failover_state = match &data.failover {
+                    Some(failover) => failover.phase.as_str(),
+                    None => "no-failover",
+                };
+                info.push_str(&format!(
+                    "{CRLF}master_failover_state:{failover_state}{CRLF}master_replid:{}{CRLF}master_replid2:{}{CRLF}\
+                     master_repl_offset:{}{CRLF}second_repl_offset:{}{CRLF}\
+                     repl_backlog_active:1{CRLF}repl_backlog_size:{}{CRLF}\
+                     repl_backlog_first_byte_offset:{}{CRLF}repl_backlog_histlen:{}",
+                    data.info.replication_id,
+                    data.info.replication_id2,
+                    data.replication_offset,
+                    data.info.second_replication_offset,
+                    backlog.capacity(),
+                    // reported counting from one, like the PSYNC offset
+                    backlog.start_offset() + 1,
+                    backlog.len(),
+                ));
+                info.into_bytes()
+            }
+            LiveData::Slave(data) => {
+                let link_up = data.link_state == MasterLinkState::Connected;
+                let last_io_seconds_ago = match (link_up, data.heartbeat_recv_time) {
+                    (true, Some(time)) => time.elapsed().as_secs() as i64,
+                    _ => -1,
+                };
+                let mut info = format!(
+                    "role:slave{CRLF}master_host:{}{CRLF}master_port:{}{CRLF}\
+                     master_link_status:{}{CRLF}master_last_io_seconds_ago:{}{CRLF}\
+                     master_sync_in_progress:{}{CRLF}slave_repl_offset:{}{CRLF}master_replid:{}",
+                    data.info.master_host,
+                    data.info.master_port,
+                    if link_up { "up" } else { "down" },
+                    last_io_seconds_ago,
+                    (data.link_state == MasterLinkState::Sync) as u8,
+                    data.offset,
+                    data.master_replid
+                        .clone()
+                        .unwrap_or_else(|| "0".repeat(REPLICATION_ID_LEN)),
+                );
+                if data.link_state == MasterLinkState::Sync {
+                    info.push_str(&format!(
+                        "{CRLF}master_sync_total_bytes:{}{CRLF}master_sync_read_bytes:{}",
+                        data.sync_total_bytes, data.sync_read_bytes
+                    ));
+                }
+                if !link_up {
+                    let down_since_seconds = data
+                        .link_down_since
+                        .map_or(-1, |time| time.elapsed().as_secs() as i64);
+                    info.push_str(&format!(
+                        "{CRLF}master_link_down_since_seconds:{down_since_seconds}"
+                    ));
+                }
+                let replicas = data.replica_manager.get_replicas_info();
+                info.push_str(&format!("{CRLF}connected_slaves:{}", replicas.len()));
+                for (index, replica) in replicas.iter().enumerate() {
+                    info.push_str(&format!("{CRLF}slave{index}:{replica}"));
+                }
+                info.into_bytes()
+            }
         }
     }
 }
@@ -44,62 +290,402 @@ impl LiveData {
 pub struct Server {
     pub metadata: ServerMetadata,
     pub live_data: Mutex<LiveData>,
+    /// Signalled on `live_data` whenever a replica acknowledges an offset or the replication
+    /// state changes under the feet of whoever is waiting on it
+    pub replication_changed: Condvar,
     pub store: Mutex<ExpiringHashMap>,
+    pub save_state: Mutex<SaveState>,
+    /// Held for the whole of an RDB save, so that saves never write the file at once
+    pub save_lock: Mutex<()>,
+    /// Set when running in cluster mode
+    pub cluster: Option<Mutex<ClusterState>>,
+    /// Set when appendonly is enabled, locked after `live_data` and `store`
+    pub aof: Option<Mutex<AppendOnlyFile>>,
 }
 
 impl Server {
-    pub fn new(metadata: ServerMetadata) -> Server {
-        let live_data = Mutex::new(LiveData::new(&metadata.replica_info));
+    pub fn new(
+        metadata: ServerMetadata,
+        replica_info: ReplicaInfo,
+        cluster: Option<ClusterState>,
+        aof: Option<AppendOnlyFile>,
+    ) -> Server {
+        let live_data = Mutex::new(LiveData::new(replica_info, &metadata));
+        let save_state = Mutex::new(SaveState::new(metadata.save_points.clone()));
         Server {
             metadata,
             live_data,
+            replication_changed: Condvar::new(),
             store: Mutex::new(ExpiringHashMap::new()),
+            save_state,
+            save_lock: Mutex::new(()),
+            cluster: cluster.map(Mutex::new),
+            aof: aof.map(Mutex::new),
         }
     }
 
-    pub fn set(&self, key: &[u8], value: &[u8], expiry: Option<Duration>) {
-        self.store.lock().unwrap().set(key, value, expiry);
+    pub fn is_master(&self) -> bool {
+        matches!(&*self.live_data.lock().unwrap(), LiveData::Master(_))
+    }
+
+    pub fn set(&self, key: &[u8], value: Value, expiry: Option<Duration>) {
+        self.store.lock().unwrap().set_value(key, value, expiry);
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
         self.store.lock().unwrap().get(key)
     }
 
-    // TODO: handle replica methods without exposing the internals of the replica manager
-    pub fn add_replica(&self, stream: TcpStream) {
-        if let LiveData::Master(master_data) = &mut *self.live_data.lock().unwrap() {
-            let replica = ReplicaManager::new_replica(stream);
-            master_data.replica_manager.add_replica(replica);
-            println!(
-                "INFO: New replica connected. Total replicas: {}",
-                master_data.replica_manager.get_connected_replica_count()
-            );
+    /// Turns this server into a replica of the given master, disconnecting our own
+    /// replicas or abandoning the current master. The dataset is kept until the new
+    /// master sends its snapshot. Returns false if we already replicate from that master.
+    pub fn become_replica(self: &Arc<Self>, master_host: String, master_port: u16) -> bool {
+        {
+            let mut live_data = self.live_data.lock().unwrap();
+            // our own history is offered to the new master, which lets a replica we
+            // just handed the role to continue from where we are
+            let (master_replid, offset) = match &mut *live_data {
+                LiveData::Slave(data) => {
+                    if data.info.master_host == master_host && data.info.master_port == master_port
+                    {
+                        return false;
+                    }
+                    data.link.stop();
+                    (data.master_replid.clone(), data.offset)
+                }
+                LiveData::Master(data) => (
+                    Some(data.info.replication_id.clone()),
+                    data.replication_offset,
+                ),
+            };
+            live_data.replica_manager().disconnect_all();
+            let backlog = match &mut *live_data {
+                LiveData::Master(data) => &mut data.backlog,
+                LiveData::Slave(data) => &mut data.backlog,
+            };
+            let backlog = std::mem::replace(backlog, ReplicationBacklog::new(0, 0));
+            println!("INFO: switching to replica of {master_host}:{master_port}");
+            let info = SlaveInfo {
+                master_host,
+                master_port,
+            };
+            *live_data = LiveData::Slave(SlaveLiveData::new(info, master_replid, offset, backlog));
+            // clients blocked in WAIT can no longer be satisfied
+            self.replication_changed.notify_all();
+        }
+        replication::link::start(self);
+        true
+    }
+
+    /// Promotes a replica to master, keeping its dataset and replication history so
+    /// the other replicas of the old master can partially resynchronize with us. Our own
+    /// replicas are disconnected to make them pick up the new replication ID.
+    /// Returns false if we already are a master.
+    pub fn become_master(&self) -> bool {
+        let mut live_data = self.live_data.lock().unwrap();
+        let LiveData::Slave(data) = &mut *live_data else {
+            return false;
+        };
+        data.link.stop();
+        data.replica_manager.disconnect_all();
+        let backlog = std::mem::replace(&mut data.backlog, ReplicationBacklog::new(0, 0));
+
+        let info = match &data.master_replid {
+            Some(replid) => MasterInfo::promoted_from(replid, data.offset),
+            None => MasterInfo::new(),
+        };
+        println!(
+            "INFO: promoted to master with replication id {} at offset {}",
+            info.replication_id, data.offset
+        );
+        *live_data = LiveData::Master(MasterLiveData::new(info, data.offset, backlog));
+        true
+    }
+
+    /// Counts the keys or elements a write changed towards the save points and logs it
+    /// to the append-only file, if there is one
+    fn record_write(&self, message: &[u8], changes: usize) {
+        self.save_state.lock().unwrap().dirty += changes as u64;
+        if let Some(aof) = &self.aof {
+            aof.lock().unwrap().append(message);
+        }
+    }
+
+    /// Holds a client write back while a failover pauses writes
+    fn wait_for_write_pause<'a>(
+        &self,
+        live_data: MutexGuard<'a, LiveData>,
+    ) -> MutexGuard<'a, LiveData> {
+        self.replication_changed
+            .wait_while(live_data, |live_data| live_data.is_write_paused())
+            .unwrap()
+    }
+
+    /// Error a client write would be refused with: the append-only file cannot be
+    /// written, a master has too few good replicas or a replica is read-only
+    fn client_write_refusal(&self, live_data: &LiveData) -> Option<Token> {
+        if let Some(error) = self.aof_write_error() {
+            return Some(error);
+        }
+        match live_data {
+            LiveData::Master(master_data) => {
+                let min_replicas = self.metadata.min_replicas_to_write;
+                (min_replicas > 0
+                    && master_data
+                        .replica_manager
+                        .get_good_replicas_count(self.metadata.min_replicas_max_lag)
+                        < min_replicas)
+                    .then(|| {
+                        Token::Error("NOREPLICAS Not enough good replicas to write.".to_string())
+                    })
+            }
+            LiveData::Slave(_) if self.metadata.replica_read_only => Some(Token::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            )),
+            LiveData::Slave(_) => None,
+        }
+    }
+
+    /// Error a client write would be refused with once the current write pause is over,
+    /// for commands which must not start what they could not finish
+    pub fn client_write_error(&self) -> Option<Token> {
+        let live_data = self.wait_for_write_pause(self.live_data.lock().unwrap());
+        self.client_write_refusal(&live_data)
+    }
+
+    /// Error refusing client writes while the append-only file cannot be written
+    fn aof_write_error(&self) -> Option<Token> {
+        let aof = self.aof.as_ref()?.lock().unwrap();
+        aof.write_error
+            .as_ref()
+            .map(|err| Token::Error(format!("MISCONF Errors writing to the AOF file: {err}")))
+    }
+
+    /// Applies a write to the store and propagates it to the replicas while holding the
+    /// replication state, so a replica registered for a full resync either sees the write
+    /// in its snapshot or in the stream that follows it, never both and never neither.
+    /// Every applied write is recorded, in the same order, for persistence. `apply`
+    /// returns the reply along with how many keys or elements the write changed.
+    ///
+    /// A master propagates the writes of its clients unless they fail, holds them back
+    /// while a failover is in progress and refuses them altogether while it has fewer good
+    /// replicas than `min_replicas_to_write`. A replica forwards every write of its
+    /// master's stream, and keeps client writes to itself unless it is read-only.
+    pub fn apply_write<F>(&self, apply: F, message: &[u8], origin: WriteOrigin) -> Token
+    where
+        F: FnOnce(&ExpiringHashMap) -> (Token, usize),
+    {
+        let mut live_data = self.live_data.lock().unwrap();
+        if let WriteOrigin::Client = origin {
+            live_data = self.wait_for_write_pause(live_data);
+            if let Some(error) = self.client_write_refusal(&live_data) {
+                return error;
+            }
+        }
+        match (&mut *live_data, origin) {
+            (LiveData::Master(master_data), WriteOrigin::Client) => {
+                let (response, changes) = apply(&self.store.lock().unwrap());
+                if !matches!(response, Token::Error(_)) {
+                    master_data.feed_replication_stream(message);
+                    master_data.last_write_offset = master_data.replication_offset;
+                    self.record_write(message, changes);
+                }
+                response
+            }
+            (LiveData::Slave(slave_data), WriteOrigin::Master(link))
+                if Arc::ptr_eq(&slave_data.link, link) && !link.is_stopped() =>
+            {
+                let (response, changes) = apply(&self.store.lock().unwrap());
+                slave_data.feed_replication_stream(message);
+                if !matches!(response, Token::Error(_)) {
+                    self.record_write(message, changes);
+                }
+                response
+            }
+            (LiveData::Slave(_), WriteOrigin::Client) => {
+                let (response, changes) = apply(&self.store.lock().unwrap());
+                if !matches!(response, Token::Error(_)) {
+                    self.record_write(message, changes);
+                }
+                response
+            }
+            (_, WriteOrigin::Aof) => apply(&self.store.lock().unwrap()).0,
+            // the link was abandoned while the write was in flight
+            (_, WriteOrigin::Master(_)) => {
+                Token::Error("ERR replication link is no longer active".to_string())
+            }
+        }
+    }
+
+    /// Registers a replica which is about to receive a full resync and snapshots the
+    /// keyspace at the same point of the replication stream. Returns the replication
+    /// ID and offset the snapshot corresponds to along with the snapshot itself, or
+    /// `None` if we are a replica which is not in sync with its own master.
+    pub fn begin_full_resync(
+        &self,
+        stream: TcpStream,
+        listening_port: Option<u16>,
+    ) -> Option<(String, usize, Vec<RdbEntry>)> {
+        let mut live_data = self.live_data.lock().unwrap();
+        let (replication_id, offset) = live_data.replication_position()?;
+        live_data
+            .replica_manager()
+            .add_replica(Replica::new_syncing(stream).with_listening_port(listening_port));
+        let entries = persistence::rdb::snapshot_entries(self);
+        Some((replication_id, offset, entries))
+    }
+
+    /// Attempts to continue a replica's stream from the backlog. On success the
+    /// `+CONTINUE` reply and the missing part of the stream have been written to the
+    /// replica and it is registered as online; `Ok(false)` means a full resync is needed.
+    ///
+    /// `psync_offset` follows the Redis convention of naming the first byte the replica
+    /// is missing, counted from one.
+    pub fn try_partial_resync(
+        &self,
+        stream: TcpStream,
+        listening_port: Option<u16>,
+        replication_id: &str,
+        psync_offset: i64,
+    ) -> std::io::Result<bool> {
+        let (our_replication_id, missing) = {
+            let mut live_data = self.live_data.lock().unwrap();
+            let Some((our_replication_id, _)) = live_data.replication_position() else {
+                return Ok(false);
+            };
+            let (can_continue, backlog) = match &*live_data {
+                LiveData::Master(data) => (
+                    data.info.can_continue(replication_id, psync_offset),
+                    &data.backlog,
+                ),
+                LiveData::Slave(data) => (our_replication_id == replication_id, &data.backlog),
+            };
+            if !can_continue || psync_offset < 1 {
+                return Ok(false);
+            }
+            let Some(missing) = backlog.read_from(psync_offset as usize - 1) else {
+                return Ok(false);
+            };
+            // the writes made while the backlog is sent are buffered, as during a full resync
+            let replica = Replica::new_syncing(stream.try_clone()?);
+            live_data
+                .replica_manager()
+                .add_replica(replica.with_listening_port(listening_port));
+            (our_replication_id, missing)
+        };
+
+        let mut writer = &stream;
+        writer.write_all(format!("+CONTINUE {our_replication_id}\r\n").as_bytes())?;
+        writer.write_all(&missing)?;
+        let replica_count = self.send_pending_output(&stream)?;
+        println!(
+            "INFO: Partial resynchronization accepted, sent {} bytes of backlog. Total replicas: {}",
+            missing.len(),
+            replica_count
+        );
+        Ok(true)
+    }
+
+    pub fn end_full_resync(&self, stream: &TcpStream) -> std::io::Result<()> {
+        let replica_count = self.send_pending_output(stream)?;
+        println!("INFO: Replica synchronized. Total replicas: {replica_count}");
+        Ok(())
+    }
+
+    /// Streams the writes buffered for a replica since it was registered and marks it
+    /// online, returning how many replicas we have. The replication state is only held
+    /// to pick up each batch, so a slow replica does not hold up everyone else.
+    fn send_pending_output(&self, stream: &TcpStream) -> std::io::Result<usize> {
+        let mut writer = stream;
+        loop {
+            let mut live_data = self.live_data.lock().unwrap();
+            let replica_manager = live_data.replica_manager();
+            let Some(pending) = replica_manager.take_pending_output(stream) else {
+                return Ok(replica_manager.get_connected_replica_count());
+            };
+            drop(live_data);
+            writer.write_all(&pending)?;
         }
     }
 
+    // TODO: handle replica methods without exposing the internals of the replica manager
     pub fn handle_disconnect(&self, conn: &Connection) {
-        if let LiveData::Master(master_data) = &mut *self.live_data.lock().unwrap() {
-            master_data.replica_manager.remove_replica(conn);
+        let mut live_data = self.live_data.lock().unwrap();
+        let replica_manager = live_data.replica_manager();
+        if replica_manager.remove_replica(conn).is_some() {
             println!(
                 "INFO: Replica disconnected. Remaining replicas: {}",
-                master_data.replica_manager.get_connected_replica_count()
+                replica_manager.get_connected_replica_count()
             );
         }
     }
 
     pub fn propagate_message(&self, message: &[u8]) {
         if let LiveData::Master(master_data) = &mut *self.live_data.lock().unwrap() {
-            master_data
-                .replica_manager
-                .propagate_message_to_replicas(message);
+            master_data.feed_replication_stream(message);
         }
     }
 
     pub fn update_replica_offset(&self, stream: &TcpStream, offset: usize) {
-        if let LiveData::Master(master_data) = &mut *self.live_data.lock().unwrap() {
-            master_data
+        self.live_data
+            .lock()
+            .unwrap()
+            .replica_manager()
+            .update_replica_offset(stream, offset);
+        self.replication_changed.notify_all();
+    }
+
+    /// Blocks until `replica_count` replicas have acknowledged every write made so far or
+    /// until `timeout` elapses, a zero timeout waiting indefinitely. Replicas are only asked
+    /// for their offsets when the acknowledgements we already have are not enough.
+    /// Returns the number of replicas known to be up to date, or `None` if we are a replica.
+    pub fn wait_for_replicas(&self, replica_count: usize, timeout: Duration) -> Option<usize> {
+        let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+        let mut live_data = self.live_data.lock().unwrap();
+        let LiveData::Master(master_data) = &mut *live_data else {
+            return None;
+        };
+        let target_offset = master_data.last_write_offset;
+        let mut acked = master_data
+            .replica_manager
+            .get_up_to_date_replicas_count(target_offset);
+        if acked >= replica_count {
+            return Some(acked);
+        }
+
+        println!(
+            "DEBUG: sending GETACK to {} replicas",
+            master_data.replica_manager.get_connected_replica_count()
+        );
+        let getack = Command::ReplConf(ReplConfCommand::GetAck("*".to_string())).to_resp_token();
+        if let Some(getack) = getack {
+            master_data.feed_replication_stream(&getack.serialize());
+        }
+
+        loop {
+            live_data = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Some(acked);
+                    }
+                    self.replication_changed
+                        .wait_timeout(live_data, remaining)
+                        .unwrap()
+                        .0
+                }
+                None => self.replication_changed.wait(live_data).unwrap(),
+            };
+            // a role change wakes us up as well, the writes can no longer be acknowledged
+            let LiveData::Master(master_data) = &*live_data else {
+                return Some(acked);
+            };
+            acked = master_data
                 .replica_manager
-                .update_replica_offset(stream, offset);
+                .get_up_to_date_replicas_count(target_offset);
+            if acked >= replica_count {
+                return Some(acked);
+            }
         }
     }
 
@@ -121,3 +707,243 @@ impl Server {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Read;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{replication::replica_manager::tests::connected_pair, server::config::Config};
+
+    /// A server started with the given command line options, on top of the defaults
+    pub(crate) fn test_server(args: &[&str], replica_info: ReplicaInfo) -> Server {
+        let config = Config::parse_from(["redis"].iter().chain(args));
+        let metadata = ServerMetadata::generate(&config);
+        Server::new(metadata, replica_info, None, None)
+    }
+
+    /// Replica of a master nobody listens on, the link of which is never started
+    pub(crate) fn replica_of_nowhere() -> ReplicaInfo {
+        ReplicaInfo::Slave(SlaveInfo {
+            master_host: "127.0.0.1".to_string(),
+            master_port: 0,
+        })
+    }
+
+    fn set(key: &'static [u8]) -> impl FnOnce(&ExpiringHashMap) -> (Token, usize) {
+        move |store| {
+            store.set_value(key, Value::String(b"value".to_vec()), None);
+            (Token::SimpleString("OK".to_string()), 1)
+        }
+    }
+
+    #[test]
+    fn test_begin_full_resync_snapshots_keyspace() {
+        let server = test_server(&[], ReplicaInfo::Master(MasterInfo::new()));
+        server.apply_write(set(b"before"), b"before", WriteOrigin::Client);
+
+        let (local, _remote) = connected_pair();
+        let (replication_id, offset, entries) = server.begin_full_resync(local, None).unwrap();
+        server.apply_write(set(b"after"), b"after", WriteOrigin::Client);
+
+        let LiveData::Master(data) = &*server.live_data.lock().unwrap() else {
+            panic!("expected a master");
+        };
+        assert_eq!(replication_id, data.info.replication_id);
+        assert_eq!(offset, b"before".len());
+        assert_eq!(data.replication_offset, offset + b"after".len());
+        assert_eq!(data.replica_manager.get_connected_replica_count(), 1);
+        let keys = entries
+            .iter()
+            .map(|entry| &entry.key[..])
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [b"before"]);
+    }
+
+    /// Registers an online replica and returns our end of its connection
+    fn add_online_replica(server: &Server) -> (TcpStream, TcpStream) {
+        let (local, remote) = connected_pair();
+        server
+            .live_data
+            .lock()
+            .unwrap()
+            .replica_manager()
+            .add_replica(Replica::new(local.try_clone().unwrap()));
+        (local, remote)
+    }
+
+    #[test]
+    fn test_wait_for_replicas_returns_once_enough_acks_arrive() {
+        let server = test_server(&[], ReplicaInfo::Master(MasterInfo::new()));
+        let (first, _first_remote) = add_online_replica(&server);
+        let (second, _second_remote) = add_online_replica(&server);
+        server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        let offset = server.live_data.lock().unwrap().replication_offset();
+
+        // nothing written since, the replicas which acknowledged it are enough
+        server.update_replica_offset(&first, offset);
+        assert_eq!(
+            server.wait_for_replicas(1, Duration::from_millis(10)),
+            Some(1)
+        );
+
+        let started = Instant::now();
+        let acked = std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| server.wait_for_replicas(2, Duration::from_secs(30)));
+            std::thread::sleep(Duration::from_millis(50));
+            server.update_replica_offset(&second, offset);
+            waiter.join().unwrap()
+        });
+        assert_eq!(acked, Some(2));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // the timeout reports what was acknowledged when it elapsed
+        assert_eq!(
+            server.wait_for_replicas(3, Duration::from_millis(50)),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_replicas_on_replica() {
+        let server = test_server(&[], replica_of_nowhere());
+        assert_eq!(server.wait_for_replicas(0, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn test_apply_write_without_enough_good_replicas() {
+        let server = test_server(
+            &["--min-replicas-to-write", "1"],
+            ReplicaInfo::Master(MasterInfo::new()),
+        );
+        let response = server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        assert_eq!(
+            response,
+            Token::Error("NOREPLICAS Not enough good replicas to write.".to_string())
+        );
+        assert!(server.get(b"key").is_none());
+        assert_eq!(server.live_data.lock().unwrap().replication_offset(), 0);
+
+        let (_local, _remote) = add_online_replica(&server);
+        let response = server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        assert_eq!(response, Token::SimpleString("OK".to_string()));
+        assert!(server.get(b"key").is_some());
+    }
+
+    #[test]
+    fn test_apply_write_on_read_only_replica() {
+        let server = test_server(&[], replica_of_nowhere());
+        let response = server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        assert_eq!(
+            response,
+            Token::Error("READONLY You can't write against a read only replica.".to_string())
+        );
+        assert!(server.get(b"key").is_none());
+
+        let server = test_server(&["--replica-read-only", "no"], replica_of_nowhere());
+        let response = server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        assert_eq!(response, Token::SimpleString("OK".to_string()));
+        assert!(server.get(b"key").is_some());
+    }
+
+    #[test]
+    fn test_replica_forwards_master_stream() {
+        let server = test_server(&[], replica_of_nowhere());
+        let (_local, mut remote) = add_online_replica(&server);
+        let link = match &*server.live_data.lock().unwrap() {
+            LiveData::Slave(data) => data.link.clone(),
+            LiveData::Master(_) => panic!("expected a replica"),
+        };
+
+        let response = server.apply_write(set(b"key"), b"write", WriteOrigin::Master(&link));
+        assert_eq!(response, Token::SimpleString("OK".to_string()));
+        assert_eq!(
+            server.live_data.lock().unwrap().replication_offset(),
+            b"write".len()
+        );
+        let mut buf = [0; 5];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"write");
+
+        link.stop();
+        let response = server.apply_write(set(b"other"), b"other", WriteOrigin::Master(&link));
+        assert!(matches!(response, Token::Error(_)));
+        assert!(server.get(b"other").is_none());
+    }
+
+    /// Asks for a partial resync, returning whether it was accepted and what was sent
+    fn partial_resync(server: &Server, replication_id: &str, psync_offset: i64) -> (bool, Vec<u8>) {
+        let (local, mut remote) = connected_pair();
+        let accepted = server
+            .try_partial_resync(local, None, replication_id, psync_offset)
+            .unwrap();
+        server
+            .live_data
+            .lock()
+            .unwrap()
+            .replica_manager()
+            .disconnect_all();
+        let mut sent = Vec::new();
+        remote.read_to_end(&mut sent).unwrap();
+        (accepted, sent)
+    }
+
+    #[test]
+    fn test_try_partial_resync() {
+        let server = test_server(&[], ReplicaInfo::Master(MasterInfo::new()));
+        server.apply_write(set(b"key"), b"write", WriteOrigin::Client);
+        let replication_id = match &*server.live_data.lock().unwrap() {
+            LiveData::Master(data) => data.info.replication_id.clone(),
+            LiveData::Slave(_) => panic!("expected a master"),
+        };
+
+        let (accepted, sent) = partial_resync(&server, &replication_id, 3);
+        assert!(accepted);
+        assert_eq!(
+            sent,
+            format!("+CONTINUE {replication_id}\r\nite").into_bytes()
+        );
+        // nothing missing is fine as well
+        assert!(partial_resync(&server, &replication_id, 6).0);
+
+        assert!(!partial_resync(&server, &replication_id, 0).0);
+        assert!(!partial_resync(&server, &replication_id, 7).0);
+        assert!(!partial_resync(&server, &"f".repeat(REPLICATION_ID_LEN), 1).0);
+    }
+
+    #[test]
+    fn test_try_partial_resync_on_replica() {
+        let server = test_server(&[], replica_of_nowhere());
+        let replication_id = "a".repeat(REPLICATION_ID_LEN);
+        // nothing to serve until we are in sync with our master
+        assert!(!partial_resync(&server, &replication_id, 1).0);
+
+        if let LiveData::Slave(data) = &mut *server.live_data.lock().unwrap() {
+            data.reset_replication_stream(replication_id.clone(), 100);
+            data.set_link_state(MasterLinkState::Connected);
+            data.feed_replication_stream(b"write");
+        }
+        let (accepted, sent) = partial_resync(&server, &replication_id, 101);
+        assert!(accepted);
+        assert_eq!(
+            sent,
+            format!("+CONTINUE {replication_id}\r\nwrite").into_bytes()
+        );
+        assert!(!partial_resync(&server, &replication_id, 100).0);
+    }
+
+    #[test]
+    fn test_apply_write_counts_changes() {
+        let server = test_server(&[], ReplicaInfo::Master(MasterInfo::new()));
+        let write = |response: Token, changes| move |_: &ExpiringHashMap| (response, changes);
+        let ok = || Token::SimpleString("OK".to_string());
+
+        server.apply_write(write(ok(), 3), b"write", WriteOrigin::Client);
+        server.apply_write(write(ok(), 0), b"write", WriteOrigin::Client);
+        let error = Token::Error("ERR failed".to_string());
+        server.apply_write(write(error, 2), b"write", WriteOrigin::Client);
+        assert_eq!(server.save_state.lock().unwrap().dirty, 3);
+    }
+}
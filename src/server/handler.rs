@@ -1,55 +1,172 @@
+use std::collections::VecDeque;
 use std::io::Write;
 use std::time::{Duration, Instant};
 use std::{net::TcpStream, sync::Arc};
 
-use crate::parser::command::{ConfigCommand, ReplConfCommand};
+use crate::client::Client;
+use crate::cluster::message::NodeHealth;
+use crate::cluster::slot::{key_hash_slot, CLUSTER_SLOTS};
+use crate::cluster::state::{ClusterState, NodeRole, BUS_PORT_OFFSET};
+use crate::common::unix_time_ms;
+use crate::parser::command::Command;
+use crate::parser::command::{ClusterCommand, ConfigCommand, ReplConfCommand, SetSlotAction};
+use crate::parser::rdb::{decode_dump_payload, encode_dump_payload, encode_rdb};
 use crate::parser::resp::Token;
-use crate::replication::rdb::{get_empty_rdb, serialize_rdb};
-use crate::server::data::LiveData;
-use crate::{parser::command::Command, server::metadata::ReplicaInfo};
+use crate::persistence;
+use crate::replication::failover;
+use crate::replication::link::{self, MasterLink};
+use crate::replication::rdb::serialize_rdb;
+use crate::server::data::{LiveData, WriteOrigin};
+use crate::storage::expiring_map::ExpiringHashMap;
+use crate::storage::value::{list_index, list_pop, list_push, list_range, Value, WrongType};
 
 use super::data::Server;
 
 pub struct CommandHandler {
-    stream: TcpStream,
+    /// Connection the commands come from, `None` when replaying the append-only file
+    stream: Option<TcpStream>,
     server: Arc<Server>,
+    /// Port announced by a replica during the handshake on this connection
+    replica_listening_port: Option<u16>,
+    /// Set when the commands come from our master, which expects no replies to them
+    master_link: Option<Arc<MasterLink>>,
+    /// Set by ASKING, lets the next command reach a slot we are importing
+    asking: bool,
 }
 
 impl CommandHandler {
-    pub fn new(stream: TcpStream, server: Arc<Server>) -> Self {
-        CommandHandler { stream, server }
+    pub fn new(
+        stream: TcpStream,
+        server: Arc<Server>,
+        master_link: Option<Arc<MasterLink>>,
+    ) -> Self {
+        CommandHandler {
+            stream: Some(stream),
+            server,
+            replica_listening_port: None,
+            master_link,
+            asking: false,
+        }
+    }
+
+    /// Handler executing the commands of the append-only file, which sends no replies
+    pub fn for_replay(server: Arc<Server>) -> Self {
+        CommandHandler {
+            stream: None,
+            server,
+            replica_listening_port: None,
+            master_link: None,
+            asking: false,
+        }
+    }
+
+    fn stream(&self) -> std::io::Result<&TcpStream> {
+        self.stream.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "command needs a connection",
+            )
+        })
     }
 
-    pub fn handle_command(&mut self, command: &Command) -> std::io::Result<()> {
+    /// Executes a command, `raw` holding the RESP bytes it was parsed from
+    pub fn handle_command(&mut self, command: &Command, raw: &[u8]) -> std::io::Result<()> {
+        // our master already checked the slots of what it sends us, and so did we
+        // before logging a write to the append-only file
+        let asking = std::mem::take(&mut self.asking);
+        if self.master_link.is_none() && self.stream.is_some() {
+            if let Some(redirect) = self.cluster_redirect(command, asking) {
+                return self.write_response(redirect);
+            }
+        }
+        if command.is_write() {
+            return self.handle_write(command, raw);
+        }
         match command {
-            Command::Ping => self.handle_ping(),
-            Command::Echo(data) => self.handle_echo(data),
-            Command::Get(key) => self.handle_get(key),
-            Command::Set { key, value, expiry } => self.handle_set(key, value, *expiry),
-            Command::Info(section) => self.handle_info(section),
-            Command::ReplConf(replconf_command) => self.handle_replconf(replconf_command),
-            Command::Psync => self.handle_psync(),
+            Command::Ping => self.handle_ping()?,
+            Command::Echo(data) => self.handle_echo(data)?,
+            Command::Get(key) => self.handle_get(key)?,
+            Command::Set { .. } => unreachable!("write commands go through handle_write"),
+            Command::Info(section) => self.handle_info(section)?,
+            Command::ReplConf(replconf_command) => self.handle_replconf(replconf_command)?,
+            Command::Psync {
+                replication_id,
+                offset,
+            } => self.handle_psync(replication_id, *offset)?,
             Command::Wait {
                 replica_count,
                 timeout,
-            } => self.handle_wait(*replica_count, *timeout),
-            Command::Config(config) => self.handle_config(&config),
+            } => self.handle_wait(*replica_count, *timeout)?,
+            Command::Config(config) => self.handle_config(config)?,
+            Command::Save => self.handle_save()?,
+            Command::BgSave => self.handle_bgsave()?,
+            Command::LastSave => self.handle_lastsave()?,
+            Command::BgRewriteAof => self.handle_bgrewriteaof()?,
+            Command::Shutdown { save } => self.handle_shutdown(*save)?,
+            Command::ReplicaOf(master) => self.handle_replicaof(master)?,
+            Command::Role => self.handle_role()?,
+            Command::Failover {
+                target,
+                timeout,
+                force,
+                abort,
+            } => self.handle_failover(target, *timeout, *force, *abort)?,
+            Command::Cluster(cluster_command) => self.handle_cluster(cluster_command)?,
+            Command::Sentinel(_) => self.write_response(Token::Error(
+                "ERR unknown command 'SENTINEL', sentinel mode is not enabled".to_string(),
+            ))?,
+            Command::Asking => self.handle_asking()?,
+            Command::Dump(key) => self.handle_dump(key)?,
+            Command::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout,
+                copy,
+                replace,
+            } => {
+                let response =
+                    self.handle_migrate(host, *port, keys, *db, *timeout, *copy, *replace);
+                self.write_response(response)?
+            }
+            Command::LRange { key, start, stop } => self.handle_lrange(key, *start, *stop)?,
+            Command::LLen(key) => self.handle_llen(key)?,
+            Command::LIndex { key, index } => self.handle_lindex(key, *index)?,
+            Command::Invalid(message) => self.write_response(Token::Error(message.clone()))?,
+            Command::Del(_)
+            | Command::Restore { .. }
+            | Command::LPush { .. }
+            | Command::RPush { .. }
+            | Command::LPop { .. }
+            | Command::RPop { .. }
+            | Command::LSet { .. }
+            | Command::LRem { .. }
+            | Command::LTrim { .. }
+            | Command::LInsert { .. }
+            | Command::LMove { .. } => {
+                unreachable!("write commands go through handle_write")
+            }
+        }
+
+        // the rest of our master's stream reaches our replicas once it has been processed
+        if let Some(master_link) = &self.master_link {
+            link::with_link_data(&self.server, master_link, |data| {
+                data.feed_replication_stream(raw)
+            });
         }
+        Ok(())
     }
 
     fn handle_ping(&mut self) -> std::io::Result<()> {
         println!("DEBUG: received PING command");
-        match &self.server.metadata.replica_info {
-            ReplicaInfo::Master(_) => {
-                let response = Token::SimpleString("PONG".to_string());
-                self.write_response(response)?;
-            }
-            ReplicaInfo::Slave(_) => {
-                if let LiveData::Slave(data) = &mut *self.server.live_data.lock().unwrap() {
-                    data.heartbeat_recv_time = Some(Instant::now());
-                }
+        if self.master_link.is_some() {
+            if let LiveData::Slave(data) = &mut *self.server.live_data.lock().unwrap() {
+                data.heartbeat_recv_time = Some(Instant::now());
             }
-        };
+        }
+        let response = Token::SimpleString("PONG".to_string());
+        self.write_response(response)?;
         Ok(())
     }
 
@@ -66,8 +183,9 @@ impl CommandHandler {
         {
             let store = self.server.store.lock().unwrap();
             let value = store.get(key);
-            response = match value {
-                Some(value) => Token::BulkString(value.to_vec()),
+            response = match value.as_ref().map(Value::as_string) {
+                Some(Ok(value)) => Token::BulkString(value.to_vec()),
+                Some(Err(err)) => err.into(),
                 None => Token::BulkString(Vec::new()),
             };
         }
@@ -75,100 +193,593 @@ impl CommandHandler {
         Ok(())
     }
 
+    /// Applies a write command and propagates it to the replicas. The bytes we received
+    /// are propagated unless the command has to be rewritten into a deterministic form,
+    /// while a write from our master is always forwarded exactly as we received it.
+    fn handle_write(&mut self, command: &Command, raw: &[u8]) -> std::io::Result<()> {
+        let (message, origin) = match (&self.master_link, &self.stream) {
+            (Some(master_link), _) => (raw.to_vec(), WriteOrigin::Master(master_link)),
+            (None, None) => (raw.to_vec(), WriteOrigin::Aof),
+            (None, Some(_)) => match command.to_propagated_token(unix_time_ms()) {
+                Some(token) => (token.serialize(), WriteOrigin::Client),
+                None => (raw.to_vec(), WriteOrigin::Client),
+            },
+        };
+        let response = self.server.apply_write(
+            |store| match command {
+                Command::Set { key, value, expiry } => Self::handle_set(store, key, value, *expiry),
+                Command::Del(keys) => Self::handle_del(store, keys),
+                Command::Restore {
+                    key,
+                    payload,
+                    expiry,
+                    replace,
+                } => Self::handle_restore(store, key, payload, *expiry, *replace),
+                Command::LPush { key, elements } => Self::handle_push(store, key, elements, true),
+                Command::RPush { key, elements } => Self::handle_push(store, key, elements, false),
+                Command::LPop { key, count } => Self::handle_pop(store, key, *count, true),
+                Command::RPop { key, count } => Self::handle_pop(store, key, *count, false),
+                Command::LSet {
+                    key,
+                    index,
+                    element,
+                } => Self::handle_lset(store, key, *index, element),
+                Command::LRem {
+                    key,
+                    count,
+                    element,
+                } => Self::handle_lrem(store, key, *count, element),
+                Command::LTrim { key, start, stop } => {
+                    Self::handle_ltrim(store, key, *start, *stop)
+                }
+                Command::LInsert {
+                    key,
+                    before,
+                    pivot,
+                    element,
+                } => Self::handle_linsert(store, key, *before, pivot, element),
+                Command::LMove {
+                    source,
+                    destination,
+                    from_left,
+                    to_left,
+                } => Self::handle_lmove(store, source, destination, *from_left, *to_left),
+                _ => unreachable!("{command:?} is not a write command"),
+            },
+            &message,
+            origin,
+        );
+        self.write_response(response)?;
+        Ok(())
+    }
+
     fn handle_set(
-        &mut self,
+        store: &ExpiringHashMap,
         key: &[u8],
         value: &[u8],
         expiry: Option<Duration>,
-    ) -> std::io::Result<()> {
+    ) -> (Token, usize) {
         println!("DEBUG: received SET command with key {key:?} value {value:?} expiry {expiry:?}");
-        self.server.set(key, value, expiry);
-        // TODO: Only send reply if we are master, and not if we are a replica receiving
-        // replicated commands and make this generic instead of adding if conditions
-        if let ReplicaInfo::Master(_) = self.server.metadata.replica_info {
-            let cmd = Command::Set {
-                key: key.to_vec(),
-                value: value.to_vec(),
-                expiry,
-            };
-            let token = cmd.to_resp_token();
-            let repl_data = token.serialize();
+        store.set(key, value, expiry);
+        (Token::SimpleString("OK".to_string()), 1)
+    }
 
-            self.server.propagate_message(repl_data.as_slice());
+    fn handle_del(store: &ExpiringHashMap, keys: &[Vec<u8>]) -> (Token, usize) {
+        println!("DEBUG: received DEL command with keys {keys:?}");
+        let removed = keys.iter().filter(|key| store.remove(key)).count();
+        (Token::Integer(removed as i64), removed)
+    }
 
-            if let LiveData::Master(data) = &mut *self.server.live_data.lock().unwrap() {
-                data.replication_offset += repl_data.len();
+    fn handle_restore(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        payload: &[u8],
+        expiry: Option<Duration>,
+        replace: bool,
+    ) -> (Token, usize) {
+        println!(
+            "DEBUG: received RESTORE command with key {key:?} expiry {expiry:?} replace {replace}"
+        );
+        if !replace && store.get(key).is_some() {
+            let response = Token::Error("BUSYKEY Target key name already exists.".to_string());
+            return (response, 0);
+        }
+        match decode_dump_payload(payload).map(Value::try_from) {
+            Ok(Ok(value)) => {
+                store.set_value(key, value, expiry);
+                (Token::SimpleString("OK".to_string()), 1)
+            }
+            Ok(Err(value)) => {
+                let response = Token::Error(format!(
+                    "ERR DUMP payload holds a {} value, only strings and lists are supported",
+                    value.type_name()
+                ));
+                (response, 0)
+            }
+            Err(err) => {
+                println!("DEBUG: refusing DUMP payload: {err}");
+                let response =
+                    Token::Error("ERR DUMP payload version or checksum are wrong".to_string());
+                (response, 0)
             }
+        }
+    }
 
-            let response = Token::SimpleString("OK".to_string());
-            self.write_response(response)?;
+    fn handle_dump(&mut self, key: &[u8]) -> std::io::Result<()> {
+        println!("DEBUG: received DUMP command with key {key:?}");
+        let value = self.server.store.lock().unwrap().get(key);
+        let response = match value {
+            Some(value) => match encode_dump_payload(&value.into()) {
+                Ok(payload) => Token::BulkString(payload),
+                Err(err) => Token::Error(format!("ERR {err}")),
+            },
+            None => Token::BulkString(Vec::new()),
+        };
+        self.write_response(response)
+    }
+
+    /// Copies the keys over to another node with RESTORE, then deletes the ones which
+    /// made it there unless asked for a copy. The deletion is propagated as a DEL.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_migrate(
+        &self,
+        host: &str,
+        port: u16,
+        keys: &[Vec<u8>],
+        db: u64,
+        timeout: Duration,
+        copy: bool,
+        replace: bool,
+    ) -> Token {
+        println!(
+            "DEBUG: received MIGRATE command to {host}:{port} with keys {keys:?} db {db} timeout {timeout:?} copy {copy} replace {replace}"
+        );
+        if db != 0 {
+            return Token::Error("ERR MIGRATE only supports database 0".to_string());
+        }
+        let entries = {
+            let store = self.server.store.lock().unwrap();
+            keys.iter()
+                .filter_map(|key| {
+                    let (value, expiry) = store.get_with_expiry(key)?;
+                    Some((key, value, expiry))
+                })
+                .collect::<Vec<_>>()
+        };
+        if entries.is_empty() {
+            return Token::SimpleString("NOKEY".to_string());
+        }
+        // keys we could not delete afterwards would be left on both nodes
+        if !copy {
+            if let Some(error) = self.server.client_write_error() {
+                return error;
+            }
+        }
+        let Ok(mut client) = Client::connect(host, port, timeout) else {
+            return Token::Error("IOERR error or timeout connecting to the client".to_string());
+        };
+
+        let mut migrated = Vec::new();
+        let mut error = None;
+        for (key, value, expiry) in entries {
+            // a zero TTL would restore the key without any expiry
+            let ttl = expiry.map_or(0, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis()
+                    .max(1)
+            });
+            let ttl = ttl.to_string();
+            let payload = match encode_dump_payload(&value.into()) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    error = Some(format!("ERR {err}"));
+                    break;
+                }
+            };
+            let mut restore: Vec<&[u8]> = vec![b"RESTORE", key, ttl.as_bytes(), &payload];
+            if replace {
+                restore.push(b"REPLACE");
+            }
+            let mut replies = Vec::new();
+            // in a cluster, the target only serves a slot it is still importing after ASKING
+            if self.server.cluster.is_some() {
+                replies.push(client.request(&["ASKING"]));
+            }
+            replies.push(client.request_binary(&restore));
+            error = replies.iter().find_map(|reply| match reply.as_deref() {
+                Ok([Token::SimpleString(_)]) => None,
+                Ok([Token::Error(message)]) => {
+                    Some(format!("ERR Target instance replied with error: {message}"))
+                }
+                _ => Some("IOERR error or timeout reading to target instance".to_string()),
+            });
+            if error.is_some() {
+                break;
+            }
+            migrated.push(key.clone());
+        }
+
+        if !copy && !migrated.is_empty() {
+            let Some(del) = Command::Del(migrated.clone()).to_resp_token() else {
+                return Token::Error("ERR failed to propagate the deletion".to_string());
+            };
+            let response = self.server.apply_write(
+                |store| {
+                    let removed = migrated.iter().filter(|key| store.remove(key)).count();
+                    (Token::SimpleString("OK".to_string()), removed)
+                },
+                &del.serialize(),
+                WriteOrigin::Client,
+            );
+            if let Token::Error(message) = response {
+                return Token::Error(format!(
+                    "ERR keys were copied to the target but not deleted here: {message}"
+                ));
+            }
+        }
+        match error {
+            Some(message) => Token::Error(message),
+            None => Token::SimpleString("OK".to_string()),
         }
-        Ok(())
+    }
+
+    fn handle_push(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        elements: &[Vec<u8>],
+        left: bool,
+    ) -> (Token, usize) {
+        println!("DEBUG: received PUSH command with key {key:?} elements {elements:?} left {left}");
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let list = value
+                    .get_or_insert_with(|| Value::List(VecDeque::new()))
+                    .as_list_mut()?;
+                for element in elements {
+                    list_push(list, element.clone(), left);
+                }
+                Ok((Token::Integer(list.len() as i64), elements.len()))
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    /// Pops a single element, or an array of up to `count` elements when given
+    fn handle_pop(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        count: Option<usize>,
+        left: bool,
+    ) -> (Token, usize) {
+        println!("DEBUG: received POP command with key {key:?} count {count:?} left {left}");
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let Some(list) = value.as_mut().map(Value::as_list_mut).transpose()? else {
+                    return Ok(match count {
+                        None => (Token::BulkString(Vec::new()), 0),
+                        Some(_) => (Token::NullArray, 0),
+                    });
+                };
+                Ok(match count {
+                    None => match list_pop(list, left) {
+                        Some(element) => (Token::BulkString(element), 1),
+                        None => (Token::BulkString(Vec::new()), 0),
+                    },
+                    Some(count) => {
+                        let popped = std::iter::from_fn(|| list_pop(list, left))
+                            .take(count)
+                            .map(Token::BulkString)
+                            .collect::<Vec<_>>();
+                        let changes = popped.len();
+                        (Token::Array(popped), changes)
+                    }
+                })
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    fn handle_lset(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        index: i64,
+        element: &[u8],
+    ) -> (Token, usize) {
+        println!("DEBUG: received LSET command with key {key:?} index {index} element {element:?}");
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let Some(list) = value.as_mut().map(Value::as_list_mut).transpose()? else {
+                    return Ok((Token::Error("ERR no such key".to_string()), 0));
+                };
+                Ok(match list_index(list.len(), index) {
+                    Some(index) => {
+                        list[index] = element.to_vec();
+                        (Token::SimpleString("OK".to_string()), 1)
+                    }
+                    None => (Token::Error("ERR index out of range".to_string()), 0),
+                })
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    /// Removes up to `count` occurrences of the element starting from the head, or
+    /// from the tail if `count` is negative, or every occurrence if it is zero
+    fn handle_lrem(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        count: i64,
+        element: &[u8],
+    ) -> (Token, usize) {
+        println!("DEBUG: received LREM command with key {key:?} count {count} element {element:?}");
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let Some(list) = value.as_mut().map(Value::as_list_mut).transpose()? else {
+                    return Ok((Token::Integer(0), 0));
+                };
+                let limit = match count {
+                    0 => usize::MAX,
+                    count => count.unsigned_abs() as usize,
+                };
+                let mut removed = 0;
+                let mut keep = |current: &Vec<u8>| {
+                    if removed < limit && current == element {
+                        removed += 1;
+                        return false;
+                    }
+                    true
+                };
+                if count >= 0 {
+                    list.retain(keep);
+                } else {
+                    let mut kept = VecDeque::with_capacity(list.len());
+                    for current in list.drain(..).rev() {
+                        if keep(&current) {
+                            kept.push_front(current);
+                        }
+                    }
+                    *list = kept;
+                }
+                Ok((Token::Integer(removed as i64), removed))
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    fn handle_ltrim(store: &ExpiringHashMap, key: &[u8], start: i64, stop: i64) -> (Token, usize) {
+        println!("DEBUG: received LTRIM command with key {key:?} start {start} stop {stop}");
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let mut removed = 0;
+                if let Some(list) = value.as_mut().map(Value::as_list_mut).transpose()? {
+                    let range = list_range(list.len(), start, stop);
+                    removed = list.len() - range.len();
+                    list.truncate(range.end);
+                    list.drain(..range.start);
+                }
+                Ok((Token::SimpleString("OK".to_string()), removed))
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    /// Replies the new length, 0 for a missing key and -1 when the pivot is not found
+    fn handle_linsert(
+        store: &ExpiringHashMap,
+        key: &[u8],
+        before: bool,
+        pivot: &[u8],
+        element: &[u8],
+    ) -> (Token, usize) {
+        println!(
+            "DEBUG: received LINSERT command with key {key:?} before {before} pivot {pivot:?} element {element:?}"
+        );
+        store
+            .update(key, |value| -> Result<(Token, usize), WrongType> {
+                let Some(list) = value.as_mut().map(Value::as_list_mut).transpose()? else {
+                    return Ok((Token::Integer(0), 0));
+                };
+                let Some(position) = list.iter().position(|current| current == pivot) else {
+                    return Ok((Token::Integer(-1), 0));
+                };
+                let position = if before { position } else { position + 1 };
+                list.insert(position, element.to_vec());
+                Ok((Token::Integer(list.len() as i64), 1))
+            })
+            .unwrap_or_else(|err| (err.into(), 0))
+    }
+
+    /// Both keys are type checked before anything moves, a missing source replying a
+    /// null whatever the destination holds, as Redis does
+    fn handle_lmove(
+        store: &ExpiringHashMap,
+        source: &[u8],
+        destination: &[u8],
+        from_left: bool,
+        to_left: bool,
+    ) -> (Token, usize) {
+        println!(
+            "DEBUG: received LMOVE command with source {source:?} destination {destination:?} from_left {from_left} to_left {to_left}"
+        );
+        let is_list = |key: &[u8]| {
+            store.read(key, |value| {
+                value
+                    .map(Value::as_list)
+                    .transpose()
+                    .map(|list| list.is_some())
+            })
+        };
+        match is_list(source) {
+            Ok(true) => {}
+            Ok(false) => return (Token::BulkString(Vec::new()), 0),
+            Err(err) => return (err.into(), 0),
+        }
+        if let Err(err) = is_list(destination) {
+            return (err.into(), 0);
+        }
+        let element = store.update(source, |value| {
+            list_pop(value.as_mut()?.as_list_mut().ok()?, from_left)
+        });
+        let Some(element) = element else {
+            return (Token::BulkString(Vec::new()), 0);
+        };
+        store.update(destination, |value| {
+            if let Ok(list) = value
+                .get_or_insert_with(|| Value::List(VecDeque::new()))
+                .as_list_mut()
+            {
+                list_push(list, element.clone(), to_left);
+            }
+        });
+        (Token::BulkString(element), 1)
+    }
+
+    fn handle_lrange(&mut self, key: &[u8], start: i64, stop: i64) -> std::io::Result<()> {
+        println!("DEBUG: received LRANGE command with key {key:?} start {start} stop {stop}");
+        let response = self
+            .server
+            .store
+            .lock()
+            .unwrap()
+            .read(key, |value| -> Result<Token, WrongType> {
+                let Some(list) = value.map(Value::as_list).transpose()? else {
+                    return Ok(Token::Array(Vec::new()));
+                };
+                Ok(Token::Array(
+                    list.range(list_range(list.len(), start, stop))
+                        .cloned()
+                        .map(Token::BulkString)
+                        .collect(),
+                ))
+            })
+            .unwrap_or_else(Token::from);
+        self.write_response(response)
+    }
+
+    fn handle_llen(&mut self, key: &[u8]) -> std::io::Result<()> {
+        println!("DEBUG: received LLEN command with key {key:?}");
+        let response = self
+            .server
+            .store
+            .lock()
+            .unwrap()
+            .read(key, |value| -> Result<Token, WrongType> {
+                let list = value.map(Value::as_list).transpose()?;
+                Ok(Token::Integer(list.map_or(0, VecDeque::len) as i64))
+            })
+            .unwrap_or_else(Token::from);
+        self.write_response(response)
+    }
+
+    fn handle_lindex(&mut self, key: &[u8], index: i64) -> std::io::Result<()> {
+        println!("DEBUG: received LINDEX command with key {key:?} index {index}");
+        let response = self
+            .server
+            .store
+            .lock()
+            .unwrap()
+            .read(key, |value| -> Result<Token, WrongType> {
+                let element = value
+                    .map(Value::as_list)
+                    .transpose()?
+                    .and_then(|list| list.get(list_index(list.len(), index)?));
+                Ok(Token::BulkString(element.cloned().unwrap_or_default()))
+            })
+            .unwrap_or_else(Token::from);
+        self.write_response(response)
     }
 
     fn handle_info(&mut self, section: &Vec<u8>) -> std::io::Result<()> {
         println!("DEBUG: received INFO command with section {section:?}");
-        match section.as_slice() {
+        match section.to_ascii_lowercase().as_slice() {
             b"replication" => {
-                self.write_response(Token::BulkString(self.server.metadata.get_replica_info()))?
+                let info = self.server.live_data.lock().unwrap().get_replica_info();
+                self.write_response(Token::BulkString(info))?
             }
-            _ => panic!("Not expecting to handle section {section:?}"),
+            b"persistence" => {
+                let info = persistence::info(&self.server);
+                self.write_response(Token::BulkString(info))?
+            }
+            // sections we do not keep anything for are empty, as in Redis
+            _ => self.write_response(Token::EmptyBulkString)?,
         }
         Ok(())
     }
 
     fn handle_replconf(&mut self, replconf_command: &ReplConfCommand) -> std::io::Result<()> {
         println!("DEBUG: received REPLCONF command {replconf_command:?}");
-        match self.server.metadata.replica_info {
-            ReplicaInfo::Master(_) => match replconf_command {
+        if self.master_link.is_none() {
+            match replconf_command {
                 ReplConfCommand::Ack(offset) => {
                     println!("DEBUG: received ACK from replica");
-                    self.server.update_replica_offset(&self.stream, *offset);
+                    self.server.update_replica_offset(self.stream()?, *offset);
                 }
-                ReplConfCommand::ListeningPort(_) | ReplConfCommand::Capa(_) => {
+                ReplConfCommand::ListeningPort(port) => {
+                    self.replica_listening_port = Some(*port);
                     println!("DEBUG: sending OK response to REPLCONF");
                     self.write_response(Token::SimpleString("OK".to_string()))?;
                 }
-                _ => panic!(
-                    "Not expecting to handle REPLCONF command {replconf_command:?} at master"
-                ),
-            },
-            ReplicaInfo::Slave(_) => {
-                // Send REPLCONF ACK as a response to REPLCONF GETACK
-                let offset = if let LiveData::Slave(data) = &*self.server.live_data.lock().unwrap()
-                {
-                    data.offset
-                } else {
-                    panic!("Not expecting to handle REPLCONF command at master");
-                };
-                let response = Command::ReplConf(ReplConfCommand::Ack(offset)).to_resp_token();
-                self.write_response(response)?;
+                ReplConfCommand::Capa(_) => {
+                    println!("DEBUG: sending OK response to REPLCONF");
+                    self.write_response(Token::SimpleString("OK".to_string()))?;
+                }
+                // only our master asks us for an ACK, as in Redis nothing is sent back
+                ReplConfCommand::GetAck(_) => {}
+                ReplConfCommand::Other(option) => self.write_response(Token::Error(format!(
+                    "ERR Unrecognized REPLCONF option: {option}"
+                )))?,
             }
-        };
+        } else if let ReplConfCommand::GetAck(_) = replconf_command {
+            // Send REPLCONF ACK as a response to REPLCONF GETACK
+            let offset = match &*self.server.live_data.lock().unwrap() {
+                LiveData::Slave(data) => data.offset,
+                // promoted while the command was in flight
+                LiveData::Master(_) => return Ok(()),
+            };
+            // the only reply our master expects from us
+            if let Some(response) = Command::ReplConf(ReplConfCommand::Ack(offset)).to_resp_token()
+            {
+                self.stream()?.write_all(&response.serialize())?;
+            }
+        }
         Ok(())
     }
 
-    fn handle_psync(&mut self) -> std::io::Result<()> {
-        println!("DEBUG: received PSYNC command");
-        match &self.server.metadata.replica_info {
-            ReplicaInfo::Master(info) => {
-                // 1. Send the FULLRESYNC response to the replica
-                let replication_offset = match &*self.server.live_data.lock().unwrap() {
-                    LiveData::Master(data) => data.replication_offset,
-                    LiveData::Slave(_) => panic!("Not expecting to handle PSYNC at slave"),
-                };
-                let response = format!("FULLRESYNC {} {}", info.replication_id, replication_offset);
-                self.write_response(Token::SimpleString(response))?;
-
-                // 2. Send the RDB file to the replica
-                let rdb_payload = serialize_rdb(&get_empty_rdb()); // TODO: move this to replication module?
-                self.stream.write_all(rdb_payload.as_slice())?;
+    fn handle_psync(&mut self, replication_id: &str, offset: i64) -> std::io::Result<()> {
+        println!(
+            "DEBUG: received PSYNC command with replication id {replication_id} offset {offset}"
+        );
+        // 0. Serve the request from the backlog if the replica's history is ours
+        if self.server.try_partial_resync(
+            self.stream()?.try_clone()?,
+            self.replica_listening_port,
+            replication_id,
+            offset,
+        )? {
+            return Ok(());
+        }
 
-                // 3. Register the replica
-                self.server.add_replica(self.stream.try_clone()?); // TODO: refactor this to use register_replica and handle errors
-            }
-            ReplicaInfo::Slave(_) => panic!("Not expecting to handle PSYNC at slave"),
+        // 1. Register the replica and snapshot the dataset at the current offset,
+        //    writes from here on are buffered until the snapshot is delivered
+        let Some((master_replid, replication_offset, entries)) = self
+            .server
+            .begin_full_resync(self.stream()?.try_clone()?, self.replica_listening_port)
+        else {
+            let response = Token::Error(
+                "NOMASTERLINK Can't SYNC while not connected with my master".to_string(),
+            );
+            self.write_response(response)?;
+            return Ok(());
         };
+
+        // 2. Send the FULLRESYNC response to the replica, the connection being dropped
+        //    along with the replica if the snapshot cannot be encoded
+        let rdb = encode_rdb(&entries, unix_time_ms() / 1000).map_err(std::io::Error::other)?;
+        let response = format!("FULLRESYNC {} {}", master_replid, replication_offset);
+        self.write_response(Token::SimpleString(response))?;
+
+        // 3. Send the RDB file to the replica
+        let rdb_payload = serialize_rdb(&rdb); // TODO: move this to replication module?
+        self.stream()?.write_all(rdb_payload.as_slice())?;
+
+        // 4. Stream the writes buffered during the transfer and mark the replica online
+        self.server.end_full_resync(self.stream()?)?;
         Ok(())
     }
 
@@ -176,81 +787,664 @@ impl CommandHandler {
         println!(
             "DEBUG: received WAIT command with replica_count {replica_count:?} and timeout {timeout:?}"
         );
-        match &self.server.metadata.replica_info {
-            ReplicaInfo::Master(_) => {
-                if replica_count == 0 {
-                    self.write_response(Token::Integer(0))?;
-                }
-
-                if let ReplicaInfo::Master(..) = self.server.metadata.replica_info {
-                    // record the replication offset at the time of receiving the WAIT command
-                    let master_offset = match &*self.server.live_data.lock().unwrap() {
-                        LiveData::Master(data) => data.replication_offset,
-                        _ => panic!("Expected master data in live_data"),
-                    };
-
-                    // Send REPLCONF GETACK to all replicas
-                    println!(
-                        "DEBUG: sending GETACK to {} replicas",
-                        self.server.get_replica_count()
-                    );
-                    let ack_cmd =
-                        Command::ReplConf(ReplConfCommand::GetAck("*".to_string())).to_resp_token();
-                    self.server
-                        .propagate_message(ack_cmd.serialize().as_slice());
-
-                    // Synchronously wait for replica_count replicas to acknowledge the offset
-                    println!("DEBUG: sleeping for {timeout:?} before getting replication status");
-                    std::thread::sleep(timeout);
-
-                    let count_replicated = self.server.get_up_to_date_replicas_count(master_offset);
-
-                    println!(
-                        "DEBUG: {count_replicated} replicas have replicated till the offset {master_offset}"
-                    );
-
-                    let response = Token::Integer(count_replicated as i64);
-                    println!("DEBUG: sending WAIT response {response:?}");
-                    self.write_response(response)?;
-                }
+        let response = match self.server.wait_for_replicas(replica_count, timeout) {
+            Some(count_replicated) => {
+                println!("DEBUG: {count_replicated} replicas have acknowledged all writes");
+                Token::Integer(count_replicated as i64)
             }
-            ReplicaInfo::Slave(_) => panic!("Not expecting to handle WAIT at slave"),
+            None => Token::Error("ERR WAIT cannot be used with replica instances.".to_string()),
         };
+        println!("DEBUG: sending WAIT response {response:?}");
+        self.write_response(response)?;
         Ok(())
     }
 
     fn handle_config(&mut self, config: &ConfigCommand) -> std::io::Result<()> {
         println!("DEBUG: received CONFIG command {config:?}");
-        match config {
+        let response = match config {
             ConfigCommand::Get(param) => {
                 let rdb_config = self.server.metadata.rdb_config.as_ref();
-                let response = match rdb_config {
-                    None => {
-                        unimplemented!("Not expecting to handle CONFIG GET when rdb_config is None")
+                let value = match param.as_str() {
+                    "dir" => rdb_config.map(|rdb| rdb.dir.clone()),
+                    "dbfilename" => rdb_config.map(|rdb| rdb.dbfilename.clone()),
+                    "save" => {
+                        let save_state = self.server.save_state.lock().unwrap();
+                        Some(persistence::format_save_points(&save_state.save_points))
                     }
-                    Some(rdb) => {
-                        let response_key = param.as_str();
-                        let response_value = match response_key {
-                            "dir" => rdb.dir.as_str(),
-                            "dbfilename" => rdb.dbfilename.as_str(),
-                            _ => panic!("Not expecting to handle CONFIG GET for param {param:?}"),
-                        };
+                    _ => None,
+                };
+                match value {
+                    Some(value) => Token::Array(vec![
+                        Token::BulkString(param.as_bytes().to_vec()),
+                        Token::BulkString(value.into_bytes()),
+                    ]),
+                    None => Token::Array(Vec::new()),
+                }
+            }
+            ConfigCommand::Set { param, value } => match param.as_str() {
+                "save" => match persistence::parse_save_points(value) {
+                    Ok(save_points) => {
+                        self.server.save_state.lock().unwrap().save_points = save_points;
+                        Token::SimpleString("OK".to_string())
+                    }
+                    Err(err) => Token::Error(format!(
+                        "ERR Invalid argument '{value}' for CONFIG SET 'save' - {err}"
+                    )),
+                },
+                _ => Token::Error(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{param}'"
+                )),
+            },
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_save(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received SAVE command");
+        let response = if self.server.metadata.rdb_config.is_none() {
+            Token::Error("ERR no RDB location configured, use --dir and --dbfilename".to_string())
+        } else if self.server.save_state.lock().unwrap().bgsave_in_progress {
+            Token::Error("ERR Background save already in progress".to_string())
+        } else {
+            match persistence::rdb::save(&self.server) {
+                Ok(()) => Token::SimpleString("OK".to_string()),
+                Err(err) => {
+                    eprintln!("ERROR: failed to save RDB with error {err:?}");
+                    Token::Error(format!("ERR failed to save RDB: {err}"))
+                }
+            }
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_bgsave(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received BGSAVE command");
+        let response = if self.server.metadata.rdb_config.is_none() {
+            Token::Error("ERR no RDB location configured, use --dir and --dbfilename".to_string())
+        } else if persistence::rdb::start_background_save(self.server.clone()) {
+            Token::SimpleString("Background saving started".to_string())
+        } else {
+            Token::Error("ERR Background save already in progress".to_string())
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_bgrewriteaof(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received BGREWRITEAOF command");
+        let response = if self.server.aof.is_none() {
+            Token::Error("ERR appendonly is not enabled, use --appendonly yes".to_string())
+        } else {
+            match persistence::aof::start_background_rewrite(self.server.clone()) {
+                Ok(true) => {
+                    Token::SimpleString("Background append only file rewriting started".to_string())
+                }
+                Ok(false) => Token::Error(
+                    "ERR Background append only file rewriting already in progress".to_string(),
+                ),
+                Err(err) => {
+                    eprintln!("ERROR: failed to start AOF rewrite with error {err:?}");
+                    Token::Error(format!("ERR failed to start AOF rewrite: {err}"))
+                }
+            }
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_shutdown(&mut self, save: Option<bool>) -> std::io::Result<()> {
+        println!("DEBUG: received SHUTDOWN command {save:?}");
+        match persistence::prepare_shutdown(&self.server, save) {
+            Ok(()) => {
+                println!("INFO: shutting down on request");
+                std::process::exit(0)
+            }
+            Err(err) => {
+                eprintln!("ERROR: failed to prepare the shutdown with error {err:?}");
+                self.write_response(Token::Error(
+                    "ERR Errors trying to SHUTDOWN. Check logs.".to_string(),
+                ))
+            }
+        }
+    }
+
+    fn handle_lastsave(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received LASTSAVE command");
+        let last_save_time = self.server.save_state.lock().unwrap().last_save_time;
+        self.write_response(Token::Integer(last_save_time as i64))?;
+        Ok(())
+    }
+
+    fn handle_replicaof(&mut self, master: &Option<(String, u16)>) -> std::io::Result<()> {
+        println!("DEBUG: received REPLICAOF command {master:?}");
+        if self.server.cluster.is_some() {
+            return self.write_response(Token::Error(
+                "ERR REPLICAOF not allowed in cluster mode.".to_string(),
+            ));
+        }
+        let changed = match master {
+            Some((host, port)) => self.server.become_replica(host.clone(), *port),
+            None => self.server.become_master(),
+        };
+        let response = match (changed, master) {
+            (false, Some(_)) => "OK Already connected to specified master",
+            _ => "OK",
+        };
+        self.write_response(Token::SimpleString(response.to_string()))?;
+        Ok(())
+    }
+
+    fn handle_role(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received ROLE command");
+        let bulk = |value: String| Token::BulkString(value.into_bytes());
+        let response = match &*self.server.live_data.lock().unwrap() {
+            LiveData::Master(data) => {
+                let replicas = data
+                    .replica_manager
+                    .get_replicas_position()
+                    .into_iter()
+                    .map(|(ip, port, offset)| {
                         Token::Array(vec![
-                            Token::BulkString(response_key.as_bytes().to_vec()),
-                            Token::BulkString(response_value.as_bytes().to_vec()),
+                            bulk(ip),
+                            bulk(port.to_string()),
+                            bulk(offset.to_string()),
                         ])
-                    }
-                };
-                self.write_response(response)?;
+                    })
+                    .collect();
+                Token::Array(vec![
+                    bulk("master".to_string()),
+                    Token::Integer(data.replication_offset as i64),
+                    Token::Array(replicas),
+                ])
+            }
+            LiveData::Slave(data) => Token::Array(vec![
+                bulk("slave".to_string()),
+                bulk(data.info.master_host.clone()),
+                Token::Integer(data.info.master_port as i64),
+                bulk(data.link_state.as_str().to_string()),
+                Token::Integer(data.offset as i64),
+            ]),
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_failover(
+        &mut self,
+        target: &Option<(String, u16)>,
+        timeout: Option<Duration>,
+        force: bool,
+        abort: bool,
+    ) -> std::io::Result<()> {
+        println!(
+            "DEBUG: received FAILOVER command with target {target:?} timeout {timeout:?} force {force} abort {abort}"
+        );
+        let result = if abort {
+            failover::abort(&self.server)
+        } else {
+            failover::start(&self.server, target.clone(), timeout, force)
+        };
+        let response = match result {
+            Ok(()) => Token::SimpleString("OK".to_string()),
+            Err(message) => Token::Error(message),
+        };
+        self.write_response(response)?;
+        Ok(())
+    }
+
+    fn handle_asking(&mut self) -> std::io::Result<()> {
+        println!("DEBUG: received ASKING command");
+        if self.server.cluster.is_none() {
+            return self.write_response(Token::Error(
+                "ERR This instance has cluster support disabled".to_string(),
+            ));
+        }
+        self.asking = true;
+        self.write_response(Token::SimpleString("OK".to_string()))
+    }
+
+    /// Returns the error to reply with when the keys of `command` belong to a slot this
+    /// node does not serve in cluster mode. While a slot is migrating, the keys already
+    /// gone are asked for on the target node, which only serves the slot it imports to
+    /// clients announcing themselves with ASKING.
+    fn cluster_redirect(&self, command: &Command, asking: bool) -> Option<Token> {
+        let cluster = self.server.cluster.as_ref()?;
+        let keys = command.keys();
+        let (first, rest) = keys.split_first()?;
+        let slot = key_hash_slot(first);
+        if rest.iter().any(|key| key_hash_slot(key) != slot) {
+            return Some(Token::Error(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+            ));
+        }
+
+        let cluster = cluster.lock().unwrap();
+        match cluster.slot_owner(slot) {
+            Some(node) if node.id == cluster.myself => {
+                let target = cluster
+                    .migrating
+                    .get(&slot)
+                    .and_then(|node_id| cluster.nodes.get(node_id))?;
+                let store = self.server.store.lock().unwrap();
+                let missing = keys.iter().filter(|key| store.get(key).is_none()).count();
+                if missing == 0 {
+                    None
+                } else if missing == keys.len() {
+                    Some(Token::Error(format!(
+                        "ASK {slot} {}:{}",
+                        target.host, target.port
+                    )))
+                } else {
+                    Some(Token::Error(
+                        "TRYAGAIN Multiple keys request during rehashing of slot".to_string(),
+                    ))
+                }
             }
-            #[allow(unused)]
-            _ => panic!("Not expecting to handle CONFIG command {config:?}"),
+            _ if asking && cluster.importing.contains_key(&slot) => None,
+            Some(node) => Some(Token::Error(format!(
+                "MOVED {slot} {}:{}",
+                node.host, node.port
+            ))),
+            None => Some(Token::Error("CLUSTERDOWN Hash slot not served".to_string())),
+        }
+    }
+
+    fn keys_in_slot(&self, slot: u16) -> Vec<Vec<u8>> {
+        let mut keys = self.server.store.lock().unwrap().keys();
+        keys.retain(|key| key_hash_slot(key) == slot);
+        keys
+    }
+
+    fn handle_cluster(&mut self, command: &ClusterCommand) -> std::io::Result<()> {
+        println!("DEBUG: received CLUSTER command {command:?}");
+        let Some(cluster) = &self.server.cluster else {
+            return self.write_response(Token::Error(
+                "ERR This instance has cluster support disabled".to_string(),
+            ));
+        };
+        let bulk = |value: String| Token::BulkString(value.into_bytes());
+        let invalid_slot = || Token::Error("ERR Invalid or out of range slot".to_string());
+        let slot_in_range = |slot: i64| (0..CLUSTER_SLOTS as i64).contains(&slot);
+        let mut new_master = None;
+        let response = {
+            let mut cluster = cluster.lock().unwrap();
+            let response = match command {
+                ClusterCommand::Info => bulk(cluster.info()),
+                ClusterCommand::MyId => bulk(cluster.myself.clone()),
+                ClusterCommand::Nodes => bulk(cluster.nodes_description()),
+                ClusterCommand::Slots => cluster_slots_reply(&cluster),
+                ClusterCommand::Shards => cluster_shards_reply(&cluster),
+                ClusterCommand::KeySlot(key) => Token::Integer(key_hash_slot(key) as i64),
+                ClusterCommand::AddSlots(slots) => match cluster.add_slots(slots) {
+                    Ok(()) => Token::SimpleString("OK".to_string()),
+                    Err(reason) => Token::Error(reason),
+                },
+                ClusterCommand::Meet {
+                    host,
+                    port,
+                    bus_port,
+                } => match bus_port.or(port.checked_add(BUS_PORT_OFFSET)) {
+                    Some(bus_port) => {
+                        cluster.meet(host.clone(), bus_port);
+                        Token::SimpleString("OK".to_string())
+                    }
+                    None => Token::Error("ERR Invalid node address specified".to_string()),
+                },
+                ClusterCommand::Replicate(node_id) => match cluster.replicate(node_id) {
+                    Ok(address) => {
+                        new_master = Some(address);
+                        Token::SimpleString("OK".to_string())
+                    }
+                    Err(reason) => Token::Error(reason),
+                },
+                ClusterCommand::SetSlot { slot, action } => {
+                    let gives_away_keys = match action {
+                        SetSlotAction::Node(node_id) if slot_in_range(*slot) => {
+                            let owner = cluster.slot_owner(*slot as u16);
+                            *node_id != cluster.myself
+                                && owner.is_some_and(|owner| owner.id == cluster.myself)
+                                && !self.keys_in_slot(*slot as u16).is_empty()
+                        }
+                        _ => false,
+                    };
+                    if gives_away_keys {
+                        Token::Error(format!(
+                            "ERR Can't assign hashslot {slot} to a different node while I still hold keys for this hash slot."
+                        ))
+                    } else {
+                        match cluster.set_slot(*slot, action) {
+                            Ok(()) => Token::SimpleString("OK".to_string()),
+                            Err(reason) => Token::Error(reason),
+                        }
+                    }
+                }
+                ClusterCommand::CountKeysInSlot(slot) if slot_in_range(*slot) => {
+                    Token::Integer(self.keys_in_slot(*slot as u16).len() as i64)
+                }
+                ClusterCommand::GetKeysInSlot { slot, count } if slot_in_range(*slot) => {
+                    let mut keys = self.keys_in_slot(*slot as u16);
+                    keys.sort();
+                    keys.truncate(*count);
+                    Token::Array(keys.into_iter().map(Token::BulkString).collect())
+                }
+                ClusterCommand::CountKeysInSlot(_) | ClusterCommand::GetKeysInSlot { .. } => {
+                    invalid_slot()
+                }
+            };
+            cluster.save_if_needed();
+            response
+        };
+        if let Some((host, port)) = new_master {
+            self.server.become_replica(host, port);
         }
+        self.write_response(response)?;
         Ok(())
     }
 
+    /// Replies to the client, commands replicated from our master are never replied to
     fn write_response(&mut self, response: Token) -> std::io::Result<()> {
-        self.stream.write_all(&response.serialize())?;
-        Ok(())
+        match &mut self.stream {
+            Some(stream) if self.master_link.is_none() => stream.write_all(&response.serialize()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `CLUSTER SLOTS`: every slot range with the master serving it followed by its replicas
+fn cluster_slots_reply(cluster: &ClusterState) -> Token {
+    let mut ranges = Vec::new();
+    for master in cluster
+        .nodes
+        .values()
+        .filter(|node| node.role == NodeRole::Master)
+    {
+        for (start, end) in cluster.slot_ranges(&master.id) {
+            let mut range = vec![Token::Integer(start as i64), Token::Integer(end as i64)];
+            let replicas = cluster
+                .nodes
+                .values()
+                .filter(|node| node.role == NodeRole::Replica(master.id.clone()));
+            for node in std::iter::once(master).chain(replicas) {
+                range.push(Token::Array(vec![
+                    Token::BulkString(node.host.clone().into_bytes()),
+                    Token::Integer(node.port as i64),
+                    Token::BulkString(node.id.clone().into_bytes()),
+                ]));
+            }
+            ranges.push((start, Token::Array(range)));
+        }
+    }
+    ranges.sort_by_key(|(start, _)| *start);
+    Token::Array(ranges.into_iter().map(|(_, range)| range).collect())
+}
+
+/// `CLUSTER SHARDS`: every master with its slot ranges and the nodes replicating it
+fn cluster_shards_reply(cluster: &ClusterState) -> Token {
+    let bulk = |value: &str| Token::BulkString(value.as_bytes().to_vec());
+    let shards = cluster
+        .nodes
+        .values()
+        .filter(|node| node.role == NodeRole::Master)
+        .map(|master| {
+            let slots = cluster
+                .slot_ranges(&master.id)
+                .into_iter()
+                .flat_map(|(start, end)| [Token::Integer(start as i64), Token::Integer(end as i64)])
+                .collect();
+            let replicas = cluster
+                .nodes
+                .values()
+                .filter(|node| node.role == NodeRole::Replica(master.id.clone()));
+            let nodes = std::iter::once(master)
+                .chain(replicas)
+                .map(|node| {
+                    let role = match node.role {
+                        NodeRole::Master => "master",
+                        NodeRole::Replica(_) => "replica",
+                    };
+                    let health = match node.health {
+                        NodeHealth::Fail => "fail",
+                        NodeHealth::Online | NodeHealth::PFail => "online",
+                    };
+                    Token::Array(vec![
+                        bulk("id"),
+                        bulk(&node.id),
+                        bulk("port"),
+                        Token::Integer(node.port as i64),
+                        bulk("ip"),
+                        bulk(&node.host),
+                        bulk("endpoint"),
+                        bulk(&node.host),
+                        bulk("role"),
+                        bulk(role),
+                        bulk("replication-offset"),
+                        Token::Integer(node.repl_offset as i64),
+                        bulk("health"),
+                        bulk(health),
+                    ])
+                })
+                .collect();
+            Token::Array(vec![
+                bulk("slots"),
+                Token::Array(slots),
+                bulk("nodes"),
+                Token::Array(nodes),
+            ])
+        })
+        .collect();
+    Token::Array(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use super::*;
+    use crate::network::connection::Connection;
+    use crate::replication::replica_manager::tests::connected_pair;
+    use crate::server::data::tests::test_server;
+    use crate::server::metadata::{MasterInfo, ReplicaInfo};
+    use crate::server::session;
+
+    /// A standalone master serving clients in the background, returned with its port
+    fn start_server() -> (Arc<Server>, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Arc::new(test_server(&[], ReplicaInfo::Master(MasterInfo::new())));
+        let clients = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = clients.clone();
+                std::thread::spawn(move || {
+                    session::handle_connection(&mut Connection::new(stream), server, None)
+                });
+            }
+        });
+        (server, port)
+    }
+
+    fn bulk_strings(elements: &[&[u8]]) -> Vec<Token> {
+        elements
+            .iter()
+            .map(|element| Token::BulkString(element.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_info_unknown_section_is_empty() {
+        let server = Arc::new(test_server(&[], ReplicaInfo::Master(MasterInfo::new())));
+        let (local, mut remote) = connected_pair();
+        let mut handler = CommandHandler::new(local, server, None);
+        for section in [&b"server"[..], b"keyspace", b"all"] {
+            handler
+                .handle_command(&Command::Info(section.to_vec()), b"")
+                .unwrap();
+        }
+        handler
+            .handle_command(&Command::Info(b"REPLICATION".to_vec()), b"")
+            .unwrap();
+        drop(handler);
+        let mut reply = Vec::new();
+        remote.read_to_end(&mut reply).unwrap();
+        assert!(reply.starts_with(b"$0\r\n\r\n$0\r\n\r\n$0\r\n\r\n$"));
+        assert!(String::from_utf8_lossy(&reply).contains("role:master"));
+    }
+
+    #[test]
+    fn test_del_counts_removed_keys() {
+        let store = ExpiringHashMap::new();
+        store.set(b"a", b"1", None);
+        store.set(b"b", b"2", None);
+        let keys = [b"a".to_vec(), b"b".to_vec(), b"missing".to_vec()];
+        assert_eq!(
+            CommandHandler::handle_del(&store, &keys),
+            (Token::Integer(2), 2)
+        );
+        assert_eq!(
+            CommandHandler::handle_del(&store, &keys),
+            (Token::Integer(0), 0)
+        );
+    }
+
+    #[test]
+    fn test_pop_replies() {
+        let store = ExpiringHashMap::new();
+        assert_eq!(
+            CommandHandler::handle_pop(&store, b"list", None, true),
+            (Token::BulkString(Vec::new()), 0)
+        );
+        let (reply, changes) = CommandHandler::handle_pop(&store, b"list", Some(2), true);
+        assert_eq!((&reply, changes), (&Token::NullArray, 0));
+        assert_eq!(reply.serialize(), b"*-1\r\n");
+
+        let elements = [b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+        CommandHandler::handle_push(&store, b"list", &elements, false);
+        assert_eq!(
+            CommandHandler::handle_pop(&store, b"list", Some(0), true),
+            (Token::Array(Vec::new()), 0)
+        );
+        assert_eq!(
+            CommandHandler::handle_pop(&store, b"list", Some(2), true),
+            (Token::Array(bulk_strings(&[b"a", b"b"])), 2)
+        );
+        assert_eq!(
+            CommandHandler::handle_pop(&store, b"list", None, false),
+            (Token::BulkString(b"d".to_vec()), 1)
+        );
+        assert_eq!(
+            CommandHandler::handle_pop(&store, b"list", Some(5), false),
+            (Token::Array(bulk_strings(&[b"c"])), 1)
+        );
+        assert!(store.get(b"list").is_none());
+    }
+
+    #[test]
+    fn test_list_writes_count_changed_elements() {
+        let store = ExpiringHashMap::new();
+        let elements = [b"a", b"b", b"a", b"c", b"a"].map(|element| element.to_vec());
+        let (_, changes) = CommandHandler::handle_push(&store, b"list", &elements, false);
+        assert_eq!(changes, 5);
+        let (_, changes) = CommandHandler::handle_lrem(&store, b"list", 2, b"a");
+        assert_eq!(changes, 2);
+        let (_, changes) = CommandHandler::handle_linsert(&store, b"list", true, b"x", b"y");
+        assert_eq!(changes, 0);
+        let (_, changes) = CommandHandler::handle_linsert(&store, b"list", true, b"c", b"y");
+        assert_eq!(changes, 1);
+        // b, y, c, a trimmed down to y, c
+        let (_, changes) = CommandHandler::handle_ltrim(&store, b"list", 1, 2);
+        assert_eq!(changes, 2);
+        let (_, changes) = CommandHandler::handle_lmove(&store, b"list", b"other", true, true);
+        assert_eq!(changes, 1);
+        let (_, changes) = CommandHandler::handle_lmove(&store, b"missing", b"other", true, true);
+        assert_eq!(changes, 0);
+
+        store.set(b"string", b"value", None);
+        let (reply, changes) = CommandHandler::handle_push(&store, b"string", &elements, true);
+        assert!(matches!(reply, Token::Error(_)));
+        assert_eq!(changes, 0);
+    }
+
+    #[test]
+    fn test_migrate_between_standalone_servers() {
+        let (target, port) = start_server();
+        let server = Arc::new(test_server(&[], ReplicaInfo::Master(MasterInfo::new())));
+        server.set(b"a", Value::String(b"1".to_vec()), None);
+        server.set(b"b", Value::String(b"2".to_vec()), None);
+        let (local, _remote) = connected_pair();
+        let handler = CommandHandler::new(local, server.clone(), None);
+
+        let keys = [b"a".to_vec(), b"b".to_vec()];
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            handler.handle_migrate("127.0.0.1", port, &keys, 0, timeout, false, false),
+            Token::SimpleString("OK".to_string())
+        );
+        assert_eq!(target.get(b"a"), Some(Value::String(b"1".to_vec())));
+        assert_eq!(target.get(b"b"), Some(Value::String(b"2".to_vec())));
+        assert!(server.get(b"a").is_none() && server.get(b"b").is_none());
+    }
+
+    #[test]
+    fn test_migrate_refused_write_copies_nothing() {
+        let (target, port) = start_server();
+        let server = Arc::new(test_server(
+            &["--min-replicas-to-write", "1"],
+            ReplicaInfo::Master(MasterInfo::new()),
+        ));
+        server.set(b"a", Value::String(b"1".to_vec()), None);
+        let (local, _remote) = connected_pair();
+        let handler = CommandHandler::new(local, server.clone(), None);
+
+        let keys = [b"a".to_vec()];
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            handler.handle_migrate("127.0.0.1", port, &keys, 0, timeout, false, false),
+            Token::Error("NOREPLICAS Not enough good replicas to write.".to_string())
+        );
+        assert!(target.get(b"a").is_none());
+        assert!(server.get(b"a").is_some());
+
+        // copying does not delete anything, so it is not a write here
+        assert_eq!(
+            handler.handle_migrate("127.0.0.1", port, &keys, 0, timeout, true, false),
+            Token::SimpleString("OK".to_string())
+        );
+        assert!(target.get(b"a").is_some() && server.get(b"a").is_some());
+    }
+
+    #[test]
+    fn test_client_replconf_getack_and_unknown_option() {
+        let server = Arc::new(test_server(&[], ReplicaInfo::Master(MasterInfo::new())));
+        let (local, mut remote) = connected_pair();
+        let mut handler = CommandHandler::new(local, server, None);
+        for command in [
+            Command::ReplConf(ReplConfCommand::GetAck("*".to_string())),
+            Command::ReplConf(ReplConfCommand::Other("rdb-only".to_string())),
+            Command::Ping,
+        ] {
+            handler.handle_command(&command, b"").unwrap();
+        }
+        drop(handler);
+        let mut reply = Vec::new();
+        remote.read_to_end(&mut reply).unwrap();
+        assert_eq!(
+            reply,
+            b"-ERR Unrecognized REPLCONF option: rdb-only\r\n+PONG\r\n"
+        );
+    }
+
+    #[test]
+    fn test_bad_list_argument_keeps_connection_open() {
+        let (_server, port) = start_server();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"*3\r\n$4\r\nLPOP\r\n$1\r\nk\r\n$2\r\n-1\r\n*1\r\n$4\r\nPING\r\n")
+            .unwrap();
+        let expected: &[u8] = b"-ERR value is out of range, must be positive\r\n+PONG\r\n";
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected);
     }
 }
@@ -1,14 +1,56 @@
-use crate::common::CRLF;
+use std::{path::PathBuf, time::Duration};
 
-use super::config::Config;
+use crate::{common::random_hex_id, persistence::SavePoint};
+
+use super::config::{AppendFsync, Config, ReplDisklessLoad};
+
+pub const REPLICATION_ID_LEN: usize = 40;
 
 #[derive(Debug)]
 pub struct MasterInfo {
     pub replication_id: String,
+    /// Replication ID of the master we were replicating from before being promoted,
+    /// all zeros if we have always been a master
+    pub replication_id2: String,
+    /// Offset up to which `replication_id2` histories are shared with ours, -1 if unset
+    pub second_replication_offset: i64,
+}
+
+impl Default for MasterInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MasterInfo {
+    pub fn new() -> Self {
+        Self {
+            replication_id: random_hex_id(REPLICATION_ID_LEN),
+            replication_id2: "0".repeat(REPLICATION_ID_LEN),
+            second_replication_offset: -1,
+        }
+    }
+
+    /// Starts a new history for a promoted replica while remembering the old one, so
+    /// that replicas of the previous master can still partially resynchronize with us
+    /// for everything up to `offset`
+    pub fn promoted_from(previous_replication_id: &str, offset: usize) -> Self {
+        Self {
+            replication_id: random_hex_id(REPLICATION_ID_LEN),
+            replication_id2: previous_replication_id.to_string(),
+            second_replication_offset: offset as i64 + 1,
+        }
+    }
+
+    /// Whether a replica asking for `psync_offset` of `replication_id` shares our history
+    pub fn can_continue(&self, replication_id: &str, psync_offset: i64) -> bool {
+        replication_id == self.replication_id
+            || (replication_id == self.replication_id2
+                && psync_offset <= self.second_replication_offset)
+    }
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct SlaveInfo {
     pub master_host: String,
     pub master_port: u16,
@@ -20,36 +62,89 @@ pub enum ReplicaInfo {
     Slave(SlaveInfo),
 }
 
+impl ReplicaInfo {
+    /// Role the server starts with, it can later be changed with REPLICAOF
+    pub fn from_config(config: &Config) -> Self {
+        match config.master_address() {
+            Some((master_host, master_port)) => {
+                println!("INFO: starting as slave");
+                ReplicaInfo::Slave(SlaveInfo {
+                    master_host,
+                    master_port,
+                })
+            }
+            None => {
+                println!("INFO: starting as master");
+                ReplicaInfo::Master(MasterInfo::new())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RdbConfig {
     pub dir: String,
     pub dbfilename: String,
 }
 
+impl RdbConfig {
+    pub fn get_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AofConfig {
+    /// Directory holding the files of the append-only file
+    pub dir: PathBuf,
+    /// Prefix of the files in `dir`
+    pub filename: String,
+    /// Where a single file append-only file of an older version would be
+    pub legacy_path: PathBuf,
+    pub fsync: AppendFsync,
+    pub use_rdb_preamble: bool,
+    pub auto_rewrite_percentage: u64,
+    pub auto_rewrite_min_size: u64,
+}
+
+impl AofConfig {
+    fn from_config(config: &Config) -> Self {
+        let data_dir = PathBuf::from(config.get_data_dir().unwrap_or("."));
+        AofConfig {
+            dir: data_dir.join(config.get_appenddirname()),
+            filename: config.get_appendfilename().to_string(),
+            legacy_path: data_dir.join(config.get_appendfilename()),
+            fsync: config.get_appendfsync(),
+            use_rdb_preamble: config.is_aof_use_rdb_preamble(),
+            auto_rewrite_percentage: config.get_auto_aof_rewrite_percentage(),
+            auto_rewrite_min_size: config.get_auto_aof_rewrite_min_size(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerMetadata {
     pub listening_port: u16,
-    pub replica_info: ReplicaInfo,
     pub rdb_config: Option<RdbConfig>,
+    /// Save points the server starts with, CONFIG SET changes those of `SaveState`
+    pub save_points: Vec<SavePoint>,
+    pub repl_backlog_size: usize,
+    pub repl_timeout: Duration,
+    pub repl_ping_replica_period: Duration,
+    pub min_replicas_to_write: usize,
+    /// Lag in seconds, compared against `Replica::lag`
+    pub min_replicas_max_lag: u64,
+    pub replica_read_only: bool,
+    pub repl_diskless_load: ReplDisklessLoad,
+    /// Set when appendonly is enabled
+    pub aof_config: Option<AofConfig>,
+    /// Node config file, set when cluster mode is enabled
+    pub cluster_config_path: Option<PathBuf>,
+    pub cluster_node_timeout: Duration,
 }
 
 impl ServerMetadata {
     pub fn generate(config: &Config) -> Self {
-        let replica_info = match config.master_address() {
-            Some((master_host, master_port)) => {
-                println!("INFO: starting as slave");
-                ReplicaInfo::Slave(SlaveInfo {
-                    master_host,
-                    master_port,
-                })
-            }
-            None => {
-                println!("INFO: starting as master");
-                ReplicaInfo::Master(MasterInfo {
-                    replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
-                })
-            }
-        };
         let rdb_config = match (config.get_data_dir(), config.get_dbfilename()) {
             (Some(dir), Some(dbfilename)) => Some(RdbConfig {
                 dir: dir.to_string(),
@@ -57,25 +152,61 @@ impl ServerMetadata {
             }),
             _ => None,
         };
+        let aof_config = config
+            .is_appendonly()
+            .then(|| AofConfig::from_config(config));
+        let cluster_config_path = config.is_cluster_enabled().then(|| {
+            PathBuf::from(config.get_data_dir().unwrap_or("."))
+                .join(config.get_cluster_config_file())
+        });
         ServerMetadata {
             listening_port: config.get_listening_port(),
-            replica_info,
             rdb_config,
+            save_points: config.get_save_points(),
+            repl_backlog_size: config.get_repl_backlog_size(),
+            repl_timeout: config.get_repl_timeout(),
+            repl_ping_replica_period: config.get_repl_ping_replica_period(),
+            min_replicas_to_write: config.get_min_replicas_to_write(),
+            min_replicas_max_lag: config.get_min_replicas_max_lag(),
+            replica_read_only: config.is_replica_read_only(),
+            repl_diskless_load: config.get_repl_diskless_load(),
+            aof_config,
+            cluster_config_path,
+            cluster_node_timeout: config.get_cluster_node_timeout(),
         }
     }
+}
 
-    pub fn get_replica_info(&self) -> Vec<u8> {
-        match &self.replica_info {
-            ReplicaInfo::Master(master_info) => {
-                format!(
-                    "role:master{CRLF}master_replid:{}{CRLF}master_repl_offset:{}",
-                    master_info.replication_id,
-                    0 // TODO: Add replication offset
-                )
-                .as_bytes()
-                .to_vec()
-            }
-            ReplicaInfo::Slave(_slave_info) => b"role:slave".to_vec(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_master_info() {
+        let info = MasterInfo::new();
+        assert_eq!(info.replication_id.len(), REPLICATION_ID_LEN);
+        assert_eq!(info.replication_id2, "0".repeat(REPLICATION_ID_LEN));
+        assert_eq!(info.second_replication_offset, -1);
+        assert_ne!(info.replication_id, MasterInfo::new().replication_id);
+    }
+
+    #[test]
+    fn test_can_continue() {
+        let info = MasterInfo::new();
+        assert!(info.can_continue(&info.replication_id, 1));
+        assert!(info.can_continue(&info.replication_id, 1_000_000));
+        assert!(!info.can_continue(&"f".repeat(REPLICATION_ID_LEN), 1));
+        // the all zeros ID of a master which was never promoted leads nowhere
+        assert!(!info.can_continue(&info.replication_id2, 1));
+
+        let previous = MasterInfo::new();
+        let promoted = MasterInfo::promoted_from(&previous.replication_id, 100);
+        assert_ne!(promoted.replication_id, previous.replication_id);
+        assert_eq!(promoted.replication_id2, previous.replication_id);
+        assert_eq!(promoted.second_replication_offset, 101);
+        assert!(promoted.can_continue(&previous.replication_id, 1));
+        assert!(promoted.can_continue(&previous.replication_id, 101));
+        assert!(!promoted.can_continue(&previous.replication_id, 102));
+        assert!(promoted.can_continue(&promoted.replication_id, 500));
     }
 }
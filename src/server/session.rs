@@ -0,0 +1,92 @@
+use std::{io, sync::Arc, time::Instant};
+
+use crate::{
+    network::connection::Connection,
+    parser::{command::parse_command, resp::ParseError},
+    replication::link::{self, MasterLink},
+};
+
+use super::{data::Server, handler::CommandHandler};
+
+/// Reads commands off the connection and dispatches them until the peer goes away.
+/// `master_link` is set when the connection is our replication link to a master.
+fn handle_read_loop(
+    conn: &mut Connection,
+    server: Arc<Server>,
+    master_link: Option<&Arc<MasterLink>>,
+) -> io::Result<()> {
+    let mut handler = CommandHandler::new(
+        conn.stream.try_clone()?,
+        server.clone(),
+        master_link.cloned(),
+    );
+
+    loop {
+        match parse_command(conn.get_buffer()) {
+            Ok(result) => {
+                // a link we were told to abandon must not apply anything further
+                if master_link.is_some_and(|link| link.is_stopped()) {
+                    return Ok(());
+                }
+
+                let command = result.command;
+
+                handler.handle_command(&command, &conn.get_buffer()[..result.len])?;
+
+                conn.consume(result.len);
+            }
+            Err(ParseError::Incomplete) => match conn.read_message() {
+                // Not enough data to parse the command
+                Ok(()) => {
+                    if let Some(master_link) = master_link {
+                        link::with_link_data(&server, master_link, |data| {
+                            data.heartbeat_recv_time = Some(Instant::now())
+                        });
+                    }
+                }
+                Err(err)
+                    if master_link.is_some()
+                        && matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                {
+                    link::check_master_heartbeat(&server, master_link.unwrap())?
+                }
+                Err(err) => return Err(err),
+            },
+            Err(ParseError::Invalid) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid message format",
+                ));
+            }
+        }
+    }
+}
+
+pub fn handle_connection(
+    conn: &mut Connection,
+    server: Arc<Server>,
+    master_link: Option<&Arc<MasterLink>>,
+) -> io::Result<()> {
+    match handle_read_loop(conn, server.clone(), master_link) {
+        Ok(_) => {
+            println!("INFO: client disconnected");
+        }
+        Err(err) => {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                println!("INFO: client disconnected");
+            } else {
+                eprintln!("ERROR: failed to handle connection with error {:?}", &err);
+            }
+        }
+    }
+    // TOOD: Instead of calling remove_replica unconditionally, propagate replica status for the client here
+    // and only make remove_replica call if the client is actively replicating
+    //
+    // We cannot know whether there is atleast one replica listening for commands since this
+    // information is only known by replication sub-system, hence propagate all commands
+    server.handle_disconnect(conn);
+    Ok(())
+}
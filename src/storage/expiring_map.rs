@@ -5,10 +5,12 @@ use std::{
     time::{Duration, Instant},
 };
 
+use super::value::Value;
+
 type BinaryData = Vec<u8>;
 type KeyType = BinaryData;
 type Expiry = Option<Instant>;
-type ValueType = (BinaryData, Expiry);
+type ValueType = (Value, Expiry);
 type Store = RwLock<HashMap<KeyType, ValueType>>;
 type StopCondition = (Mutex<bool>, Condvar);
 
@@ -52,7 +54,7 @@ impl ExpiringHashMap {
         map
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<BinaryData> {
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
         let store = self.store.write().unwrap();
 
         if let Some((_, Some(ttl))) = store.get(key) {
@@ -66,11 +68,99 @@ impl ExpiringHashMap {
         store.get(key).map(|(value, _)| value).cloned()
     }
 
+    /// Stores a string value, replacing whatever the key held
     pub fn set(&self, key: &[u8], value: &[u8], expiry: Option<Duration>) {
+        self.set_value(key, Value::String(value.to_vec()), expiry);
+    }
+
+    /// Stores a value of any type, replacing whatever the key held. An empty list
+    /// removes the key instead.
+    pub fn set_value(&self, key: &[u8], value: Value, expiry: Option<Duration>) {
         let ttl = Self::calculate_ttl(expiry);
         let mut store = self.store.write().unwrap();
 
-        store.insert(key.to_vec(), (value.to_vec(), ttl));
+        if value.is_empty() {
+            store.remove(key);
+        } else {
+            store.insert(key.to_vec(), (value, ttl));
+        }
+    }
+
+    /// Runs `f` on the value of a live key, or on `None` if there is no such key
+    pub fn read<R>(&self, key: &[u8], f: impl FnOnce(Option<&Value>) -> R) -> R {
+        let store = self.store.read().unwrap();
+        match store.get(key) {
+            Some((_, Some(ttl))) if ttl < &Instant::now() => f(None),
+            Some((value, _)) => f(Some(value)),
+            None => f(None),
+        }
+    }
+
+    /// Lets `f` modify the value of a key in place, `None` standing for a missing key.
+    /// The key keeps its expiry, unless `f` creates it in which case it has none, and
+    /// is removed if `f` leaves it without a value or with an empty list.
+    pub fn update<R>(&self, key: &[u8], f: impl FnOnce(&mut Option<Value>) -> R) -> R {
+        let mut store = self.store.write().unwrap();
+        let current_time = Instant::now();
+        let (mut value, expiry) = match store.remove(key) {
+            Some((_, Some(ttl))) if ttl < current_time => (None, None),
+            Some((value, expiry)) => (Some(value), expiry),
+            None => (None, None),
+        };
+
+        let result = f(&mut value);
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            store.insert(key.to_vec(), (value, expiry));
+        }
+        result
+    }
+
+    /// Returns the value of a live key along with its expiry deadline
+    pub fn get_with_expiry(&self, key: &[u8]) -> Option<(Value, Expiry)> {
+        let store = self.store.read().unwrap();
+        match store.get(key) {
+            Some((_, Some(ttl))) if ttl < &Instant::now() => None,
+            Some((value, expiry)) => Some((value.clone(), *expiry)),
+            None => None,
+        }
+    }
+
+    /// Removes a key, returning whether it existed and had not expired yet
+    pub fn remove(&self, key: &[u8]) -> bool {
+        let mut store = self.store.write().unwrap();
+        match store.remove(key) {
+            Some((_, Some(ttl))) => ttl >= Instant::now(),
+            Some((_, None)) => true,
+            None => false,
+        }
+    }
+
+    /// Every live key, in no particular order
+    pub fn keys(&self) -> Vec<KeyType> {
+        let store = self.store.read().unwrap();
+        let current_time = Instant::now();
+
+        store
+            .iter()
+            .filter(|(_, (_, expiry))| expiry.is_none_or(|ttl| ttl >= current_time))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.store.write().unwrap().clear();
+    }
+
+    /// Copies out every live key along with its expiry deadline
+    pub fn snapshot(&self) -> Vec<(KeyType, Value, Expiry)> {
+        let store = self.store.read().unwrap();
+        let current_time = Instant::now();
+
+        store
+            .iter()
+            .filter(|(_, (_, expiry))| expiry.is_none_or(|ttl| ttl >= current_time))
+            .map(|(key, (value, expiry))| (key.clone(), value.clone(), *expiry))
+            .collect()
     }
 
     fn calculate_ttl(expiry: Option<Duration>) -> Option<Instant> {
@@ -1 +1,2 @@
 pub mod expiring_map;
+pub mod value;
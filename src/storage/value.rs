@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+
+use crate::parser::rdb::RdbValue;
+use crate::parser::resp::Token;
+
+/// A value held by a key of the store
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+}
+
+/// A command was run against a key holding a type it does not operate on
+#[derive(Debug, PartialEq)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        )
+    }
+}
+
+impl From<WrongType> for Token {
+    fn from(value: WrongType) -> Self {
+        Token::Error(value.to_string())
+    }
+}
+
+impl Value {
+    /// Name of the type as reported by Redis
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Vec<u8>, WrongType> {
+        match self {
+            Value::String(value) => Ok(value),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Vec<u8>>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Vec<u8>>, WrongType> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(WrongType),
+        }
+    }
+
+    /// Whether the value holds nothing, which for a list means its key must go
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+        }
+    }
+}
+
+impl From<Value> for RdbValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(value) => RdbValue::String(value),
+            Value::List(list) => RdbValue::List(list.into()),
+        }
+    }
+}
+
+/// Gives the value back when it is of a type the store cannot hold
+impl TryFrom<RdbValue> for Value {
+    type Error = RdbValue;
+
+    fn try_from(value: RdbValue) -> Result<Self, Self::Error> {
+        match value {
+            RdbValue::String(value) => Ok(Value::String(value)),
+            RdbValue::List(list) => Ok(Value::List(list.into())),
+            value => Err(value),
+        }
+    }
+}
+
+/// Resolves an index which may count from the tail, -1 being the last element, into
+/// a position of a list of `len` elements
+pub fn list_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+/// Resolves the inclusive `start` and `stop` indexes of LRANGE and LTRIM into the
+/// positions they cover, clamping them to the list like Redis does
+pub fn list_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+/// Takes an element from the head of a list, or its tail when `left` is false
+pub fn list_pop(list: &mut VecDeque<Vec<u8>>, left: bool) -> Option<Vec<u8>> {
+    match left {
+        true => list.pop_front(),
+        false => list.pop_back(),
+    }
+}
+
+/// Adds an element at the head of a list, or its tail when `left` is false
+pub fn list_push(list: &mut VecDeque<Vec<u8>>, element: Vec<u8>, left: bool) {
+    match left {
+        true => list.push_front(element),
+        false => list.push_back(element),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_index() {
+        assert_eq!(list_index(3, 0), Some(0));
+        assert_eq!(list_index(3, 2), Some(2));
+        assert_eq!(list_index(3, -1), Some(2));
+        assert_eq!(list_index(3, -3), Some(0));
+        assert_eq!(list_index(3, 3), None);
+        assert_eq!(list_index(3, -4), None);
+        assert_eq!(list_index(0, 0), None);
+    }
+
+    #[test]
+    fn test_list_range() {
+        assert_eq!(list_range(5, 0, -1), 0..5);
+        assert_eq!(list_range(5, 1, 2), 1..3);
+        assert_eq!(list_range(5, -2, 100), 3..5);
+        assert_eq!(list_range(5, -100, 0), 0..1);
+        assert!(list_range(5, 3, 1).is_empty());
+        assert!(list_range(5, 5, 10).is_empty());
+        assert!(list_range(5, 0, -6).is_empty());
+        assert!(list_range(0, 0, -1).is_empty());
+    }
+
+    #[test]
+    fn test_rdb_value_conversions() {
+        let list = Value::List(VecDeque::from([b"a".to_vec(), b"b".to_vec()]));
+        let rdb_value = RdbValue::from(list.clone());
+        assert_eq!(
+            rdb_value,
+            RdbValue::List(vec![b"a".to_vec(), b"b".to_vec()])
+        );
+        assert_eq!(Value::try_from(rdb_value), Ok(list));
+
+        let set = RdbValue::Set(vec![b"a".to_vec()]);
+        assert_eq!(Value::try_from(set.clone()), Err(set));
+    }
+}